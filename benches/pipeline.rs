@@ -0,0 +1,34 @@
+//! drive the peer pipeline with synthetic peers and report its throughput, eg.
+//!
+//! cargo bench --features bench --bench pipeline -- --peers 8 --pieces 1024 --piece-length 262144 --rate 10485760
+
+use std::{env, str::FromStr};
+
+use tsunami::bench::{self, BenchOptions};
+
+fn value<T: FromStr>(args: &mut impl Iterator<Item = String>, name: &str) -> T {
+    match args.next().map(|v| v.parse()) {
+        Some(Ok(v)) => v,
+        _ => panic!("{name} expects a number"),
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let mut opts = BenchOptions::default();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--peers" => opts.peers = value(&mut args, &arg),
+            "--pieces" => opts.pieces = value(&mut args, &arg),
+            "--piece-length" => opts.piece_length = value(&mut args, &arg),
+            "--rate" => opts.rate = Some(value(&mut args, &arg)),
+            // cargo passes --bench to every benchmark
+            _ => {}
+        }
+    }
+
+    let report = bench::run(&opts).await.expect("benchmark failed");
+    println!("{report}");
+}
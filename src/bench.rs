@@ -0,0 +1,333 @@
+//! synthetic load for the peer pipeline, used by `benches/pipeline.rs` to measure throughput
+//! before and after optimizations
+
+use std::{
+    collections::HashMap,
+    env, fmt, fs, io,
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bitvec::prelude::{bitbox, Lsb0};
+use futures::future::join_all;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use ring::digest;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    task, time,
+};
+
+use crate::{
+    disk::DiskReader,
+    peer::{Message, Peer, Timeouts},
+    picker::{PickContext, PieceStrategy, RarestFirst},
+    proxy::Dialer,
+    storage::FileSpan,
+    torrent::Sha1Hash,
+};
+
+// size of the blocks synthetic peers send pieces in
+const BLOCK_LEN: u32 = 16 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// number of synthetic peers sending pieces at once
+    pub peers: usize,
+    pub pieces: u32,
+    pub piece_length: u32,
+    /// bytes/s each synthetic peer sends at, unlimited if None
+    pub rate: Option<u64>,
+}
+
+impl Default for BenchOptions {
+    fn default() -> BenchOptions {
+        BenchOptions {
+            peers: 4,
+            pieces: 256,
+            piece_length: 256 * 1024,
+            rate: None,
+        }
+    }
+}
+
+/// Report is what a single run of the pipeline measured. stage durations are the time spent in
+/// that stage summed over every peer
+#[derive(Debug, Default)]
+pub struct Report {
+    /// peer messages decoded
+    pub messages: u64,
+    /// bytes of pieces which matched their hash
+    pub verified: u64,
+    pub pick: Duration,
+    pub hash: Duration,
+    pub disk_write: Duration,
+    pub disk_read: Duration,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64();
+        let mib = self.verified as f64 / (1024.0 * 1024.0);
+        let rate = self.messages as f64 / secs;
+        let (write, read) = (self.disk_write, self.disk_read);
+
+        writeln!(f, "elapsed:  {:?}", self.elapsed)?;
+        writeln!(f, "messages: {} ({rate:.0}/s)", self.messages)?;
+        writeln!(f, "verified: {mib:.1} MiB ({:.1} MiB/s)", mib / secs)?;
+        writeln!(f, "pick:     {:?}", self.pick)?;
+        writeln!(f, "hash:     {:?}", self.hash)?;
+        writeln!(f, "disk:     {write:?} write, {read:?} read")
+    }
+}
+
+/// Swarm is the torrent being benchmarked: random pieces and their hashes
+struct Swarm {
+    info_hash: Sha1Hash,
+    pieces: Vec<Vec<u8>>,
+    hashes: Vec<Sha1Hash>,
+}
+
+/// download a random torrent from opts.peers synthetic peers over loopback, running every
+/// piece through the decoder, hasher and disk, then read it back from disk and verify it again
+pub async fn run(opts: &BenchOptions) -> io::Result<Report> {
+    let swarm = Arc::new(Swarm::new(opts.pieces, opts.piece_length));
+    let mut report = Report::default();
+
+    let path = env::temp_dir().join(format!("tsunami_bench_{}", process::id()));
+    fs::File::create(&path)?.set_len(opts.pieces as u64 * opts.piece_length as u64)?;
+
+    let start = Instant::now();
+    let assigned = assign_pieces(&swarm, opts.peers.max(1), &mut report);
+
+    let mut peers = vec![];
+    let dialer = Dialer::default();
+    for pieces in assigned {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        task::spawn(seed(listener, swarm.clone(), pieces, opts.rate));
+
+        let (peer_id, timeouts) = (b"-TS0001-benchmarking", Timeouts::default());
+        let peer = Peer::connect(addr, &swarm.info_hash, peer_id, 0, &dialer, timeouts)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e))?;
+        peers.push(peer);
+    }
+
+    let downloads = peers.into_iter().map(|peer| download(peer, &swarm, &path));
+    for res in join_all(downloads).await {
+        let stats = res?;
+        report.messages += stats.messages;
+        report.verified += stats.verified;
+        report.hash += stats.hash;
+        report.disk_write += stats.disk_write;
+    }
+
+    let read_start = Instant::now();
+    let span = FileSpan {
+        path: path.clone(),
+        length: opts.pieces as u64 * opts.piece_length as u64,
+        padding: false,
+    };
+    let reader = DiskReader::new(vec![span], opts.piece_length);
+    for (i, hash) in swarm.hashes.iter().enumerate() {
+        let piece = reader.read(i as u32, 0, opts.piece_length).await?;
+        if sha1(&piece) != *hash {
+            let msg = "piece corrupted on disk";
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+    }
+    report.disk_read = read_start.elapsed();
+    report.elapsed = start.elapsed();
+
+    fs::remove_file(&path)?;
+    Ok(report)
+}
+
+impl Swarm {
+    fn new(pieces: u32, piece_length: u32) -> Swarm {
+        let mut rng = SmallRng::seed_from_u64(pieces as u64);
+        let mut info_hash = [0; 20];
+        rng.fill(&mut info_hash);
+
+        let pieces: Vec<_> = (0..pieces)
+            .map(|_| {
+                let mut piece = vec![0; piece_length as usize];
+                rng.fill(&mut piece[..]);
+                piece
+            })
+            .collect();
+
+        Swarm {
+            info_hash,
+            hashes: pieces.iter().map(|p| sha1(p)).collect(),
+            pieces,
+        }
+    }
+}
+
+/// split the torrent's pieces between peers, in the order the picker chooses them
+fn assign_pieces(swarm: &Swarm, peers: usize, report: &mut Report) -> Vec<Vec<u32>> {
+    let total = swarm.pieces.len();
+    let mut rng = SmallRng::seed_from_u64(total as u64);
+    let availability: Vec<u16> = (0..total).map(|_| rng.gen_range(1..50)).collect();
+    let peer_has = bitbox![usize, Lsb0; 1; total];
+    let mut ours = bitbox![usize, Lsb0; 0; total];
+
+    let start = Instant::now();
+    let mut assigned = vec![vec![]; peers];
+    let picker = RarestFirst;
+    for i in 0.. {
+        let ctx = PickContext {
+            peer_has: &peer_has,
+            ours: &ours,
+            availability: &availability,
+        };
+        let Some(piece) = picker.pick(&ctx) else {
+            break;
+        };
+
+        ours.set(piece as usize, true);
+        assigned[i % peers].push(piece);
+    }
+    report.pick = start.elapsed();
+
+    assigned
+}
+
+/// act as a seeding peer: answer one handshake then send pieces, limited to rate bytes/s
+async fn seed(
+    listener: TcpListener,
+    swarm: Arc<Swarm>,
+    pieces: Vec<u32>,
+    rate: Option<u64>,
+) -> io::Result<()> {
+    let (mut conn, _) = listener.accept().await?;
+
+    let mut handshake = [0; 68];
+    conn.read_exact(&mut handshake).await?;
+    conn.write_all(&handshake[..48]).await?;
+    conn.write_all(b"-XX0001-syntheticpee").await?;
+
+    let start = Instant::now();
+    let mut sent = 0;
+    for index in pieces {
+        let piece = &swarm.pieces[index as usize];
+
+        for (i, block) in piece.chunks(BLOCK_LEN as usize).enumerate() {
+            let mut header = [0; 13];
+            header[..4].copy_from_slice(&(9 + block.len() as u32).to_be_bytes());
+            header[4] = 7;
+            header[5..9].copy_from_slice(&index.to_be_bytes());
+            header[9..].copy_from_slice(&(i as u32 * BLOCK_LEN).to_be_bytes());
+            conn.write_all(&header).await?;
+            conn.write_all(block).await?;
+
+            sent += header.len() + block.len();
+            if let Some(rate) = rate {
+                let due = Duration::from_secs_f64(sent as f64 / rate.max(1) as f64);
+                time::sleep_until((start + due).into()).await;
+            }
+        }
+    }
+
+    conn.shutdown().await
+}
+
+#[derive(Debug, Default)]
+struct PeerStats {
+    messages: u64,
+    verified: u64,
+    hash: Duration,
+    disk_write: Duration,
+}
+
+/// receive pieces from peer until it disconnects, verifying and writing each completed piece
+async fn download(mut peer: Peer, swarm: &Swarm, path: &Path) -> io::Result<PeerStats> {
+    let mut stats = PeerStats::default();
+    let piece_length = swarm.pieces[0].len();
+    let mut partial: HashMap<u32, (Vec<u8>, usize)> = HashMap::new();
+
+    // the synthetic peer closes the connection once it has sent everything
+    while let Ok(msg) = peer.decode_message().await {
+        stats.messages += 1;
+        let (index, begin, block) = match msg {
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => (index, begin, block),
+            _ => continue,
+        };
+
+        let (piece, received) = partial
+            .entry(index)
+            .or_insert_with(|| (vec![0; piece_length], 0));
+        let begin = begin as usize;
+        piece[begin..begin + block.len()].copy_from_slice(&block);
+        *received += block.len();
+        if *received < piece_length {
+            continue;
+        }
+
+        let (piece, _) = partial.remove(&index).unwrap();
+        let hash_start = Instant::now();
+        let valid = swarm.hashes.get(index as usize) == Some(&sha1(&piece));
+        stats.hash += hash_start.elapsed();
+        if !valid {
+            continue;
+        }
+
+        let write_start = Instant::now();
+        let offset = index as u64 * piece_length as u64;
+        write_piece(path.to_path_buf(), offset, piece).await?;
+        stats.disk_write += write_start.elapsed();
+        stats.verified += piece_length as u64;
+    }
+
+    Ok(stats)
+}
+
+async fn write_piece(path: PathBuf, offset: u64, piece: Vec<u8>) -> io::Result<()> {
+    let write = task::spawn_blocking(move || {
+        let mut f = fs::OpenOptions::new().write(true).open(path)?;
+        f.seek(SeekFrom::Start(offset))?;
+        f.write_all(&piece)
+    });
+
+    match write.await {
+        Ok(res) => res,
+        Err(_) => Err(io::ErrorKind::Other.into()),
+    }
+}
+
+fn sha1(data: &[u8]) -> Sha1Hash {
+    digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, data)
+        .as_ref()
+        .try_into()
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, BenchOptions};
+
+    #[tokio::test]
+    async fn pipeline() {
+        let opts = BenchOptions {
+            peers: 3,
+            pieces: 8,
+            piece_length: 40 * 1024,
+            rate: None,
+        };
+        let report = run(&opts).await.unwrap();
+
+        // 3 blocks per piece, the last one short
+        assert_eq!(report.messages, 8 * 3);
+        assert_eq!(report.verified, 8 * 40 * 1024);
+    }
+}
@@ -0,0 +1,385 @@
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bitvec::prelude::{BitSlice, Msb0};
+
+use crate::{
+    request_queue::{Block, BLOCK_LEN},
+    resume::PartialPiece,
+};
+
+/// time a peer has to send a block we asked for before it's asked of someone else
+pub(crate) const BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// BlockScheduler splits the pieces being downloaded into [BLOCK_LEN] blocks and keeps track of
+/// each one: whether it's arrived, and if not, which peer it was asked of and when. blocks are
+/// put together as they arrive so a complete piece can be checked against its hash. blocks of
+/// pieces already started are handed out first so fewer pieces are left half done. blocks a
+/// peer doesn't send in time, or won't send since it choked us or went away, are asked of
+/// someone else
+#[derive(Debug)]
+pub(crate) struct BlockScheduler {
+    piece_length: u32,
+    total_length: u64,
+    // pieces being downloaded, lowest index first
+    pieces: BTreeMap<u32, PieceBlocks>,
+}
+
+#[derive(Debug)]
+struct PieceBlocks {
+    length: u32,
+    blocks: Vec<BlockState>,
+    // the piece as it's put together
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockState {
+    Missing,
+    Requested(SocketAddr, Instant),
+    Received,
+}
+
+impl BlockScheduler {
+    /// a scheduler for a torrent of total_length bytes split into pieces of piece_length bytes
+    pub(crate) fn new(piece_length: u32, total_length: u64) -> BlockScheduler {
+        BlockScheduler {
+            piece_length,
+            total_length,
+            pieces: BTreeMap::new(),
+        }
+    }
+
+    /// start downloading piece index, eg. once the picker has chosen it. a piece which is
+    /// already being downloaded keeps its blocks
+    pub(crate) fn start(&mut self, index: u32) {
+        let start = index as u64 * self.piece_length as u64;
+        let left = self.total_length.saturating_sub(start);
+        let length = left.min(self.piece_length as u64) as u32;
+
+        self.pieces.entry(index).or_insert_with(|| PieceBlocks {
+            length,
+            blocks: vec![BlockState::Missing; length.div_ceil(BLOCK_LEN) as usize],
+            data: vec![0; length as usize],
+        });
+    }
+
+    /// whether a peer which has the pieces in has has any we're downloading
+    pub(crate) fn wants(&self, has: &BitSlice<u8, Msb0>) -> bool {
+        let has = |&index: &u32| has.get(index as usize).is_some_and(|b| *b);
+        self.pieces.keys().any(has)
+    }
+
+    /// up to room blocks for the peer at addr to be asked for, from the pieces being downloaded
+    /// which it has. they're marked as requested from it at now
+    pub(crate) fn next_requests(
+        &mut self,
+        addr: SocketAddr,
+        has: &BitSlice<u8, Msb0>,
+        room: usize,
+        now: Instant,
+    ) -> Vec<Block> {
+        let mut requests = vec![];
+        for (&index, piece) in self.pieces.iter_mut() {
+            if !has.get(index as usize).is_some_and(|b| *b) {
+                continue;
+            }
+
+            let length = piece.length;
+            let missing = piece.blocks.iter_mut().enumerate();
+            let missing = missing.filter(|(_, state)| **state == BlockState::Missing);
+            for (i, state) in missing.take(room - requests.len()) {
+                *state = BlockState::Requested(addr, now);
+                requests.push(block(index, length, i));
+            }
+            if requests.len() == room {
+                break;
+            }
+        }
+        requests
+    }
+
+    /// whether block is one of a piece being downloaded which hasn't arrived yet. a block which
+    /// was asked of several peers is only needed from the first to send it
+    pub(crate) fn needs(&self, block: Block) -> bool {
+        let Some(piece) = self.pieces.get(&block.index) else {
+            return false;
+        };
+        let i = (block.begin / BLOCK_LEN) as usize;
+        let valid = i < piece.blocks.len() && self::block(block.index, piece.length, i) == block;
+        valid && piece.blocks[i] != BlockState::Received
+    }
+
+    /// record the data of a block which arrived at begin in piece index, from whoever it was
+    /// asked of, returning the whole piece if it's now complete. a complete piece is no longer
+    /// being downloaded, it's started again if it fails its hash check
+    pub(crate) fn received(&mut self, index: u32, begin: u32, data: &[u8]) -> Option<Vec<u8>> {
+        let piece = self.pieces.get_mut(&index)?;
+        let i = (begin / BLOCK_LEN) as usize;
+        let received = Block {
+            index,
+            begin,
+            length: data.len() as u32,
+        };
+        if i >= piece.blocks.len() || block(index, piece.length, i) != received {
+            return None;
+        }
+
+        piece.blocks[i] = BlockState::Received;
+        piece.data[begin as usize..][..data.len()].copy_from_slice(data);
+        if !piece.blocks.iter().all(|&s| s == BlockState::Received) {
+            return None;
+        }
+        self.pieces.remove(&index).map(|piece| piece.data)
+    }
+
+    /// forget requests which have been waiting longer than timeout at now so they can be asked
+    /// of someone else, returning them along with the peer each was asked of
+    pub(crate) fn timed_out(
+        &mut self,
+        now: Instant,
+        timeout: Duration,
+    ) -> Vec<(SocketAddr, Block)> {
+        let mut expired = vec![];
+        for (&index, piece) in self.pieces.iter_mut() {
+            for (i, state) in piece.blocks.iter_mut().enumerate() {
+                let BlockState::Requested(addr, at) = *state else {
+                    continue;
+                };
+                if now.saturating_duration_since(at) >= timeout {
+                    *state = BlockState::Missing;
+                    expired.push((addr, block(index, piece.length, i)));
+                }
+            }
+        }
+        expired
+    }
+
+    /// forget the requests to addr which keep returns false for, eg. ones it rejected
+    pub(crate) fn retain_requests(&mut self, addr: SocketAddr, keep: impl Fn(Block) -> bool) {
+        for (&index, piece) in self.pieces.iter_mut() {
+            for (i, state) in piece.blocks.iter_mut().enumerate() {
+                let requested = matches!(*state, BlockState::Requested(a, _) if a == addr);
+                if requested && !keep(block(index, piece.length, i)) {
+                    *state = BlockState::Missing;
+                }
+            }
+        }
+    }
+
+    /// forget every request to addr, eg. once it's disconnected
+    pub(crate) fn release_peer(&mut self, addr: SocketAddr) {
+        self.retain_requests(addr, |_| false);
+    }
+
+    /// stop downloading piece index, eg. once it's no longer wanted, returning the requests
+    /// for its blocks which are still outstanding along with the peer each was asked of. None
+    /// if it wasn't being downloaded
+    pub(crate) fn cancel(&mut self, index: u32) -> Option<Vec<(SocketAddr, Block)>> {
+        let piece = self.pieces.remove(&index)?;
+        let requested = piece.blocks.iter().enumerate();
+        let requested = requested.filter_map(|(i, state)| match *state {
+            BlockState::Requested(addr, _) => Some((addr, block(index, piece.length, i))),
+            _ => None,
+        });
+        Some(requested.collect())
+    }
+
+    /// pieces being downloaded which some blocks have arrived for, to be saved in resume data.
+    /// the ones furthest along are kept first, up to max bytes of them
+    pub(crate) fn partial(&self, max: usize) -> Vec<PartialPiece> {
+        let received = |s: &BlockState| *s == BlockState::Received;
+        let arrived = |piece: &PieceBlocks| piece.blocks.iter().filter(|s| received(s)).count();
+        let mut started: Vec<_> = self.pieces.iter().filter(|(_, p)| arrived(p) > 0).collect();
+        started.sort_by_key(|(_, piece)| Reverse(arrived(piece)));
+
+        let mut size = 0;
+        let kept = started.into_iter().take_while(|(_, piece)| {
+            size += piece.data.len();
+            size <= max
+        });
+        let partial = kept.map(|(&index, piece)| PartialPiece {
+            index,
+            blocks: piece.blocks.iter().map(received).collect(),
+            data: piece.data.clone(),
+        });
+        partial.collect()
+    }
+
+    /// start downloading a piece again with the blocks which had arrived when it was saved,
+    /// returning whether it was. pieces whose data doesn't fit, or which already had every
+    /// block and so were checked, aren't
+    pub(crate) fn restore(&mut self, partial: &PartialPiece) -> bool {
+        let index = partial.index;
+        let start = index as u64 * self.piece_length as u64;
+        let left = self.total_length.saturating_sub(start);
+        let length = left.min(self.piece_length as u64);
+        let arrived = |i: usize| partial.blocks.get(i).is_some_and(|b| *b);
+        let blocks = (length as u32).div_ceil(BLOCK_LEN) as usize;
+        if partial.data.len() as u64 != length || (0..blocks).all(arrived) {
+            return false;
+        }
+
+        let state = |i| match arrived(i) {
+            true => BlockState::Received,
+            false => BlockState::Missing,
+        };
+        let piece = PieceBlocks {
+            length: length as u32,
+            blocks: (0..blocks).map(state).collect(),
+            data: partial.data.clone(),
+        };
+        self.pieces.insert(index, piece);
+        true
+    }
+}
+
+// the i'th block of piece index, which is length bytes long. the last block may be short
+fn block(index: u32, length: u32, i: usize) -> Block {
+    let begin = i as u32 * BLOCK_LEN;
+    Block {
+        index,
+        begin,
+        length: (length - begin).min(BLOCK_LEN),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    use bitvec::prelude::{bitbox, Msb0};
+
+    use crate::{
+        block_scheduler::BlockScheduler,
+        request_queue::{Block, BLOCK_LEN},
+        resume::PartialPiece,
+    };
+
+    #[test]
+    fn blocks() {
+        let a: SocketAddr = "1.1.1.1:6881".parse().unwrap();
+        let b: SocketAddr = "2.2.2.2:6881".parse().unwrap();
+        let now = Instant::now();
+        let block = |index, begin, length| Block {
+            index,
+            begin,
+            length,
+        };
+
+        // two pieces of three blocks, the last one short
+        let mut scheduler = BlockScheduler::new(3 * BLOCK_LEN, 5 * BLOCK_LEN as u64 + 10);
+        scheduler.start(1);
+        scheduler.start(0);
+        let has = bitbox![u8, Msb0; 0, 1];
+        assert!(scheduler.wants(&has));
+        assert!(!scheduler.wants(&bitbox![u8, Msb0; 0, 0]));
+
+        // blocks are handed out a piece at a time, and each only once
+        let requests = scheduler.next_requests(a, &bitbox![u8, Msb0; 1, 1], 4, now);
+        let expected = vec![
+            block(0, 0, BLOCK_LEN),
+            block(0, BLOCK_LEN, BLOCK_LEN),
+            block(0, 2 * BLOCK_LEN, BLOCK_LEN),
+            block(1, 0, BLOCK_LEN),
+        ];
+        assert_eq!(requests, expected);
+        let requests = scheduler.next_requests(b, &has, 4, now);
+        let expected = vec![block(1, BLOCK_LEN, BLOCK_LEN), block(1, 2 * BLOCK_LEN, 10)];
+        assert_eq!(requests, expected);
+        assert!(scheduler.next_requests(b, &has, 4, now).is_empty());
+
+        // requests which take too long or are dropped are handed out again
+        let later = now + Duration::from_secs(10);
+        let expired = scheduler.timed_out(later, Duration::from_secs(10));
+        assert_eq!(expired.len(), 6);
+        assert!(expired.contains(&(b, block(1, 2 * BLOCK_LEN, 10))));
+        let requests = scheduler.next_requests(b, &has, 1, later);
+        assert_eq!(requests, vec![block(1, 0, BLOCK_LEN)]);
+        scheduler.release_peer(b);
+        assert_eq!(scheduler.next_requests(a, &has, 1, later), requests);
+        scheduler.retain_requests(a, |block| block.begin != 0);
+        assert_eq!(scheduler.next_requests(a, &has, 1, later), requests);
+
+        // a piece is done once all its blocks arrive, from anyone
+        let full = vec![1; BLOCK_LEN as usize];
+        assert!(scheduler.needs(block(1, 0, BLOCK_LEN)));
+        assert_eq!(scheduler.received(1, 0, &full), None);
+        assert!(!scheduler.needs(block(1, 0, BLOCK_LEN)));
+        assert!(!scheduler.needs(block(1, 5, 10)));
+        assert_eq!(scheduler.received(1, 5, &[2; 10]), None);
+        assert_eq!(scheduler.received(1, BLOCK_LEN, &full), None);
+        assert_eq!(scheduler.received(1, 2 * BLOCK_LEN, &[3; 11]), None);
+        let piece = scheduler.received(1, 2 * BLOCK_LEN, &[3; 10]).unwrap();
+        assert_eq!(piece.len(), 2 * BLOCK_LEN as usize + 10);
+        assert!(piece[..2 * BLOCK_LEN as usize].iter().all(|&b| b == 1));
+        assert_eq!(piece[2 * BLOCK_LEN as usize..], [3; 10]);
+        assert!(!scheduler.needs(block(1, 2 * BLOCK_LEN, 10)));
+        assert!(!scheduler.wants(&has));
+        assert!(scheduler.wants(&bitbox![u8, Msb0; 1]));
+
+        // a cancelled piece is forgotten, returning what's still asked for
+        let mut scheduler = BlockScheduler::new(3 * BLOCK_LEN, 5 * BLOCK_LEN as u64 + 10);
+        scheduler.start(0);
+        let requests = scheduler.next_requests(a, &bitbox![u8, Msb0; 1], 2, now);
+        scheduler.received(0, 0, &full);
+        assert_eq!(scheduler.cancel(0), Some(vec![(a, requests[1])]));
+        assert!(!scheduler.needs(requests[1]));
+        assert_eq!(scheduler.cancel(0), None);
+    }
+
+    #[test]
+    fn partial() {
+        let a: SocketAddr = "1.1.1.1:6881".parse().unwrap();
+        let now = Instant::now();
+        let has = bitbox![u8, Msb0; 1, 1];
+        let mut scheduler = BlockScheduler::new(3 * BLOCK_LEN, 5 * BLOCK_LEN as u64 + 10);
+        scheduler.start(0);
+        scheduler.start(1);
+        scheduler.next_requests(a, &has, 6, now);
+        scheduler.received(1, 2 * BLOCK_LEN, &[3; 10]);
+
+        // only pieces with blocks which arrived are saved, as many as fit
+        let partial = scheduler.partial(usize::MAX);
+        assert_eq!(partial.len(), 1);
+        assert_eq!(partial[0].index, 1);
+        assert_eq!(partial[0].blocks, bitbox![u8, Msb0; 0, 0, 1]);
+        scheduler.received(0, BLOCK_LEN, &[1; BLOCK_LEN as usize]);
+        scheduler.received(0, 0, &[1; BLOCK_LEN as usize]);
+        let first = scheduler.partial(3 * BLOCK_LEN as usize);
+        assert_eq!(first.iter().map(|p| p.index).collect::<Vec<_>>(), [0]);
+
+        // the blocks which arrived aren't asked for again
+        let mut scheduler = BlockScheduler::new(3 * BLOCK_LEN, 5 * BLOCK_LEN as u64 + 10);
+        assert!(scheduler.restore(&partial[0]));
+        let requests = scheduler.next_requests(a, &has, 6, now);
+        assert_eq!(requests.len(), 2);
+        assert!(!scheduler.needs(Block {
+            index: 1,
+            begin: 2 * BLOCK_LEN,
+            length: 10,
+        }));
+        scheduler.received(1, 0, &[1; BLOCK_LEN as usize]);
+        let full = vec![2; BLOCK_LEN as usize];
+        let piece = scheduler.received(1, BLOCK_LEN, &full).unwrap();
+        assert_eq!(piece[2 * BLOCK_LEN as usize..], [3; 10]);
+
+        // nor are pieces which don't fit, or had every block
+        let mut piece = partial[0].clone();
+        piece.data.pop();
+        assert!(!scheduler.restore(&piece));
+        piece = PartialPiece {
+            blocks: bitbox![u8, Msb0; 1, 1, 1],
+            ..partial[0].clone()
+        };
+        assert!(!scheduler.restore(&piece));
+        assert!(scheduler.partial(usize::MAX).is_empty());
+    }
+}
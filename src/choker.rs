@@ -0,0 +1,281 @@
+use std::{cmp::Reverse, net::SocketAddr, time::Instant};
+
+use rand::{seq::SliceRandom, Rng};
+
+use crate::peer_class::PeerClass;
+
+/// UploadSlots decides how many peers are unchoked (uploaded to) at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadSlots {
+    Fixed(usize),
+    /// one slot for every `per_slot` bytes/s of upload bandwidth, clamped to `min..=max`. the
+    /// configured upload limit is used as the bandwidth if set, otherwise the measured rate is
+    Auto {
+        per_slot: u64,
+        min: usize,
+        max: usize,
+    },
+}
+
+impl Default for UploadSlots {
+    fn default() -> UploadSlots {
+        UploadSlots::Fixed(4)
+    }
+}
+
+impl UploadSlots {
+    /// number of slots available for upload bandwidth (in bytes/s)
+    pub fn slots(&self, bandwidth: u64) -> usize {
+        match *self {
+            UploadSlots::Fixed(n) => n,
+            UploadSlots::Auto { per_slot, min, max } => {
+                let slots = bandwidth / per_slot.max(1);
+                (slots.min(usize::MAX as u64) as usize).clamp(min, max.max(min))
+            }
+        }
+    }
+}
+
+/// SeedChoking decides which peers get the upload slots of a torrent we've completed. there's
+/// nothing to download from them, so what they upload to us can't be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SeedChoking {
+    /// the peers we upload to fastest, so the slots go where they're used best
+    #[default]
+    FastestUpload,
+    /// the peers which have waited longest since they last had a slot, so every peer gets a
+    /// turn
+    RoundRobin,
+}
+
+/// Choker tracks how many peers may be unchoked, re-evaluating the count as the upload limit or
+/// measured throughput changes, and picks the peers which get the slots
+#[derive(Debug)]
+pub struct Choker {
+    policy: UploadSlots,
+    rate_limit: Option<u64>,
+    measured_rate: u64,
+    slots: usize,
+    // LAN peers aren't subject to rate_limit
+    exempt_lan: bool,
+    seed_choking: SeedChoking,
+}
+
+/// Candidate is an interested peer considered for an upload slot, see [Choker::unchoke]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Candidate {
+    pub addr: SocketAddr,
+    /// bytes/s the peer sends us
+    pub download_rate: u64,
+    /// bytes/s we send the peer
+    pub upload_rate: u64,
+    /// when the peer last had an upload slot, now if it has one. None if it never has
+    pub last_unchoked: Option<Instant>,
+    pub snubbed: bool,
+}
+
+impl Choker {
+    pub fn new(policy: UploadSlots, rate_limit: Option<u64>) -> Choker {
+        Choker {
+            policy,
+            rate_limit,
+            measured_rate: 0,
+            slots: policy.slots(rate_limit.unwrap_or(0)),
+            exempt_lan: false,
+            seed_choking: SeedChoking::default(),
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: UploadSlots) {
+        self.policy = policy;
+        self.update_slots();
+    }
+
+    pub fn set_seed_choking(&mut self, seed_choking: SeedChoking) {
+        self.seed_choking = seed_choking;
+    }
+
+    pub fn set_exempt_lan(&mut self, exempt: bool) {
+        self.exempt_lan = exempt;
+    }
+
+    /// upload rate limit for peers of class
+    pub fn rate_limit(&self, class: PeerClass) -> Option<u64> {
+        match class {
+            PeerClass::Lan if self.exempt_lan => None,
+            _ => self.rate_limit,
+        }
+    }
+
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    /// the upload limit each unchoked peer of class is held to once the measured rate reaches
+    /// the rate limit, an even share of the limit per slot. None while there's bandwidth to
+    /// spare, so peers which can take more get it
+    pub fn upload_share(&self, class: PeerClass) -> Option<u64> {
+        let limit = self.rate_limit(class)?;
+        let saturated = self.measured_rate >= limit;
+        saturated.then(|| limit / self.slots.max(1) as u64)
+    }
+
+    pub fn set_rate_limit(&mut self, rate_limit: Option<u64>) {
+        self.rate_limit = rate_limit;
+        self.update_slots();
+    }
+
+    /// record the latest measured upload rate in bytes/s
+    pub fn set_measured_rate(&mut self, rate: u64) {
+        self.measured_rate = rate;
+        self.update_slots();
+    }
+
+    /// pick the candidates which get an upload slot. while downloading, the peers which upload
+    /// to us fastest get them in return, passing over peers which have snubbed us. complete
+    /// torrents rank peers by [SeedChoking] instead
+    pub(crate) fn unchoke(&self, candidates: &[Candidate], complete: bool) -> Vec<SocketAddr> {
+        let mut ranked = candidates.to_vec();
+        match (complete, self.seed_choking) {
+            (false, _) => {
+                ranked.retain(|c| !c.snubbed);
+                ranked.sort_by_key(|c| Reverse(c.download_rate));
+            }
+            (true, SeedChoking::FastestUpload) => ranked.sort_by_key(|c| Reverse(c.upload_rate)),
+            // None sorts first, so peers which never had a slot go before everyone else
+            (true, SeedChoking::RoundRobin) => ranked.sort_by_key(|c| c.last_unchoked),
+        }
+
+        let ranked = ranked.iter().take(self.slots);
+        ranked.map(|c| c.addr).collect()
+    }
+
+    /// pick one of the choked peers in candidates to optimistically unchoke at random. each
+    /// candidate is a peer's address and whether it's snubbed us, snubbed peers are only picked
+    /// when there's no one else
+    pub(crate) fn optimistic_unchoke(
+        candidates: &[(SocketAddr, bool)],
+        rng: &mut impl Rng,
+    ) -> Option<SocketAddr> {
+        let (snubbed, others): (Vec<_>, Vec<_>) = candidates.iter().partition(|&&(_, s)| s);
+        let pick = others.choose(rng).or_else(|| snubbed.choose(rng));
+        pick.map(|&&(addr, _)| addr)
+    }
+
+    fn update_slots(&mut self) {
+        let bandwidth = self.rate_limit.unwrap_or(self.measured_rate);
+        self.slots = self.policy.slots(bandwidth);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    use crate::{
+        choker::{Candidate, Choker, SeedChoking, UploadSlots},
+        peer_class::PeerClass,
+    };
+
+    #[test]
+    fn auto_slots() {
+        let policy = UploadSlots::Auto {
+            per_slot: 10 * 1024,
+            min: 2,
+            max: 8,
+        };
+
+        let mut choker = Choker::new(policy, None);
+        assert_eq!(choker.slots(), 2);
+
+        choker.set_measured_rate(50 * 1024);
+        assert_eq!(choker.slots(), 5);
+
+        choker.set_measured_rate(1024 * 1024);
+        assert_eq!(choker.slots(), 8);
+
+        // a configured limit takes precedence over the measured rate
+        choker.set_rate_limit(Some(30 * 1024));
+        assert_eq!(choker.slots(), 3);
+
+        assert_eq!(UploadSlots::Fixed(4).slots(1024 * 1024), 4);
+    }
+
+    #[test]
+    fn lan_rate_limit() {
+        let mut choker = Choker::new(UploadSlots::default(), Some(1024));
+        assert_eq!(choker.rate_limit(PeerClass::Lan), Some(1024));
+
+        choker.set_exempt_lan(true);
+        assert_eq!(choker.rate_limit(PeerClass::Lan), None);
+        assert_eq!(choker.rate_limit(PeerClass::Wan), Some(1024));
+    }
+
+    #[test]
+    fn upload_share() {
+        let mut choker = Choker::new(UploadSlots::Fixed(4), Some(1000));
+        choker.set_exempt_lan(true);
+        choker.set_measured_rate(900);
+        assert_eq!(choker.upload_share(PeerClass::Wan), None);
+
+        // once the limit is reached each slot gets its share
+        choker.set_measured_rate(1000);
+        assert_eq!(choker.upload_share(PeerClass::Wan), Some(250));
+        assert_eq!(choker.upload_share(PeerClass::Lan), None);
+        choker.set_rate_limit(None);
+        assert_eq!(choker.upload_share(PeerClass::Wan), None);
+    }
+
+    #[test]
+    fn unchoke() {
+        let start = Instant::now();
+        let candidate = |port, download_rate, upload_rate, last_unchoked: Option<u64>| Candidate {
+            addr: SocketAddr::from(([1, 2, 3, 4], port)),
+            download_rate,
+            upload_rate,
+            last_unchoked: last_unchoked.map(|secs| start + Duration::from_secs(secs)),
+            snubbed: port == 4,
+        };
+        let candidates = [
+            candidate(1, 100, 500, Some(30)),
+            candidate(2, 300, 100, None),
+            candidate(3, 200, 300, Some(10)),
+            candidate(4, 900, 900, Some(20)),
+        ];
+        let ports = |addrs: Vec<SocketAddr>| addrs.iter().map(SocketAddr::port).collect::<Vec<_>>();
+        let mut choker = Choker::new(UploadSlots::Fixed(2), None);
+
+        // downloading, the peers sending us the most, unless they've snubbed us
+        assert_eq!(ports(choker.unchoke(&candidates, false)), [2, 3]);
+
+        // seeding, the peers we send the most or the ones waiting longest
+        assert_eq!(ports(choker.unchoke(&candidates, true)), [4, 1]);
+        choker.set_seed_choking(SeedChoking::RoundRobin);
+        assert_eq!(ports(choker.unchoke(&candidates, true)), [2, 3]);
+
+        choker.set_policy(UploadSlots::Fixed(3));
+        assert_eq!(ports(choker.unchoke(&candidates, true)), [2, 3, 4]);
+    }
+
+    #[test]
+    fn optimistic_unchoke() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let snubbed: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        let other: SocketAddr = "5.6.7.8:6881".parse().unwrap();
+
+        for _ in 0..10 {
+            let candidates = [(snubbed, true), (other, false)];
+            let pick = Choker::optimistic_unchoke(&candidates, &mut rng);
+            assert_eq!(pick, Some(other));
+        }
+
+        let pick = Choker::optimistic_unchoke(&[(snubbed, true)], &mut rng);
+        assert_eq!(pick, Some(snubbed));
+        assert_eq!(Choker::optimistic_unchoke(&[], &mut rng), None);
+    }
+}
@@ -0,0 +1,200 @@
+use crate::peer::PeerId;
+
+// client codes of Azureus-style peer ids, `-XX1234-`
+const AZUREUS_CLIENTS: &[(&[u8; 2], &str)] = &[
+    (b"AZ", "Vuze"),
+    (b"BC", "BitComet"),
+    (b"BI", "BiglyBT"),
+    (b"BT", "BitTorrent"),
+    (b"DE", "Deluge"),
+    (b"FD", "Free Download Manager"),
+    (b"FW", "FrostWire"),
+    (b"KT", "KTorrent"),
+    (b"LT", "libtorrent"),
+    (b"lt", "rTorrent"),
+    (b"PI", "PicoTorrent"),
+    (b"qB", "qBittorrent"),
+    (b"TR", "Transmission"),
+    (b"TS", "Tsunami"),
+    (b"UM", "µTorrent Mac"),
+    (b"UT", "µTorrent"),
+    (b"WW", "WebTorrent"),
+    (b"XL", "Xunlei"),
+];
+
+// client letters of Shadow-style peer ids, `S58B-----`
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+/// ClientInfo is the client software a peer is running, as far as it can be told from its
+/// peer id or the `v` field of its extension handshake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl ClientInfo {
+    /// identify a peer's client. the `v` of its extension handshake is preferred since clients
+    /// pick it themselves, falling back to decoding Azureus and Shadow-style peer ids. None if
+    /// neither says anything recognisable
+    pub fn identify(peer_id: &PeerId, v: Option<&str>) -> Option<ClientInfo> {
+        let v = v.map(str::trim).filter(|v| !v.is_empty());
+        v.map(Self::from_v)
+            .or_else(|| Self::azureus(peer_id))
+            .or_else(|| Self::shadow(peer_id))
+    }
+
+    // clients usually send their name followed by their version, eg. `Transmission 4.0.5` or
+    // `qBittorrent/4.5.6`
+    fn from_v(v: &str) -> ClientInfo {
+        let split = v.rfind([' ', '/']).map(|i| (&v[..i], &v[i + 1..]));
+        match split {
+            Some((name, version)) if version.starts_with(|c: char| c.is_ascii_digit()) => {
+                ClientInfo {
+                    name: name.trim_end().into(),
+                    version: Some(version.into()),
+                }
+            }
+            _ => ClientInfo {
+                name: v.into(),
+                version: None,
+            },
+        }
+    }
+
+    // `-XX1234-`: a two letter client code and four version characters, digits or letters for
+    // numbers over 9. trailing zeroes past the third part are left out, so `-TR4050-` is 4.0.5
+    fn azureus(peer_id: &PeerId) -> Option<ClientInfo> {
+        let &[b'-', a, b, ref version @ .., b'-'] = &peer_id[..8] else {
+            return None;
+        };
+        if !(a.is_ascii_alphabetic() && b.is_ascii_alphabetic()) {
+            return None;
+        }
+
+        let parts = version.iter().map(|&c| match c {
+            b'0'..=b'9' => Some(c - b'0'),
+            b'A'..=b'Z' => Some(c - b'A' + 10),
+            _ => None,
+        });
+        let parts: Vec<_> = parts.collect::<Option<_>>()?;
+        let len = match parts[3] {
+            0 => 3,
+            _ => 4,
+        };
+
+        let code = [a, b];
+        let known = AZUREUS_CLIENTS.iter().find(|(c, _)| **c == code);
+        let name = match known {
+            Some((_, name)) => name.to_string(),
+            None => String::from_utf8_lossy(&code).into(),
+        };
+        let version = parts[..len].iter().map(u8::to_string);
+        Some(ClientInfo {
+            name,
+            version: Some(version.collect::<Vec<_>>().join(".")),
+        })
+    }
+
+    // a client letter followed by up to five version characters and at least two dashes of
+    // padding. each character is a part of the version: 0-9, then A-Z for 10-35, a-z for 36-61
+    fn shadow(peer_id: &PeerId) -> Option<ClientInfo> {
+        let (_, name) = SHADOW_CLIENTS.iter().find(|(c, _)| *c == peer_id[0])?;
+
+        let len = peer_id[1..7].iter().position(|&c| c == b'-')?;
+        if len == 0 || peer_id[len + 2] != b'-' {
+            return None;
+        }
+
+        let parts = peer_id[1..len + 1].iter().map(|&c| match c {
+            b'0'..=b'9' => Some(c - b'0'),
+            b'A'..=b'Z' => Some(c - b'A' + 10),
+            b'a'..=b'z' => Some(c - b'a' + 36),
+            _ => None,
+        });
+        let parts: Vec<_> = parts.map(|p| Some(p?.to_string())).collect::<Option<_>>()?;
+        Some(ClientInfo {
+            name: name.to_string(),
+            version: Some(parts.join(".")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{client::ClientInfo, peer::PeerId};
+
+    fn id(prefix: &[u8]) -> PeerId {
+        let mut id = *b"....................";
+        id[..prefix.len()].copy_from_slice(prefix);
+        id
+    }
+
+    fn client(name: &str, version: &str) -> Option<ClientInfo> {
+        Some(ClientInfo {
+            name: name.into(),
+            version: Some(version.into()),
+        })
+    }
+
+    #[test]
+    fn azureus() {
+        let cases = [
+            (&b"-TR4050-"[..], client("Transmission", "4.0.5")),
+            (b"-qB456A-", client("qBittorrent", "4.5.6.10")),
+            (b"-LT1230-", client("libtorrent", "1.2.3")),
+            (b"-ZZ0100-", client("ZZ", "0.1.0")),
+            (b"-TR40!0-", None),
+            (b"-12345 -", None),
+        ];
+
+        for (prefix, expected) in cases {
+            assert_eq!(ClientInfo::identify(&id(prefix), None), expected);
+        }
+    }
+
+    #[test]
+    fn shadow() {
+        let cases = [
+            (&b"S58B-----"[..], client("Shadow", "5.8.11")),
+            (b"T03I--", client("BitTornado", "0.3.18")),
+            (b"Ra--", client("Tribler", "36")),
+            (b"S-----", None),
+            (b"S58B-x", None),
+            (b"X58B---", None),
+        ];
+
+        for (prefix, expected) in cases {
+            assert_eq!(ClientInfo::identify(&id(prefix), None), expected);
+        }
+    }
+
+    #[test]
+    fn extension_handshake() {
+        let peer_id = id(b"-TR4050-");
+
+        // v beats the peer id
+        let info = ClientInfo::identify(&peer_id, Some("qBittorrent/4.5.6"));
+        assert_eq!(info, client("qBittorrent", "4.5.6"));
+        let info = ClientInfo::identify(&peer_id, Some("µTorrent 3.5.5"));
+        assert_eq!(info, client("µTorrent", "3.5.5"));
+
+        let info = ClientInfo::identify(&peer_id, Some("Some Client"));
+        let expected = ClientInfo {
+            name: "Some Client".into(),
+            version: None,
+        };
+        assert_eq!(info, Some(expected));
+        let info = ClientInfo::identify(&peer_id, Some(" "));
+        assert_eq!(info, client("Transmission", "4.0.5"));
+        assert_eq!(ClientInfo::identify(&id(b""), None), None);
+    }
+}
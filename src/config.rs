@@ -0,0 +1,211 @@
+use std::{
+    collections::HashMap, net::IpAddr, ops::RangeInclusive, path::PathBuf, sync::Arc,
+    time::Duration,
+};
+
+pub use crate::{
+    choker::{SeedChoking, UploadSlots},
+    events::EventPolicy,
+    peer_class::PeerClass,
+    proxy::{Proxy, ProxyKind},
+    resume::ResumeData,
+    utils::SanitizePolicy,
+};
+use crate::{picker::PieceStrategy, storage::StorageProvider};
+
+/// Config holds session wide settings shared by every torrent in a [crate::tsunami::Tsunami]
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// maximum number of bytes all active torrents may occupy on disk. torrents that would push
+    /// the projected usage over the quota are added paused instead of being started
+    pub disk_quota: Option<u64>,
+
+    /// local address all outgoing peer and tracker connections are bound to, eg. the address of
+    /// a VPN interface. defaults to letting the OS pick
+    pub bind_address: Option<IpAddr>,
+
+    /// time allowed for a tracker request, including connecting, before giving up on the
+    /// tracker. defaults to 30s
+    pub http_timeout: Option<Duration>,
+
+    /// port incoming peer connections are accepted on, see [crate::tsunami::Tsunami::listen].
+    /// it's reported to trackers and peers. defaults to 6881; sessions running side by side
+    /// should each use their own
+    pub listen_port: Option<u16>,
+
+    /// ports tried in order when listen_port can't be bound, eg. because another client has it.
+    /// the port actually bound is the one reported
+    pub listen_port_range: Option<RangeInclusive<u16>>,
+
+    /// number of pending incoming connections the OS queues before refusing new ones.
+    /// defaults to 128
+    pub listen_backlog: Option<u32>,
+
+    /// maximum number of incoming connections accepted per second. connections over the limit
+    /// wait in the listen backlog
+    pub accept_rate: Option<u32>,
+
+    /// time a peer has to complete its handshake before it's dropped. for outgoing connections
+    /// this starts once the peer accepts the connection. defaults to 10s
+    pub handshake_timeout: Option<Duration>,
+
+    /// time a peer has to accept an outgoing connection before it's given up on. defaults to 5s
+    pub connect_timeout: Option<Duration>,
+
+    /// time a peer has to send any block we asked for before it's considered to have snubbed
+    /// us. snubbed peers get one request at a time and are passed over for optimistic unchokes
+    /// until they send something. defaults to 60s
+    pub snub_timeout: Option<Duration>,
+
+    /// number of peers uploaded to at once, either fixed or derived from upload bandwidth
+    pub upload_slots: UploadSlots,
+
+    /// which peers a torrent uploads to once it's complete. while downloading, the peers
+    /// uploading to us fastest are uploaded to in return
+    pub seed_choking: SeedChoking,
+
+    /// maximum number of peers each torrent connects to
+    pub max_peers: Option<usize>,
+
+    /// maximum number of peers connected to across every torrent in the session, including
+    /// connections still being made
+    pub max_connections: Option<usize>,
+
+    /// maximum number of outgoing connections being made at once across the session. further
+    /// connections wait for one of these to succeed or fail
+    pub max_half_open: Option<usize>,
+
+    /// let [PeerClass::Lan] peers ignore rate limits, [Config::max_peers] and
+    /// [Config::max_connections], so transfers between local machines run at full speed while
+    /// internet traffic stays capped
+    pub exempt_lan: bool,
+
+    /// credentials attached to every tracker request sent to a host, keyed by host name (eg.
+    /// `tracker.example.com`). many private trackers require these
+    pub tracker_auth: HashMap<String, TrackerAuth>,
+
+    /// proxy every tracker request is sent through
+    pub proxy: Option<Proxy>,
+
+    /// proxy every outgoing peer connection is made through, independent of [Config::proxy].
+    /// this should be a [ProxyKind::Socks5] proxy, HTTP proxies rarely allow tunnelling to the
+    /// ports peers listen on. incoming connections don't go through it
+    pub peer_proxy: Option<Proxy>,
+
+    /// bytes of downloaded pieces each torrent lets wait to be checked and written to disk.
+    /// once there are more, no new blocks are requested until the disk catches up. defaults
+    /// to 64 MiB
+    pub max_write_queue: Option<u64>,
+
+    /// bytes of recently read pieces kept in memory to serve uploads from, so pieces many
+    /// peers want aren't read from disk for each of them. the room is shared by every torrent
+    /// in the session. defaults to 32 MiB, 0 turns the cache off
+    pub read_cache_size: Option<u64>,
+
+    /// how events are queued for the consumer of [crate::tsunami::Tsunami::take_events].
+    /// defaults to keeping the newest 1024 events
+    pub events: EventPolicy,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TrackerAuth {
+    /// sent as-is in a `Cookie` header
+    pub cookie: Option<String>,
+    /// username and password for HTTP basic auth
+    pub basic: Option<(String, String)>,
+    /// extra query parameters appended to the tracker url, eg. `("passkey", "...")`
+    pub query: Vec<(String, String)>,
+}
+
+/// AddTorrentOptions are per torrent settings chosen when a torrent is added to a session
+#[derive(Debug, Clone, Default)]
+pub struct AddTorrentOptions {
+    pub conflict: ConflictPolicy,
+
+    /// overrides [Config::bind_address] for this torrent's connections
+    pub bind_address: Option<IpAddr>,
+
+    /// how file names that aren't valid on this OS are handled
+    pub sanitize: SanitizePolicy,
+
+    /// accept any non-zero piece length instead of only powers of two between 16 KiB and
+    /// 128 MiB
+    pub lenient_piece_length: bool,
+
+    /// state saved from a previous run by `Torrent::resume_data`. ignored if it belongs to a
+    /// different torrent
+    pub resume: Option<ResumeData>,
+
+    pub announce: AnnouncePolicy,
+
+    /// overrides [Config::upload_slots] for this torrent
+    pub upload_slots: Option<UploadSlots>,
+
+    /// how the torrent's files take up disk space
+    pub allocation: Allocation,
+
+    /// where the torrent's files are written until they're complete
+    pub incomplete: Incomplete,
+
+    /// where the torrent's pieces are kept, its files on disk if None. see
+    /// [crate::storage::InMemory]
+    pub storage: Option<Arc<dyn StorageProvider>>,
+
+    /// chooses which piece to download next from a peer, rarest first with ties broken at
+    /// random if None. see [crate::picker::Sequential]
+    pub piece_strategy: Option<Arc<dyn PieceStrategy>>,
+}
+
+/// Allocation decides how a torrent's files are created on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Allocation {
+    /// files are created at their full length without writing anything, so they only take up
+    /// space as pieces arrive on filesystems with sparse file support
+    #[default]
+    Sparse,
+    /// every file is allocated at its full size when the torrent is added, with `fallocate` or
+    /// `SetFileInformationByHandle` where they're available and by filling it with zeros where
+    /// not. files aren't fragmented as pieces arrive out of order, and a torrent which won't
+    /// fit on disk fails to add rather than part way through downloading
+    Full,
+}
+
+/// Incomplete decides where a torrent's files are written while they're being downloaded, so
+/// programs watching the download directory never see half written files. each file is renamed
+/// into place once it's complete, which is atomic unless it's moved across filesystems. files
+/// which are already in place when the torrent is added stay there
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Incomplete {
+    /// in place, under their own names
+    #[default]
+    InPlace,
+    /// alongside where they belong, with a `.part` suffix
+    PartSuffix,
+    /// in this directory, laid out as they will be in the download directory
+    Dir(PathBuf),
+}
+
+/// AnnouncePolicy decides which of a torrent's trackers are announced to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AnnouncePolicy {
+    /// announce to the first tracker that responds, trying each tier in order (BEP-12)
+    #[default]
+    FailoverTiers,
+    /// announce to every tier at once, to the first tracker that responds in each
+    AllTiers,
+    /// announce to every tracker at once
+    AllTrackers,
+}
+
+/// ConflictPolicy decides what happens when a torrent's file (or directory for multi-file
+/// torrents) already exists in the download directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// keep the existing data and verify it before downloading anything
+    #[default]
+    Reuse,
+    /// download into a new location with a numbered suffix, eg. `file (1).txt`
+    Rename,
+    /// refuse to add the torrent
+    Fail,
+}
@@ -0,0 +1,82 @@
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit};
+
+use crate::config::Config;
+
+/// ConnectionPermit holds a place under [Config::max_connections] for as long as it's kept
+pub(crate) type ConnectionPermit = OwnedSemaphorePermit;
+
+/// ConnectionLimits caps the peer connections of every torrent in a session: the number open
+/// at once, see [Config::max_connections], and the number being dialed at once, see
+/// [Config::max_half_open]. it's shared by every torrent in a session
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionLimits {
+    connections: Arc<Semaphore>,
+    half_open: Arc<Semaphore>,
+}
+
+impl ConnectionLimits {
+    pub(crate) fn new(config: &Config) -> ConnectionLimits {
+        let permits = |max: Option<usize>| max.unwrap_or(Semaphore::MAX_PERMITS);
+        ConnectionLimits {
+            connections: Arc::new(Semaphore::new(permits(config.max_connections))),
+            half_open: Arc::new(Semaphore::new(permits(config.max_half_open))),
+        }
+    }
+
+    /// a place for one more connection, None if the session has as many as it allows. a place
+    /// is taken before dialing so connections in progress count towards the cap
+    pub(crate) fn try_connection(&self) -> Option<ConnectionPermit> {
+        self.connections.clone().try_acquire_owned().ok()
+    }
+
+    /// wait until another connection can be dialed. the permit is held until the connection
+    /// attempt finishes, successfully or not
+    pub(crate) async fn half_open(&self) -> SemaphorePermit<'_> {
+        // the semaphore is never closed
+        self.half_open.acquire().await.unwrap()
+    }
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> ConnectionLimits {
+        ConnectionLimits::new(&Config::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{future::FutureExt, poll};
+
+    use crate::{config::Config, connection_limits::ConnectionLimits};
+
+    #[tokio::test]
+    async fn limits() {
+        let config = Config {
+            max_connections: Some(2),
+            max_half_open: Some(1),
+            ..Default::default()
+        };
+        let limits = ConnectionLimits::new(&config);
+        let shared = limits.clone();
+
+        // places are shared by every clone and given back when dropped
+        let a = limits.try_connection().unwrap();
+        let _b = shared.try_connection().unwrap();
+        assert!(limits.try_connection().is_none());
+        drop(a);
+        assert!(shared.try_connection().is_some());
+
+        // a second dial waits for the first to finish
+        let dialing = limits.half_open().await;
+        let mut next = shared.half_open().boxed();
+        assert!(poll!(&mut next).is_pending());
+        drop(dialing);
+        assert!(poll!(&mut next).is_ready());
+
+        let unlimited = ConnectionLimits::default();
+        let permits = (0..1000).map_while(|_| unlimited.try_connection());
+        assert_eq!(permits.count(), 1000);
+    }
+}
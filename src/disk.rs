@@ -0,0 +1,454 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io, mem,
+    sync::{Arc, Mutex},
+};
+
+use hyper::body::Bytes;
+use tokio::sync::{oneshot, Semaphore};
+
+use crate::storage::{DiskStorage, FileSpan, Layout, Storage};
+
+// number of pieces read from disk at once
+const MAX_READS: usize = 4;
+// bytes of recently read pieces kept in memory, unless Config::read_cache_size says otherwise
+pub(crate) const CACHE_SIZE: u64 = 32 * 1024 * 1024;
+
+type ReadResult = Result<Bytes, io::ErrorKind>;
+
+/// DiskReader serves blocks of a torrent's pieces to peers. every block of a piece is read
+/// from disk together, and peers asking for a piece which is already being read wait for that
+/// read instead of starting their own. the most recently used pieces are kept in a [ReadCache],
+/// so hot pieces (eg. just after a torrent is released, when everyone wants
+/// the same few pieces) and the rest of a piece a peer is working through aren't read again.
+/// pieces we've downloaded and verified are written through it too. the pieces are kept in a
+/// [Storage], the torrent's files on disk unless it's given another
+#[derive(Debug)]
+pub struct DiskReader {
+    storage: Arc<dyn Storage>,
+    layout: Layout,
+    cache: ReadCache,
+    // tells this reader's pieces apart from other readers' in cache
+    id: u64,
+
+    limit: Semaphore,
+    state: Mutex<ReadState>,
+}
+
+#[derive(Debug, Default)]
+struct ReadState {
+    // peers waiting on a piece that is being read
+    pending: HashMap<u32, Vec<oneshot::Sender<ReadResult>>>,
+    // reads served from memory, and reads which had to go to disk
+    hits: u64,
+    misses: u64,
+}
+
+/// ReadCache keeps the most recently used pieces of every [DiskReader] sharing it in memory, up
+/// to size bytes between them. it's shared by every torrent in a session, so the memory it
+/// takes doesn't grow with the number of torrents
+#[derive(Debug, Clone)]
+pub(crate) struct ReadCache(Arc<Mutex<CacheState>>);
+
+#[derive(Debug)]
+struct CacheState {
+    size: u64,
+    // recently read pieces by reader id and index, least recently used first
+    pieces: VecDeque<((u64, u32), Bytes)>,
+    // bytes in pieces
+    cached: u64,
+    // id of the next reader to use the cache
+    next_id: u64,
+}
+
+impl DiskReader {
+    pub fn new(files: Vec<FileSpan>, piece_length: u32) -> DiskReader {
+        let cache = ReadCache::new(CACHE_SIZE);
+        DiskReader {
+            storage: Arc::new(DiskStorage::new(files.clone(), piece_length)),
+            layout: Layout::new(files, piece_length),
+            id: cache.reader_id(),
+            cache,
+
+            limit: Semaphore::new(MAX_READS),
+            state: Default::default(),
+        }
+    }
+
+    /// keep recently read pieces in cache rather than a cache of this reader's own
+    pub(crate) fn with_cache(mut self, cache: ReadCache) -> DiskReader {
+        self.cache.clear(self.id);
+        self.id = cache.reader_id();
+        self.cache = cache;
+        self
+    }
+
+    /// keep pieces in storage, which must be laid out the same way, rather than on disk
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> DiskReader {
+        self.storage = storage;
+        self
+    }
+
+    /// number of reads served from memory and number which went to disk
+    pub(crate) fn cache_stats(&self) -> (u64, u64) {
+        let state = self.state.lock().unwrap();
+        (state.hits, state.misses)
+    }
+
+    /// read len bytes starting at offset in piece
+    pub async fn read(&self, piece: u32, offset: u32, len: u32) -> io::Result<Bytes> {
+        let data = self.read_piece(piece).await?;
+
+        let (start, end) = (offset as usize, offset as usize + len as usize);
+        if end > data.len() {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+
+        Ok(data.slice(start..end))
+    }
+
+    /// write the whole of piece index, once it's passed its hash check. files are created as
+    /// needed, padding files are skipped
+    pub async fn write(&self, piece: u32, data: Vec<u8>) -> io::Result<()> {
+        // limit is never closed
+        let _permit = self.limit.acquire().await.unwrap();
+        let res = self.storage.write_block(piece, 0, data).await;
+
+        // a piece read before it was written is stale
+        self.cache.evict((self.id, piece));
+        res
+    }
+
+    async fn read_piece(&self, piece: u32) -> io::Result<Bytes> {
+        // whoever we wait on may go away part way through the read, we read the piece ourselves
+        // then, see [Reading]
+        loop {
+            let cached = self.cache.get((self.id, piece));
+            let waiting = {
+                let mut state = self.state.lock().unwrap();
+
+                if let Some(data) = cached {
+                    state.hits += 1;
+                    return Ok(data);
+                }
+
+                match state.pending.get_mut(&piece) {
+                    Some(waiters) => {
+                        let (tx, rx) = oneshot::channel();
+                        waiters.push(tx);
+                        state.hits += 1;
+                        Some(rx)
+                    }
+                    None => {
+                        state.pending.insert(piece, vec![]);
+                        None
+                    }
+                }
+            };
+
+            // someone else is already reading this piece
+            if let Some(rx) = waiting {
+                match rx.await {
+                    Ok(res) => return res.map_err(io::Error::from),
+                    Err(_) => continue,
+                }
+            }
+
+            let reading = Reading {
+                state: &self.state,
+                piece,
+            };
+            let res = self.read_from_disk(piece).await;
+
+            if let Ok(data) = &res {
+                self.cache.insert((self.id, piece), data.clone());
+            }
+            let mut state = self.state.lock().unwrap();
+            state.misses += 1;
+
+            let waiters = state.pending.remove(&piece).unwrap_or_default();
+            for tx in waiters {
+                let _ = tx.send(res.as_ref().map(Bytes::clone).map_err(io::Error::kind));
+            }
+            // the piece is no longer pending, a new read of it may already have started
+            mem::forget(reading);
+
+            return res;
+        }
+    }
+
+    async fn read_from_disk(&self, piece: u32) -> io::Result<Bytes> {
+        let len = self.layout.piece_size(piece);
+        let len = len.ok_or(io::ErrorKind::InvalidInput)?;
+
+        // limit is never closed
+        let _permit = self.limit.acquire().await.unwrap();
+        Ok(self.storage.read_block(piece, 0, len).await?.into())
+    }
+
+    /// where the torrent's pieces are kept
+    pub(crate) fn storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
+
+    /// where the torrent's pieces lie in its files
+    pub(crate) fn layout(&self) -> &Layout {
+        &self.layout
+    }
+}
+
+// Reading marks piece as being read until the read finishes. if the read is dropped part way
+// through, eg. the peer which asked for it went away, the piece stops being pending and the
+// peers waiting on it read it themselves rather than waiting forever
+struct Reading<'a> {
+    state: &'a Mutex<ReadState>,
+    piece: u32,
+}
+
+impl Drop for Reading<'_> {
+    fn drop(&mut self) {
+        // dropping the waiters' senders tells them the read was abandoned
+        self.state.lock().unwrap().pending.remove(&self.piece);
+    }
+}
+
+impl Drop for DiskReader {
+    fn drop(&mut self) {
+        // nothing can read this reader's pieces anymore
+        self.cache.clear(self.id);
+    }
+}
+
+impl ReadCache {
+    /// keep up to size bytes of recently read pieces in memory, 0 turns the cache off
+    pub(crate) fn new(size: u64) -> ReadCache {
+        ReadCache(Arc::new(Mutex::new(CacheState {
+            size,
+            pieces: VecDeque::new(),
+            cached: 0,
+            next_id: 0,
+        })))
+    }
+
+    fn reader_id(&self) -> u64 {
+        let mut state = self.0.lock().unwrap();
+        state.next_id += 1;
+        state.next_id
+    }
+
+    fn get(&self, key: (u64, u32)) -> Option<Bytes> {
+        let mut state = self.0.lock().unwrap();
+        let i = state.pieces.iter().position(|(k, _)| *k == key)?;
+
+        // it's now the most recently used
+        let cached = state.pieces.remove(i).unwrap();
+        let data = cached.1.clone();
+        state.pieces.push_back(cached);
+        Some(data)
+    }
+
+    // cache a piece which was just read, dropping the least recently used ones to make room
+    fn insert(&self, key: (u64, u32), data: Bytes) {
+        let mut state = self.0.lock().unwrap();
+        let len = data.len() as u64;
+        if len > state.size {
+            return;
+        }
+
+        state.evict(key);
+        while state.cached + len > state.size {
+            let Some((_, old)) = state.pieces.pop_front() else {
+                break;
+            };
+            state.cached -= old.len() as u64;
+        }
+        state.cached += len;
+        state.pieces.push_back((key, data));
+    }
+
+    fn evict(&self, key: (u64, u32)) {
+        self.0.lock().unwrap().evict(key);
+    }
+
+    // drop every piece of reader id
+    fn clear(&self, id: u64) {
+        let mut state = self.0.lock().unwrap();
+        let state = &mut *state;
+        state.pieces.retain(|((reader, _), data)| {
+            let keep = *reader != id;
+            if !keep {
+                state.cached -= data.len() as u64;
+            }
+            keep
+        });
+    }
+}
+
+impl CacheState {
+    fn evict(&mut self, key: (u64, u32)) {
+        let Some(i) = self.pieces.iter().position(|(k, _)| *k == key) else {
+            return;
+        };
+        if let Some((_, data)) = self.pieces.remove(i) {
+            self.cached -= data.len() as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use futures::{future::join_all, FutureExt};
+
+    use super::{DiskReader, FileSpan, ReadCache, MAX_READS};
+
+    #[tokio::test]
+    async fn batched_reads() {
+        let dir = env::temp_dir().join(format!("tsunami_disk_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), b"abcdef").unwrap();
+        fs::write(dir.join("b"), b"ghij").unwrap();
+
+        let span = |name, length, padding| FileSpan {
+            path: dir.join(name),
+            length,
+            padding,
+        };
+        let files = vec![
+            span("a", 6, false),
+            span("pad", 2, true),
+            span("b", 4, false),
+        ];
+        let reader = DiskReader::new(files, 4);
+
+        let reads = (0..4).map(|i| reader.read(1, i, 4 - i));
+        let blocks = join_all(reads).await;
+        assert_eq!(reader.cache_stats(), (3, 1));
+        assert_eq!(blocks[0].as_ref().unwrap(), &b"ef\0\0"[..]);
+        assert_eq!(blocks[3].as_ref().unwrap(), &b"\0"[..]);
+
+        // cached
+        assert_eq!(reader.read(1, 0, 2).await.unwrap(), &b"ef"[..]);
+        assert_eq!(reader.cache_stats(), (4, 1));
+
+        // last piece is short
+        assert_eq!(reader.read(2, 0, 4).await.unwrap(), &b"ghij"[..]);
+        assert!(reader.read(2, 2, 4).await.is_err());
+        assert!(reader.read(3, 0, 1).await.is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_cache() {
+        let dir = env::temp_dir().join(format!("tsunami_cache_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), b"abcdefghijkl").unwrap();
+        let files = vec![FileSpan {
+            path: dir.join("a"),
+            length: 12,
+            padding: false,
+        }];
+
+        // room for two pieces, the least recently used one goes to make room for a third
+        let cache = ReadCache::new(8);
+        let reader = DiskReader::new(files.clone(), 4).with_cache(cache.clone());
+        reader.read(0, 0, 4).await.unwrap();
+        reader.read(1, 0, 4).await.unwrap();
+        reader.read(0, 0, 4).await.unwrap();
+        reader.read(2, 0, 4).await.unwrap();
+        assert_eq!(reader.cache_stats(), (1, 3));
+        assert_eq!(reader.read(0, 2, 2).await.unwrap(), &b"cd"[..]);
+        assert_eq!(reader.cache_stats(), (2, 3));
+        assert_eq!(reader.read(1, 0, 4).await.unwrap(), &b"efgh"[..]);
+        assert_eq!(reader.cache_stats(), (2, 4));
+        assert_eq!(cache.0.lock().unwrap().cached, 8);
+
+        // the room is shared with every reader using the cache
+        let other = DiskReader::new(files.clone(), 4).with_cache(cache.clone());
+        other.read(0, 0, 4).await.unwrap();
+        assert_eq!(reader.read(0, 0, 4).await.unwrap(), &b"abcd"[..]);
+        assert_eq!(reader.cache_stats(), (2, 5));
+        assert_eq!(cache.0.lock().unwrap().cached, 8);
+
+        // a reader's pieces go with it
+        drop(reader);
+        assert_eq!(cache.0.lock().unwrap().cached, 4);
+        assert_eq!(other.read(0, 0, 4).await.unwrap(), &b"abcd"[..]);
+        assert_eq!(other.cache_stats(), (1, 1));
+
+        // pieces bigger than the cache aren't kept
+        let reader = DiskReader::new(files, 4).with_cache(ReadCache::new(0));
+        reader.read(0, 0, 4).await.unwrap();
+        reader.read(0, 0, 4).await.unwrap();
+        assert_eq!(reader.cache_stats(), (0, 2));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dropped_read() {
+        let dir = env::temp_dir().join(format!("tsunami_dropped_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), b"abcd").unwrap();
+        let files = vec![FileSpan {
+            path: dir.join("a"),
+            length: 4,
+            padding: false,
+        }];
+        let reader = DiskReader::new(files, 4);
+
+        // hold up every read until the permits are released
+        let permits = reader.limit.acquire_many(MAX_READS as u32).await.unwrap();
+        let mut first = Box::pin(reader.read(0, 0, 4));
+        let mut second = Box::pin(reader.read(0, 0, 4));
+        assert!((&mut first).now_or_never().is_none());
+        assert!((&mut second).now_or_never().is_none());
+
+        // the piece isn't left pending once the read is dropped, whoever waited on it reads
+        // the piece itself instead
+        drop(first);
+        assert!(reader.state.lock().unwrap().pending.is_empty());
+        assert!((&mut second).now_or_never().is_none());
+        assert!(reader.state.lock().unwrap().pending.contains_key(&0));
+        drop(permits);
+        assert_eq!(second.await.unwrap(), &b"abcd"[..]);
+        assert_eq!(reader.read(0, 0, 4).await.unwrap(), &b"abcd"[..]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn write() {
+        let dir = env::temp_dir().join(format!("tsunami_write_{}", process::id()));
+        let span = |name, length, padding| FileSpan {
+            path: dir.join(name),
+            length,
+            padding,
+        };
+        let files = vec![
+            span("a", 6, false),
+            span("pad", 2, true),
+            span("sub/b", 4, false),
+        ];
+        let reader = DiskReader::new(files, 4);
+
+        // pieces can be written in any order, files are created as they're reached
+        reader.write(2, b"ijkl".to_vec()).await.unwrap();
+        assert!(reader.read(0, 0, 4).await.is_err());
+        reader.write(0, b"abcd".to_vec()).await.unwrap();
+        assert_eq!(reader.read(0, 0, 4).await.unwrap(), &b"abcd"[..]);
+
+        // what was cached is replaced
+        reader.write(0, b"ABCD".to_vec()).await.unwrap();
+        assert_eq!(reader.read(0, 0, 4).await.unwrap(), &b"ABCD"[..]);
+        reader.write(1, b"ef\0\0".to_vec()).await.unwrap();
+        assert_eq!(fs::read(dir.join("a")).unwrap(), b"ABCDef");
+        assert_eq!(fs::read(dir.join("sub/b")).unwrap(), b"ijkl");
+        assert!(!dir.join("pad").exists());
+        assert!(reader.write(2, b"ijklm".to_vec()).await.is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use std::{io, result::Result as StdResult};
+use std::{io, path::PathBuf, result::Result as StdResult};
 
 use hyper::http::uri::InvalidUri;
 use thiserror::Error;
@@ -13,11 +13,93 @@ pub enum Error {
     #[error("exhausted all available trackers")]
     NoTrackerAvailable,
 
+    #[error("torrent has no trackers or other peer sources")]
+    NoPeerSource,
+
+    #[error("none of the torrent's trackers support scraping")]
+    ScrapeUnsupported,
+
     #[error("invalid tracker uri")]
     InvalidTrackerUri(#[from] InvalidUri),
 
+    #[error("no support for tracker `{0}`")]
+    UnsupportedTracker(String),
+
+    #[error("tracker connection failed")]
+    Io(#[from] io::Error),
+
     #[error("hyper error")]
     Hyper(#[from] hyper::Error),
+
+    #[error("http request timed out")]
+    Timeout,
+
+    #[error("invalid tracker request")]
+    InvalidRequest(#[from] hyper::http::Error),
+
+    #[error("http request failed with status {0}")]
+    HttpStatus(u16),
+
+    #[error("web seed sent the wrong amount of data")]
+    InvalidWebSeedResp,
+
+    #[error("web seed is busy, retry in {0}s")]
+    WebSeedBusy(u32),
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TorrentParseError {
+    #[error("torrent is not valid bencode")]
+    Bencode,
+
+    #[error("torrent is missing required key `{0}`")]
+    MissingKey(&'static str),
+
+    #[error("torrent key `{0}` has an invalid value")]
+    InvalidKey(&'static str),
+
+    #[error("pieces must be a list of 20 byte sha-1 hashes")]
+    InvalidPieces,
+
+    #[error("torrent has more than 2^32 pieces")]
+    TooManyPieces,
+
+    #[error("piece length must be a positive u32")]
+    InvalidPieceLength,
+
+    #[error("torrent must have exactly one of `length` or `files`")]
+    InvalidFileLayout,
+
+    #[error("invalid file entry")]
+    InvalidFile,
+
+    #[error("file name `{0}` is not valid on this system")]
+    InvalidPath(String),
+
+    #[error("{0} escapes the torrent's download directory")]
+    PathTraversal(PathBuf),
+
+    #[error("piece layers don't match the file tree's pieces roots")]
+    InvalidPieceLayers,
+
+    #[error("total torrent size overflows u64")]
+    SizeOverflow,
+
+    #[error("base_dir must be an absolute path")]
+    InvalidBaseDir,
+
+    #[error("{0} already exists")]
+    FileExists(PathBuf),
+}
+
+/// AddTorrentError is why a torrent couldn't be added to a session
+#[derive(Debug, Error)]
+pub enum AddTorrentError {
+    #[error("invalid torrent")]
+    Parse(#[from] TorrentParseError),
+
+    #[error("couldn't set up the torrent's files")]
+    Storage(#[from] io::Error),
 }
 
 #[derive(Debug, Error)]
@@ -27,4 +109,68 @@ pub enum DecodeError {
 
     #[error("unknown message id {0} (len: {1})")]
     MessageId(u8, u32),
+
+    #[error("peer sent nothing for too long")]
+    Idle,
+
+    #[error("bitfield has spare bits set")]
+    Bitfield,
+
+    #[error("invalid extension handshake")]
+    ExtensionHandshake,
+}
+
+/// HandshakeError is why a handshake with a peer failed, in either direction. timeouts and io
+/// errors mean the peer is unreachable and may be retried later, the rest that it answered with
+/// something we can't use
+#[derive(Debug, Error)]
+pub enum HandshakeError {
+    #[error("io error")]
+    Io(#[from] io::Error),
+
+    #[error("peer didn't accept the connection in time")]
+    ConnectTimeout,
+
+    #[error("peer didn't complete the handshake in time")]
+    Timeout,
+
+    #[error("peer doesn't speak the bittorrent protocol")]
+    Protocol,
+
+    #[error("peer is serving a different torrent, or one we don't have")]
+    InfoHash,
+}
+
+/// CommandError is returned by [crate::handle::TorrentHandle] operations
+#[derive(Debug, Error)]
+pub enum CommandError {
+    #[error("torrent was removed from its session")]
+    TorrentRemoved,
+
+    #[error("torrent has no file at index {0}")]
+    InvalidFileIndex(usize),
+
+    #[error("invalid file name `{0}`")]
+    InvalidFileName(String),
+
+    #[error("torrent has no piece at index {0}")]
+    InvalidPieceIndex(u32),
+
+    #[error("invalid tracker url `{0}`")]
+    InvalidTracker(String),
+
+    #[error("torrent has no tracker `{0}`")]
+    UnknownTracker(String),
+
+    #[error("torrent's storage is in use")]
+    StorageBusy,
+
+    #[error("resuming the torrent would exceed the session's disk quota")]
+    QuotaExceeded,
+
+    #[error("storage error")]
+    Storage(#[from] io::Error),
+
+    #[error("announce failed")]
+    Announce(#[from] Error),
 }
@@ -0,0 +1,196 @@
+use tokio::sync::{
+    broadcast::{
+        self,
+        error::{RecvError, TryRecvError},
+    },
+    mpsc,
+};
+
+use crate::{resume::ResumeData, torrent::Sha1Hash};
+
+// default number of events queued for a consumer
+const CAPACITY: usize = 1024;
+
+/// Event is a notification emitted by a session for consumers such as UIs or loggers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// a torrent was not started because the projected disk usage of all active torrents
+    /// (`projected`) would exceed the session's disk quota
+    QuotaExceeded {
+        info_hash: Sha1Hash,
+        projected: u64,
+        quota: u64,
+    },
+    /// a tracker accepted an announce but sent a `warning message` along with it
+    TrackerWarning {
+        info_hash: Sha1Hash,
+        tracker: String,
+        message: String,
+    },
+    /// a torrent's data is being checked against its hashes, see
+    /// [crate::handle::TorrentHandle::force_recheck]. `checked` of its `pieces` pieces have been
+    /// checked so far, the check is done once they're equal
+    RecheckProgress {
+        info_hash: Sha1Hash,
+        checked: u32,
+        pieces: u32,
+    },
+    /// a torrent's file couldn't be finished off once it was complete, eg. moved into place
+    /// from where it was written while incomplete. it's tried again when the torrent's next
+    /// added
+    FileError {
+        info_hash: Sha1Hash,
+        file: usize,
+        error: String,
+    },
+    /// a torrent was stopped, with its resume data as of the stop. adding the torrent again
+    /// with it picks up where it left off, see [crate::config::AddTorrentOptions::resume]
+    Stopped {
+        info_hash: Sha1Hash,
+        resume: Box<ResumeData>,
+    },
+    /// the consumer fell behind and `dropped` of the oldest events were discarded, see
+    /// [EventPolicy::DropOldest]
+    Overflow { dropped: u64 },
+}
+
+/// EventPolicy decides what happens when a session emits events faster than they're consumed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventPolicy {
+    /// never drop events. once `capacity` events are queued the session waits for the consumer
+    /// to catch up, so a slow (or absent) consumer stalls the session. that includes
+    /// [crate::tsunami::Tsunami::add_torrent] and
+    /// [crate::tsunami::Tsunami::resume_torrent] when they emit an [Event::QuotaExceeded]
+    Lossless { capacity: usize },
+    /// keep at most `capacity` events, discarding the oldest to make room. the consumer is told
+    /// how many events it missed with an [Event::Overflow]
+    DropOldest { capacity: usize },
+}
+
+impl Default for EventPolicy {
+    fn default() -> EventPolicy {
+        EventPolicy::DropOldest { capacity: CAPACITY }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Tx {
+    Lossless(mpsc::Sender<Event>),
+    DropOldest(broadcast::Sender<Event>),
+}
+
+#[derive(Debug)]
+enum Rx {
+    Lossless(mpsc::Receiver<Event>),
+    DropOldest(broadcast::Receiver<Event>),
+}
+
+#[derive(Debug, Clone)]
+pub struct EventSender(Tx);
+
+/// EventReceiver is the consuming end of a session's events, see
+/// [crate::tsunami::Tsunami::take_events]
+#[derive(Debug)]
+pub struct EventReceiver(Rx);
+
+impl EventSender {
+    pub fn new(policy: EventPolicy) -> (EventSender, EventReceiver) {
+        let (tx, rx) = match policy {
+            EventPolicy::Lossless { capacity } => {
+                let (tx, rx) = mpsc::channel(capacity.max(1));
+                (Tx::Lossless(tx), Rx::Lossless(rx))
+            }
+            EventPolicy::DropOldest { capacity } => {
+                let (tx, rx) = broadcast::channel(capacity.max(1));
+                (Tx::DropOldest(tx), Rx::DropOldest(rx))
+            }
+        };
+
+        (EventSender(tx), EventReceiver(rx))
+    }
+
+    /// emit an event, silently dropping it if nobody is listening. with
+    /// [EventPolicy::Lossless] this waits while the consumer's queue is full
+    pub async fn emit(&self, event: Event) {
+        match &self.0 {
+            Tx::Lossless(tx) => {
+                let _ = tx.send(event).await;
+            }
+            Tx::DropOldest(tx) => {
+                let _ = tx.send(event);
+            }
+        }
+    }
+}
+
+impl EventReceiver {
+    /// wait for the next event. returns None once the session is dropped and every queued event
+    /// was received
+    pub async fn recv(&mut self) -> Option<Event> {
+        match &mut self.0 {
+            Rx::Lossless(rx) => rx.recv().await,
+            Rx::DropOldest(rx) => match rx.recv().await {
+                Ok(event) => Some(event),
+                Err(RecvError::Lagged(dropped)) => Some(Event::Overflow { dropped }),
+                Err(RecvError::Closed) => None,
+            },
+        }
+    }
+
+    /// the next event if one is queued
+    pub fn try_recv(&mut self) -> Option<Event> {
+        match &mut self.0 {
+            Rx::Lossless(rx) => rx.try_recv().ok(),
+            Rx::DropOldest(rx) => match rx.try_recv() {
+                Ok(event) => Some(event),
+                Err(TryRecvError::Lagged(dropped)) => Some(Event::Overflow { dropped }),
+                Err(_) => None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::{Event, EventPolicy, EventSender};
+
+    fn event(n: u64) -> Event {
+        Event::QuotaExceeded {
+            info_hash: [0; 20],
+            projected: n,
+            quota: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        let (tx, mut rx) = EventSender::new(EventPolicy::DropOldest { capacity: 2 });
+        for n in 0..5 {
+            tx.emit(event(n)).await;
+        }
+
+        assert_eq!(rx.try_recv(), Some(Event::Overflow { dropped: 3 }));
+        assert_eq!(rx.try_recv(), Some(event(3)));
+        assert_eq!(rx.recv().await, Some(event(4)));
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[tokio::test]
+    async fn lossless() {
+        let (tx, mut rx) = EventSender::new(EventPolicy::Lossless { capacity: 1 });
+        tx.emit(event(0)).await;
+
+        // queue is full, emitting waits for the consumer
+        let mut blocked = Box::pin(tx.emit(event(1)));
+        assert!((&mut blocked).now_or_never().is_none());
+
+        assert_eq!(rx.recv().await, Some(event(0)));
+        blocked.await;
+        assert_eq!(rx.try_recv(), Some(event(1)));
+
+        drop(tx);
+        assert_eq!(rx.recv().await, None);
+    }
+}
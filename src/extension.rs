@@ -0,0 +1,447 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use bitflags::bitflags;
+
+use crate::{
+    torrent_ast::Bencode,
+    utils::{encode_compact_peers, parse_compact_peers, parse_compact_peers6},
+};
+
+/// the id peers send us ut_pex messages with, see [PexMessage]
+pub(crate) const UT_PEX: u8 = 1;
+/// the id peers send us lt_donthave messages with. the payload is the index of a piece the
+/// sender no longer has, see BEP-54
+pub(crate) const LT_DONTHAVE: u8 = 2;
+/// the id peers send us ut_holepunch messages with, see [Holepunch]
+pub(crate) const UT_HOLEPUNCH: u8 = 3;
+
+/// extensions we support and the ids peers should send them to us with. a peer picks its own
+/// ids, see [ExtensionHandshake::m]
+pub(crate) const OUR_EXTENSIONS: &[(&str, u8)] = &[
+    ("ut_pex", UT_PEX),
+    ("lt_donthave", LT_DONTHAVE),
+    ("ut_holepunch", UT_HOLEPUNCH),
+];
+
+// extensions which mustn't be used on private torrents, they should only get peers from their
+// trackers
+const PUBLIC_ONLY: &[&str] = &["ut_pex"];
+
+/// most peers a single PEX message should add or drop. messages with more are cut short
+pub(crate) const MAX_PEX_PEERS: usize = 50;
+
+/// number of outstanding requests we advertise accepting from each peer
+pub(crate) const REQQ: u32 = 250;
+
+/// ExtensionHandshake is the payload of the BEP-10 extension handshake, the extended message
+/// with id 0. it's sent once after the bittorrent handshake and may be resent to enable or
+/// disable extensions
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtensionHandshake {
+    /// extension names mapped to the message id the sender wants them sent with. an id of 0
+    /// disables the extension
+    pub m: HashMap<String, u8>,
+    /// client name and version
+    pub v: Option<String>,
+    /// number of outstanding requests the sender accepts
+    pub reqq: Option<u32>,
+    /// size of the info dictionary, sent by peers supporting ut_metadata that have it
+    pub metadata_size: Option<u64>,
+    /// the sender only uploads, eg. it's a partial seed with every piece it wants, BEP-21
+    pub upload_only: Option<bool>,
+}
+
+impl ExtensionHandshake {
+    /// the handshake we send. metadata_size is None until we have the torrent's metadata.
+    /// private torrents leave out extensions which find peers
+    pub fn ours(metadata_size: Option<u64>, private: bool) -> ExtensionHandshake {
+        let ours = OUR_EXTENSIONS.iter();
+        let ours = ours.filter(|(name, _)| !private || !PUBLIC_ONLY.contains(name));
+        let m = ours.map(|&(name, id)| (name.into(), id));
+
+        ExtensionHandshake {
+            m: m.collect(),
+            v: Some(concat!("Tsunami ", env!("CARGO_PKG_VERSION")).into()),
+            reqq: Some(REQQ),
+            metadata_size,
+            upload_only: None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut m = HashMap::new();
+        for (name, &id) in &self.m {
+            m.insert(name.as_bytes(), Bencode::Num(id as i64));
+        }
+        let mut dict = HashMap::from([(&b"m"[..], Bencode::Dict(m))]);
+
+        if let Some(v) = &self.v {
+            dict.insert(b"v", Bencode::Str(v));
+        }
+        if let Some(reqq) = self.reqq {
+            dict.insert(b"reqq", Bencode::Num(reqq as i64));
+        }
+        if let Some(size) = self.metadata_size {
+            dict.insert(b"metadata_size", Bencode::Num(size as i64));
+        }
+        if let Some(upload_only) = self.upload_only {
+            dict.insert(b"upload_only", Bencode::Num(upload_only as i64));
+        }
+
+        let mut buf = vec![];
+        Bencode::Dict(dict).encode(&mut buf);
+        buf
+    }
+
+    /// decode a handshake. keys other than the ones we use are ignored, as are values of the
+    /// wrong type since clients disagree on some of them
+    pub fn decode(buf: &[u8]) -> Option<ExtensionHandshake> {
+        let mut dict = Bencode::decode(buf)?.dict()?;
+
+        let mut m = HashMap::new();
+        if let Some(ids) = dict.remove(&b"m"[..]) {
+            for (name, id) in ids.dict()? {
+                let (Ok(name), Some(id)) = (std::str::from_utf8(name), id.num()) else {
+                    continue;
+                };
+                if let Ok(id) = id.try_into() {
+                    m.insert(name.into(), id);
+                }
+            }
+        }
+
+        let v = dict.remove(&b"v"[..]).and_then(Bencode::bytes);
+        let num = |key: &[u8]| dict.get(key).cloned().and_then(Bencode::num);
+
+        Some(ExtensionHandshake {
+            m,
+            v: v.map(|v| String::from_utf8_lossy(v).into()),
+            reqq: num(b"reqq").and_then(|n| n.try_into().ok()),
+            metadata_size: num(b"metadata_size").and_then(|n| n.try_into().ok()),
+            upload_only: num(b"upload_only").map(|n| n != 0),
+        })
+    }
+
+    /// apply a handshake resent by the same peer. ids of 0 disable their extension and fields
+    /// left out keep their previous value
+    pub fn update(&mut self, other: ExtensionHandshake) {
+        for (name, id) in other.m {
+            match id {
+                0 => self.m.remove(&name),
+                id => self.m.insert(name, id),
+            };
+        }
+
+        self.v = other.v.or(self.v.take());
+        self.reqq = other.reqq.or(self.reqq);
+        self.metadata_size = other.metadata_size.or(self.metadata_size);
+        self.upload_only = other.upload_only.or(self.upload_only);
+    }
+}
+
+bitflags! {
+    /// PexFlags describe a peer added by a [PexMessage]
+    pub struct PexFlags: u8 {
+        /// the peer prefers encrypted connections
+        const ENCRYPTION = 0x01;
+        /// the peer has every piece
+        const SEED = 0x02;
+        const UTP = 0x04;
+        const HOLEPUNCH = 0x08;
+        /// the peer accepts incoming connections
+        const REACHABLE = 0x10;
+    }
+}
+
+/// PexMessage is the payload of a BEP-11 ut_pex message, the peers the sender connected to and
+/// disconnected from since its last one. the first message sent to a peer adds every peer the
+/// sender is connected to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PexMessage {
+    pub added: Vec<(SocketAddr, PexFlags)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        // flags are listed in the same order as their peers, split by address family
+        let (mut flags, mut flags6) = (vec![], vec![]);
+        for (addr, f) in &self.added {
+            match addr {
+                SocketAddr::V4(_) => flags.push(f.bits()),
+                SocketAddr::V6(_) => flags6.push(f.bits()),
+            }
+        }
+
+        let added: Vec<_> = self.added.iter().map(|&(addr, _)| addr).collect();
+        let (added, added6) = encode_compact_peers(&added);
+        let (dropped, dropped6) = encode_compact_peers(&self.dropped);
+        let dict = HashMap::from([
+            (&b"added"[..], Bencode::BStr(&added)),
+            (b"added.f", Bencode::BStr(&flags)),
+            (b"added6", Bencode::BStr(&added6)),
+            (b"added6.f", Bencode::BStr(&flags6)),
+            (b"dropped", Bencode::BStr(&dropped)),
+            (b"dropped6", Bencode::BStr(&dropped6)),
+        ]);
+
+        let mut buf = vec![];
+        Bencode::Dict(dict).encode(&mut buf);
+        buf
+    }
+
+    /// decode a message, keeping at most [MAX_PEX_PEERS] added and dropped peers. peers
+    /// without flags get none
+    pub fn decode(buf: &[u8]) -> Option<PexMessage> {
+        let dict = Bencode::decode(buf)?.dict()?;
+        let bytes = |key: &[u8]| dict.get(key).cloned().and_then(Bencode::bytes);
+        let flags = |key: &[u8]| Self::flags(bytes(key).unwrap_or_default());
+
+        let v4 = |key: &[u8]| bytes(key).map(parse_compact_peers).unwrap_or_default();
+        let v6 = |key: &[u8]| bytes(key).map(parse_compact_peers6).unwrap_or_default();
+
+        let added = v4(b"added").into_iter().zip(flags(b"added.f"));
+        let added6 = v6(b"added6").into_iter().zip(flags(b"added6.f"));
+        let dropped = v4(b"dropped").into_iter().chain(v6(b"dropped6"));
+
+        Some(PexMessage {
+            added: added.chain(added6).take(MAX_PEX_PEERS).collect(),
+            dropped: dropped.take(MAX_PEX_PEERS).collect(),
+        })
+    }
+
+    // flags for each added peer, padded out with empty flags for peers the sender left out
+    fn flags(buf: &[u8]) -> impl Iterator<Item = PexFlags> + '_ {
+        let flags = buf.iter().map(|&f| PexFlags::from_bits_truncate(f));
+        flags.chain(std::iter::repeat(PexFlags::empty()))
+    }
+}
+
+/// Holepunch is a BEP-55 ut_holepunch message, used to connect two peers which can't accept
+/// connections through a relay peer connected to both of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Holepunch {
+    /// sent to the relay, asking it to introduce us to the peer at addr
+    Rendezvous(SocketAddr),
+    /// sent by the relay to both peers, each should connect to the peer at addr at once
+    Connect(SocketAddr),
+    /// sent by the relay when it can't introduce us to the peer at addr
+    Error(SocketAddr, HolepunchError),
+}
+
+/// HolepunchError is why a relay refused a [Holepunch::Rendezvous]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolepunchError {
+    /// the relay doesn't know of the peer
+    NoSuchPeer = 1,
+    /// the relay isn't connected to the peer
+    NotConnected = 2,
+    /// the peer doesn't support ut_holepunch
+    NoSupport = 3,
+    /// the peer is the sender
+    NoSelf = 4,
+}
+
+impl Holepunch {
+    pub fn addr(&self) -> SocketAddr {
+        match *self {
+            Holepunch::Rendezvous(addr) | Holepunch::Connect(addr) => addr,
+            Holepunch::Error(addr, _) => addr,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        // msg type | addr type | addr | port | error code
+        let (kind, err) = match *self {
+            Holepunch::Rendezvous(_) => (0, 0),
+            Holepunch::Connect(_) => (1, 0),
+            Holepunch::Error(_, err) => (2, err as u32),
+        };
+
+        let addr = self.addr();
+        let mut buf = vec![kind];
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                buf.push(0);
+                buf.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                buf.push(1);
+                buf.extend_from_slice(&ip.octets());
+            }
+        }
+        buf.extend_from_slice(&addr.port().to_be_bytes());
+        buf.extend_from_slice(&err.to_be_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Holepunch> {
+        let (&[kind, addr_type], rest) = buf.split_first_chunk()?;
+        let (ip, rest): (IpAddr, _) = match addr_type {
+            0 => {
+                let (ip, rest) = rest.split_first_chunk::<4>()?;
+                (Ipv4Addr::from(*ip).into(), rest)
+            }
+            1 => {
+                let (ip, rest) = rest.split_first_chunk::<16>()?;
+                (Ipv6Addr::from(*ip).into(), rest)
+            }
+            _ => return None,
+        };
+        let (port, rest) = rest.split_first_chunk()?;
+        let err: &[u8; 4] = rest.try_into().ok()?;
+        let addr = SocketAddr::new(ip, u16::from_be_bytes(*port));
+
+        match (kind, u32::from_be_bytes(*err)) {
+            (0, _) => Some(Holepunch::Rendezvous(addr)),
+            (1, _) => Some(Holepunch::Connect(addr)),
+            (2, 1) => Some(Holepunch::Error(addr, HolepunchError::NoSuchPeer)),
+            (2, 2) => Some(Holepunch::Error(addr, HolepunchError::NotConnected)),
+            (2, 3) => Some(Holepunch::Error(addr, HolepunchError::NoSupport)),
+            (2, 4) => Some(Holepunch::Error(addr, HolepunchError::NoSelf)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{
+        ExtensionHandshake, Holepunch, HolepunchError, PexFlags, PexMessage, MAX_PEX_PEERS,
+    };
+
+    #[test]
+    fn decode() {
+        // sent by qBittorrent
+        let buf = b"d12:complete_agoi1e1:md11:lt_donthavei7e10:share_modei8e11:upload_onlyi3e\
+                    12:ut_holepunchi4e11:ut_metadatai2e6:ut_pexi1ee13:metadata_sizei31235e\
+                    4:reqqi500e11:upload_onlyi0e1:v17:qBittorrent/4.5.26:yourip4:\x7f\x00\x00\x01e";
+        let hs = ExtensionHandshake::decode(buf).unwrap();
+
+        assert_eq!(hs.m.len(), 6);
+        assert_eq!(hs.m["ut_metadata"], 2);
+        assert_eq!(hs.m["ut_pex"], 1);
+        assert_eq!(hs.v.as_deref(), Some("qBittorrent/4.5.2"));
+        assert_eq!(hs.reqq, Some(500));
+        assert_eq!(hs.metadata_size, Some(31235));
+        assert_eq!(hs.upload_only, Some(false));
+
+        assert_eq!(ExtensionHandshake::decode(b"de"), Some(Default::default()));
+        assert_eq!(ExtensionHandshake::decode(b"d1:mi1ee"), None);
+        assert_eq!(ExtensionHandshake::decode(b"le"), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let hs = ExtensionHandshake {
+            m: HashMap::from([("ut_metadata".into(), 3), ("ut_pex".into(), 1)]),
+            upload_only: Some(true),
+            ..ExtensionHandshake::ours(Some(1 << 20), false)
+        };
+
+        let buf = hs.encode();
+        assert_eq!(ExtensionHandshake::decode(&buf), Some(hs));
+    }
+
+    #[test]
+    fn update() {
+        let mut hs = ExtensionHandshake {
+            m: HashMap::from([("ut_metadata".into(), 3), ("ut_pex".into(), 1)]),
+            reqq: Some(250),
+            ..Default::default()
+        };
+
+        let resent = ExtensionHandshake {
+            m: HashMap::from([("ut_pex".into(), 0), ("lt_donthave".into(), 7)]),
+            metadata_size: Some(100),
+            upload_only: Some(true),
+            ..Default::default()
+        };
+        hs.update(resent);
+
+        let m = HashMap::from([("ut_metadata".into(), 3), ("lt_donthave".into(), 7)]);
+        assert_eq!(hs.m, m);
+        assert_eq!(hs.reqq, Some(250));
+        assert_eq!(hs.metadata_size, Some(100));
+        assert_eq!(hs.upload_only, Some(true));
+    }
+
+    #[test]
+    fn ours() {
+        let public = ExtensionHandshake::ours(None, false);
+        assert_eq!(public.m.get("ut_pex"), Some(&super::UT_PEX));
+
+        let private = ExtensionHandshake::ours(None, true);
+        assert_eq!(private.m.len(), 2);
+        assert_eq!(private.m.get("ut_pex"), None);
+        assert_eq!(private.m.get("lt_donthave"), Some(&super::LT_DONTHAVE));
+    }
+
+    #[test]
+    fn pex() {
+        // sent by qBittorrent
+        let buf = b"d5:added12:\x0a\x00\x00\x01\x1a\xe1\xc0\xa8\x01\x02\x1b\x397:added.f1:\x02\
+                    6:added60:8:added6.f0:7:dropped6:\x0a\x00\x00\x03\x00\x508:dropped60:e";
+        let msg = PexMessage::decode(buf).unwrap();
+
+        let added = vec![
+            ("10.0.0.1:6881".parse().unwrap(), PexFlags::SEED),
+            ("192.168.1.2:6969".parse().unwrap(), PexFlags::empty()),
+        ];
+        assert_eq!(msg.added, added);
+        assert_eq!(msg.dropped, vec!["10.0.0.3:80".parse().unwrap()]);
+
+        let msg = PexMessage {
+            added: vec![
+                ("10.0.0.1:6881".parse().unwrap(), PexFlags::ENCRYPTION),
+                ("[::1]:6881".parse().unwrap(), PexFlags::UTP),
+            ],
+            dropped: vec!["[::2]:80".parse().unwrap()],
+        };
+        assert_eq!(PexMessage::decode(&msg.encode()), Some(msg));
+
+        assert_eq!(PexMessage::decode(b"de"), Some(PexMessage::default()));
+        assert_eq!(PexMessage::decode(b"le"), None);
+
+        let many = PexMessage {
+            added: vec![("10.0.0.1:6881".parse().unwrap(), PexFlags::empty()); 60],
+            dropped: vec![],
+        };
+        let msg = PexMessage::decode(&many.encode()).unwrap();
+        assert_eq!(msg.added.len(), MAX_PEX_PEERS);
+    }
+
+    #[test]
+    fn holepunch() {
+        let addr = "10.0.0.1:6881".parse().unwrap();
+        let buf = b"\x00\x00\x0a\x00\x00\x01\x1a\xe1\x00\x00\x00\x00";
+        assert_eq!(Holepunch::decode(buf), Some(Holepunch::Rendezvous(addr)));
+        assert_eq!(Holepunch::Rendezvous(addr).encode(), buf);
+
+        let addr6 = "[::1]:6881".parse().unwrap();
+        let msgs = [
+            Holepunch::Connect(addr6),
+            Holepunch::Error(addr, HolepunchError::NotConnected),
+            Holepunch::Error(addr6, HolepunchError::NoSelf),
+        ];
+        for msg in msgs {
+            assert_eq!(Holepunch::decode(&msg.encode()), Some(msg));
+        }
+
+        // missing the error code, an unknown error and an unknown address type
+        assert_eq!(Holepunch::decode(&buf[..8]), None);
+        let unknown_err = b"\x02\x00\x0a\x00\x00\x01\x1a\xe1\x00\x00\x00\x05";
+        assert_eq!(Holepunch::decode(unknown_err), None);
+        let unknown_addr = b"\x00\x02\x0a\x00\x00\x01\x1a\xe1\x00\x00\x00\x00";
+        assert_eq!(Holepunch::decode(unknown_addr), None);
+    }
+}
@@ -0,0 +1,150 @@
+use std::path::PathBuf;
+
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+
+use crate::{error::CommandError, picker::FilePriority, torrent::Sha1Hash};
+
+pub(crate) type Reply = oneshot::Sender<Result<(), CommandError>>;
+
+/// Command is a request sent from a [TorrentHandle] to its torrent, along with where to send
+/// the result
+#[derive(Debug)]
+pub(crate) enum Command {
+    Pause(Reply),
+    Resume(Reply),
+    Reannounce(Reply),
+    MoveStorage(PathBuf, Reply),
+    RenameFile(usize, String, Reply),
+    RenameRoot(String, Reply),
+    AddTracker(String, usize, Reply),
+    RemoveTracker(String, Reply),
+    SetTrackers(Vec<Vec<String>>, Reply),
+    SetFilePriority(usize, FilePriority, Reply),
+    SetPiecePriority(u32, FilePriority, Reply),
+    ForceRecheck(Reply),
+    // sent by the torrent's announce task when an announce is due, see [crate::tracker::Announcer]
+    Announce,
+}
+
+/// TorrentHandle controls a torrent from anywhere, eg. another task. handles are cheap to clone
+/// and every operation reports whether it succeeded. operations on a torrent which has been
+/// removed from its session fail with [CommandError::TorrentRemoved]
+#[derive(Debug, Clone)]
+pub struct TorrentHandle {
+    info_hash: Sha1Hash,
+    tx: UnboundedSender<Command>,
+}
+
+pub(crate) fn channel(info_hash: Sha1Hash) -> (TorrentHandle, UnboundedReceiver<Command>) {
+    let (tx, rx) = unbounded_channel();
+    (TorrentHandle { info_hash, tx }, rx)
+}
+
+impl TorrentHandle {
+    pub fn info_hash(&self) -> &Sha1Hash {
+        &self.info_hash
+    }
+
+    pub async fn pause(&self) -> Result<(), CommandError> {
+        self.send(Command::Pause).await
+    }
+
+    /// resume the torrent. in a session with a disk quota this fails with
+    /// [CommandError::QuotaExceeded] if there isn't room for the torrent, see
+    /// [crate::tsunami::Tsunami::resume_torrent]
+    pub async fn resume(&self) -> Result<(), CommandError> {
+        self.send(Command::Resume).await
+    }
+
+    /// announce to the torrent's trackers now, regardless of when the next announce is due
+    pub async fn reannounce(&self) -> Result<(), CommandError> {
+        self.send(Command::Reannounce).await
+    }
+
+    /// move the torrent's files into dir. the torrent must not be active while its files are
+    /// moved, otherwise this fails with [CommandError::StorageBusy]
+    pub async fn move_storage(&self, dir: PathBuf) -> Result<(), CommandError> {
+        self.send(|reply| Command::MoveStorage(dir, reply)).await
+    }
+
+    /// rename the file at index in the torrent's file list, keeping it in the same directory.
+    /// name is sanitized like the torrent's own file names were, see
+    /// [crate::config::AddTorrentOptions::sanitize]. as with [TorrentHandle::move_storage], the
+    /// torrent must not be active
+    pub async fn rename_file(&self, index: usize, name: String) -> Result<(), CommandError> {
+        let cmd = |reply| Command::RenameFile(index, name, reply);
+        self.send(cmd).await
+    }
+
+    /// rename the directory a multi-file torrent's files are in, or the file of a single-file
+    /// torrent, see [TorrentHandle::rename_file]
+    pub async fn rename_root(&self, name: String) -> Result<(), CommandError> {
+        self.send(|reply| Command::RenameRoot(name, reply)).await
+    }
+
+    /// add a tracker to the end of tier, or to a new last tier if there's no such tier. adding a
+    /// tracker the torrent already has does nothing. new trackers are announced to on the next
+    /// announce, see [TorrentHandle::reannounce]
+    pub async fn add_tracker(&self, url: String, tier: usize) -> Result<(), CommandError> {
+        let cmd = |reply| Command::AddTracker(url, tier, reply);
+        self.send(cmd).await
+    }
+
+    pub async fn remove_tracker(&self, url: String) -> Result<(), CommandError> {
+        self.send(|reply| Command::RemoveTracker(url, reply)).await
+    }
+
+    /// replace the torrent's whole announce list, see [crate::torrent::Torrent::set_trackers]
+    pub async fn set_trackers(&self, trackers: Vec<Vec<String>>) -> Result<(), CommandError> {
+        let cmd = |reply| Command::SetTrackers(trackers, reply);
+        self.send(cmd).await
+    }
+
+    /// set how much file is wanted, by its index in the torrent's file list. files are downloaded
+    /// most wanted first and skipped files aren't downloaded at all. a torrent whose wanted files
+    /// are complete seeds them
+    pub async fn set_file_priority(
+        &self,
+        file: usize,
+        priority: FilePriority,
+    ) -> Result<(), CommandError> {
+        let cmd = |reply| Command::SetFilePriority(file, priority, reply);
+        self.send(cmd).await
+    }
+
+    /// set how much a single piece is wanted, eg. to fetch what's about to be played first. this
+    /// lasts until the priority of a file the piece is part of changes
+    pub async fn set_piece_priority(
+        &self,
+        piece: u32,
+        priority: FilePriority,
+    ) -> Result<(), CommandError> {
+        let cmd = |reply| Command::SetPiecePriority(piece, priority, reply);
+        self.send(cmd).await
+    }
+
+    /// check the torrent's data on disk against its hashes again, eg. after moving files in by
+    /// hand. this returns once the check has started, its progress is reported as
+    /// [crate::events::Event::RecheckProgress]
+    pub async fn force_recheck(&self) -> Result<(), CommandError> {
+        self.send(Command::ForceRecheck).await
+    }
+
+    /// ask the torrent to announce, returning false if it was dropped
+    pub(crate) fn request_announce(&self) -> bool {
+        self.tx.send(Command::Announce).is_ok()
+    }
+
+    async fn send(&self, cmd: impl FnOnce(Reply) -> Command) -> Result<(), CommandError> {
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send(cmd(reply))
+            .map_err(|_| CommandError::TorrentRemoved)?;
+
+        // the torrent was dropped before getting to our command
+        rx.await.unwrap_or(Err(CommandError::TorrentRemoved))
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use hyper::body::Bytes;
+use ring::digest;
+use tokio::{sync::oneshot, task};
+
+use crate::{merkle::PieceHash, torrent::Sha1Hash};
+
+// number of pieces a worker takes off the queue at a time
+const BATCH: usize = 4;
+
+/// PieceCheck is the hashes a piece's data must match
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PieceCheck {
+    pub(crate) sha1: Sha1Hash,
+    // pieces of hybrid torrents must match their v2 hash too, once we know it. holds the number
+    // of the piece's bytes which are the file's
+    pub(crate) v2: Option<(PieceHash, usize)>,
+}
+
+/// Hasher checks pieces against their hashes on tokio's blocking pool, so hashing a large piece
+/// never stalls the tasks driving our peers. pieces wait in a queue which at most max_workers
+/// workers take from, a batch at a time, so a burst of completed pieces doesn't take every
+/// blocking thread. it's shared by every torrent in a session
+#[derive(Debug, Clone)]
+pub(crate) struct Hasher(Arc<HashState>);
+
+#[derive(Debug)]
+struct HashState {
+    max_workers: usize,
+    queue: Mutex<Queue>,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    jobs: VecDeque<Job>,
+    // workers hashing jobs right now
+    workers: usize,
+}
+
+#[derive(Debug)]
+struct Job {
+    check: PieceCheck,
+    data: Bytes,
+    done: oneshot::Sender<(bool, Bytes)>,
+}
+
+impl PieceCheck {
+    pub(crate) fn matches(&self, data: &[u8]) -> bool {
+        let sha1 = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, data);
+        let v2 = |(hash, len): (PieceHash, usize)| data.get(..len).is_some_and(|d| hash.verify(d));
+        sha1.as_ref() == self.sha1 && self.v2.is_none_or(v2)
+    }
+}
+
+impl Default for Hasher {
+    /// a hasher with a worker per cpu
+    fn default() -> Hasher {
+        let cpus = thread::available_parallelism().map_or(1, usize::from);
+        Hasher::new(cpus)
+    }
+}
+
+impl Hasher {
+    pub(crate) fn new(max_workers: usize) -> Hasher {
+        Hasher(Arc::new(HashState {
+            max_workers: max_workers.max(1),
+            queue: Default::default(),
+        }))
+    }
+
+    /// check data against check once it's its turn, returning whether it matched along with
+    /// the data. None if the check couldn't be run
+    pub(crate) async fn check(&self, check: PieceCheck, data: Bytes) -> Option<(bool, Bytes)> {
+        let (done, rx) = oneshot::channel();
+        let start = {
+            let mut queue = self.0.queue.lock().unwrap();
+            queue.jobs.push_back(Job { check, data, done });
+            let start = queue.workers < self.0.max_workers;
+            if start {
+                queue.workers += 1;
+            }
+            start
+        };
+
+        if start {
+            let state = self.0.clone();
+            task::spawn_blocking(move || state.work());
+        }
+        rx.await.ok()
+    }
+
+    #[cfg(test)]
+    fn workers(&self) -> usize {
+        self.0.queue.lock().unwrap().workers
+    }
+}
+
+impl HashState {
+    // hash queued jobs a batch at a time until there are none left
+    fn work(&self) {
+        loop {
+            let batch: Vec<_> = {
+                let mut queue = self.queue.lock().unwrap();
+                if queue.jobs.is_empty() {
+                    queue.workers -= 1;
+                    return;
+                }
+                let n = queue.jobs.len().min(BATCH);
+                queue.jobs.drain(..n).collect()
+            };
+
+            for job in batch {
+                let matched = job.check.matches(&job.data);
+                let _ = job.done.send((matched, job.data));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::future::join_all;
+    use hyper::body::Bytes;
+    use ring::digest;
+    use tokio::time;
+
+    use crate::hasher::{Hasher, PieceCheck};
+
+    #[tokio::test]
+    async fn check() {
+        let hasher = Hasher::new(2);
+        let sha1 = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &[7; 100]);
+        let check = PieceCheck {
+            sha1: sha1.as_ref().try_into().unwrap(),
+            v2: None,
+        };
+
+        // more pieces than workers all get checked, and their data handed back
+        let pieces = (0..20u8).map(|i| Bytes::from(vec![7 + i % 2; 100]));
+        let checks = pieces.map(|data| hasher.check(check, data));
+        let results = join_all(checks).await;
+        for (i, res) in results.into_iter().enumerate() {
+            let (matched, data) = res.unwrap();
+            assert_eq!(matched, i % 2 == 0);
+            assert_eq!(data.len(), 100);
+        }
+
+        // workers stop once the queue is empty
+        while hasher.workers() > 0 {
+            time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+}
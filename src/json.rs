@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+
+use nom::{
+    branch::alt,
+    bytes::complete::{escaped_transform, is_not, tag, take_while_m_n},
+    character::complete::{char as nchar, multispace0},
+    combinator::{map, map_opt, opt, value},
+    multi::separated_list0,
+    number::complete::double,
+    sequence::{delimited, preceded, separated_pair},
+};
+
+/// Json is a decoded JSON value, as sent by WebSocket trackers
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    List(Vec<Json>),
+    Object(HashMap<String, Json>),
+}
+
+type Parsed<'a, T> = nom::IResult<&'a str, T>;
+
+impl Json {
+    /// decode input, which must be a single JSON value
+    pub fn decode(input: &str) -> Option<Json> {
+        let Ok(("", json)) = Json::parse_value(input) else {
+            return None;
+        };
+
+        Some(json)
+    }
+
+    /// the value of a number, if it's a non-negative integer
+    pub fn uint(&self) -> Option<u64> {
+        match *self {
+            Json::Num(n) if n >= 0.0 && n.fract() == 0.0 => Some(n as u64),
+            _ => None,
+        }
+    }
+
+    pub fn str(&self) -> Option<&str> {
+        match self {
+            Json::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn list(self) -> Option<Vec<Json>> {
+        match self {
+            Json::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn object(self) -> Option<HashMap<String, Json>> {
+        match self {
+            Json::Object(o) => Some(o),
+            _ => None,
+        }
+    }
+
+    /// append s to buf as a JSON string
+    pub fn encode_str(s: &str, buf: &mut String) {
+        buf.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => buf.push_str("\\\""),
+                '\\' => buf.push_str("\\\\"),
+                c if (c as u32) < 0x20 => buf.push_str(&format!("\\u{:04x}", c as u32)),
+                c => buf.push(c),
+            }
+        }
+        buf.push('"');
+    }
+
+    // nom json parsers
+
+    fn parse_value(input: &str) -> Parsed<'_, Json> {
+        delimited(
+            multispace0,
+            alt((
+                value(Json::Null, tag("null")),
+                value(Json::Bool(true), tag("true")),
+                value(Json::Bool(false), tag("false")),
+                map(double, Json::Num),
+                map(Self::parse_str, Json::Str),
+                map(Self::parse_list, Json::List),
+                map(Self::parse_object, Json::Object),
+            )),
+            multispace0,
+        )(input)
+    }
+
+    fn parse_str(input: &str) -> Parsed<'_, String> {
+        let escape = alt((
+            value('"', nchar('"')),
+            value('\\', nchar('\\')),
+            value('/', nchar('/')),
+            value('\u{8}', nchar('b')),
+            value('\u{c}', nchar('f')),
+            value('\n', nchar('n')),
+            value('\r', nchar('r')),
+            value('\t', nchar('t')),
+            // surrogate pairs aren't joined, lone surrogates become U+FFFD
+            map_opt(
+                preceded(
+                    nchar('u'),
+                    take_while_m_n(4, 4, |c: char| c.is_ascii_hexdigit()),
+                ),
+                |hex| {
+                    let c = u32::from_str_radix(hex, 16).ok()?;
+                    Some(char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER))
+                },
+            ),
+        ));
+
+        map(
+            delimited(
+                nchar('"'),
+                opt(escaped_transform(is_not("\"\\"), '\\', escape)),
+                nchar('"'),
+            ),
+            Option::unwrap_or_default,
+        )(input)
+    }
+
+    fn parse_list(input: &str) -> Parsed<'_, Vec<Json>> {
+        delimited(
+            nchar('['),
+            separated_list0(nchar(','), Self::parse_value),
+            preceded(multispace0, nchar(']')),
+        )(input)
+    }
+
+    fn parse_object(input: &str) -> Parsed<'_, HashMap<String, Json>> {
+        let key = delimited(multispace0, Self::parse_str, multispace0);
+        let pair = separated_pair(key, nchar(':'), Self::parse_value);
+
+        map(
+            delimited(
+                nchar('{'),
+                separated_list0(nchar(','), pair),
+                preceded(multispace0, nchar('}')),
+            ),
+            |kv| kv.into_iter().collect(),
+        )(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::Json;
+
+    #[test]
+    fn decode() {
+        let input = r#" {"action": "announce", "interval": 120, "offers": [],
+            "info_hash": "\u0001ÿ\"", "nested": {"a": [1, -2.5, null, true]}} "#;
+        let mut json = Json::decode(input).unwrap().object().unwrap();
+
+        assert_eq!(json["action"].str(), Some("announce"));
+        assert_eq!(json["interval"].uint(), Some(120));
+        assert_eq!(json["info_hash"].str(), Some("\u{1}\u{ff}\""));
+        assert_eq!(json.remove("offers").unwrap().list(), Some(vec![]));
+
+        let nested = json.remove("nested").unwrap().object().unwrap();
+        let list = vec![
+            Json::Num(1.0),
+            Json::Num(-2.5),
+            Json::Null,
+            Json::Bool(true),
+        ];
+        assert_eq!(nested, HashMap::from([("a".into(), Json::List(list))]));
+
+        assert_eq!(Json::decode(r#""""#), Some(Json::Str("".into())));
+        assert_eq!(Json::decode("{}"), Some(Json::Object(HashMap::new())));
+        assert_eq!(Json::decode(r#"{"a": 1"#), None);
+        assert_eq!(Json::decode("[1, 2] 3"), None);
+    }
+
+    #[test]
+    fn encode_str() {
+        let mut buf = String::new();
+        Json::encode_str("a\"\\\u{1}\u{ff}", &mut buf);
+        assert_eq!(buf, r#""a\"\\\u0001ÿ""#);
+        assert_eq!(
+            Json::decode(&buf),
+            Some(Json::Str("a\"\\\u{1}\u{ff}".into()))
+        );
+    }
+}
@@ -8,14 +8,56 @@
 )]
 #![feature(io_slice_advance, iterator_try_collect)]
 
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
+mod block_scheduler;
+#[allow(dead_code)]
+mod choker;
+pub mod client;
+pub mod config;
+mod connection_limits;
+#[allow(dead_code)]
+mod disk;
 mod error;
+pub mod events;
+mod extension;
+pub mod handle;
+mod hasher;
+mod json;
+#[allow(dead_code)]
+mod listener;
+#[allow(dead_code)]
+mod merkle;
+mod proxy;
 mod torrent_ast;
 #[allow(dead_code)]
 mod utils;
 
 #[allow(dead_code, irrefutable_let_patterns)]
 mod peer;
+pub mod peer_class;
+#[allow(dead_code)]
+mod peer_handle;
+#[allow(dead_code)]
+pub mod peer_store;
+pub mod picker;
+mod rate_limit;
+#[allow(dead_code)]
+mod request_queue;
+pub mod resume;
+mod send_queue;
+mod smart_ban;
+pub mod stats;
+pub mod storage;
+mod streaming;
 #[allow(dead_code)]
 mod torrent;
+mod tracker;
 #[allow(dead_code)]
 pub mod tsunami;
+mod upload_queue;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+mod web_seed;
+mod websocket;
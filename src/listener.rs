@@ -0,0 +1,236 @@
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    net::{TcpListener, TcpSocket, TcpStream},
+    time,
+};
+
+use crate::{config::Config, smart_ban::BanList};
+
+// defaults for settings left unset in Config
+pub(crate) const LISTEN_PORT: u16 = 6881;
+const BACKLOG: u32 = 128;
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Listener accepts incoming peer connections. the rate connections are accepted at can be
+/// limited and every connection must finish its handshake before a deadline, so a flood of
+/// connections (or connections which never send anything) can't tie up the session
+#[derive(Debug)]
+pub struct Listener {
+    listener: TcpListener,
+    // max connections accepted per second
+    rate: Option<u32>,
+    handshake_timeout: Duration,
+    // connections from these addresses are closed as soon as they're accepted
+    banned: BanList,
+
+    window_start: Instant,
+    accepted: u32,
+}
+
+/// Incoming is an accepted connection which hasn't completed its handshake yet
+#[derive(Debug)]
+pub struct Incoming {
+    pub stream: TcpStream,
+    pub addr: SocketAddr,
+    deadline: Instant,
+}
+
+impl Listener {
+    pub fn bind(addr: SocketAddr, config: &Config) -> io::Result<Listener> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        socket.set_reuseaddr(true)?;
+        socket.bind(addr)?;
+
+        Ok(Listener {
+            listener: socket.listen(config.listen_backlog.unwrap_or(BACKLOG))?,
+            rate: config.accept_rate,
+            handshake_timeout: config.handshake_timeout.unwrap_or(HANDSHAKE_TIMEOUT),
+            banned: BanList::default(),
+
+            window_start: Instant::now(),
+            accepted: 0,
+        })
+    }
+
+    /// bind to [Config::listen_port] on ip, falling back to each port of
+    /// [Config::listen_port_range] in turn until one is free
+    pub fn bind_port(ip: IpAddr, config: &Config) -> io::Result<Listener> {
+        let port = config.listen_port.unwrap_or(LISTEN_PORT);
+        let fallback = config.listen_port_range.clone().into_iter().flatten();
+
+        let mut last_err = None;
+        for port in std::iter::once(port).chain(fallback) {
+            match Self::bind(SocketAddr::new(ip, port), config) {
+                Ok(listener) => return Ok(listener),
+                Err(e) => last_err = Some(e),
+            }
+        }
+
+        Err(last_err.unwrap())
+    }
+
+    /// refuse peers the session has banned, see [BanList]
+    pub(crate) fn set_ban_list(&mut self, banned: BanList) {
+        self.banned = banned;
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// wait for the next connection, first waiting out the current second if we've already
+    /// accepted as many connections as the accept rate allows. connections stay in the OS
+    /// backlog in the meantime, and new ones are refused once it's full. connections from banned
+    /// peers are closed without counting towards the rate
+    pub async fn accept(&mut self) -> io::Result<Incoming> {
+        if let Some(rate) = self.rate {
+            if self.window_start.elapsed() >= Duration::from_secs(1) {
+                self.window_start = Instant::now();
+                self.accepted = 0;
+            }
+
+            if self.accepted >= rate {
+                let next_window = self.window_start + Duration::from_secs(1);
+                time::sleep_until(next_window.into()).await;
+
+                self.window_start = Instant::now();
+                self.accepted = 0;
+            }
+        }
+
+        let (stream, addr) = loop {
+            let (stream, addr) = self.listener.accept().await?;
+            if !self.banned.contains(addr.ip()) {
+                break (stream, addr);
+            }
+        };
+        self.accepted += 1;
+
+        Ok(Incoming {
+            stream,
+            addr,
+            deadline: Instant::now() + self.handshake_timeout,
+        })
+    }
+}
+
+impl Incoming {
+    /// run handshake on this connection, failing with [io::ErrorKind::TimedOut] if it isn't
+    /// done before the handshake deadline. the deadline starts when the connection is accepted
+    pub async fn handshake<F, T>(self, handshake: impl FnOnce(TcpStream) -> F) -> io::Result<T>
+    where
+        F: Future<Output = io::Result<T>>,
+    {
+        match time::timeout_at(self.deadline.into(), handshake(self.stream)).await {
+            Ok(res) => res,
+            Err(_) => Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        net::IpAddr,
+        time::{Duration, Instant},
+    };
+
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpSocket, TcpStream},
+    };
+
+    use crate::{config::Config, listener::Listener, smart_ban::BanList};
+
+    #[tokio::test]
+    async fn handshake_timeout() {
+        let config = Config {
+            handshake_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let mut listener = Listener::bind("127.0.0.1:0".parse().unwrap(), &config).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // a peer that connects but never sends its handshake
+        let _idle = TcpStream::connect(addr).await.unwrap();
+        let incoming = listener.accept().await.unwrap();
+
+        let res = incoming
+            .handshake(|mut stream| async move { stream.read_u8().await })
+            .await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn banned() {
+        let mut listener =
+            Listener::bind("127.0.0.1:0".parse().unwrap(), &Config::default()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let banned = BanList::default();
+        banned.insert([127, 0, 0, 2].into());
+        listener.set_ban_list(banned);
+
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+        let mut dropped = socket.connect(addr).await.unwrap();
+        let _allowed = TcpStream::connect(addr).await.unwrap();
+
+        let incoming = listener.accept().await.unwrap();
+        assert_eq!(incoming.addr.ip(), IpAddr::from([127, 0, 0, 1]));
+        let err = dropped.read_u8().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn port_range() {
+        let taken = Listener::bind("127.0.0.1:0".parse().unwrap(), &Config::default()).unwrap();
+        let port = taken.local_addr().unwrap().port();
+
+        let config = Config {
+            listen_port: Some(port),
+            listen_port_range: Some(port..=port.saturating_add(20)),
+            ..Default::default()
+        };
+        let listener = Listener::bind_port([127, 0, 0, 1].into(), &config).unwrap();
+        let bound = listener.local_addr().unwrap().port();
+        assert!(port < bound && bound <= port.saturating_add(20));
+
+        // nothing else to try
+        let config = Config {
+            listen_port: Some(port),
+            ..Default::default()
+        };
+        assert!(Listener::bind_port([127, 0, 0, 1].into(), &config).is_err());
+    }
+
+    #[tokio::test]
+    async fn accept_rate() {
+        let config = Config {
+            accept_rate: Some(2),
+            ..Default::default()
+        };
+        let mut listener = Listener::bind("127.0.0.1:0".parse().unwrap(), &config).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut conns = vec![];
+        for _ in 0..3 {
+            conns.push(TcpStream::connect(addr).await.unwrap());
+        }
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            listener.accept().await.unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(900));
+    }
+}
@@ -0,0 +1,358 @@
+use ring::digest;
+
+pub type Sha256Hash = [u8; 32];
+
+/// size of the leaf blocks BEP-52 merkle trees are built from
+pub const BLOCK_SIZE: u64 = 16 * 1024;
+
+pub fn hash(data: &[u8]) -> Sha256Hash {
+    digest::digest(&digest::SHA256, data)
+        .as_ref()
+        .try_into()
+        .unwrap()
+}
+
+fn hash_pair(left: &Sha256Hash, right: &Sha256Hash) -> Sha256Hash {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(left);
+    ctx.update(right);
+    ctx.finish().as_ref().try_into().unwrap()
+}
+
+/// root of a subtree `height` layers tall where every leaf is a zero hash. these are used to pad
+/// layers out to a power of two
+pub fn pad_hash(height: u32) -> Sha256Hash {
+    (0..height).fold([0; 32], |pad, _| hash_pair(&pad, &pad))
+}
+
+/// compute the merkle root of layer after padding it to a power of two with pad
+///
+/// # Examples
+/// ```ignore
+/// # use tsunami::merkle::{root, pad_hash};
+/// assert!(root(&[], pad_hash(2)) == pad_hash(2));
+/// assert!(root(&[pad_hash(0); 3], pad_hash(0)) == pad_hash(2));
+/// ```
+pub fn root(layer: &[Sha256Hash], pad: Sha256Hash) -> Sha256Hash {
+    let mut layer = layer.to_vec();
+    layer.resize(layer.len().next_power_of_two().max(1), pad);
+
+    while layer.len() > 1 {
+        layer = parent_layer(&layer);
+    }
+
+    layer[0]
+}
+
+// the layer above layer, which must have an even length
+fn parent_layer(layer: &[Sha256Hash]) -> Vec<Sha256Hash> {
+    layer
+        .chunks(2)
+        .map(|pair| hash_pair(&pair[0], &pair[1]))
+        .collect()
+}
+
+/// HashRequest asks for `length` hashes of a file's merkle tree starting at `index` in
+/// `base_layer`, where layer 0 is the tree's blocks. the hashes are followed by up to
+/// `proof_layers` uncle hashes proving them against `pieces_root`, see BEP-52
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashRequest {
+    pub pieces_root: Sha256Hash,
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+// most hashes asked for in a single request
+const MAX_REQUEST_HASHES: usize = 512;
+
+/// FileTree is what we know of a v2 file's merkle tree: its pieces root and its piece layer.
+/// the piece layer comes from the torrent's `piece layers`, or from peers when we don't have
+/// them, see [FileTree::add_hashes]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileTree {
+    root: Sha256Hash,
+    // the hash of each of the file's pieces, None until we know it. the pad hashes filling the
+    // layer out to a power of two aren't stored
+    pieces: Vec<Option<Sha256Hash>>,
+    // layer the pieces are in
+    piece_layer: u32,
+}
+
+impl FileTree {
+    /// the tree of a file we don't know the piece layer of yet. piece_length must be a power of
+    /// two and at least [BLOCK_SIZE]
+    pub fn new(root: Sha256Hash, length: u64, piece_length: u64) -> FileTree {
+        let blocks = length.div_ceil(BLOCK_SIZE).max(1);
+        let num_pieces = length.div_ceil(piece_length).max(1) as usize;
+
+        // files within a single piece hash their blocks straight up to the root
+        let (piece_layer, pieces) = match num_pieces {
+            1 => (blocks.next_power_of_two().ilog2(), vec![Some(root)]),
+            n => ((piece_length / BLOCK_SIZE).ilog2(), vec![None; n]),
+        };
+
+        FileTree {
+            root,
+            pieces,
+            piece_layer,
+        }
+    }
+
+    /// fill in the piece layer from a torrent's `piece layers`, returning false if it doesn't
+    /// hash up to the root
+    pub fn set_piece_layer(&mut self, layer: &[Sha256Hash]) -> bool {
+        if layer.len() != self.pieces.len() || root(layer, pad_hash(self.piece_layer)) != self.root
+        {
+            return false;
+        }
+
+        self.pieces = layer.iter().copied().map(Some).collect();
+        true
+    }
+
+    pub fn root(&self) -> &Sha256Hash {
+        &self.root
+    }
+
+    /// whether we know the hash of every piece
+    pub fn has_piece_layer(&self) -> bool {
+        self.pieces.iter().all(Option::is_some)
+    }
+
+    pub fn piece_hash(&self, index: usize) -> Option<Sha256Hash> {
+        self.pieces.get(index).copied().flatten()
+    }
+
+    /// check piece index's data against its hash, or None if we don't know the hash yet
+    pub fn verify_piece(&self, index: usize, data: &[u8]) -> Option<bool> {
+        Some(self.piece(index)?.verify(data))
+    }
+
+    /// what piece index must hash to, or None if we don't know its hash yet
+    pub fn piece(&self, index: usize) -> Option<PieceHash> {
+        Some(PieceHash {
+            hash: self.piece_hash(index)?,
+            layer: self.piece_layer,
+        })
+    }
+
+    /// the hashes req asks for followed by their proof, or None if we can't answer it. we can
+    /// only serve layers from the piece layer up, and only once we know the whole piece layer
+    pub fn hashes(&self, req: &HashRequest) -> Option<Vec<Sha256Hash>> {
+        let (index, length) = (req.index as usize, req.length as usize);
+        if req.base_layer < self.piece_layer
+            || !length.is_power_of_two()
+            || length > MAX_REQUEST_HASHES
+            || index % length != 0
+        {
+            return None;
+        }
+
+        let mut layer: Vec<_> = self.pieces.iter().copied().collect::<Option<_>>()?;
+        layer.resize(layer.len().next_power_of_two(), pad_hash(self.piece_layer));
+        for _ in self.piece_layer..req.base_layer {
+            if layer.len() == 1 {
+                return None;
+            }
+            layer = parent_layer(&layer);
+        }
+
+        let mut hashes = layer.get(index..index + length)?.to_vec();
+
+        // climb to the root of the subtree covering the hashes, then add the uncle of every
+        // node on the way up to the root
+        for _ in 0..length.trailing_zeros() {
+            layer = parent_layer(&layer);
+        }
+        let mut pos = index / length;
+        for _ in 0..req.proof_layers {
+            if layer.len() == 1 {
+                break;
+            }
+            hashes.push(layer[pos ^ 1]);
+            layer = parent_layer(&layer);
+            pos /= 2;
+        }
+
+        Some(hashes)
+    }
+
+    /// check hashes a peer sent in answer to req against our root, returning whether they're
+    /// valid. they must come with every uncle hash up to the root. hashes in the piece layer
+    /// are kept
+    pub fn add_hashes(&mut self, req: &HashRequest, hashes: &[Sha256Hash]) -> bool {
+        let (index, length) = (req.index as usize, req.length as usize);
+        if !length.is_power_of_two() || index % length != 0 || hashes.len() < length {
+            return false;
+        }
+
+        // every uncle takes us up a layer, which must end at the root
+        let (base, uncles) = hashes.split_at(length);
+        let top = req.base_layer as usize + length.trailing_zeros() as usize + uncles.len();
+        if top != self.height() as usize {
+            return false;
+        }
+
+        let mut node = root(base, [0; 32]);
+        let mut pos = index / length;
+        for uncle in uncles {
+            node = match pos % 2 {
+                0 => hash_pair(&node, uncle),
+                _ => hash_pair(uncle, &node),
+            };
+            pos /= 2;
+        }
+        if node != self.root || pos != 0 {
+            return false;
+        }
+
+        if req.base_layer == self.piece_layer {
+            for (piece, &hash) in self.pieces.iter_mut().skip(index).zip(base) {
+                *piece = Some(hash);
+            }
+        }
+        true
+    }
+
+    /// requests for the piece layer hashes we're missing, each with every uncle hash up to the
+    /// root
+    pub fn missing(&self) -> Vec<HashRequest> {
+        let length = self.pieces.len().next_power_of_two();
+        let length = length.min(MAX_REQUEST_HASHES);
+        let proof_layers = self.height() - self.piece_layer - length.trailing_zeros();
+
+        let chunks = self.pieces.chunks(length).enumerate();
+        let missing = chunks.filter(|(_, chunk)| chunk.iter().any(Option::is_none));
+        missing
+            .map(|(i, _)| HashRequest {
+                pieces_root: self.root,
+                base_layer: self.piece_layer,
+                index: (i * length) as u32,
+                length: length as u32,
+                proof_layers,
+            })
+            .collect()
+    }
+
+    // layer of the root
+    fn height(&self) -> u32 {
+        self.piece_layer + self.pieces.len().next_power_of_two().trailing_zeros()
+    }
+}
+
+/// PieceHash is the hash a single piece of a v2 file must match, see [FileTree::piece]. it's
+/// small enough to take along when the piece is checked in the background
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PieceHash {
+    hash: Sha256Hash,
+    // layer the piece is in
+    layer: u32,
+}
+
+impl PieceHash {
+    /// check a piece's data against the hash. the last piece of a file holds only what's left
+    /// of the file, not the padding after it
+    pub fn verify(&self, data: &[u8]) -> bool {
+        // the last piece is padded out to a full piece with zero hashes
+        let blocks: Vec<_> = data.chunks(BLOCK_SIZE as usize).map(hash).collect();
+        let mut piece = root(&blocks, [0; 32]);
+        for height in blocks.len().next_power_of_two().trailing_zeros()..self.layer {
+            piece = hash_pair(&piece, &pad_hash(height));
+        }
+
+        piece == self.hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash, pad_hash, root, FileTree, Sha256Hash, BLOCK_SIZE};
+
+    const PIECE_LENGTH: u64 = 2 * BLOCK_SIZE;
+
+    /// a file of five and a half pieces, its piece layer and root
+    fn file() -> (Vec<u8>, Vec<Sha256Hash>, Sha256Hash) {
+        let data: Vec<u8> = (0..PIECE_LENGTH * 11 / 2).map(|i| i as u8).collect();
+        let layer: Vec<_> = data
+            .chunks(PIECE_LENGTH as usize)
+            .map(|piece| {
+                // the last piece is padded out with zero hashes
+                let mut blocks: Vec<_> = piece.chunks(BLOCK_SIZE as usize).map(hash).collect();
+                blocks.resize(2, [0; 32]);
+                root(&blocks, [0; 32])
+            })
+            .collect();
+        let root = root(&layer, pad_hash(1));
+
+        (data, layer, root)
+    }
+
+    #[test]
+    fn piece_layer() {
+        let (data, layer, root) = file();
+        let mut tree = FileTree::new(root, data.len() as u64, PIECE_LENGTH);
+        assert!(!tree.has_piece_layer());
+        assert_eq!(tree.verify_piece(0, &data), None);
+
+        assert!(!tree.set_piece_layer(&layer[1..]));
+        assert!(tree.set_piece_layer(&layer));
+        assert!(tree.has_piece_layer());
+
+        let pieces: Vec<_> = data.chunks(PIECE_LENGTH as usize).collect();
+        assert_eq!(tree.verify_piece(5, pieces[5]), Some(true));
+        assert_eq!(tree.verify_piece(4, pieces[5]), Some(false));
+
+        // files within a piece only have their root
+        let small = FileTree::new(hash(&data[..100]), 100, PIECE_LENGTH);
+        assert_eq!(small.verify_piece(0, &data[..100]), Some(true));
+        assert_eq!(small.verify_piece(0, &data[..101]), Some(false));
+        let (first, rest) = data.split_at(BLOCK_SIZE as usize);
+        let blocks = [hash(first), hash(&rest[..1])];
+        let small_root = super::root(&blocks, [0; 32]);
+        let small = FileTree::new(small_root, BLOCK_SIZE + 1, 4 * PIECE_LENGTH);
+        let piece = small.piece(0).unwrap();
+        assert!(piece.verify(&data[..BLOCK_SIZE as usize + 1]));
+        assert!(!piece.verify(&data[..2 * BLOCK_SIZE as usize]));
+    }
+
+    #[test]
+    fn hash_requests() {
+        let (data, layer, root) = file();
+        let mut ours = FileTree::new(root, data.len() as u64, PIECE_LENGTH);
+        ours.set_piece_layer(&layer);
+        let mut theirs = FileTree::new(root, data.len() as u64, PIECE_LENGTH);
+
+        let reqs = theirs.missing();
+        assert_eq!(reqs.len(), 1);
+        assert_eq!((reqs[0].base_layer, reqs[0].length), (1, 8));
+
+        let hashes = ours.hashes(&reqs[0]).unwrap();
+        assert!(theirs.add_hashes(&reqs[0], &hashes));
+        assert!(theirs.has_piece_layer());
+        assert!(theirs.missing().is_empty());
+
+        // half the layer with a single uncle
+        let mut req = reqs[0];
+        (req.index, req.length, req.proof_layers) = (4, 4, 3);
+        let hashes = ours.hashes(&req).unwrap();
+        assert_eq!(hashes.len(), 5);
+        assert_eq!(hashes[..2], layer[4..]);
+
+        let mut theirs = FileTree::new(root, data.len() as u64, PIECE_LENGTH);
+        let mut bad = hashes.clone();
+        bad[0][0] ^= 1;
+        assert!(!theirs.add_hashes(&req, &bad));
+        assert!(!theirs.add_hashes(&req, &hashes[..4]));
+        assert!(theirs.add_hashes(&req, &hashes));
+        assert_eq!(theirs.piece_hash(5), Some(layer[5]));
+        assert_eq!(theirs.piece_hash(0), None);
+
+        // below the piece layer and misaligned
+        (req.base_layer, req.index) = (0, 4);
+        assert_eq!(ours.hashes(&req), None);
+        (req.base_layer, req.index) = (1, 2);
+        assert_eq!(ours.hashes(&req), None);
+    }
+}
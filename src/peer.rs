@@ -1,187 +1,835 @@
-use std::{io, io::IoSlice};
+use std::{collections::HashMap, io, io::IoSlice, net::SocketAddr, time::Duration};
 
 use bitflags::bitflags;
-use bitvec::prelude::{bitbox, BitBox, Lsb0};
+use bitvec::prelude::{bitbox, BitBox, BitVec, Msb0};
 use byteorder::{ByteOrder, BE};
+use futures::{future, select_biased, FutureExt};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufStream},
-    net::{TcpStream, ToSocketAddrs},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream},
+    net::TcpStream,
+    sync::mpsc::{error::TryRecvError, UnboundedReceiver, UnboundedSender},
+    time::{self, Instant},
 };
 
-use crate::error::{DecodeError, Result};
+use crate::{
+    client::ClientInfo,
+    config::Config,
+    error::{DecodeError, HandshakeError, Result},
+    extension::{ExtensionHandshake, Holepunch, PexMessage, LT_DONTHAVE},
+    listener::HANDSHAKE_TIMEOUT,
+    merkle::{HashRequest, Sha256Hash},
+    peer_handle::{PeerCommand, PeerEvent},
+    proxy::Dialer,
+    rate_limit::TokenBucket,
+    send_queue::{Backlog, SendQueue},
+    stats::{PeerStats, TransferStats},
+    torrent::Sha1Hash,
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// a keep-alive is sent after this long without sending anything else
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(100);
+// peers which send nothing, not even keep-alives, for this long are dropped
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// PeerId is the id a peer sends in its handshake. it's usually a client prefix followed by
+/// random bytes, so it isn't necessarily valid utf8, see [crate::utils::Lossy]
+pub type PeerId = [u8; 20];
+
+/// Bitfield is the pieces a peer has, in the order they're sent: the high bit of the first byte
+/// is piece 0
+pub type Bitfield = BitBox<u8, Msb0>;
+
+/// the start of every handshake, the protocol string prefixed with its length
+pub(crate) const PROTOCOL: &[u8; 20] = b"\x13BitTorrent protocol";
+
+// extensions advertised in our handshake
+const OUR_RESERVED: ReservedBits = ReservedBits::EXTENSION.union(ReservedBits::FAST);
 
 #[derive(Debug)]
 pub struct Peer {
-    peer_id: String,
-    bitfield: BitBox,
-
-    status: Status,
+    info: PeerInfo,
+    state: PeerState,
     conn: BufStream<TcpStream>,
 }
 
+/// PeerInfo is what a peer has told us about itself, kept up to date by [PeerInfo::update]
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    peer_id: PeerId,
+    bitfield: Bitfield,
+    // extensions the peer advertised in its handshake
+    reserved: ReservedBits,
+    // the peer's extension handshake, see [PeerInfo::extension_id]
+    extensions: ExtensionHandshake,
+    // udp port of the peer's DHT node, from its last [Message::Port]
+    dht_port: Option<u16>,
+    // the peer wants to download from us
+    interested: bool,
+    stats: TransferStats,
+}
+
+/// Timeouts bound how long [Peer::connect] waits on an unresponsive peer
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// time the peer has to accept the connection
+    pub connect: Duration,
+    /// time the peer has to answer our handshake once connected
+    pub handshake: Duration,
+}
+
+impl Timeouts {
+    pub fn new(config: &Config) -> Timeouts {
+        Timeouts {
+            connect: config.connect_timeout.unwrap_or(CONNECT_TIMEOUT),
+            handshake: config.handshake_timeout.unwrap_or(HANDSHAKE_TIMEOUT),
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts::new(&Config::default())
+    }
+}
+
+bitflags! {
+    /// ReservedBits are the extensions advertised in the 8 reserved bytes of a handshake, read as
+    /// a big endian integer. unknown bits are ignored
+    pub struct ReservedBits: u64 {
+        /// the extension protocol, BEP-10
+        const EXTENSION = 1 << 20;
+        /// the fast extension, BEP-6
+        const FAST = 1 << 2;
+        /// the peer supports v2 torrents and their hash messages, BEP-52
+        const V2 = 1 << 4;
+        /// the peer runs a DHT node and sends [Message::Port], BEP-5
+        const DHT = 1 << 0;
+    }
+}
+
+impl ReservedBits {
+    fn from_bytes(buf: &[u8]) -> ReservedBits {
+        ReservedBits::from_bits_truncate(BE::read_u64(buf))
+    }
+}
+
 bitflags! {
+    // whether each end of a connection is choking and interested in the other, BEP-3
     struct Status: u8 {
-        const SELF_CHOKED = 1 << 0;
-        const SELF_INTERESTED = 1 << 1;
-        const PEER_CHOKED = 1 << 2;
+        const AM_CHOKING = 1 << 0;
+        const AM_INTERESTED = 1 << 1;
+        const PEER_CHOKING = 1 << 2;
         const PEER_INTERESTED = 1 << 3;
     }
 }
 
+/// PeerState is the choke and interest state of both ends of a connection, updated with every
+/// message sent and received. it decides which of the peer's messages are allowed, see
+/// [PeerState::recv]. connections start with both ends choking and not interested
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PeerState {
+    status: Status,
+    // both ends support the fast extension, BEP-6
+    fast: bool,
+}
+
+/// Verdict is what to do with a message the peer sent, see [PeerState::recv]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Accept,
+    /// the peer asked for a block while we're choking it. with the fast extension it's told
+    /// with a [Message::RejectRequest], otherwise the request is dropped
+    Reject,
+    /// the message isn't allowed, drop it
+    Ignore,
+}
+
+impl PeerState {
+    /// the state of a new connection to a peer which advertised reserved in its handshake
+    pub(crate) fn new(reserved: ReservedBits) -> PeerState {
+        PeerState {
+            status: Status::AM_CHOKING | Status::PEER_CHOKING,
+            fast: (OUR_RESERVED & reserved).contains(ReservedBits::FAST),
+        }
+    }
+
+    /// whether we're choking the peer
+    pub(crate) fn am_choking(&self) -> bool {
+        self.status.contains(Status::AM_CHOKING)
+    }
+
+    /// whether we're interested in the peer
+    pub(crate) fn am_interested(&self) -> bool {
+        self.status.contains(Status::AM_INTERESTED)
+    }
+
+    /// whether the peer is choking us
+    pub(crate) fn peer_choking(&self) -> bool {
+        self.status.contains(Status::PEER_CHOKING)
+    }
+
+    /// whether the peer is interested in us
+    pub(crate) fn peer_interested(&self) -> bool {
+        self.status.contains(Status::PEER_INTERESTED)
+    }
+
+    /// whether both ends support the fast extension
+    pub(crate) fn fast(&self) -> bool {
+        self.fast
+    }
+
+    /// update the state with a message the peer sent, returning whether it's allowed. requests are
+    /// only allowed while we aren't choking the peer, and rejects, have all and have none only from
+    /// peers using the fast extension. blocks are allowed even while the peer chokes us since they
+    /// may have been sent before the choke, it's up to the caller to check we asked for them
+    pub(crate) fn recv(&mut self, msg: &Message) -> Verdict {
+        match msg {
+            Message::Choke => self.status.insert(Status::PEER_CHOKING),
+            Message::Unchoke => self.status.remove(Status::PEER_CHOKING),
+            Message::Interested => self.status.insert(Status::PEER_INTERESTED),
+            Message::NotInterested => self.status.remove(Status::PEER_INTERESTED),
+            Message::Request { .. } if self.am_choking() => return Verdict::Reject,
+            Message::RejectRequest { .. } | Message::HaveAll | Message::HaveNone if !self.fast => {
+                return Verdict::Ignore
+            }
+            _ => {}
+        }
+
+        Verdict::Accept
+    }
+
+    /// update the state with a message we're sending the peer
+    pub(crate) fn send(&mut self, msg: &Message) {
+        match msg {
+            Message::Choke => self.status.insert(Status::AM_CHOKING),
+            Message::Unchoke => self.status.remove(Status::AM_CHOKING),
+            Message::Interested => self.status.insert(Status::AM_INTERESTED),
+            Message::NotInterested => self.status.remove(Status::AM_INTERESTED),
+            _ => {}
+        }
+    }
+}
+
+impl Default for PeerState {
+    fn default() -> PeerState {
+        PeerState::new(ReservedBits::empty())
+    }
+}
+
 impl Peer {
     const MAX_MSG_LENGTH: u32 = 1024 * 16; // 16 KiB
 
+    // extended messages carry a bencoded dict, possibly followed by a block of metadata
+    const MAX_EXTENDED_LENGTH: u32 = Self::MAX_MSG_LENGTH + 1024;
+
+    /// connect to the peer at addr with dialer and exchange handshakes
     pub async fn connect(
-        addr: impl ToSocketAddrs,
+        addr: SocketAddr,
         info_hash: &[u8],
         peer_id: &[u8],
         total_pieces: usize,
-    ) -> Option<Peer> {
+        dialer: &Dialer,
+        timeouts: Timeouts,
+    ) -> Result<Peer, HandshakeError> {
         // Handshake layout:
         // length | value
         // -------+-------------------
         //      1 | 19 (hex: \x13)
-        //     19 | "Bittorrent Protocol"
+        //     19 | "BitTorrent protocol"
         //      8 | extn flags; [0u8; 8] (hex: \x00 * 8)
         //     20 | sha-1
         //     20 | peer_id
         // ------ | total
         //     68
-        let mut conn = TcpStream::connect(addr).await.ok()?;
+        let dial = time::timeout(timeouts.connect, dialer.dial(addr));
+        let mut conn = dial.await.map_err(|_| HandshakeError::ConnectTimeout)??;
         let (mut rx, mut tx) = conn.split();
 
         // write our end of the handshake
-        let send = async {
-            const BT_PREFIX: &[u8; 28] = b"\x13Bittorrent Protocol\x00\x00\x00\x00\x00\x00\x00\x00";
-
-            // todo: tokio docs state only the last buffer may be partially consumed, can we include
-            //       an empty IoSlice and avoid manually checking if all bytes have been written?
-            let mut io_bufs = &mut [
-                IoSlice::new(BT_PREFIX),
-                IoSlice::new(info_hash),
-                IoSlice::new(peer_id),
-            ][..];
-
-            while !io_bufs.is_empty() {
-                let n = tx.write_vectored(io_bufs).await?;
-                IoSlice::advance_slices(&mut io_bufs, n);
-            }
-
-            Ok(())
-        };
+        let send = async { Ok(Self::send_handshake(&mut tx, info_hash, peer_id).await?) };
 
         // read a bittorrent greeting
         let recv = async {
-            const BT_PREFIX: &[u8; 20] = b"\x13Bittorrent Protocol";
-            let err = Err(io::Error::from(io::ErrorKind::Other));
-            let mut buf = vec![0; 20];
+            let mut buf = [0; 20];
 
             // protocol prefix
-            if let _ = rx.read_exact(&mut buf).await? && buf != BT_PREFIX {
-                return err;
+            if let _ = rx.read_exact(&mut buf).await? && buf != *PROTOCOL {
+                return Err(HandshakeError::Protocol);
             }
 
-            // extension flags (no extensions currently supported)
-            if let _ = rx.read_exact(&mut buf[..8]).await? && buf[..8] != [0; 8] {
-                return err;
-            }
+            // extension flags
+            rx.read_exact(&mut buf[..8]).await?;
+            let reserved = ReservedBits::from_bytes(&buf[..8]);
 
             // info_hash
-            if let _ = rx.read_exact(&mut buf).await? && buf != info_hash {
-                return err;
+            if let _ = rx.read_exact(&mut buf).await? && buf[..] != *info_hash {
+                return Err(HandshakeError::InfoHash);
             }
 
             // peer id
-            buf.fill(0);
             rx.read_exact(&mut buf).await?;
-            String::from_utf8(buf).or(err)
+            Ok((reserved, buf))
         };
 
-        let (_, peer_id) = futures::try_join!(send, recv).ok()?;
+        let handshake = time::timeout(timeouts.handshake, async { futures::try_join!(send, recv) });
+        let (_, (reserved, peer_id)) = handshake.await.map_err(|_| HandshakeError::Timeout)??;
 
-        Some(Peer {
-            status: Status::SELF_CHOKED | Status::PEER_CHOKED,
-            bitfield: bitbox![usize, Lsb0; 0; total_pieces],
+        Ok(Peer {
+            info: PeerInfo::new(peer_id, reserved, total_pieces),
+            state: PeerState::new(reserved),
             conn: BufStream::new(conn),
-            peer_id,
         })
     }
 
-    fn peer_choked(&mut self, status: bool) {
-        self.status.set(Status::PEER_CHOKED, status);
+    /// answer the handshake of a peer which connected to us. the remote handshake is read first
+    /// so we know which torrent the peer wants, info_hashes maps the info hash of every torrent
+    /// we accept peers for to its number of pieces. returns the peer and the info hash it asked
+    /// for. peers asking for a torrent we don't have get no reply
+    pub async fn accept(
+        mut conn: TcpStream,
+        info_hashes: &HashMap<Sha1Hash, usize>,
+        peer_id: &[u8],
+    ) -> Result<(Peer, Sha1Hash), HandshakeError> {
+        let mut buf = [0; 20];
+
+        conn.read_exact(&mut buf).await?;
+        if buf != *PROTOCOL {
+            return Err(HandshakeError::Protocol);
+        }
+
+        conn.read_exact(&mut buf[..8]).await?;
+        let reserved = ReservedBits::from_bytes(&buf[..8]);
+
+        let mut info_hash = [0; 20];
+        conn.read_exact(&mut info_hash).await?;
+        let &total_pieces = info_hashes
+            .get(&info_hash)
+            .ok_or(HandshakeError::InfoHash)?;
+
+        Self::send_handshake(&mut conn, &info_hash, peer_id).await?;
+
+        conn.read_exact(&mut buf).await?;
+
+        let peer = Peer {
+            info: PeerInfo::new(buf, reserved, total_pieces),
+            state: PeerState::new(reserved),
+            conn: BufStream::new(conn),
+        };
+        Ok((peer, info_hash))
+    }
+
+    async fn send_handshake(
+        tx: &mut (impl AsyncWrite + Unpin),
+        info_hash: &[u8],
+        peer_id: &[u8],
+    ) -> io::Result<()> {
+        // todo: tokio docs state only the last buffer may be partially consumed, can we include
+        //       an empty IoSlice and avoid manually checking if all bytes have been written?
+        let reserved = OUR_RESERVED.bits().to_be_bytes();
+        let mut io_bufs = &mut [
+            IoSlice::new(PROTOCOL),
+            IoSlice::new(&reserved),
+            IoSlice::new(info_hash),
+            IoSlice::new(peer_id),
+        ][..];
+
+        while !io_bufs.is_empty() {
+            let n = tx.write_vectored(io_bufs).await?;
+            IoSlice::advance_slices(&mut io_bufs, n);
+        }
+
+        Ok(())
+    }
+
+    /// what the peer has told us about itself
+    pub fn info(&self) -> &PeerInfo {
+        &self.info
+    }
+
+    /// the choke and interest state of the connection
+    pub(crate) fn state(&self) -> PeerState {
+        self.state
+    }
+
+    /// flush any buffered messages and close our end of the connection
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        self.conn.shutdown().await
+    }
+
+    /// queue msg to be sent. messages are buffered until [Peer::flush] is called or the buffer
+    /// fills up, so several can go out together
+    pub async fn send(&mut self, msg: Message) -> io::Result<()> {
+        if let Message::Piece { block, .. } = &msg {
+            self.info.record_upload(block.len());
+        }
+        self.state.send(&msg);
+
+        let mut buf = Vec::with_capacity(msg.encoded_len());
+        msg.encode_into(&mut buf);
+        self.conn.write_all(&buf).await
+    }
+
+    /// send any queued messages
+    pub async fn flush(&mut self) -> io::Result<()> {
+        self.conn.flush().await
+    }
+
+    /// send our extension handshake, see [ExtensionHandshake::ours]. peers which didn't set
+    /// [ReservedBits::EXTENSION] don't understand it
+    pub async fn send_extension_handshake(
+        &mut self,
+        metadata_size: Option<u64>,
+        private: bool,
+    ) -> io::Result<()> {
+        let payload = ExtensionHandshake::ours(metadata_size, private).encode();
+        self.send(Message::Extended {
+            id: 0,
+            payload: payload.into(),
+        })
+        .await?;
+        self.flush().await
+    }
+
+    /// send msg if the peer supports ut_pex, returning whether it was sent
+    pub async fn send_pex(&mut self, msg: &PexMessage) -> io::Result<bool> {
+        self.send_extended("ut_pex", msg.encode()).await
+    }
+
+    /// tell the peer which udp port our DHT node listens on, see [Message::Port]. this should
+    /// only be sent to peers which set [ReservedBits::DHT]
+    pub async fn send_port(&mut self, port: u16) -> io::Result<()> {
+        self.send(Message::Port(port)).await?;
+        self.flush().await
+    }
+
+    /// tell the peer we no longer have piece index if it supports lt_donthave, returning whether
+    /// it was sent. this takes back a have sent for a piece which failed verification or was
+    /// dropped
+    pub async fn send_dont_have(&mut self, index: u32) -> io::Result<bool> {
+        let payload = index.to_be_bytes().into();
+        self.send_extended("lt_donthave", payload).await
     }
 
-    fn peer_interested(&mut self, status: bool) {
-        self.status.set(Status::PEER_INTERESTED, status);
+    /// send msg if the peer supports ut_holepunch, returning whether it was sent
+    pub async fn send_holepunch(&mut self, msg: Holepunch) -> io::Result<bool> {
+        self.send_extended("ut_holepunch", msg.encode()).await
     }
 
-    fn check_msg_len(&self, id: u8, len: u32) -> bool {
-        let bitfield_len = (1 + self.bitfield.len() / 8) as u32;
+    // send an extended message for extension name if the peer supports it, returning whether it
+    // was sent
+    async fn send_extended(&mut self, name: &str, payload: Vec<u8>) -> io::Result<bool> {
+        let Some(id) = self.info.extension_id(name) else {
+            return Ok(false);
+        };
+
+        let payload = payload.into();
+        self.send(Message::Extended { id, payload }).await?;
+        self.flush().await?;
+        Ok(true)
+    }
+
+    fn check_msg_len(total_pieces: usize, id: u8, len: u32) -> bool {
+        let bitfield_len = (1 + total_pieces.div_ceil(8)) as u32;
 
         match (id, len) {
-            (0 | 1 | 2 | 3, 1) => true,
+            (0 | 1 | 2 | 3 | 14 | 15, 1) => true,
             (4, 5) => true,
             (5, n) if n == bitfield_len => true,
-            (6 | 8, 13) => true,
-            (7, n) if n >= 9 && n < Self::MAX_MSG_LENGTH => true,
+            (6 | 8 | 16, 13) => true,
+            // MAX_MSG_LENGTH limits the block, not the index and begin fields
+            (7, n) if n >= 9 && n - 9 <= Self::MAX_MSG_LENGTH => true,
             (9, 3) => true,
+            (20, n) if n >= 2 && n - 2 <= Self::MAX_EXTENDED_LENGTH => true,
+            (21 | 23, 49) => true,
+            (22, n) if n >= 49 && (n - 49) % 32 == 0 && n - 49 <= Self::MAX_EXTENDED_LENGTH => true,
             _ => false,
         }
     }
 
-    async fn decode_message(&mut self) -> Result<Message, DecodeError> {
-        let length = self.conn.read_u32().await?;
+    /// read the next message, updating the peer's info and the connection's state with it, see
+    /// [PeerInfo::update]. every message is returned, whether [PeerState::recv] allows it or not
+    pub(crate) async fn decode_message(&mut self) -> Result<Message, DecodeError> {
+        let msg = Self::read_message(&mut self.conn, self.info.bitfield.len()).await?;
+        self.info.update(&msg)?;
+        self.state.recv(&msg);
+        Ok(msg)
+    }
+
+    async fn read_message(
+        conn: &mut (impl AsyncRead + Unpin),
+        total_pieces: usize,
+    ) -> Result<Message, DecodeError> {
+        let length = conn.read_u32().await?;
         if length == 0 {
             return Ok(Message::KeepAlive);
         }
-        let msg_id = self.conn.read_u8().await?;
+        let msg_id = conn.read_u8().await?;
 
         // check msg_id matches expected message length, only Piece msgs are variable length
-        if !self.check_msg_len(msg_id, length) {
+        if !Self::check_msg_len(total_pieces, msg_id, length) {
             return Err(DecodeError::MessageId(msg_id, length));
         }
 
-        let mut buf = vec![0; length as usize - 4].into_boxed_slice();
-        self.conn.read_exact(&mut buf).await?;
+        // length includes the message id
+        let payload_len = length as usize - 1;
 
+        // only bitfields, blocks, extended messages and hashes need the heap, every other payload
+        // fits on the stack
         let msg = match msg_id {
-            0 => Message::Choke,
-            1 => Message::Unchoke,
-            2 => Message::Interested,
-            3 => Message::NotInterested,
-            4 => Message::Have(BE::read_u32(&buf[..])),
-            5 => Message::Bitfield(buf),
-            6 => Message::Request {
-                index: BE::read_u32(&buf[..]),
-                begin: BE::read_u32(&buf[..]),
-                length: BE::read_u32(&buf[..]),
-            },
-            7 => Message::Piece {
-                index: BE::read_u32(&buf[..]),
-                begin: BE::read_u32(&buf[..]),
-                block: buf,
-            },
-            8 => Message::Cancel {
-                index: BE::read_u32(&buf[..]),
-                begin: BE::read_u32(&buf[..]),
-                length: BE::read_u32(&buf[..]),
-            },
-            9 => Message::Port(BE::read_u16(&buf[..])),
-            _ => return Err(DecodeError::MessageId(msg_id, length)),
+            5 => {
+                let mut buf = vec![0; payload_len];
+                conn.read_exact(&mut buf).await?;
+
+                // the bits after the last piece pad out the last byte and must be zero
+                let spare = buf.len() * 8 - total_pieces;
+                let padding = buf.last().map_or(0, |&last| last & ((1 << spare) - 1));
+                if padding != 0 {
+                    return Err(DecodeError::Bitfield);
+                }
+
+                let mut bitfield = BitVec::from_vec(buf);
+                bitfield.truncate(total_pieces);
+                Message::Bitfield(bitfield.into_boxed_bitslice())
+            }
+            20 => {
+                let id = conn.read_u8().await?;
+                let mut payload = vec![0; payload_len - 1].into_boxed_slice();
+                conn.read_exact(&mut payload).await?;
+
+                Message::Extended { id, payload }
+            }
+            7 => {
+                let mut head = [0; 8];
+                conn.read_exact(&mut head).await?;
+                let mut block = vec![0; payload_len - 8].into_boxed_slice();
+                conn.read_exact(&mut block).await?;
+
+                Message::Piece {
+                    index: BE::read_u32(&head[..]),
+                    begin: BE::read_u32(&head[4..]),
+                    block,
+                }
+            }
+            22 => {
+                let mut head = [0; 48];
+                conn.read_exact(&mut head).await?;
+                let mut hashes = vec![[0; 32]; (payload_len - 48) / 32];
+                for hash in &mut hashes {
+                    conn.read_exact(hash).await?;
+                }
+
+                Message::Hashes(Self::hash_request(&head), hashes.into())
+            }
+            _ => {
+                // check_msg_len limits these to 48 bytes
+                let mut buf = [0; 48];
+                let buf = &mut buf[..payload_len];
+                conn.read_exact(buf).await?;
+
+                match msg_id {
+                    0 => Message::Choke,
+                    1 => Message::Unchoke,
+                    2 => Message::Interested,
+                    3 => Message::NotInterested,
+                    4 => Message::Have(BE::read_u32(buf)),
+                    6 => Message::Request {
+                        index: BE::read_u32(buf),
+                        begin: BE::read_u32(&buf[4..]),
+                        length: BE::read_u32(&buf[8..]),
+                    },
+                    8 => Message::Cancel {
+                        index: BE::read_u32(buf),
+                        begin: BE::read_u32(&buf[4..]),
+                        length: BE::read_u32(&buf[8..]),
+                    },
+                    9 => Message::Port(BE::read_u16(buf)),
+                    14 => Message::HaveAll,
+                    15 => Message::HaveNone,
+                    16 => Message::RejectRequest {
+                        index: BE::read_u32(buf),
+                        begin: BE::read_u32(&buf[4..]),
+                        length: BE::read_u32(&buf[8..]),
+                    },
+                    21 => Message::HashRequest(Self::hash_request(buf)),
+                    23 => Message::HashReject(Self::hash_request(buf)),
+                    _ => return Err(DecodeError::MessageId(msg_id, length)),
+                }
+            }
         };
 
         Ok(msg)
     }
+
+    // the fields shared by hash requests, hashes and hash rejects
+    fn hash_request(buf: &[u8]) -> HashRequest {
+        HashRequest {
+            pieces_root: buf[..32].try_into().unwrap(),
+            base_layer: BE::read_u32(&buf[32..]),
+            index: BE::read_u32(&buf[36..]),
+            length: BE::read_u32(&buf[40..]),
+            proof_layers: BE::read_u32(&buf[44..]),
+        }
+    }
+
+    /// drive the connection until either side hangs up. messages from the peer are sent to events
+    /// and messages received on commands are sent to the peer, control messages ahead of piece
+    /// data, see [SendQueue]. backlog is the piece data queued, which is taken off as it's written.
+    /// piece data is held to the upload limit set by [PeerCommand::UploadLimit], if any, while
+    /// control messages are never held back. a keep-alive is sent whenever we've been quiet for
+    /// KEEP_ALIVE_INTERVAL, and the peer is dropped once it's been quiet for IDLE_TIMEOUT. returns
+    /// Ok once commands or events is closed or we're told to shut down, see
+    /// [crate::peer_handle::PeerHandle]
+    pub(crate) async fn run(
+        self,
+        addr: SocketAddr,
+        mut commands: UnboundedReceiver<PeerCommand>,
+        events: UnboundedSender<PeerEvent>,
+        backlog: Backlog,
+    ) -> Result<(), DecodeError> {
+        // reading a message isn't cancel safe, so the read half is moved into a single read
+        // which survives every loop iteration until it completes
+        async fn recv<R: AsyncRead + Unpin>(
+            mut rx: R,
+            total_pieces: usize,
+        ) -> (R, Result<Message, DecodeError>) {
+            let msg = Peer::read_message(&mut rx, total_pieces).await;
+            (rx, msg)
+        }
+
+        let total_pieces = self.info.bitfield.len();
+        let (rx, mut tx) = tokio::io::split(self.conn);
+        let mut read = Box::pin(recv(rx, total_pieces).fuse());
+        let mut queue = SendQueue::new(backlog);
+        let mut upload_limit = None;
+        // we've been told to shut down once everything queued is sent
+        let mut closing = false;
+        let mut last_sent = Instant::now();
+        let mut last_recv = Instant::now();
+
+        loop {
+            // take everything sent since the last write, so urgent messages can jump the queue
+            while !closing {
+                match commands.try_recv() {
+                    Ok(PeerCommand::Send(msg)) => queue.push(msg),
+                    Ok(PeerCommand::UploadLimit(rate)) => Self::limit(&mut upload_limit, rate),
+                    Ok(PeerCommand::Shutdown) => closing = true,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            }
+
+            // handle anything the peer already sent before writing more
+            if let Some((rx, msg)) = (&mut read).now_or_never() {
+                if events.send(PeerEvent::Message(addr, msg?)).is_err() {
+                    return Ok(());
+                }
+                last_recv = Instant::now();
+                read = Box::pin(recv(rx, total_pieces).fuse());
+                continue;
+            }
+            if last_recv.elapsed() >= IDLE_TIMEOUT {
+                return Err(DecodeError::Idle);
+            }
+
+            // piece data waits while we're over the upload limit
+            let now = Instant::now();
+            let throttled = upload_limit.as_mut().and_then(|b| b.ready_at(now));
+            let msg = match throttled {
+                Some(_) => queue.pop_urgent(),
+                None => queue.pop(),
+            };
+            if let Some(msg) = msg {
+                if let (Some(bucket), Message::Piece { block, .. }) = (&mut upload_limit, &msg) {
+                    bucket.take(block.len(), now);
+                }
+                let mut buf = Vec::with_capacity(msg.encoded_len());
+                msg.encode_into(&mut buf);
+                tx.write_all(&buf).await?;
+                last_sent = Instant::now();
+                continue;
+            }
+            if closing && queue.is_empty() {
+                tx.shutdown().await?;
+                return Ok(());
+            }
+            // messages queued together go out together, once nothing more can be written
+            tx.flush().await?;
+
+            let keep_alive = time::sleep_until(last_sent + KEEP_ALIVE_INTERVAL);
+            let idle = time::sleep_until(last_recv + IDLE_TIMEOUT);
+            let unthrottled = async {
+                match throttled {
+                    Some(at) => time::sleep_until(at).await,
+                    None => future::pending().await,
+                }
+            };
+
+            select_biased! {
+                (rx, msg) = read => {
+                    if events.send(PeerEvent::Message(addr, msg?)).is_err() {
+                        return Ok(());
+                    }
+                    last_recv = Instant::now();
+                    read = Box::pin(recv(rx, total_pieces).fuse());
+                }
+                cmd = commands.recv().fuse() => match cmd {
+                    Some(PeerCommand::Send(msg)) => queue.push(msg),
+                    Some(PeerCommand::UploadLimit(rate)) => Self::limit(&mut upload_limit, rate),
+                    Some(PeerCommand::Shutdown) => closing = true,
+                    None => return Ok(()),
+                },
+                _ = Box::pin(unthrottled).fuse() => {}
+                _ = Box::pin(keep_alive).fuse() => queue.push(Message::KeepAlive),
+                _ = Box::pin(idle).fuse() => return Err(DecodeError::Idle),
+            }
+        }
+    }
+
+    // apply an upload limit from [PeerCommand::UploadLimit]. a changed rate keeps what's in the
+    // bucket so the peer can't dodge the limit by having it changed
+    fn limit(bucket: &mut Option<TokenBucket>, rate: Option<u64>) {
+        let now = Instant::now();
+        *bucket = match (bucket.take(), rate) {
+            (Some(mut bucket), Some(rate)) => {
+                bucket.set_rate(rate, now);
+                Some(bucket)
+            }
+            (None, Some(rate)) => Some(TokenBucket::new(rate, now)),
+            (_, None) => None,
+        };
+    }
 }
 
+impl PeerInfo {
+    fn new(peer_id: PeerId, reserved: ReservedBits, total_pieces: usize) -> PeerInfo {
+        PeerInfo {
+            peer_id,
+            bitfield: bitbox![u8, Msb0; 0; total_pieces],
+            reserved,
+            extensions: Default::default(),
+            dht_port: None,
+            interested: false,
+            stats: TransferStats::new(std::time::Instant::now()),
+        }
+    }
+
+    pub fn peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    /// extensions the peer supports, see [ReservedBits]
+    pub fn reserved(&self) -> ReservedBits {
+        self.reserved
+    }
+
+    /// the id the peer wants extension `name` sent with, if it supports it. see
+    /// [ExtensionHandshake::m]
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.extensions.m.get(name).copied()
+    }
+
+    /// the peer's extension handshake, empty until it sends one
+    pub fn extensions(&self) -> &ExtensionHandshake {
+        &self.extensions
+    }
+
+    /// udp port the peer's DHT node listens on, if it sent one. this is where a DHT node should
+    /// ping the peer before adding it to its routing table, see BEP-5
+    pub fn dht_port(&self) -> Option<u16> {
+        self.dht_port
+    }
+
+    /// the peer's client, identified from its extension handshake or peer id
+    pub fn client(&self) -> Option<ClientInfo> {
+        ClientInfo::identify(&self.peer_id, self.extensions.v.as_deref())
+    }
+
+    /// whether the peer last told us it's interested in downloading from us
+    pub fn is_interested(&self) -> bool {
+        self.interested
+    }
+
+    /// the pieces the peer has
+    pub fn bitfield(&self) -> &Bitfield {
+        &self.bitfield
+    }
+
+    /// whether the peer has piece index
+    pub fn has(&self, index: u32) -> bool {
+        self.bitfield.get(index as usize).is_some_and(|b| *b)
+    }
+
+    /// whether the peer's bitfield says it has every piece
+    pub fn is_seed(&self) -> bool {
+        self.bitfield.all()
+    }
+
+    /// piece data transferred with the peer so far, and how fast
+    pub fn stats(&self) -> PeerStats {
+        self.stats.snapshot(std::time::Instant::now())
+    }
+
+    /// count a block sent to the peer towards its stats
+    pub(crate) fn record_upload(&mut self, bytes: usize) {
+        let now = std::time::Instant::now();
+        self.stats.record_upload(bytes as u64, now);
+    }
+
+    /// update our view of the peer with a message it sent. a bitfield, have all or have none
+    /// replaces the peer's bitfield and have and lt_donthave messages update it, ignoring pieces we
+    /// don't have. a port sets the peer's DHT port, an extension handshake updates its extensions,
+    /// blocks count towards its stats and interested and not interested say whether it wants to
+    /// download from us
+    pub(crate) fn update(&mut self, msg: &Message) -> Result<(), DecodeError> {
+        match msg {
+            Message::Piece { block, .. } => {
+                let now = std::time::Instant::now();
+                self.stats.record_download(block.len() as u64, now);
+            }
+            Message::Bitfield(bitfield) => self.bitfield = bitfield.clone(),
+            Message::HaveAll => self.bitfield.fill(true),
+            Message::HaveNone => self.bitfield.fill(false),
+            Message::Have(index) => self.set_has(*index, true),
+            Message::Extended {
+                id: LT_DONTHAVE,
+                payload,
+            } => {
+                if let Ok(index) = <[u8; 4]>::try_from(&payload[..]) {
+                    self.set_has(u32::from_be_bytes(index), false);
+                }
+            }
+            Message::Port(port) => self.dht_port = Some(*port),
+            Message::Interested => self.interested = true,
+            Message::NotInterested => self.interested = false,
+            Message::Extended { id: 0, payload } => {
+                let handshake =
+                    ExtensionHandshake::decode(payload).ok_or(DecodeError::ExtensionHandshake)?;
+                self.extensions.update(handshake);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    // record whether the peer has piece index, ignoring indices past the last piece
+    fn set_has(&mut self, index: u32, has: bool) {
+        if let Some(mut bit) = self.bitfield.get_mut(index as usize) {
+            *bit = has;
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub enum Message {
-    KeepAlive,                          //        | len = 0
-    Choke,                              // id = 0 | len = 1
-    Unchoke,                            // id = 1 | len = 1
-    Interested,                         // id = 2 | len = 1
-    NotInterested,                      // id = 3 | len = 1
-    Have(/* piece index */ u32),        // id = 4 | len = 5
-    Bitfield(/* bitfield */ Box<[u8]>), // id = 5 | len = 1+x
+    KeepAlive,                         //        | len = 0
+    Choke,                             // id = 0 | len = 1
+    Unchoke,                           // id = 1 | len = 1
+    Interested,                        // id = 2 | len = 1
+    NotInterested,                     // id = 3 | len = 1
+    Have(/* piece index */ u32),       // id = 4 | len = 5
+    Bitfield(/* bitfield */ Bitfield), // id = 5 | len = 1+x
     // id = 6 | len = 13
     Request {
         index: u32,
@@ -201,18 +849,193 @@ pub enum Message {
         length: u32,
     },
     Port(/* listen port */ u16), // id = 9 | len = 3
+    HaveAll,                     // id = 14 | len = 1, in place of a bitfield, see BEP-6
+    HaveNone,                    // id = 15 | len = 1
+    // id = 16 | len = 13, a request which won't be served, see BEP-6
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    // id = 20 | len = 2+x, see BEP-10. id is the extended message id, 0 for the handshake
+    Extended {
+        id: u8,
+        payload: Box<[u8]>,
+    },
+    // id = 21 | len = 49, see BEP-52
+    HashRequest(HashRequest),
+    // id = 22 | len = 49+32x, the requested hashes followed by their proof
+    Hashes(HashRequest, Box<[Sha256Hash]>),
+    // id = 23 | len = 49
+    HashReject(HashRequest),
+}
+
+impl Message {
+    /// length of the message once encoded, including its length prefix
+    pub fn encoded_len(&self) -> usize {
+        let len = match self {
+            Message::KeepAlive => 0,
+            Message::Choke | Message::Unchoke | Message::Interested | Message::NotInterested => 1,
+            Message::HaveAll | Message::HaveNone => 1,
+            Message::Have(_) => 5,
+            Message::Bitfield(bitfield) => 1 + bitfield.len().div_ceil(8),
+            Message::Request { .. } | Message::Cancel { .. } | Message::RejectRequest { .. } => 13,
+            Message::Piece { block, .. } => 9 + block.len(),
+            Message::Port(_) => 3,
+            Message::Extended { payload, .. } => 2 + payload.len(),
+            Message::HashRequest(_) | Message::HashReject(_) => 49,
+            Message::Hashes(_, hashes) => 49 + 32 * hashes.len(),
+        };
+
+        4 + len
+    }
+
+    /// append the message to buf as a length prefixed frame, the inverse of
+    /// [Peer::decode_message]
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&(self.encoded_len() as u32 - 4).to_be_bytes());
+
+        match self {
+            Message::KeepAlive => {}
+            Message::Choke => buf.push(0),
+            Message::Unchoke => buf.push(1),
+            Message::Interested => buf.push(2),
+            Message::NotInterested => buf.push(3),
+            Message::Have(index) => {
+                buf.push(4);
+                buf.extend_from_slice(&index.to_be_bytes());
+            }
+            Message::Bitfield(bitfield) => {
+                buf.push(5);
+                buf.extend_from_slice(bitfield.as_raw_slice());
+
+                // clear the spare bits, which the bitfield may not have kept zeroed
+                let spare = bitfield.as_raw_slice().len() * 8 - bitfield.len();
+                if let Some(last) = buf.last_mut() {
+                    *last &= 0xff << spare;
+                }
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                buf.push(6);
+                for n in [index, begin, length] {
+                    buf.extend_from_slice(&n.to_be_bytes());
+                }
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                buf.push(7);
+                buf.extend_from_slice(&index.to_be_bytes());
+                buf.extend_from_slice(&begin.to_be_bytes());
+                buf.extend_from_slice(block);
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                buf.push(8);
+                for n in [index, begin, length] {
+                    buf.extend_from_slice(&n.to_be_bytes());
+                }
+            }
+            Message::Port(port) => {
+                buf.push(9);
+                buf.extend_from_slice(&port.to_be_bytes());
+            }
+            Message::HaveAll => buf.push(14),
+            Message::HaveNone => buf.push(15),
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                buf.push(16);
+                for n in [index, begin, length] {
+                    buf.extend_from_slice(&n.to_be_bytes());
+                }
+            }
+            Message::Extended { id, payload } => {
+                buf.push(20);
+                buf.push(*id);
+                buf.extend_from_slice(payload);
+            }
+            Message::HashRequest(req) => {
+                buf.push(21);
+                Self::encode_hash_request(req, buf);
+            }
+            Message::Hashes(req, hashes) => {
+                buf.push(22);
+                Self::encode_hash_request(req, buf);
+                hashes.iter().for_each(|hash| buf.extend_from_slice(hash));
+            }
+            Message::HashReject(req) => {
+                buf.push(23);
+                Self::encode_hash_request(req, buf);
+            }
+        }
+    }
+
+    fn encode_hash_request(req: &HashRequest, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&req.pieces_root);
+        for n in [req.base_layer, req.index, req.length, req.proof_layers] {
+            buf.extend_from_slice(&n.to_be_bytes());
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use std::mem::{size_of, size_of_val};
+    use std::{
+        collections::HashMap,
+        mem::{size_of, size_of_val},
+        time::Duration,
+    };
 
+    use bitvec::prelude::{bitbox, BitBox, Msb0};
     use tokio::{
-        io::BufStream,
+        io::{AsyncReadExt, AsyncWriteExt, BufStream},
         net::{TcpListener, TcpStream},
+        sync::mpsc,
+        time::Instant,
+    };
+
+    use crate::{
+        error::{DecodeError, HandshakeError},
+        extension::{ExtensionHandshake, PexFlags, PexMessage, LT_DONTHAVE, UT_PEX},
+        merkle::HashRequest,
+        peer::{
+            Message, Peer, PeerInfo, PeerState, ReservedBits, Timeouts, Verdict, IDLE_TIMEOUT,
+            KEEP_ALIVE_INTERVAL, PROTOCOL,
+        },
+        peer_handle::{PeerCommand, PeerEvent},
+        proxy::Dialer,
+        send_queue::Backlog,
     };
 
-    use crate::peer::{Peer, Status};
+    const OUR_ID: &[u8; 20] = b"-TS0001-|testClient|";
+    const REMOTE_ID: &[u8; 20] = b"-TS0001-|remotePeer|";
+    const INFO_HASH: &[u8; 20] =
+        b"\xc9\xe1\x57\x63\xf7\x22\xf2\x3e\x98\xa2\x9d\xec\xdf\xae\x34\x1b\x98\xd5\x30\x56";
+
+    // handshakes captured from real clients. all of them set the extension protocol, fast
+    // extension and DHT reserved bits
+    const LIBTORRENT: &[u8; 68] = b"\x13BitTorrent protocol\x00\x00\x00\x00\x00\x10\x00\x05\
+        \xc9\xe1\x57\x63\xf7\x22\xf2\x3e\x98\xa2\x9d\xec\xdf\xae\x34\x1b\x98\xd5\x30\x56\
+        -LT2080-bFq2NdMx0Ypz";
+    const TRANSMISSION: &[u8; 68] = b"\x13BitTorrent protocol\x00\x00\x00\x00\x00\x10\x00\x05\
+        \xc9\xe1\x57\x63\xf7\x22\xf2\x3e\x98\xa2\x9d\xec\xdf\xae\x34\x1b\x98\xd5\x30\x56\
+        -TR3000-n0uhtfj2d8lk";
+    // utorrent peer ids end in random bytes, which aren't valid utf8
+    const UTORRENT: &[u8; 68] = b"\x13BitTorrent protocol\x00\x00\x00\x00\x00\x10\x00\x05\
+        \xc9\xe1\x57\x63\xf7\x22\xf2\x3e\x98\xa2\x9d\xec\xdf\xae\x34\x1b\x98\xd5\x30\x56\
+        -UT355W-\x8c\x1e\xd4\x02\xf7\x9b\x13\xe0\x5a\xc6\x00\x7d";
 
     struct MsgData {
         length: u32,
@@ -221,22 +1044,28 @@ mod test {
         block: Box<[u8]>,
     }
 
+    // a peer with total_pieces pieces at the other end of conn, which advertised reserved
+    fn test_peer(conn: TcpStream, reserved: ReservedBits, total_pieces: usize) -> Peer {
+        Peer {
+            info: PeerInfo::new([0; 20], reserved, total_pieces),
+            state: PeerState::default(),
+            conn: BufStream::new(conn),
+        }
+    }
+
     #[tokio::test]
     async fn arr_size() {
         let addr = "127.0.0.1:34567";
         let _l = TcpListener::bind(addr).await.unwrap();
 
-        let mut p = Peer {
-            peer_id: "".to_string(),
-            bitfield: Default::default(),
-            status: Status { bits: 0 },
-            conn: BufStream::new(TcpStream::connect(addr).await.unwrap()),
-        };
+        let conn = TcpStream::connect(addr).await.unwrap();
 
-        println!(
-            "connect: {} bytes",
-            size_of_val(&Peer::connect(addr, &b""[..], &b""[..], 0))
-        );
+        let mut p = test_peer(conn, ReservedBits::empty(), 0);
+
+        let (dialer, timeouts) = (Dialer::default(), Timeouts::default());
+        let addr = addr.parse().unwrap();
+        let connect = Peer::connect(addr, &b""[..], &b""[..], 0, &dialer, timeouts);
+        println!("connect: {} bytes", size_of_val(&connect));
 
         println!(
             "decode_message baseline is {:?} bytes",
@@ -245,4 +1074,570 @@ mod test {
 
         println!("decode_message: {} bytes", size_of_val(&p.decode_message()));
     }
+
+    #[tokio::test]
+    async fn framing() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let conn = TcpStream::connect(addr).await.unwrap();
+
+        let mut p = test_peer(conn, ReservedBits::empty(), 0);
+        let (mut remote, _) = listener.accept().await.unwrap();
+
+        let mut frames = vec![];
+        for id in [6, 8] {
+            frames.extend_from_slice(&[0, 0, 0, 13, id, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]);
+        }
+        // a full 16 KiB block
+        frames.extend_from_slice(&(9 + 16384u32).to_be_bytes());
+        frames.extend_from_slice(&[7, 0, 0, 0, 1, 0, 0, 0, 2]);
+        frames.extend_from_slice(&[7; 16384]);
+        frames.extend_from_slice(&[0, 0, 0, 0]);
+        remote.write_all(&frames).await.unwrap();
+
+        let Message::Request {
+            index,
+            begin,
+            length,
+        } = p.decode_message().await.unwrap()
+        else {
+            panic!("expected a request");
+        };
+        assert_eq!((index, begin, length), (1, 2, 3));
+        let Message::Cancel {
+            index,
+            begin,
+            length,
+        } = p.decode_message().await.unwrap()
+        else {
+            panic!("expected a cancel");
+        };
+        assert_eq!((index, begin, length), (1, 2, 3));
+        let Message::Piece {
+            index,
+            begin,
+            block,
+        } = p.decode_message().await.unwrap()
+        else {
+            panic!("expected a piece");
+        };
+        assert_eq!((index, begin), (1, 2));
+        assert!(block.len() == 16384 && block.iter().all(|&b| b == 7));
+        let msg = p.decode_message().await.unwrap();
+        assert!(matches!(msg, Message::KeepAlive));
+    }
+
+    #[tokio::test]
+    async fn send_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let conn = TcpStream::connect(addr).await.unwrap();
+
+        let mut p = test_peer(conn, ReservedBits::empty(), 0);
+        let (mut remote, _) = listener.accept().await.unwrap();
+
+        p.send_port(6882).await.unwrap();
+        let mut msg = [0; 7];
+        remote.read_exact(&mut msg).await.unwrap();
+        assert_eq!(msg, [0, 0, 0, 3, 9, 0x1a, 0xe2]);
+
+        // the peer's own DHT port is remembered
+        assert_eq!(p.info.dht_port(), None);
+        let port = [0, 0, 0, 3, 9, 0x1a, 0xe1];
+        remote.write_all(&port).await.unwrap();
+        assert_eq!(p.decode_message().await.unwrap(), Message::Port(6881));
+        assert_eq!(p.info.dht_port(), Some(6881));
+    }
+
+    #[tokio::test]
+    async fn send_message() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let conn = TcpStream::connect(addr).await.unwrap();
+
+        let mut p = test_peer(conn, ReservedBits::empty(), 0);
+        let (remote, _) = listener.accept().await.unwrap();
+        let mut remote = test_peer(remote, ReservedBits::empty(), 16);
+
+        let req = HashRequest {
+            pieces_root: [7; 32],
+            base_layer: 1,
+            index: 4,
+            length: 2,
+            proof_layers: 3,
+        };
+        let msgs = || {
+            [
+                Message::KeepAlive,
+                Message::Choke,
+                Message::Unchoke,
+                Message::Interested,
+                Message::NotInterested,
+                Message::Have(7),
+                Message::Bitfield(BitBox::from_boxed_slice([0xff, 0x01].into())),
+                Message::Request {
+                    index: 1,
+                    begin: 16384,
+                    length: 16384,
+                },
+                Message::Piece {
+                    index: 1,
+                    begin: 0,
+                    block: [1, 2, 3].into(),
+                },
+                Message::Cancel {
+                    index: 1,
+                    begin: 16384,
+                    length: 16384,
+                },
+                Message::Port(6881),
+                Message::HaveAll,
+                Message::HaveNone,
+                Message::RejectRequest {
+                    index: 2,
+                    begin: 0,
+                    length: 16384,
+                },
+                Message::Extended {
+                    id: 3,
+                    payload: b"d8:msg_typei0e5:piecei0ee".to_vec().into(),
+                },
+                Message::HashRequest(req),
+                Message::Hashes(req, [[1; 32], [2; 32], [3; 32]].into()),
+                Message::HashReject(req),
+            ]
+        };
+
+        for msg in msgs() {
+            p.send(msg).await.unwrap();
+        }
+        p.flush().await.unwrap();
+
+        for msg in msgs() {
+            assert_eq!(remote.decode_message().await.unwrap(), msg);
+        }
+
+        let mut buf = vec![];
+        Message::Have(0x01020304).encode_into(&mut buf);
+        assert_eq!(buf, [0, 0, 0, 5, 4, 1, 2, 3, 4]);
+
+        buf.clear();
+        Message::HashReject(req).encode_into(&mut buf);
+        assert_eq!(buf[..5], [0, 0, 0, 49, 23]);
+        assert_eq!(buf[37..], [0, 0, 0, 1, 0, 0, 0, 4, 0, 0, 0, 2, 0, 0, 0, 3]);
+    }
+
+    #[test]
+    fn state() {
+        let request = Message::Request {
+            index: 0,
+            begin: 0,
+            length: 16384,
+        };
+        let reject = Message::RejectRequest {
+            index: 0,
+            begin: 0,
+            length: 16384,
+        };
+        let mut state = PeerState::default();
+        assert!(state.am_choking() && state.peer_choking());
+        assert!(!state.am_interested() && !state.peer_interested());
+
+        // requests are only served once we unchoke the peer
+        assert_eq!(state.recv(&request), Verdict::Reject);
+        state.send(&Message::Unchoke);
+        assert_eq!(state.recv(&request), Verdict::Accept);
+        state.send(&Message::Choke);
+        assert_eq!(state.recv(&request), Verdict::Reject);
+
+        // interest and chokes from either end are tracked separately
+        state.send(&Message::Interested);
+        assert_eq!(state.recv(&Message::Interested), Verdict::Accept);
+        assert_eq!(state.recv(&Message::Unchoke), Verdict::Accept);
+        assert!(state.am_interested() && state.peer_interested());
+        assert!(state.am_choking() && !state.peer_choking());
+        state.recv(&Message::NotInterested);
+        state.send(&Message::NotInterested);
+        assert!(!state.am_interested() && !state.peer_interested());
+
+        // rejects, have all and have none are part of the fast extension
+        assert!(!state.fast());
+        assert_eq!(state.recv(&reject), Verdict::Ignore);
+        assert_eq!(state.recv(&Message::HaveAll), Verdict::Ignore);
+        let mut fast = PeerState::new(ReservedBits::FAST);
+        assert!(fast.fast());
+        assert_eq!(fast.recv(&reject), Verdict::Accept);
+        assert_eq!(fast.recv(&Message::HaveNone), Verdict::Accept);
+    }
+
+    #[tokio::test]
+    async fn handshake() {
+        for capture in [LIBTORRENT, TRANSMISSION, UTORRENT] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            let remote = async {
+                let (mut remote, _) = listener.accept().await.unwrap();
+                let mut ours = [0; 68];
+                remote.read_exact(&mut ours).await.unwrap();
+                remote.write_all(capture).await.unwrap();
+                ours
+            };
+            let (dialer, timeouts) = (Dialer::default(), Timeouts::default());
+            let connect = Peer::connect(addr, INFO_HASH, OUR_ID, 8, &dialer, timeouts);
+
+            let (peer, ours) = futures::join!(connect, remote);
+            let peer = peer.unwrap();
+            assert_eq!(peer.info.peer_id, capture[48..]);
+            let reserved = ReservedBits::EXTENSION | ReservedBits::FAST | ReservedBits::DHT;
+            assert_eq!(peer.info.reserved(), reserved);
+
+            assert_eq!(&ours[..20], PROTOCOL);
+            assert_eq!(ours[20..28], [0, 0, 0, 0, 0, 0x10, 0, 0x04]);
+            assert_eq!(&ours[28..48], INFO_HASH);
+            assert_eq!(&ours[48..], OUR_ID);
+        }
+    }
+
+    #[tokio::test]
+    async fn accept() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let info_hashes = HashMap::from([(*INFO_HASH, 8)]);
+
+        let incoming = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            Peer::accept(stream, &info_hashes, OUR_ID).await
+        };
+        let (dialer, timeouts) = (Dialer::default(), Timeouts::default());
+        let connect = Peer::connect(addr, INFO_HASH, REMOTE_ID, 8, &dialer, timeouts);
+
+        let (ours, theirs) = futures::join!(incoming, connect);
+        let (ours, info_hash) = ours.unwrap();
+        assert_eq!(&info_hash, INFO_HASH);
+        assert_eq!(&ours.info.peer_id, REMOTE_ID);
+        assert_eq!(&theirs.unwrap().info.peer_id, OUR_ID);
+
+        // a torrent we don't have is refused without replying
+        let incoming = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            Peer::accept(stream, &info_hashes, OUR_ID).await
+        };
+        let (dialer, timeouts) = (Dialer::default(), Timeouts::default());
+        let connect = Peer::connect(addr, &[1; 20], REMOTE_ID, 8, &dialer, timeouts);
+
+        let (ours, theirs) = futures::join!(incoming, connect);
+        assert!(matches!(ours, Err(HandshakeError::InfoHash)));
+        assert!(matches!(theirs, Err(HandshakeError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn handshake_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let timeouts = Timeouts {
+            handshake: Duration::from_millis(50),
+            ..Timeouts::default()
+        };
+
+        // a peer that accepts the connection but never answers
+        let dialer = Dialer::default();
+        let connect = Peer::connect(addr, INFO_HASH, OUR_ID, 8, &dialer, timeouts);
+        let (res, _remote) = futures::join!(connect, listener.accept());
+        assert!(matches!(res, Err(HandshakeError::Timeout)));
+
+        // a peer that answers with something other than a bittorrent handshake
+        let remote = async {
+            let (mut remote, _) = listener.accept().await.unwrap();
+            remote.write_all(&[b'x'; 68]).await.unwrap();
+            remote
+        };
+        let dialer = Dialer::default();
+        let connect = Peer::connect(addr, INFO_HASH, OUR_ID, 8, &dialer, timeouts);
+        let (res, _remote) = futures::join!(connect, remote);
+        assert!(matches!(res, Err(HandshakeError::Protocol)));
+
+        // a peer serving another torrent
+        let remote = async {
+            let (mut remote, _) = listener.accept().await.unwrap();
+            let mut capture = *LIBTORRENT;
+            capture[28..48].fill(1);
+            remote.write_all(&capture).await.unwrap();
+            remote
+        };
+        let dialer = Dialer::default();
+        let connect = Peer::connect(addr, INFO_HASH, OUR_ID, 8, &dialer, timeouts);
+        let (res, _remote) = futures::join!(connect, remote);
+        assert!(matches!(res, Err(HandshakeError::InfoHash)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let conn = TcpStream::connect(addr).await.unwrap();
+
+        let p = test_peer(conn, ReservedBits::empty(), 0);
+        let (mut remote, _) = listener.accept().await.unwrap();
+        let (out_tx, out_rx) = mpsc::unbounded_channel();
+        let (in_tx, mut in_rx) = mpsc::unbounded_channel();
+
+        let start = Instant::now();
+        let remote = async {
+            let mut buf = [0; 5];
+
+            // messages go both ways
+            remote.write_all(&[0, 0, 0, 1, 2]).await.unwrap();
+            let event = in_rx.recv().await;
+            assert!(matches!(event, Some(PeerEvent::Message(a, Message::Interested)) if a == addr));
+            out_tx.send(PeerCommand::Send(Message::Unchoke)).unwrap();
+            remote.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [0, 0, 0, 1, 1]);
+
+            // we keep quiet, so a keep-alive follows
+            remote.read_exact(&mut buf[..4]).await.unwrap();
+            assert_eq!(buf[..4], [0; 4]);
+            assert!(start.elapsed() >= KEEP_ALIVE_INTERVAL);
+            remote
+        };
+
+        // the remote never sends anything else, so it's dropped
+        let run = p.run(addr, out_rx, in_tx, Backlog::default());
+        let (res, _remote) = futures::join!(run, remote);
+        assert!(matches!(res, Err(DecodeError::Idle)));
+        assert!(start.elapsed() >= IDLE_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn run_priority() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let conn = TcpStream::connect(addr).await.unwrap();
+
+        let p = test_peer(conn, ReservedBits::empty(), 0);
+        let (mut remote, _) = listener.accept().await.unwrap();
+        let (out_tx, out_rx) = mpsc::unbounded_channel();
+        let (in_tx, _in_rx) = mpsc::unbounded_channel();
+
+        // a choke queued behind blocks is sent ahead of them
+        let backlog = Backlog::default();
+        for index in 0..4 {
+            let block = vec![0; 16 * 1024].into();
+            let msg = Message::Piece {
+                index,
+                begin: 0,
+                block,
+            };
+            backlog.add(&msg);
+            out_tx.send(PeerCommand::Send(msg)).unwrap();
+        }
+        out_tx.send(PeerCommand::Send(Message::Choke)).unwrap();
+        out_tx.send(PeerCommand::Shutdown).unwrap();
+        assert_eq!(backlog.get(), 4 * (13 + 16 * 1024));
+
+        let run = p.run(addr, out_rx, in_tx, backlog.clone());
+        let read = async {
+            let mut buf = vec![];
+            remote.read_to_end(&mut buf).await.unwrap();
+            buf
+        };
+        let (res, buf) = futures::join!(run, read);
+        assert!(res.is_ok());
+        assert_eq!(buf[..5], [0, 0, 0, 1, 0]);
+        assert_eq!(buf.len(), 5 + 4 * (13 + 16 * 1024));
+        assert_eq!(backlog.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn run_upload_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let conn = TcpStream::connect(addr).await.unwrap();
+
+        let p = test_peer(conn, ReservedBits::empty(), 0);
+        let (mut remote, _) = listener.accept().await.unwrap();
+        let (out_tx, out_rx) = mpsc::unbounded_channel();
+        let (in_tx, _in_rx) = mpsc::unbounded_channel();
+
+        // a second's worth goes out straight away, then a block every 250ms
+        let limit = PeerCommand::UploadLimit(Some(64 * 1024));
+        out_tx.send(limit).unwrap();
+        for index in 0..8 {
+            let block = vec![0; 16 * 1024].into();
+            let msg = Message::Piece {
+                index,
+                begin: 0,
+                block,
+            };
+            out_tx.send(PeerCommand::Send(msg)).unwrap();
+        }
+        out_tx.send(PeerCommand::Shutdown).unwrap();
+
+        let start = Instant::now();
+        let run = p.run(addr, out_rx, in_tx, Backlog::default());
+        let block_len = 13 + 16 * 1024;
+        let read = async {
+            let mut buf = vec![0; 4 * block_len];
+            remote.read_exact(&mut buf).await.unwrap();
+
+            // control messages aren't held back
+            out_tx.send(PeerCommand::Send(Message::Choke)).unwrap();
+            let mut rest = vec![];
+            remote.read_to_end(&mut rest).await.unwrap();
+            rest
+        };
+        let (res, rest) = futures::join!(run, read);
+        assert!(res.is_ok());
+        assert_eq!(rest.len(), 5 + 4 * block_len);
+        let choke = [0, 0, 0, 1, 0];
+        assert!(rest[..5] == choke || rest[block_len..][..5] == choke);
+        assert!(start.elapsed() >= Duration::from_millis(700));
+    }
+
+    #[tokio::test]
+    async fn bitfield() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let conn = TcpStream::connect(addr).await.unwrap();
+
+        let mut p = test_peer(conn, ReservedBits::empty(), 10);
+        let (mut remote, _) = listener.accept().await.unwrap();
+
+        // pieces 0, 1 and 9
+        let frame = [0, 0, 0, 3, 5, 0xc0, 0x40];
+        remote.write_all(&frame).await.unwrap();
+        let msg = p.decode_message().await.unwrap();
+        let mut expected = bitbox![u8, Msb0; 0; 10];
+        expected.set(0, true);
+        expected.set(1, true);
+        expected.set(9, true);
+        assert_eq!(msg, Message::Bitfield(expected.clone()));
+        assert_eq!(p.info.bitfield, expected);
+
+        let mut buf = vec![];
+        msg.encode_into(&mut buf);
+        assert_eq!(buf, frame);
+
+        // a spare bit is set
+        let frame = [0, 0, 0, 3, 5, 0xc0, 0x60];
+        remote.write_all(&frame).await.unwrap();
+        let res = p.decode_message().await;
+        assert!(matches!(res, Err(DecodeError::Bitfield)));
+
+        // too short for 10 pieces
+        remote.write_all(&[0, 0, 0, 2, 5, 0xc0]).await.unwrap();
+        let res = p.decode_message().await;
+        assert!(matches!(res, Err(DecodeError::MessageId(5, 2))));
+    }
+
+    #[tokio::test]
+    async fn dont_have() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let conn = TcpStream::connect(addr).await.unwrap();
+
+        let mut p = test_peer(conn, ReservedBits::EXTENSION, 10);
+        let (remote, _) = listener.accept().await.unwrap();
+        let mut remote = test_peer(remote, ReservedBits::EXTENSION, 0);
+        remote.info.extensions = ExtensionHandshake::ours(None, false);
+
+        remote.send(Message::Have(3)).await.unwrap();
+        // past the last piece
+        remote.send(Message::Have(20)).await.unwrap();
+        remote.flush().await.unwrap();
+        p.decode_message().await.unwrap();
+        p.decode_message().await.unwrap();
+        assert_eq!(p.info.bitfield.count_ones(), 1);
+        assert!(p.info.bitfield[3]);
+
+        assert!(remote.send_dont_have(3).await.unwrap());
+        let msg = p.decode_message().await.unwrap();
+        let (id, payload) = (LT_DONTHAVE, [0, 0, 0, 3].into());
+        assert_eq!(msg, Message::Extended { id, payload });
+        assert!(p.info.bitfield.not_any());
+
+        // p never got an extension handshake
+        assert!(!p.send_dont_have(3).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn stats() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let conn = TcpStream::connect(addr).await.unwrap();
+
+        let mut p = test_peer(conn, ReservedBits::empty(), 10);
+        let (remote, _) = listener.accept().await.unwrap();
+        let mut remote = test_peer(remote, ReservedBits::empty(), 10);
+
+        let piece = Message::Piece {
+            index: 0,
+            begin: 0,
+            block: vec![0; 100].into(),
+        };
+        remote.send(piece).await.unwrap();
+        remote.send(Message::Have(1)).await.unwrap();
+        remote.flush().await.unwrap();
+        p.decode_message().await.unwrap();
+        p.decode_message().await.unwrap();
+
+        // only block data is counted
+        assert_eq!(p.info().stats().downloaded, 100);
+        assert_eq!(p.info().stats().uploaded, 0);
+        assert_eq!(remote.info().stats().uploaded, 100);
+    }
+
+    #[tokio::test]
+    async fn extension_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let conn = TcpStream::connect(addr).await.unwrap();
+
+        let mut p = test_peer(conn, ReservedBits::empty(), 0);
+        let (remote, _) = listener.accept().await.unwrap();
+        let mut remote = test_peer(remote, ReservedBits::EXTENSION, 0);
+
+        let ours = ExtensionHandshake::ours(Some(31235), false);
+        let sent = p.send_extension_handshake(Some(31235), false);
+        sent.await.unwrap();
+        let msg = remote.decode_message().await.unwrap();
+        assert!(matches!(msg, Message::Extended { id: 0, .. }));
+        assert_eq!(remote.info.extensions(), &ours);
+        assert_eq!(remote.info.extension_id("ut_metadata"), None);
+
+        // a resent handshake enabling an extension
+        let payload = b"d1:md11:ut_metadatai2eee".to_vec().into();
+        p.send(Message::Extended { id: 0, payload }).await.unwrap();
+        p.flush().await.unwrap();
+        remote.decode_message().await.unwrap();
+        assert_eq!(remote.info.extension_id("ut_metadata"), Some(2));
+        assert_eq!(remote.info.extensions().metadata_size, Some(31235));
+
+        let payload = b"not bencode".to_vec().into();
+        p.send(Message::Extended { id: 0, payload }).await.unwrap();
+        p.flush().await.unwrap();
+        let res = remote.decode_message().await;
+        assert!(matches!(res, Err(DecodeError::ExtensionHandshake)));
+
+        // pex only goes to peers which enabled it
+        let pex = PexMessage {
+            added: vec![("10.0.0.1:6881".parse().unwrap(), PexFlags::SEED)],
+            dropped: vec![],
+        };
+        assert!(!p.send_pex(&pex).await.unwrap());
+        assert!(remote.send_pex(&pex).await.unwrap());
+        let msg = p.decode_message().await.unwrap();
+        let (id, payload) = (UT_PEX, pex.encode().into());
+        assert_eq!(msg, Message::Extended { id, payload });
+    }
 }
@@ -0,0 +1,65 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// PeerClass groups peers by where they connect from, so local peers can skip the limits meant
+/// for internet traffic. see [crate::config::Config::exempt_lan]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerClass {
+    /// private, loopback and link-local addresses
+    Lan,
+    Wan,
+}
+
+impl PeerClass {
+    pub fn of(ip: IpAddr) -> PeerClass {
+        let lan = match ip {
+            IpAddr::V4(ip) => Self::is_lan_v4(ip),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => Self::is_lan_v4(ip),
+                None => Self::is_lan_v6(ip),
+            },
+        };
+
+        match lan {
+            true => PeerClass::Lan,
+            false => PeerClass::Wan,
+        }
+    }
+
+    fn is_lan_v4(ip: Ipv4Addr) -> bool {
+        ip.is_private() || ip.is_loopback() || ip.is_link_local()
+    }
+
+    fn is_lan_v6(ip: Ipv6Addr) -> bool {
+        let seg = ip.segments()[0];
+
+        // unique local fc00::/7 and link-local fe80::/10
+        ip.is_loopback() || (seg & 0xfe00) == 0xfc00 || (seg & 0xffc0) == 0xfe80
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PeerClass;
+
+    #[test]
+    fn classify() {
+        let cases = [
+            ("10.1.2.3", PeerClass::Lan),
+            ("172.16.0.1", PeerClass::Lan),
+            ("172.32.0.1", PeerClass::Wan),
+            ("192.168.1.20", PeerClass::Lan),
+            ("127.0.0.1", PeerClass::Lan),
+            ("169.254.10.10", PeerClass::Lan),
+            ("8.8.8.8", PeerClass::Wan),
+            ("::1", PeerClass::Lan),
+            ("fd12:3456::1", PeerClass::Lan),
+            ("fe80::1", PeerClass::Lan),
+            ("::ffff:192.168.1.1", PeerClass::Lan),
+            ("2001:db8::1", PeerClass::Wan),
+        ];
+
+        for (ip, class) in cases {
+            assert_eq!(PeerClass::of(ip.parse().unwrap()), class, "{ip}");
+        }
+    }
+}
@@ -0,0 +1,575 @@
+use std::{
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use hyper::body::Bytes;
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+use crate::{
+    connection_limits::ConnectionPermit,
+    error::{self, DecodeError},
+    handle::Reply,
+    peer::{Message, Peer, PeerInfo, PeerState, Verdict},
+    request_queue::{Block, RequestQueue, BLOCK_LEN},
+    send_queue::Backlog,
+    torrent::Announced,
+    upload_queue::UploadQueue,
+};
+
+// piece data queued for a peer before it's sent no more blocks, enough to keep a fast
+// connection busy until more are read from disk
+const MAX_BACKLOG: usize = 1024 * 1024;
+
+/// PeerCommand is sent from a torrent to the task driving one of its peers
+#[derive(Debug)]
+pub(crate) enum PeerCommand {
+    Send(Message),
+    /// cap the piece data sent to the peer at a rate in bytes/s, None lifts the cap
+    UploadLimit(Option<u64>),
+    /// flush anything queued and close the connection
+    Shutdown,
+}
+
+/// PeerEvent is sent to a torrent from the tasks driving its peers, reading blocks for them and
+/// verifying the pieces they send
+#[derive(Debug)]
+pub(crate) enum PeerEvent {
+    /// the peer at addr sent a message
+    Message(SocketAddr, Message),
+    /// a block the peer at addr asked for was read from disk, see [PeerHandle::next_upload]
+    Read(SocketAddr, Block, io::Result<Bytes>),
+    /// the connection to addr closed, with the error which closed it if any. this is the last
+    /// event for addr
+    Closed(SocketAddr, Option<DecodeError>),
+    /// piece index was checked against its hash, and written to disk if it passed. holds whether
+    /// it passed
+    Verified(u32, io::Result<bool>),
+    /// piece index was read back from disk and checked against its hash by a recheck, holds
+    /// whether it passed, see [crate::torrent::Torrent::force_recheck]
+    Checked(u32, bool),
+    /// piece index arrived from the web seed at an index into the torrent's web seeds, or
+    /// couldn't be fetched, see [crate::torrent::Torrent::request_web_seeds]
+    WebSeed(usize, u32, error::Result<Vec<u8>>),
+    /// the peer at addr, which a relay introduced us to, was connected to. see
+    /// [crate::torrent::Torrent::on_holepunch]
+    Holepunched(SocketAddr, Box<Peer>, Option<ConnectionPermit>),
+    /// a dial to the peer at addr finished, holding the peer if it was connected to. see
+    /// [crate::torrent::Torrent::connect_peers]
+    Dialed(SocketAddr, Option<Box<Peer>>, Option<ConnectionPermit>),
+    /// an announce sent in the background finished, along with where to send its outcome if a
+    /// handle asked for it. see [crate::torrent::Torrent::announce_in_background]
+    Announced(Announced, Option<Reply>),
+}
+
+/// PeerHandle is a torrent's end of a connected peer. the connection is owned by a task of its
+/// own so a slow peer never holds up the torrent: messages are queued for the task to send, and
+/// the task hands back what the peer sends as [PeerEvent]s
+#[derive(Debug)]
+pub(crate) struct PeerHandle {
+    addr: SocketAddr,
+    // our view of the peer, updated from the messages it sends, see [PeerHandle::update]
+    info: PeerInfo,
+    // blocks requested from the peer it hasn't sent yet
+    requests: RequestQueue,
+    // blocks the peer requested we haven't sent yet
+    uploads: UploadQueue,
+    commands: UnboundedSender<PeerCommand>,
+    // piece data queued for the peer's task to send
+    backlog: Backlog,
+    // whether each end is choking and interested in the other, and when we last stopped
+    // uploading to the peer
+    state: PeerState,
+    last_unchoked: Option<Instant>,
+    // caps on the piece data sent to the peer in bytes/s: the one set for the peer, its share
+    // of the torrent's limit and the lower of the two which its task enforces
+    peer_limit: Option<u64>,
+    upload_share: Option<u64>,
+    upload_limit: Option<u64>,
+    // the connection's place under the session's connection cap, given back when the handle is
+    // dropped. None for connections exempt from the cap
+    _permit: Option<ConnectionPermit>,
+}
+
+impl PeerHandle {
+    /// spawn a task driving peer's connection, see [Peer::run]. events for the peer are sent to
+    /// events, tagged with addr. permit is held until the handle is dropped
+    pub(crate) fn spawn(
+        peer: Peer,
+        addr: SocketAddr,
+        events: UnboundedSender<PeerEvent>,
+        permit: Option<ConnectionPermit>,
+    ) -> PeerHandle {
+        let (commands, rx) = mpsc::unbounded_channel();
+        let (info, state) = (peer.info().clone(), peer.state());
+        let backlog = Backlog::default();
+
+        let queued = backlog.clone();
+        tokio::spawn(async move {
+            let res = peer.run(addr, rx, events.clone(), queued).await;
+            let _ = events.send(PeerEvent::Closed(addr, res.err()));
+        });
+
+        PeerHandle {
+            addr,
+            info,
+            requests: RequestQueue::new(Instant::now()),
+            uploads: UploadQueue::default(),
+            commands,
+            backlog,
+            state,
+            last_unchoked: None,
+            peer_limit: None,
+            upload_share: None,
+            upload_limit: None,
+            _permit: permit,
+        }
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub(crate) fn info(&self) -> &PeerInfo {
+        &self.info
+    }
+
+    /// blocks requested from the peer it hasn't sent yet
+    pub(crate) fn requests(&self) -> &RequestQueue {
+        &self.requests
+    }
+
+    /// mark the peer as snubbed if it's sent nothing we asked for in timeout, returning whether
+    /// it's snubbed, see [RequestQueue::check_snubbed]
+    pub(crate) fn check_snubbed(&mut self, timeout: Duration) -> bool {
+        self.requests.check_snubbed(Instant::now(), timeout)
+    }
+
+    /// update our view of the peer with a message it sent, returning whether the message is
+    /// allowed and should be handled, see [PeerState::recv] and [PeerInfo::update].
+    ///
+    /// blocks the peer sends are taken off its request queue and ones we didn't ask for are
+    /// dropped. a choke empties the queue since the peer drops our requests, unless it uses the
+    /// fast extension and rejects them instead. requests from the peer are queued for upload
+    /// while it's unchoked, unless they're for more than [BLOCK_LEN] or its queue is full, and
+    /// cancels take them back off
+    pub(crate) fn update(&mut self, msg: &Message) -> Result<bool, DecodeError> {
+        let verdict = self.state.recv(msg);
+        match *msg {
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                let block = Block {
+                    index,
+                    begin,
+                    length,
+                };
+                let valid = (1..=BLOCK_LEN).contains(&length);
+                if !(verdict == Verdict::Accept && valid && self.uploads.push(block)) {
+                    self.reject(block);
+                    return Ok(false);
+                }
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                let block = Block {
+                    index,
+                    begin,
+                    length,
+                };
+                if self.uploads.cancel(block) {
+                    self.reject(block);
+                }
+            }
+            Message::Piece {
+                index,
+                begin,
+                ref block,
+            } => {
+                let length = block.len() as u32;
+                let block = Block {
+                    index,
+                    begin,
+                    length,
+                };
+                if !self.requests.received(block, Instant::now()) {
+                    return Ok(false);
+                }
+            }
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } if verdict == Verdict::Accept => {
+                let block = Block {
+                    index,
+                    begin,
+                    length,
+                };
+                self.requests.remove(block);
+            }
+            Message::Choke if !self.state.fast() => {
+                self.requests.clear();
+            }
+            _ => {}
+        }
+
+        if verdict == Verdict::Ignore {
+            return Ok(false);
+        }
+        self.info.update(msg)?;
+        Ok(true)
+    }
+
+    /// whether both ends support the fast extension, BEP-6
+    pub(crate) fn fast(&self) -> bool {
+        self.state.fast()
+    }
+
+    /// whether the peer is choking us, in which case it won't answer our requests
+    pub(crate) fn is_choking_us(&self) -> bool {
+        self.state.peer_choking()
+    }
+
+    /// tell the peer whether we'd like to download from it if that's a change, returning false
+    /// if the connection has closed
+    pub(crate) fn set_interested(&mut self, interested: bool) -> bool {
+        if interested == self.state.am_interested() {
+            return !self.is_closed();
+        }
+
+        let msg = match interested {
+            true => Message::Interested,
+            false => Message::NotInterested,
+        };
+        self.state.send(&msg);
+        self.send(msg)
+    }
+
+    /// whether we're choking the peer
+    pub(crate) fn is_choked(&self) -> bool {
+        self.state.am_choking()
+    }
+
+    /// when the peer last had an upload slot, now if it has one. None if it never has
+    pub(crate) fn last_unchoked(&self, now: Instant) -> Option<Instant> {
+        match self.is_choked() {
+            true => self.last_unchoked,
+            false => Some(now),
+        }
+    }
+
+    /// choke or unchoke the peer, telling it if that's a change. choking the peer drops its
+    /// requests, rejecting them if it uses the fast extension. returns false if the connection
+    /// has closed
+    pub(crate) fn set_choked(&mut self, choked: bool) -> bool {
+        if choked == self.is_choked() {
+            return !self.is_closed();
+        }
+
+        let msg = match choked {
+            true => Message::Choke,
+            false => Message::Unchoke,
+        };
+        self.state.send(&msg);
+        if !self.send(msg) {
+            return false;
+        }
+
+        if choked {
+            self.last_unchoked = Some(Instant::now());
+            for block in self.uploads.clear() {
+                self.reject(block);
+            }
+        }
+        true
+    }
+
+    // tell a peer using the fast extension we won't send block, other peers expect requests we
+    // don't serve to be dropped
+    fn reject(&self, block: Block) {
+        if self.state.fast() {
+            self.send(Message::RejectRequest {
+                index: block.index,
+                begin: block.begin,
+                length: block.length,
+            });
+        }
+    }
+
+    /// the cap on piece data sent to the peer in bytes/s, None if there's none
+    pub(crate) fn upload_limit(&self) -> Option<u64> {
+        self.upload_limit
+    }
+
+    /// cap the piece data sent to the peer at limit bytes/s, None lifts the cap. the peer is
+    /// still held to its share of the torrent's limit if that's lower
+    pub(crate) fn set_upload_limit(&mut self, limit: Option<u64>) {
+        self.peer_limit = limit;
+        self.apply_upload_limit();
+    }
+
+    /// hold the peer to share bytes/s of the torrent's upload limit, see
+    /// [crate::choker::Choker::upload_share]
+    pub(crate) fn set_upload_share(&mut self, share: Option<u64>) {
+        self.upload_share = share;
+        self.apply_upload_limit();
+    }
+
+    // tell the peer's task about a change to the lower of its caps
+    fn apply_upload_limit(&mut self) {
+        let limit = match (self.peer_limit, self.upload_share) {
+            (Some(limit), Some(share)) => Some(limit.min(share)),
+            (limit, share) => limit.or(share),
+        };
+        if limit != self.upload_limit {
+            self.upload_limit = limit;
+            let _ = self.commands.send(PeerCommand::UploadLimit(limit));
+        }
+    }
+
+    /// request block from the peer if its request queue has room, returning whether it was
+    /// requested. see [RequestQueue] for how much is queued
+    pub(crate) fn request(&mut self, block: Block) -> bool {
+        let msg = Message::Request {
+            index: block.index,
+            begin: block.begin,
+            length: block.length,
+        };
+        if self.requests.room() == 0 || !self.send(msg) {
+            return false;
+        }
+
+        self.requests.push(block, Instant::now());
+        true
+    }
+
+    /// cancel a request for block if it's still outstanding, eg. once another peer has sent it
+    pub(crate) fn cancel(&mut self, block: Block) {
+        if self.requests.remove(block) {
+            self.send_cancel(block);
+        }
+    }
+
+    /// cancel every outstanding request, eg. when the torrent stops
+    pub(crate) fn cancel_all(&mut self) {
+        for block in self.requests.clear() {
+            self.send_cancel(block);
+        }
+    }
+
+    /// cancel a request for block the peer took too long to send, counting it against the peer,
+    /// see [RequestQueue::timed_out]
+    pub(crate) fn timed_out(&mut self, block: Block) {
+        if self.requests.timed_out(block) {
+            self.send_cancel(block);
+        }
+    }
+
+    fn send_cancel(&self, block: Block) {
+        self.send(Message::Cancel {
+            index: block.index,
+            begin: block.begin,
+            length: block.length,
+        });
+    }
+
+    /// queue msg to be sent, returning false if the connection has closed. control messages
+    /// are sent ahead of any piece data already queued
+    pub(crate) fn send(&self, msg: Message) -> bool {
+        self.backlog.add(&msg);
+        self.commands.send(PeerCommand::Send(msg)).is_ok()
+    }
+
+    /// bytes of piece data queued for the peer which haven't been sent yet
+    pub(crate) fn backlog(&self) -> usize {
+        self.backlog.get()
+    }
+
+    /// whether the peer can take more blocks, ie. it isn't still sending [MAX_BACKLOG] worth.
+    /// uploads to peers which can't should wait rather than queue more
+    pub(crate) fn can_upload(&self) -> bool {
+        self.backlog() < MAX_BACKLOG
+    }
+
+    /// blocks the peer requested we haven't sent yet
+    pub(crate) fn uploads(&self) -> &UploadQueue {
+        &self.uploads
+    }
+
+    /// the next block the peer asked for which should be read from disk, None if there's none
+    /// or [MAX_BACKLOG] is already queued or being read for it. once read, the block should be
+    /// handed to [PeerHandle::finish_upload]
+    pub(crate) fn next_upload(&mut self) -> Option<Block> {
+        if self.backlog() + self.uploads.reading() >= MAX_BACKLOG {
+            return None;
+        }
+        self.uploads.next()
+    }
+
+    /// queue a block read for the peer if it still wants it, returning whether it was queued.
+    /// blocks which couldn't be read, eg. past the end of their piece, are refused
+    pub(crate) fn finish_upload(&mut self, block: Block, data: io::Result<Bytes>) -> bool {
+        let wanted = self.uploads.finish(block);
+        match data {
+            Ok(data) if wanted => self.send_block(block.index, block.begin, data[..].into()),
+            Err(_) if wanted => {
+                self.reject(block);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// take a block the peer asked for which we won't send off its queue, eg. of a piece we
+    /// don't have, rejecting it if the peer uses the fast extension
+    pub(crate) fn refuse_upload(&mut self, block: Block) {
+        if self.uploads.cancel(block) {
+            self.reject(block);
+        }
+    }
+
+    /// queue a block of piece index for the peer, counting it towards its stats. returns false
+    /// if the connection has closed
+    pub(crate) fn send_block(&mut self, index: u32, begin: u32, block: Box<[u8]>) -> bool {
+        self.info.record_upload(block.len());
+        self.send(Message::Piece {
+            index,
+            begin,
+            block,
+        })
+    }
+
+    /// queue an extended message for extension name if the peer supports it, returning whether
+    /// it was queued
+    pub(crate) fn send_extended(&self, name: &str, payload: &[u8]) -> bool {
+        let Some(id) = self.info.extension_id(name) else {
+            return false;
+        };
+
+        let payload = payload.into();
+        self.send(Message::Extended { id, payload })
+    }
+
+    /// whether the connection has closed
+    pub(crate) fn is_closed(&self) -> bool {
+        self.commands.is_closed()
+    }
+
+    /// close the connection once everything queued before this has been sent
+    pub(crate) fn shutdown(&self) {
+        let _ = self.commands.send(PeerCommand::Shutdown);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::{PeerEvent, PeerHandle};
+    use crate::{
+        extension::ExtensionHandshake,
+        peer::{Message, Peer, Timeouts},
+        proxy::Dialer,
+        request_queue::Block,
+    };
+
+    #[tokio::test]
+    async fn spawn() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let info_hash = [1; 20];
+
+        let (dialer, timeouts) = (Dialer::default(), Timeouts::default());
+        let connect = Peer::connect(addr, &info_hash, &[2; 20], 8, &dialer, timeouts);
+        let accept = async {
+            let (mut remote, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 68];
+            remote.read_exact(&mut buf).await.unwrap();
+            // without the fast extension
+            buf[27] = 0;
+            buf[48..].copy_from_slice(&[3; 20]);
+            remote.write_all(&buf).await.unwrap();
+            remote
+        };
+        let (peer, mut remote) = futures::join!(connect, accept);
+
+        let (tx, mut events) = mpsc::unbounded_channel();
+        let mut handle = PeerHandle::spawn(peer.unwrap(), addr, tx, None);
+        assert_eq!(handle.info().peer_id(), &[3; 20]);
+
+        // the peer's messages come back as events
+        let payload = ExtensionHandshake::ours(None, false).encode();
+        let mut buf = vec![0, 0, 0, 0, 20, 0];
+        buf[3] = (payload.len() + 2) as u8;
+        buf.extend_from_slice(&payload);
+        remote.write_all(&buf).await.unwrap();
+
+        let Some(PeerEvent::Message(from, msg)) = events.recv().await else {
+            panic!("expected a message");
+        };
+        assert_eq!(from, addr);
+        assert_eq!(handle.info().extension_id("ut_pex"), None);
+        handle.update(&msg).unwrap();
+        assert!(handle.info().extension_id("ut_pex").is_some());
+
+        // requests are limited by the peer's queue depth
+        let block = |index| Block {
+            index,
+            begin: 0,
+            length: 1,
+        };
+        assert!(handle.request(block(0)));
+        assert!(handle.request(block(1)));
+        assert!(!handle.request(block(2)));
+        let piece = Message::Piece {
+            index: 0,
+            begin: 0,
+            block: Box::new([0]),
+        };
+        assert!(handle.update(&piece).unwrap());
+        assert_eq!(handle.requests().len(), 1);
+        assert_eq!(handle.info().stats().downloaded, 1);
+
+        // blocks we didn't ask for, and requests while the peer is choked, are dropped
+        assert!(!handle.update(&piece).unwrap());
+        assert_eq!(handle.info().stats().downloaded, 1);
+        let request = Message::Request {
+            index: 0,
+            begin: 0,
+            length: 1,
+        };
+        assert!(!handle.update(&request).unwrap());
+        assert_eq!(handle.uploads().len(), 0);
+        assert!(handle.update(&Message::Choke).unwrap());
+        assert!(handle.requests().is_empty());
+        let mut buf = [0; 34];
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf[..5], [0, 0, 0, 13, 6]);
+
+        // queued messages are sent in order, then the connection is closed
+        assert!(handle.send(Message::Interested));
+        assert!(handle.send_extended("ut_pex", b"de"));
+        assert!(!handle.send_extended("ut_metadata", &[]));
+        handle.shutdown();
+
+        let mut buf = vec![];
+        remote.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, [0, 0, 0, 1, 2, 0, 0, 0, 4, 20, 1, b'd', b'e']);
+
+        drop(remote);
+        assert!(matches!(events.recv().await, Some(PeerEvent::Closed(a, None)) if a == addr));
+        assert!(handle.is_closed());
+        assert!(!handle.send(Message::Interested));
+    }
+}
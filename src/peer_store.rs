@@ -0,0 +1,207 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+};
+
+use bitflags::bitflags;
+
+use crate::peer_handle::PeerHandle;
+
+bitflags! {
+    /// PeerSources are where a peer was learned from. a peer can be heard of from several
+    pub struct PeerSources: u8 {
+        const TRACKER = 1 << 0;
+        const DHT = 1 << 1;
+        const PEX = 1 << 2;
+        const LSD = 1 << 3;
+        /// saved in resume data, see [crate::resume::ResumeData::peers]
+        const RESUME = 1 << 4;
+        /// the peer connected to us
+        const INCOMING = 1 << 5;
+    }
+}
+
+/// PeerStore is every peer a torrent knows of, keyed by address so peers heard of repeatedly
+/// (from several trackers, or trackers and PEX) are only kept once. our own and banned addresses
+/// are never stored
+#[derive(Debug, Default)]
+pub(crate) struct PeerStore {
+    peers: HashMap<SocketAddr, KnownPeer>,
+    // addresses we can be reached at, which trackers may hand back to us
+    ours: HashSet<SocketAddr>,
+    banned: HashSet<IpAddr>,
+}
+
+#[derive(Debug)]
+struct KnownPeer {
+    conn: Option<PeerHandle>,
+    sources: PeerSources,
+}
+
+impl PeerStore {
+    /// add a peer learned from source, returning whether it's new. ours and banned addresses
+    /// are ignored
+    pub(crate) fn add(&mut self, addr: SocketAddr, source: PeerSources) -> bool {
+        if self.ours.contains(&addr) || self.banned.contains(&addr.ip()) {
+            return false;
+        }
+
+        match self.peers.get_mut(&addr) {
+            Some(known) => {
+                known.sources |= source;
+                false
+            }
+            None => {
+                let known = KnownPeer {
+                    conn: None,
+                    sources: source,
+                };
+                self.peers.insert(addr, known);
+                true
+            }
+        }
+    }
+
+    /// record an open connection to addr, which must already be known. returns false, dropping
+    /// peer, if addr isn't known
+    pub(crate) fn connected(&mut self, addr: SocketAddr, peer: PeerHandle) -> bool {
+        match self.peers.get_mut(&addr) {
+            Some(known) => {
+                known.conn = Some(peer);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// mark addr as one of our own, forgetting it if it was stored
+    pub(crate) fn add_ours(&mut self, addr: SocketAddr) {
+        if self.ours.insert(addr) {
+            self.peers.remove(&addr);
+        }
+    }
+
+    /// forget every peer at ip and refuse to store it again. connections to it are returned so
+    /// they can be closed
+    pub(crate) fn ban(&mut self, ip: IpAddr) -> Vec<PeerHandle> {
+        self.banned.insert(ip);
+
+        let addrs: Vec<_> = self
+            .peers
+            .keys()
+            .filter(|a| a.ip() == ip)
+            .copied()
+            .collect();
+        let removed = addrs.iter().filter_map(|addr| self.peers.remove(addr));
+        removed.filter_map(|known| known.conn).collect()
+    }
+
+    pub(crate) fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.contains(&ip)
+    }
+
+    pub(crate) fn sources(&self, addr: SocketAddr) -> Option<PeerSources> {
+        self.peers.get(&addr).map(|known| known.sources)
+    }
+
+    /// peers we aren't connected to
+    pub(crate) fn pending(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        let pending = self.peers.iter().filter(|(_, known)| known.conn.is_none());
+        pending.map(|(&addr, _)| addr)
+    }
+
+    /// addresses of the peers we're connected to
+    pub(crate) fn connections(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        let connected = self.peers.iter().filter(|(_, known)| known.conn.is_some());
+        connected.map(|(&addr, _)| addr)
+    }
+
+    /// the connection to addr, if we're connected to it
+    pub(crate) fn connection(&self, addr: SocketAddr) -> Option<&PeerHandle> {
+        self.peers.get(&addr)?.conn.as_ref()
+    }
+
+    pub(crate) fn connection_mut(&mut self, addr: SocketAddr) -> Option<&mut PeerHandle> {
+        self.peers.get_mut(&addr)?.conn.as_mut()
+    }
+
+    /// the peers we're connected to
+    pub(crate) fn handles(&self) -> impl Iterator<Item = (SocketAddr, &PeerHandle)> + '_ {
+        let connected = self.peers.iter();
+        connected.filter_map(|(&addr, known)| Some((addr, known.conn.as_ref()?)))
+    }
+
+    pub(crate) fn handles_mut(&mut self) -> impl Iterator<Item = (SocketAddr, &mut PeerHandle)> {
+        let connected = self.peers.iter_mut();
+        connected.filter_map(|(&addr, known)| Some((addr, known.conn.as_mut()?)))
+    }
+
+    /// forget the connection to addr if it's closed, keeping its address, and return it. a newer
+    /// connection to the same address is kept
+    pub(crate) fn disconnected(&mut self, addr: SocketAddr) -> Option<PeerHandle> {
+        let known = self.peers.get_mut(&addr)?;
+        match known.conn.as_ref().is_some_and(PeerHandle::is_closed) {
+            true => known.conn.take(),
+            false => None,
+        }
+    }
+
+    /// take every connection so they can be closed, keeping their addresses
+    pub(crate) fn take_connections(&mut self) -> impl Iterator<Item = PeerHandle> + '_ {
+        self.peers
+            .values_mut()
+            .filter_map(|known| known.conn.take())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{PeerSources, PeerStore};
+
+    #[test]
+    fn dedupe() {
+        let mut store = PeerStore::default();
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+
+        assert!(store.add(addr, PeerSources::TRACKER));
+        assert!(!store.add(addr, PeerSources::TRACKER));
+        assert!(!store.add(addr, PeerSources::PEX));
+        assert_eq!(store.len(), 1);
+        assert_eq!(
+            store.sources(addr),
+            Some(PeerSources::TRACKER | PeerSources::PEX)
+        );
+
+        // the same ip on another port is another peer
+        assert!(store.add("10.0.0.1:6882".parse().unwrap(), PeerSources::DHT));
+        assert_eq!(store.pending().count(), 2);
+    }
+
+    #[test]
+    fn filtered() {
+        let mut store = PeerStore::default();
+        let ours: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        let banned: SocketAddr = "5.6.7.8:6881".parse().unwrap();
+
+        assert!(store.add(ours, PeerSources::TRACKER));
+        store.add_ours(ours);
+        assert!(store.is_empty());
+        assert!(!store.add(ours, PeerSources::TRACKER));
+
+        assert!(store.add(banned, PeerSources::TRACKER));
+        assert!(store.ban(banned.ip()).is_empty());
+        assert!(store.is_banned(banned.ip()));
+        assert!(!store.add("5.6.7.8:51413".parse().unwrap(), PeerSources::PEX));
+        assert!(store.is_empty());
+    }
+}
@@ -0,0 +1,315 @@
+use std::{cmp::Reverse, fmt::Debug, sync::Arc};
+
+use bitvec::prelude::{bitbox, bitvec, BitBox, BitSlice, Msb0};
+use rand::Rng;
+
+/// PickContext is everything a [PieceStrategy] can base its decision on
+#[derive(Debug, Clone, Copy)]
+pub struct PickContext<'a> {
+    /// pieces the peer we're about to request from has, of those we want most
+    pub peer_has: &'a BitSlice,
+    /// pieces we either have or are already downloading
+    pub ours: &'a BitSlice,
+    /// number of connected peers that have each piece
+    pub availability: &'a [u16],
+}
+
+impl<'a> PickContext<'a> {
+    /// pieces the peer has that we still need
+    pub fn candidates(&self) -> impl Iterator<Item = u32> + 'a {
+        let ours = self.ours;
+
+        self.peer_has
+            .iter_ones()
+            .filter(move |&i| !ours[i])
+            .map(|i| i as u32)
+    }
+}
+
+/// PieceStrategy chooses which piece to download next from a peer, in place of the
+/// [PiecePicker]'s rarest first, see [crate::config::AddTorrentOptions::piece_strategy]. it only
+/// chooses among the pieces of the highest [FilePriority] the peer has, and pieces with a
+/// deadline are requested before any it picks, see
+/// [crate::torrent::Torrent::set_piece_deadline]. tsunami provides [RarestFirst] and
+/// [Sequential], but any type implementing this trait can be used, eg. to prefer pieces from
+/// peers on the local network
+pub trait PieceStrategy: Debug + Send + Sync {
+    /// pick the next piece to download, or None if the peer has nothing we need
+    fn pick(&self, ctx: &PickContext) -> Option<u32>;
+}
+
+/// download the pieces fewest peers have first, keeping rare pieces alive in the swarm
+#[derive(Debug, Default)]
+pub struct RarestFirst;
+
+impl PieceStrategy for RarestFirst {
+    fn pick(&self, ctx: &PickContext) -> Option<u32> {
+        ctx.candidates()
+            .min_by_key(|&i| ctx.availability.get(i as usize).copied().unwrap_or(0))
+    }
+}
+
+/// download pieces in order, useful for previewing media files
+#[derive(Debug, Default)]
+pub struct Sequential;
+
+impl PieceStrategy for Sequential {
+    fn pick(&self, ctx: &PickContext) -> Option<u32> {
+        ctx.candidates().next()
+    }
+}
+
+/// FilePriority is how much a file or piece is wanted, see
+/// [crate::handle::TorrentHandle::set_file_priority]. a piece is as wanted as the most wanted
+/// file it holds part of unless it's given a priority of its own, and higher priority pieces are
+/// downloaded first. skipped files aren't downloaded, other than the parts sharing a piece with
+/// a file which is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FilePriority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// PiecePicker counts how many of our peers have each piece, from their bitfields and have
+/// messages, and remembers which pieces we have or are downloading, to choose the rarest piece
+/// we still need from a peer. pieces of higher priority come before rarer ones
+#[derive(Debug, Clone)]
+pub struct PiecePicker {
+    availability: Vec<u16>,
+    ours: BitBox,
+    priorities: Vec<FilePriority>,
+    strategy: Option<Arc<dyn PieceStrategy>>,
+}
+
+impl PiecePicker {
+    pub fn new(pieces: usize) -> PiecePicker {
+        PiecePicker {
+            availability: vec![0; pieces],
+            ours: bitbox![0; pieces],
+            priorities: vec![FilePriority::Normal; pieces],
+            strategy: None,
+        }
+    }
+
+    /// choose among the most wanted pieces with strategy rather than rarest first
+    pub fn with_strategy(self, strategy: Option<Arc<dyn PieceStrategy>>) -> PiecePicker {
+        PiecePicker { strategy, ..self }
+    }
+
+    /// count the pieces of a peer's bitfield, eg. when it sends one. a peer's old bitfield should
+    /// be removed before its new one is added
+    pub fn add_peer(&mut self, has: &BitSlice<u8, Msb0>) {
+        for (count, _) in self.availability.iter_mut().zip(has).filter(|(_, b)| **b) {
+            *count = count.saturating_add(1);
+        }
+    }
+
+    /// stop counting the pieces of a peer's bitfield, eg. once it disconnects
+    pub fn remove_peer(&mut self, has: &BitSlice<u8, Msb0>) {
+        for (count, _) in self.availability.iter_mut().zip(has).filter(|(_, b)| **b) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    /// a peer told us it has piece index. pieces past the last one are ignored
+    pub fn have(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count = count.saturating_add(1);
+        }
+    }
+
+    /// a peer which had piece index no longer does, eg. after an lt_donthave
+    pub fn lost(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    /// mark piece index as one we have or are downloading so it isn't picked, or not if it
+    /// failed its hash check or its download was abandoned
+    pub fn set_ours(&mut self, index: u32, ours: bool) {
+        if let Some(mut bit) = self.ours.get_mut(index as usize) {
+            *bit = ours;
+        }
+    }
+
+    /// set how much piece index is wanted, skipped pieces are never picked
+    pub fn set_priority(&mut self, index: u32, priority: FilePriority) {
+        if let Some(p) = self.priorities.get_mut(index as usize) {
+            *p = priority;
+        }
+    }
+
+    pub fn priority(&self, index: u32) -> Option<FilePriority> {
+        self.priorities.get(index as usize).copied()
+    }
+
+    /// number of peers that have each piece
+    pub fn availability(&self) -> &[u16] {
+        &self.availability
+    }
+
+    /// how many complete copies of the torrent our peers have between them: the availability of
+    /// the rarest piece, plus the fraction of pieces which are more common than it. 1.5 means
+    /// every piece is available from at least one peer and half of them from two or more
+    pub fn distributed_copies(&self) -> f64 {
+        let Some(&rarest) = self.availability.iter().min() else {
+            return 0.0;
+        };
+        let common = self.availability.iter().filter(|&&n| n > rarest).count();
+        rarest as f64 + common as f64 / self.availability.len() as f64
+    }
+
+    /// pieces we have or are downloading
+    pub fn ours(&self) -> &BitSlice {
+        &self.ours
+    }
+
+    /// the highest priority, rarest piece the peer has that we still need, picked at random from
+    /// the equally good ones so peers don't all download the same piece. None if the peer has
+    /// nothing we need
+    pub fn pick(&self, peer_has: &BitSlice<u8, Msb0>, rng: &mut impl Rng) -> Option<u32> {
+        let needed = |&i: &usize| self.ours.get(i).is_some_and(|b| !*b);
+        let wanted = |&i: &usize| self.priorities[i] != FilePriority::Skip;
+        let candidates = peer_has.iter_ones().filter(needed).filter(wanted);
+        if let Some(strategy) = &self.strategy {
+            return self.pick_with(&**strategy, candidates);
+        }
+
+        // reservoir sampling, each of the n best pieces seen so far replaces the pick with
+        // probability 1/n
+        let mut pick = None;
+        let (mut best, mut n) = ((Reverse(FilePriority::Skip), u16::MAX), 0);
+        for i in candidates {
+            let key = (Reverse(self.priorities[i]), self.availability[i]);
+            if key < best {
+                (best, n) = (key, 0);
+            } else if key > best {
+                continue;
+            }
+
+            n += 1;
+            if rng.gen_range(0..n) == 0 {
+                pick = Some(i as u32);
+            }
+        }
+        pick
+    }
+
+    // let strategy pick one of candidates of the highest priority among them
+    fn pick_with(
+        &self,
+        strategy: &dyn PieceStrategy,
+        candidates: impl Iterator<Item = usize>,
+    ) -> Option<u32> {
+        let candidates: Vec<_> = candidates.collect();
+        let best = candidates.iter().map(|&i| self.priorities[i]).max()?;
+        let mut peer_has = bitvec![0; self.ours.len()];
+        for &i in candidates.iter().filter(|&&i| self.priorities[i] == best) {
+            peer_has.set(i, true);
+        }
+
+        let ctx = PickContext {
+            peer_has: &peer_has,
+            ours: &self.ours,
+            availability: &self.availability,
+        };
+        let pick = strategy.pick(&ctx)?;
+        let valid = peer_has.get(pick as usize).is_some_and(|b| *b);
+        valid.then_some(pick)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bitvec::prelude::{bitbox, bits, Lsb0, Msb0};
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    use crate::picker::{
+        FilePriority, PickContext, PiecePicker, PieceStrategy, RarestFirst, Sequential,
+    };
+
+    #[test]
+    fn strategies() {
+        let ctx = PickContext {
+            peer_has: bits![usize, Lsb0; 1, 1, 1, 1, 0],
+            ours: bits![usize, Lsb0; 1, 0, 0, 0, 0],
+            availability: &[1, 4, 2, 3, 1],
+        };
+
+        assert_eq!(Sequential.pick(&ctx), Some(1));
+        assert_eq!(RarestFirst.pick(&ctx), Some(2));
+
+        let nothing = PickContext {
+            peer_has: bits![usize, Lsb0; 1, 0, 0, 0, 0],
+            ..ctx
+        };
+        assert_eq!(RarestFirst.pick(&nothing), None);
+
+        // a picker's strategy only chooses among the most wanted pieces
+        let mut rng = SmallRng::seed_from_u64(0);
+        let has = bitbox![u8, Msb0; 1, 1, 1, 1, 0];
+        let mut picker = PiecePicker::new(5).with_strategy(Some(Arc::new(Sequential)));
+        picker.add_peer(&has);
+        picker.set_ours(0, true);
+        assert_eq!(picker.pick(&has, &mut rng), Some(1));
+        picker.set_priority(3, FilePriority::High);
+        assert_eq!(picker.pick(&has, &mut rng), Some(3));
+        picker.set_ours(3, true);
+        assert_eq!(picker.pick(&has, &mut rng), Some(1));
+    }
+    #[test]
+    fn piece_picker() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut picker = PiecePicker::new(5);
+        let a = bitbox![u8, Msb0; 1, 1, 1, 0, 0];
+        let b = bitbox![u8, Msb0; 0, 1, 1, 1, 0];
+        picker.add_peer(&a);
+        picker.add_peer(&b);
+        picker.have(4);
+        picker.have(9);
+        assert_eq!(picker.availability(), &[1, 2, 2, 1, 1]);
+        assert_eq!(picker.distributed_copies(), 1.4);
+
+        // a's rarest piece is the only one no one else has
+        assert_eq!(picker.pick(&a, &mut rng), Some(0));
+        picker.set_ours(0, true);
+        assert_eq!(picker.ours().count_ones(), 1);
+
+        // equally rare pieces are picked at random
+        let picks: Vec<_> = (0..20).map(|_| picker.pick(&a, &mut rng)).collect();
+        assert!(picks.iter().all(|&p| p == Some(1) || p == Some(2)));
+        assert!(picks.contains(&Some(1)) && picks.contains(&Some(2)));
+
+        // once b is gone, its pieces are rarer
+        picker.remove_peer(&b);
+        picker.lost(4);
+        assert_eq!(picker.availability(), &[1, 1, 1, 0, 0]);
+        assert_eq!(picker.distributed_copies(), 0.6);
+        assert_eq!(PiecePicker::new(0).distributed_copies(), 0.0);
+        picker.set_ours(1, true);
+        assert_eq!(picker.pick(&a, &mut rng), Some(2));
+        picker.set_ours(2, true);
+        assert_eq!(picker.pick(&a, &mut rng), None);
+        picker.set_ours(2, false);
+        assert_eq!(picker.pick(&a, &mut rng), Some(2));
+
+        // higher priority pieces come first however common they are, skipped ones never do
+        picker.add_peer(&a);
+        picker.set_ours(1, false);
+        picker.set_priority(1, FilePriority::High);
+        picker.set_priority(9, FilePriority::High);
+        assert_eq!(picker.priority(1), Some(FilePriority::High));
+        assert_eq!(picker.pick(&a, &mut rng), Some(1));
+        picker.set_priority(1, FilePriority::Skip);
+        picker.set_priority(2, FilePriority::Low);
+        assert_eq!(picker.pick(&a, &mut rng), Some(2));
+        picker.set_priority(2, FilePriority::Skip);
+        assert_eq!(picker.pick(&a, &mut rng), None);
+    }
+}
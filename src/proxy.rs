@@ -0,0 +1,423 @@
+use std::{
+    error::Error as StdError,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use hyper::{client::HttpConnector, service::Service, Uri};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{self, TcpSocket, TcpStream},
+};
+
+use crate::utils::{invalid, refused};
+
+// longest CONNECT response (status line and headers) we accept from a proxy
+const MAX_CONNECT_RESP: usize = 8 * 1024;
+
+/// Proxy is a proxy server tracker requests or peer connections are sent through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    /// `host:port` of the proxy server
+    pub addr: String,
+    /// username and password, if the proxy requires them
+    pub auth: Option<(String, String)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    /// tunnel connections with an HTTP `CONNECT` request
+    Http,
+    /// SOCKS5 (RFC 1928). host names are resolved by the proxy, so trackers aren't looked up
+    /// locally
+    Socks5,
+}
+
+/// Dialer opens outgoing peer connections, either directly or through a [Proxy]. connections
+/// are bound to local_addr if one is given, when going through a proxy that's the connection
+/// to the proxy
+#[derive(Debug, Clone, Default)]
+pub struct Dialer {
+    proxy: Option<Arc<Proxy>>,
+    local_addr: Option<IpAddr>,
+}
+
+/// Connector opens connections for an http client, either directly or through a [Proxy]
+#[derive(Debug, Clone)]
+pub struct Connector {
+    http: HttpConnector,
+    proxy: Option<Arc<Proxy>>,
+    local_addr: Option<IpAddr>,
+}
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+type Connecting = Pin<Box<dyn Future<Output = Result<TcpStream, BoxError>> + Send>>;
+
+impl Connector {
+    pub fn new(http: HttpConnector, proxy: Option<Proxy>, local_addr: Option<IpAddr>) -> Self {
+        Connector {
+            http,
+            proxy: proxy.map(Arc::new),
+            local_addr,
+        }
+    }
+}
+
+impl Dialer {
+    pub fn new(proxy: Option<Proxy>, local_addr: Option<IpAddr>) -> Dialer {
+        Dialer {
+            proxy: proxy.map(Arc::new),
+            local_addr,
+        }
+    }
+
+    /// open a tcp connection to addr
+    pub async fn dial(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        if let Some(proxy) = &self.proxy {
+            // ipv6 hosts are bracketed, as they would be in a uri
+            let host = match addr.ip() {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => format!("[{ip}]"),
+            };
+            return proxy.tunnel(&host, addr.port(), self.local_addr).await;
+        }
+
+        let Some(local_addr) = self.local_addr else {
+            return TcpStream::connect(addr).await;
+        };
+
+        // only remote addresses in the same family as local_addr are reachable from it
+        if addr.is_ipv4() != local_addr.is_ipv4() {
+            return Err(io::ErrorKind::AddrNotAvailable.into());
+        }
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        socket.bind(SocketAddr::new(local_addr, 0))?;
+        socket.connect(addr).await
+    }
+}
+
+impl Service<Uri> for Connector {
+    type Response = TcpStream;
+    type Error = BoxError;
+    type Future = Connecting;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Connecting {
+        let Some(proxy) = self.proxy.clone() else {
+            let connect = self.http.call(dst);
+            return Box::pin(async move { Ok(connect.await?) });
+        };
+
+        let local_addr = self.local_addr;
+        Box::pin(async move { Ok(proxy.connect(&dst, local_addr).await?) })
+    }
+}
+
+impl Proxy {
+    /// open a connection to dst through this proxy. the returned stream is connected to dst as
+    /// if there were no proxy in between
+    pub async fn connect(&self, dst: &Uri, local_addr: Option<IpAddr>) -> io::Result<TcpStream> {
+        let host = dst.host().ok_or_else(|| invalid("uri has no host"))?;
+        let port = dst.port_u16().unwrap_or(match dst.scheme_str() {
+            Some("https") => 443,
+            _ => 80,
+        });
+
+        self.tunnel(host, port, local_addr).await
+    }
+
+    /// open a connection to port on host through this proxy. ipv6 hosts must be bracketed
+    async fn tunnel(
+        &self,
+        host: &str,
+        port: u16,
+        local_addr: Option<IpAddr>,
+    ) -> io::Result<TcpStream> {
+        let mut stream = self.connect_proxy(local_addr).await?;
+        match self.kind {
+            ProxyKind::Http => self.http_connect(&mut stream, host, port).await?,
+            ProxyKind::Socks5 => self.socks5_connect(&mut stream, host, port).await?,
+        }
+
+        Ok(stream)
+    }
+
+    async fn connect_proxy(&self, local_addr: Option<IpAddr>) -> io::Result<TcpStream> {
+        let mut last_err = None;
+
+        for addr in net::lookup_host(&self.addr).await? {
+            let res: io::Result<_> = try {
+                let socket = match addr {
+                    SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                    SocketAddr::V6(_) => TcpSocket::new_v6()?,
+                };
+                if let Some(ip) = local_addr {
+                    socket.bind(SocketAddr::new(ip, 0))?;
+                }
+                socket.connect(addr).await?
+            };
+
+            match res {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = Some(e),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| invalid("proxy address did not resolve")))
+    }
+
+    async fn http_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+        let mut req = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+        if let Some((user, pass)) = &self.auth {
+            let creds = base64::encode(format!("{user}:{pass}"));
+            req.push_str(&format!("Proxy-Authorization: Basic {creds}\r\n"));
+        }
+        req.push_str("\r\n");
+        stream.write_all(req.as_bytes()).await?;
+
+        // read one byte at a time so nothing sent through the tunnel is consumed
+        let mut resp = vec![];
+        while !resp.ends_with(b"\r\n\r\n") {
+            if resp.len() == MAX_CONNECT_RESP {
+                return Err(invalid("proxy response too long"));
+            }
+            resp.push(stream.read_u8().await?);
+        }
+
+        let status = resp.split(|&b| b == b' ').nth(1);
+        match status {
+            Some(b"200") => Ok(()),
+            _ => Err(refused("proxy refused CONNECT request")),
+        }
+    }
+
+    async fn socks5_connect(
+        &self,
+        stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+    ) -> io::Result<()> {
+        // offer username/password auth only if we have credentials
+        let methods: &[u8] = match self.auth {
+            Some(_) => &[5, 2, 0, 2],
+            None => &[5, 1, 0],
+        };
+        stream.write_all(methods).await?;
+
+        let mut choice = [0; 2];
+        stream.read_exact(&mut choice).await?;
+        match (choice, &self.auth) {
+            ([5, 0], _) => {}
+            ([5, 2], Some((user, pass))) => {
+                let (user, pass) = (user.as_bytes(), pass.as_bytes());
+                if user.len() > 255 || pass.len() > 255 {
+                    return Err(invalid("proxy credentials too long"));
+                }
+
+                let mut auth = vec![1, user.len() as u8];
+                auth.extend_from_slice(user);
+                auth.push(pass.len() as u8);
+                auth.extend_from_slice(pass);
+                stream.write_all(&auth).await?;
+
+                let mut status = [0; 2];
+                stream.read_exact(&mut status).await?;
+                if status[1] != 0 {
+                    return Err(refused("proxy rejected credentials"));
+                }
+            }
+            _ => return Err(refused("proxy requires an unsupported auth method")),
+        }
+
+        // ipv6 hosts come bracketed from the uri
+        let mut req = vec![5, 1, 0];
+        match host.trim_start_matches('[').trim_end_matches(']').parse() {
+            Ok(IpAddr::V4(ip)) => {
+                req.push(1);
+                req.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                req.push(4);
+                req.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                let name = host.as_bytes();
+                if name.len() > 255 {
+                    return Err(invalid("host name too long"));
+                }
+                req.extend_from_slice(&[3, name.len() as u8]);
+                req.extend_from_slice(name);
+            }
+        }
+        req.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&req).await?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[..2] != [5, 0] {
+            return Err(refused("proxy refused connection"));
+        }
+
+        // skip the address the proxy bound to
+        let len = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => stream.read_u8().await? as usize,
+            _ => return Err(invalid("proxy sent an invalid reply")),
+        };
+        let mut bound = vec![0; len + 2];
+        stream.read_exact(&mut bound).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use hyper::{Body, Request};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{Dialer, Proxy, ProxyKind};
+    use crate::utils;
+
+    // respond to a single http request on stream, which the proxy tunnelled to us
+    async fn serve(mut stream: TcpStream) {
+        let mut req = vec![];
+        while !req.ends_with(b"\r\n\r\n") {
+            req.push(stream.read_u8().await.unwrap());
+        }
+        assert!(req.starts_with(b"GET /announce HTTP/1.1\r\n"));
+
+        let resp = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok";
+        stream.write_all(resp).await.unwrap();
+    }
+
+    async fn get(proxy: Proxy) -> Vec<u8> {
+        let client = utils::http_client(None, None, Some(proxy));
+        let req = Request::get("http://tracker.example.com:8080/announce")
+            .body(Body::empty())
+            .unwrap();
+
+        utils::get_body(&client, req).await.unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn http_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = Proxy {
+            kind: ProxyKind::Http,
+            addr: listener.local_addr().unwrap().to_string(),
+            auth: Some(("user".into(), "pass".into())),
+        };
+
+        let server = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut req = vec![];
+            while !req.ends_with(b"\r\n\r\n") {
+                req.push(stream.read_u8().await.unwrap());
+            }
+            let req = String::from_utf8(req).unwrap();
+            assert!(req.starts_with("CONNECT tracker.example.com:8080 HTTP/1.1\r\n"));
+            assert!(req.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+            serve(stream).await;
+        };
+
+        let (body, _) = futures::join!(get(proxy), server);
+        assert_eq!(body, b"ok");
+    }
+
+    #[tokio::test]
+    async fn socks5() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = Proxy {
+            kind: ProxyKind::Socks5,
+            addr: listener.local_addr().unwrap().to_string(),
+            auth: Some(("user".into(), "pass".into())),
+        };
+
+        let server = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [5, 2, 0, 2]);
+            stream.write_all(&[5, 2]).await.unwrap();
+
+            let mut buf = [0; 11];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, *b"\x01\x04user\x04pass");
+            stream.write_all(&[1, 0]).await.unwrap();
+
+            let host = b"tracker.example.com";
+            let mut buf = vec![0; 5 + host.len() + 2];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf[..5], [5, 1, 0, 3, host.len() as u8]);
+            assert_eq!(&buf[5..5 + host.len()], host);
+            assert_eq!(buf[5 + host.len()..], 8080u16.to_be_bytes());
+
+            let reply = [5, 0, 0, 1, 127, 0, 0, 1, 0, 80];
+            stream.write_all(&reply).await.unwrap();
+            serve(stream).await;
+        };
+
+        let (body, _) = futures::join!(get(proxy), server);
+        assert_eq!(body, b"ok");
+    }
+
+    #[tokio::test]
+    async fn socks5_dialer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = Proxy {
+            kind: ProxyKind::Socks5,
+            addr: listener.local_addr().unwrap().to_string(),
+            auth: None,
+        };
+        let dialer = Dialer::new(Some(proxy), None);
+
+        let server = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 3];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [5, 1, 0]);
+            stream.write_all(&[5, 0]).await.unwrap();
+
+            // peers are asked for by address
+            let mut buf = [0; 22];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf[..4], [5, 1, 0, 4]);
+            assert_eq!(buf[4..20], "::1".parse::<Ipv6Addr>().unwrap().octets());
+            assert_eq!(buf[20..], 6881u16.to_be_bytes());
+
+            let reply = [5, 0, 0, 1, 127, 0, 0, 1, 0, 80];
+            stream.write_all(&reply).await.unwrap();
+            stream.write_all(b"hi").await.unwrap();
+        };
+
+        let dial = async {
+            let mut stream = dialer.dial("[::1]:6881".parse().unwrap()).await.unwrap();
+            let mut buf = [0; 2];
+            stream.read_exact(&mut buf).await.unwrap();
+            buf
+        };
+
+        let (buf, _) = futures::join!(dial, server);
+        assert_eq!(&buf, b"hi");
+    }
+}
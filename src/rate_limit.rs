@@ -0,0 +1,88 @@
+use tokio::time::{Duration, Instant};
+
+/// TokenBucket holds a transfer to a rate in bytes/s. it refills at the rate and holds at most
+/// a second's worth, so a peer which has been quiet can't save up a long burst. a transfer may
+/// overdraw the bucket, the next one then waits until it has refilled
+#[derive(Debug, Clone)]
+pub(crate) struct TokenBucket {
+    rate: u64,
+    // bytes which can be sent now, negative while overdrawn
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// a full bucket for rate bytes/s
+    pub(crate) fn new(rate: u64, now: Instant) -> TokenBucket {
+        let rate = rate.max(1);
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            last: now,
+        }
+    }
+
+    /// change the rate, keeping what's in the bucket up to the new rate's worth
+    pub(crate) fn set_rate(&mut self, rate: u64, now: Instant) {
+        self.refill(now);
+        self.rate = rate.max(1);
+        self.tokens = self.tokens.min(self.rate as f64);
+    }
+
+    /// when bytes can next be sent, None if they can be now
+    pub(crate) fn ready_at(&mut self, now: Instant) -> Option<Instant> {
+        self.refill(now);
+        if self.tokens > 0.0 {
+            return None;
+        }
+
+        // a nanosecond at least, an empty bucket would be ready again straight away otherwise
+        let wait = (-self.tokens / self.rate as f64 * 1e9).ceil().max(1.0);
+        Some(now + Duration::from_nanos(wait as u64))
+    }
+
+    /// take bytes which are being sent out of the bucket
+    pub(crate) fn take(&mut self, bytes: usize, now: Instant) {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::{Duration, Instant};
+
+    use crate::rate_limit::TokenBucket;
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut bucket = TokenBucket::new(1000, start);
+
+        // a full bucket sends straight away, then waits for what it overdrew
+        assert_eq!(bucket.ready_at(start), None);
+        bucket.take(1500, start);
+        assert_eq!(bucket.ready_at(start), Some(at(500)));
+        assert_eq!(bucket.ready_at(at(250)), Some(at(500)));
+        assert_eq!(bucket.ready_at(at(501)), None);
+
+        // quiet time fills the bucket up to a second's worth
+        bucket.take(1, at(501));
+        assert_eq!(bucket.ready_at(at(10_000)), None);
+        bucket.take(2000, at(10_000));
+        assert_eq!(bucket.ready_at(at(10_000)), Some(at(11_000)));
+
+        // a lower rate trims the bucket
+        let mut bucket = TokenBucket::new(1000, start);
+        bucket.set_rate(100, start);
+        bucket.take(150, start);
+        assert_eq!(bucket.ready_at(start), Some(at(500)));
+    }
+}
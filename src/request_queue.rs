@@ -0,0 +1,338 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// length of the blocks pieces are requested in
+pub(crate) const BLOCK_LEN: u32 = 16 * 1024;
+
+// the queue is kept deep enough to hold this much of the peer's download rate, enough to cover
+// the round trip to the peer with room to spare
+const QUEUE_TIME: Duration = Duration::from_secs(3);
+// bounds on the queue depth, in blocks. the minimum keeps a request in flight while the next is
+// sent, the maximum is what most clients accept before dropping requests
+const MIN_DEPTH: usize = 2;
+const MAX_DEPTH: usize = 500;
+// how long bytes are counted for before the download rate is updated
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+/// time a peer has to send a block we asked for before it's considered to have snubbed us, see
+/// [crate::config::Config::snub_timeout]
+pub(crate) const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+// a peer's reliability starts here. it loses a point for every request it lets time out and
+// earns one back for every block it sends, peers with none left get one request at a time
+const MAX_RELIABILITY: u8 = 8;
+
+/// Block is a block of a piece requested from a peer, see [crate::peer::Message::Request]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Block {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+/// RequestQueue is the blocks requested from a peer which it hasn't sent yet. the queue's depth
+/// follows the peer's bandwidth-delay product: it starts small and doubles every round trip, like
+/// tcp slow start, until the download rate stops growing, then holds [QUEUE_TIME] worth of blocks
+/// at the measured rate. fast peers are kept busy without queueing more on slow ones than they
+/// can send before we'd rather ask someone else. peers which stop sending what we ask for are
+/// snubbed and only get one request at a time until they send something, as are peers which let
+/// too many requests time out
+#[derive(Debug)]
+pub(crate) struct RequestQueue {
+    outstanding: VecDeque<Block>,
+    depth: usize,
+    slow_start: bool,
+    snubbed: bool,
+    reliability: u8,
+    // when the peer last sent a block, or when we started waiting on it if that's later
+    last_progress: Instant,
+    // download rate in bytes/s, 0 until the first window ends
+    rate: u64,
+    // bytes received since window_start
+    window_bytes: u64,
+    window_start: Instant,
+}
+
+impl RequestQueue {
+    pub(crate) fn new(now: Instant) -> RequestQueue {
+        RequestQueue {
+            outstanding: VecDeque::new(),
+            depth: MIN_DEPTH,
+            slow_start: true,
+            snubbed: false,
+            reliability: MAX_RELIABILITY,
+            last_progress: now,
+            rate: 0,
+            window_bytes: 0,
+            window_start: now,
+        }
+    }
+
+    /// number of blocks we'd like outstanding at once
+    pub(crate) fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// number of blocks that can be requested before the queue is full
+    pub(crate) fn room(&self) -> usize {
+        let depth = match self.snubbed || self.reliability == 0 {
+            true => 1,
+            false => self.depth,
+        };
+        depth.saturating_sub(self.outstanding.len())
+    }
+
+    /// whether the peer has snubbed us, see [RequestQueue::check_snubbed]
+    pub(crate) fn is_snubbed(&self) -> bool {
+        self.snubbed
+    }
+
+    /// mark the peer as snubbed if it's sent nothing we asked for in timeout, returning whether
+    /// it's snubbed. a snubbed peer leaves slow start, so its queue regrows from the measured rate
+    /// once it sends something
+    pub(crate) fn check_snubbed(&mut self, now: Instant, timeout: Duration) -> bool {
+        let waited = now.saturating_duration_since(self.last_progress);
+        if !self.outstanding.is_empty() && waited >= timeout {
+            self.snubbed = true;
+            self.slow_start = false;
+        }
+        self.snubbed
+    }
+
+    /// how well the peer has kept up with our requests, see [RequestQueue::timed_out]
+    pub(crate) fn reliability(&self) -> u8 {
+        self.reliability
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+
+    /// whether block has been requested and hasn't arrived yet
+    pub(crate) fn contains(&self, block: Block) -> bool {
+        self.outstanding.contains(&block)
+    }
+
+    /// measured download rate in bytes/s
+    pub(crate) fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// record a request sent to the peer at now
+    pub(crate) fn push(&mut self, block: Block, now: Instant) {
+        // the peer had nothing to send while the queue was empty
+        if self.outstanding.is_empty() {
+            self.last_progress = now;
+        }
+        self.outstanding.push_back(block);
+    }
+
+    /// record a block the peer sent, returning whether we asked for it. blocks we didn't ask for
+    /// aren't counted towards the download rate
+    pub(crate) fn received(&mut self, block: Block, now: Instant) -> bool {
+        let Some(i) = self.outstanding.iter().position(|&b| b == block) else {
+            return false;
+        };
+        self.outstanding.remove(i);
+        self.snubbed = false;
+        self.reliability = (self.reliability + 1).min(MAX_RELIABILITY);
+        self.last_progress = now;
+
+        if self.slow_start {
+            self.depth = (self.depth + 1).min(MAX_DEPTH);
+        }
+        self.window_bytes += block.length as u64;
+
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= RATE_WINDOW {
+            self.update_rate(elapsed);
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+
+        true
+    }
+
+    /// forget a request, eg. one we cancelled or the peer rejected
+    pub(crate) fn remove(&mut self, block: Block) -> bool {
+        let len = self.outstanding.len();
+        self.outstanding.retain(|&b| b != block);
+        self.outstanding.len() != len
+    }
+
+    /// forget a request the peer took too long to send so it can be asked of someone else,
+    /// counting it against the peer's reliability. returns whether it was outstanding
+    pub(crate) fn timed_out(&mut self, block: Block) -> bool {
+        if !self.remove(block) {
+            return false;
+        }
+        self.reliability = self.reliability.saturating_sub(1);
+        true
+    }
+
+    /// forget every request, returning them so they can be asked of someone else. peers which
+    /// choke us drop our requests
+    pub(crate) fn clear(&mut self) -> Vec<Block> {
+        self.outstanding.drain(..).collect()
+    }
+
+    fn update_rate(&mut self, elapsed: Duration) {
+        let rate = (self.window_bytes as u128 * 1000 / elapsed.as_millis()) as u64;
+
+        // slow start ends once a window grows the rate by less than an eighth
+        if self.slow_start && self.rate > 0 && rate < self.rate + self.rate / 8 {
+            self.slow_start = false;
+        }
+        // average with the last window to smooth out bursts
+        self.rate = match self.rate {
+            0 => rate,
+            prev => (prev + rate) / 2,
+        };
+
+        if !self.slow_start {
+            let queued = self.rate as u128 * QUEUE_TIME.as_millis() / 1000;
+            let depth = (queued / BLOCK_LEN as u128).min(MAX_DEPTH as u128) as usize;
+            self.depth = depth.max(MIN_DEPTH);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::request_queue::{
+        Block, RequestQueue, BLOCK_LEN, MAX_DEPTH, MAX_RELIABILITY, MIN_DEPTH, SNUB_TIMEOUT,
+    };
+
+    fn block(index: u32) -> Block {
+        Block {
+            index,
+            begin: 0,
+            length: BLOCK_LEN,
+        }
+    }
+
+    /// request then receive n blocks, spread evenly over window, keeping the queue full
+    fn download(queue: &mut RequestQueue, now: &mut Instant, n: u32, window: Duration) {
+        for i in 0..n {
+            queue.push(block(i), *now);
+            while queue.room() > 0 {
+                queue.push(block(i), *now);
+            }
+            *now += window / n;
+            assert!(queue.received(block(i), *now));
+        }
+    }
+
+    #[test]
+    fn slow_start() {
+        let mut now = Instant::now();
+        let mut queue = RequestQueue::new(now);
+        assert_eq!(queue.depth(), MIN_DEPTH);
+        assert_eq!(queue.room(), MIN_DEPTH);
+
+        // every block received deepens the queue while the rate grows
+        download(&mut queue, &mut now, 10, Duration::from_secs(1));
+        assert_eq!(queue.depth(), MIN_DEPTH + 10);
+        assert_eq!(queue.rate(), 10 * BLOCK_LEN as u64);
+        download(&mut queue, &mut now, 40, Duration::from_secs(1));
+        assert_eq!(queue.depth(), MIN_DEPTH + 50);
+
+        // the rate levels off at 40 blocks/s, ending slow start. the queue settles at about 3s
+        // worth of blocks
+        for _ in 0..10 {
+            download(&mut queue, &mut now, 40, Duration::from_secs(1));
+        }
+        assert!(!queue.slow_start);
+        assert!((115..=120).contains(&queue.depth()));
+
+        // and shrinks when the peer slows down
+        for _ in 0..8 {
+            download(&mut queue, &mut now, 1, Duration::from_secs(2));
+        }
+        assert_eq!(queue.depth(), MIN_DEPTH);
+    }
+
+    #[test]
+    fn max_depth() {
+        let mut now = Instant::now();
+        let mut queue = RequestQueue::new(now);
+        for _ in 0..4 {
+            download(&mut queue, &mut now, 2000, Duration::from_secs(1));
+        }
+        assert_eq!(queue.depth(), MAX_DEPTH);
+    }
+
+    #[test]
+    fn outstanding() {
+        let now = Instant::now();
+        let mut queue = RequestQueue::new(now);
+        queue.push(block(0), now);
+        queue.push(block(1), now);
+        assert_eq!(queue.room(), 0);
+
+        // blocks we didn't ask for are ignored
+        assert!(!queue.received(block(2), now));
+        assert!(queue.remove(block(0)));
+        assert!(!queue.remove(block(0)));
+        assert_eq!(queue.len(), 1);
+
+        assert_eq!(queue.clear(), [block(1)]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn snubbed() {
+        let mut now = Instant::now();
+        let mut queue = RequestQueue::new(now);
+        download(&mut queue, &mut now, 10, Duration::from_secs(1));
+        assert!(!queue.check_snubbed(now, SNUB_TIMEOUT));
+
+        // an empty queue isn't waiting on the peer
+        queue.clear();
+        now += SNUB_TIMEOUT;
+        assert!(!queue.check_snubbed(now, SNUB_TIMEOUT));
+
+        queue.push(block(0), now);
+        queue.push(block(1), now);
+        now += SNUB_TIMEOUT / 2;
+        assert!(!queue.check_snubbed(now, SNUB_TIMEOUT));
+        now += SNUB_TIMEOUT / 2;
+        assert!(queue.check_snubbed(now, SNUB_TIMEOUT));
+        assert!(queue.is_snubbed());
+        assert_eq!(queue.room(), 0);
+
+        // a block ends the snub
+        assert!(queue.received(block(0), now));
+        assert!(!queue.is_snubbed());
+        assert_eq!(queue.room(), queue.depth() - 1);
+        assert!(!queue.check_snubbed(now, SNUB_TIMEOUT));
+    }
+
+    #[test]
+    fn reliability() {
+        let now = Instant::now();
+        let mut queue = RequestQueue::new(now);
+        assert_eq!(queue.reliability(), MAX_RELIABILITY);
+
+        // every request the peer lets time out costs it, until it only gets one at a time
+        for i in 0..MAX_RELIABILITY as u32 {
+            queue.push(block(i), now);
+            assert!(queue.timed_out(block(i)));
+        }
+        assert!(!queue.timed_out(block(0)));
+        assert_eq!(queue.reliability(), 0);
+        assert_eq!(queue.room(), 1);
+
+        // blocks it sends win it back
+        queue.push(block(0), now);
+        assert!(queue.received(block(0), now));
+        assert_eq!(queue.reliability(), 1);
+        assert_eq!(queue.room(), queue.depth());
+    }
+}
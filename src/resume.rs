@@ -0,0 +1,181 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    path::PathBuf,
+};
+
+use crate::{peer::Bitfield, torrent::Sha1Hash, torrent_ast::Bencode, utils};
+
+/// ResumeData is the part of a torrent's state worth keeping between runs. it's stored as a
+/// bencoded dictionary, see [ResumeData::encode]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResumeData {
+    pub info_hash: Sha1Hash,
+    /// peers we recently had working connections to, newest first. these are tried as soon as
+    /// the torrent is started, without waiting for trackers to respond
+    pub peers: Vec<SocketAddr>,
+    /// tracker tiers as they were when saved, including any edits and BEP-12 reordering. None
+    /// keeps the metainfo's trackers
+    pub trackers: Option<Vec<Vec<String>>>,
+    /// pieces we had, which are taken as ours again without being checked. None if unknown
+    pub pieces: Option<Bitfield>,
+    /// pieces which were part way downloaded, so the blocks which arrived aren't asked for again
+    pub partial: Vec<PartialPiece>,
+    /// paths of the files which were renamed, by index, relative to the torrent's directory
+    pub renamed: BTreeMap<usize, PathBuf>,
+}
+
+/// PartialPiece is a piece which some of the blocks of had arrived when resume data was saved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialPiece {
+    pub index: u32,
+    /// which of the piece's 16 KiB blocks had arrived
+    pub blocks: Bitfield,
+    /// the piece as it had been put together, blocks which hadn't arrived are zeros
+    pub data: Vec<u8>,
+}
+
+impl ResumeData {
+    pub fn encode(&self) -> Vec<u8> {
+        let (peers, peers6) = utils::encode_compact_peers(&self.peers);
+        let mut dict = HashMap::from([
+            (&b"info-hash"[..], Bencode::BStr(&self.info_hash)),
+            (&b"peers"[..], Bencode::BStr(&peers)),
+            (&b"peers6"[..], Bencode::BStr(&peers6)),
+        ]);
+
+        if let Some(trackers) = &self.trackers {
+            let tiers = trackers
+                .iter()
+                .map(|tier| Bencode::List(tier.iter().map(|tr| Bencode::Str(tr)).collect()));
+            dict.insert(b"trackers", Bencode::List(tiers.collect()));
+        }
+        if let Some(pieces) = &self.pieces {
+            dict.insert(b"pieces", Bencode::BStr(pieces.as_raw_slice()));
+        }
+        if !self.partial.is_empty() {
+            let partial = self.partial.iter().map(|piece| {
+                Bencode::Dict(HashMap::from([
+                    (&b"piece"[..], Bencode::Num(piece.index as i64)),
+                    (&b"blocks"[..], Bencode::BStr(piece.blocks.as_raw_slice())),
+                    (&b"data"[..], Bencode::BStr(&piece.data)),
+                ]))
+            });
+            dict.insert(b"partial", Bencode::List(partial.collect()));
+        }
+        if !self.renamed.is_empty() {
+            let renamed = self.renamed.iter().map(|(&index, path)| {
+                let path = path.iter().filter_map(|c| c.to_str()).map(Bencode::Str);
+                Bencode::Dict(HashMap::from([
+                    (&b"file"[..], Bencode::Num(index as i64)),
+                    (&b"path"[..], Bencode::List(path.collect())),
+                ]))
+            });
+            dict.insert(b"renamed", Bencode::List(renamed.collect()));
+        }
+
+        let mut buf = vec![];
+        Bencode::Dict(dict).encode(&mut buf);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<ResumeData> {
+        let mut dict = Bencode::decode(buf)?.dict()?;
+
+        let mut peers = utils::parse_compact_peers(dict.remove(&b"peers"[..])?.bytes()?);
+        if let Some(peers6) = dict.remove(&b"peers6"[..]) {
+            peers.extend(utils::parse_compact_peers6(peers6.bytes()?));
+        }
+
+        let trackers = match dict.remove(&b"trackers"[..]) {
+            Some(tiers) => Some(tiers.map_list(|tier| tier.map_list(|t| Some(t.str()?.into())))?),
+            None => None,
+        };
+        let bitfield = |b: Bencode| Some(Bitfield::from_boxed_slice(b.bytes()?.into()));
+        let pieces = match dict.remove(&b"pieces"[..]) {
+            Some(pieces) => Some(bitfield(pieces)?),
+            None => None,
+        };
+        let partial = match dict.remove(&b"partial"[..]) {
+            Some(partial) => partial.map_list(|piece| {
+                let mut piece = piece.dict()?;
+                Some(PartialPiece {
+                    index: piece.remove(&b"piece"[..])?.num()?.try_into().ok()?,
+                    blocks: bitfield(piece.remove(&b"blocks"[..])?)?,
+                    data: piece.remove(&b"data"[..])?.bytes()?.to_vec(),
+                })
+            })?,
+            None => vec![],
+        };
+        let renamed = match dict.remove(&b"renamed"[..]) {
+            Some(renamed) => renamed.map_list(|file| {
+                let mut file = file.dict()?;
+                let index: usize = file.remove(&b"file"[..])?.num()?.try_into().ok()?;
+                let path = file.remove(&b"path"[..])?.map_list(Bencode::str)?;
+                Some((index, path.into_iter().collect::<PathBuf>()))
+            })?,
+            None => vec![],
+        };
+
+        Some(ResumeData {
+            info_hash: dict.remove(&b"info-hash"[..])?.bytes()?.try_into().ok()?,
+            peers,
+            trackers,
+            pieces,
+            partial,
+            renamed: renamed.into_iter().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use bitvec::prelude::{bitbox, Msb0};
+
+    use super::{PartialPiece, ResumeData};
+
+    #[test]
+    fn round_trip() {
+        let data = ResumeData {
+            info_hash: [0xab; 20],
+            peers: vec![
+                "1.2.3.4:6881".parse().unwrap(),
+                "10.0.0.1:80".parse().unwrap(),
+                "[2001:db8::1]:6881".parse().unwrap(),
+            ],
+            trackers: None,
+            pieces: None,
+            partial: vec![],
+            renamed: Default::default(),
+        };
+
+        let buf = data.encode();
+        assert_eq!(ResumeData::decode(&buf).as_ref(), Some(&data));
+
+        let data = ResumeData {
+            trackers: Some(vec![vec!["http://a".into(), "http://b".into()], vec![]]),
+            ..data
+        };
+        let buf = data.encode();
+        assert_eq!(ResumeData::decode(&buf).as_ref(), Some(&data));
+
+        // bitfields are padded out to whole bytes
+        let data = ResumeData {
+            pieces: Some(bitbox![u8, Msb0; 1, 0, 1, 1, 0, 0, 0, 0]),
+            partial: vec![PartialPiece {
+                index: 1,
+                blocks: bitbox![u8, Msb0; 0, 1, 0, 0, 0, 0, 0, 0],
+                data: vec![7; 10],
+            }],
+            renamed: BTreeMap::from([(2, ["dir", "b c"].iter().collect())]),
+            ..data
+        };
+        let buf = data.encode();
+        assert_eq!(ResumeData::decode(&buf), Some(data));
+
+        assert_eq!(ResumeData::decode(b"de"), None);
+        assert_eq!(ResumeData::decode(b"d9:info-hash3:abc5:peers0:e"), None);
+    }
+}
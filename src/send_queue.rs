@@ -0,0 +1,118 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::peer::Message;
+
+/// SendQueue is the messages waiting to be written to a peer. piece data is bulk and waits
+/// behind everything else, so a choke or cancel isn't stuck behind megabytes of blocks
+#[derive(Debug, Default)]
+pub(crate) struct SendQueue {
+    urgent: VecDeque<Message>,
+    bulk: VecDeque<Message>,
+    backlog: Backlog,
+}
+
+/// Backlog is the bytes of bulk data queued for a peer. it's shared by the peer's handle, which
+/// adds blocks as it hands them to the peer's task, and the task's [SendQueue], which takes them
+/// off as they're written. uploads to a peer with a large backlog should wait
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Backlog(Arc<AtomicUsize>);
+
+impl SendQueue {
+    pub(crate) fn new(backlog: Backlog) -> SendQueue {
+        SendQueue {
+            backlog,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn push(&mut self, msg: Message) {
+        match is_bulk(&msg) {
+            true => self.bulk.push_back(msg),
+            false => self.urgent.push_back(msg),
+        }
+    }
+
+    /// the next message to write, urgent messages first
+    pub(crate) fn pop(&mut self) -> Option<Message> {
+        if let Some(msg) = self.pop_urgent() {
+            return Some(msg);
+        }
+
+        let msg = self.bulk.pop_front()?;
+        self.backlog.sub(&msg);
+        Some(msg)
+    }
+
+    /// the next urgent message, leaving piece data queued
+    pub(crate) fn pop_urgent(&mut self) -> Option<Message> {
+        self.urgent.pop_front()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.urgent.is_empty() && self.bulk.is_empty()
+    }
+}
+
+impl Backlog {
+    /// bytes of bulk data queued
+    pub(crate) fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// count msg if it's bulk data which is about to be queued
+    pub(crate) fn add(&self, msg: &Message) {
+        if is_bulk(msg) {
+            self.0.fetch_add(msg.encoded_len(), Ordering::Relaxed);
+        }
+    }
+
+    // take off a bulk message that's been written
+    fn sub(&self, msg: &Message) {
+        self.0.fetch_sub(msg.encoded_len(), Ordering::Relaxed);
+    }
+}
+
+fn is_bulk(msg: &Message) -> bool {
+    matches!(msg, Message::Piece { .. })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        peer::Message,
+        send_queue::{Backlog, SendQueue},
+    };
+
+    #[test]
+    fn priority() {
+        let backlog = Backlog::default();
+        let mut queue = SendQueue::new(backlog.clone());
+        let piece = |index| Message::Piece {
+            index,
+            begin: 0,
+            block: vec![0; 100].into(),
+        };
+
+        for msg in [piece(0), Message::Unchoke, piece(1), Message::Choke] {
+            backlog.add(&msg);
+            queue.push(msg);
+        }
+        assert_eq!(backlog.get(), 2 * 113);
+
+        // control messages jump ahead of piece data, each tier keeps its order
+        assert_eq!(queue.pop(), Some(Message::Unchoke));
+        assert_eq!(queue.pop(), Some(Message::Choke));
+        assert_eq!(queue.pop(), Some(piece(0)));
+        assert_eq!(backlog.get(), 113);
+        assert_eq!(queue.pop(), Some(piece(1)));
+        assert_eq!(queue.pop(), None);
+        assert!(queue.is_empty());
+        assert_eq!(backlog.get(), 0);
+    }
+}
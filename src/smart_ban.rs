@@ -0,0 +1,118 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+// peers which sent blocks of this many pieces that failed their hash check are banned
+const MAX_STRIKES: u32 = 2;
+
+/// BanList is the addresses of peers banned for sending corrupt data. it's shared by every
+/// torrent in a session and the session's listener, and lasts as long as the session
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BanList(Arc<Mutex<HashSet<IpAddr>>>);
+
+impl BanList {
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        self.0.lock().unwrap().contains(&ip)
+    }
+
+    /// ban ip, returning whether it wasn't already
+    pub(crate) fn insert(&self, ip: IpAddr) -> bool {
+        self.0.lock().unwrap().insert(ip)
+    }
+}
+
+/// SmartBan works out which peers send corrupt data. the peers which sent blocks of a piece are
+/// remembered until it's verified, and every one of them gets a strike if it fails its hash
+/// check. peers with [MAX_STRIKES] strikes are to be banned, as are peers which sent a failed
+/// piece on their own
+#[derive(Debug, Default)]
+pub(crate) struct SmartBan {
+    // peers which sent blocks of each unverified piece
+    contributors: HashMap<u32, HashSet<IpAddr>>,
+    strikes: HashMap<IpAddr, u32>,
+}
+
+impl SmartBan {
+    /// record that the peer at ip sent a block of piece
+    pub(crate) fn record_block(&mut self, piece: u32, ip: IpAddr) {
+        self.contributors.entry(piece).or_default().insert(ip);
+    }
+
+    /// forget who sent piece once it passes its hash check
+    pub(crate) fn piece_passed(&mut self, piece: u32) {
+        self.contributors.remove(&piece);
+    }
+
+    /// give every peer which sent blocks of piece a strike, returning the ones which should now
+    /// be banned. the piece will be downloaded again, so who sent it is forgotten
+    pub(crate) fn piece_failed(&mut self, piece: u32) -> Vec<IpAddr> {
+        let Some(contributors) = self.contributors.remove(&piece) else {
+            return vec![];
+        };
+
+        // a piece sent by a single peer can only have been corrupted by it
+        let strike = match contributors.len() {
+            1 => MAX_STRIKES,
+            _ => 1,
+        };
+
+        let mut banned = vec![];
+        for ip in contributors {
+            let strikes = self.strikes.entry(ip).or_default();
+            *strikes += strike;
+            if *strikes >= MAX_STRIKES {
+                self.strikes.remove(&ip);
+                banned.push(ip);
+            }
+        }
+        banned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::smart_ban::{BanList, SmartBan};
+
+    #[test]
+    fn strikes() {
+        let a: IpAddr = "1.2.3.4".parse().unwrap();
+        let b: IpAddr = "5.6.7.8".parse().unwrap();
+        let c: IpAddr = "9.9.9.9".parse().unwrap();
+        let mut smart_ban = SmartBan::default();
+
+        // a and b share the blame for piece 0, then a and c for piece 1
+        smart_ban.record_block(0, a);
+        smart_ban.record_block(0, b);
+        smart_ban.record_block(0, a);
+        assert!(smart_ban.piece_failed(0).is_empty());
+        smart_ban.record_block(1, a);
+        smart_ban.record_block(1, c);
+        assert_eq!(smart_ban.piece_failed(1), [a]);
+
+        // verified pieces don't count against anyone
+        smart_ban.record_block(2, b);
+        smart_ban.record_block(2, c);
+        smart_ban.piece_passed(2);
+        assert!(smart_ban.piece_failed(2).is_empty());
+
+        // c sent all of piece 3
+        smart_ban.record_block(3, c);
+        assert_eq!(smart_ban.piece_failed(3), [c]);
+    }
+
+    #[test]
+    fn ban_list() {
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let banned = BanList::default();
+        let shared = banned.clone();
+
+        assert!(!shared.contains(ip));
+        assert!(banned.insert(ip));
+        assert!(!banned.insert(ip));
+        assert!(shared.contains(ip));
+    }
+}
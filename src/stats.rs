@@ -0,0 +1,280 @@
+use std::time::{Duration, Instant};
+
+// seconds averaged over by the short and long rates
+const SHORT_WINDOW: u64 = 5;
+const LONG_WINDOW: u64 = 30;
+
+/// PeerStats are the bytes of piece data transferred with a peer, and the rates they were
+/// transferred at averaged over the last 5 and 30 seconds, in bytes/s
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub upload_rate: u64,
+    pub upload_rate_30s: u64,
+    pub download_rate: u64,
+    pub download_rate_30s: u64,
+    /// the peer hasn't sent a block we asked for in [crate::config::Config::snub_timeout]
+    pub snubbed: bool,
+}
+
+/// TorrentStats are a torrent's progress through the files it wants and the piece data it's
+/// transferred, see [crate::torrent::Torrent::stats]. only pieces which passed their hash check
+/// count as downloaded. rates are in bytes/s as in [PeerStats]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TorrentStats {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// bytes of the wanted files we don't have yet
+    pub left: u64,
+    /// how much of the wanted files we have, from 0 to 100
+    pub progress: f64,
+    pub upload_rate: u64,
+    pub upload_rate_30s: u64,
+    pub download_rate: u64,
+    pub download_rate_30s: u64,
+    /// time left to finish at the 30s download rate, None while nothing is being downloaded
+    pub eta: Option<Duration>,
+    /// reads of piece data served from the read cache, and reads which went to disk, since the
+    /// torrent's files were last opened, see [crate::config::Config::read_cache_size]
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+}
+
+/// SessionStats are the transfers of every torrent in a session added up, see
+/// [crate::tsunami::Tsunami::stats]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionStats {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub upload_rate: u64,
+    pub download_rate: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+}
+
+/// TransferStats counts the piece data sent to and received from a peer, see [PeerStats]
+#[derive(Debug, Clone)]
+pub(crate) struct TransferStats {
+    uploaded: u64,
+    downloaded: u64,
+    upload_rate: RollingRate,
+    download_rate: RollingRate,
+}
+
+impl TransferStats {
+    pub(crate) fn new(now: Instant) -> TransferStats {
+        TransferStats {
+            uploaded: 0,
+            downloaded: 0,
+            upload_rate: RollingRate::new(now),
+            download_rate: RollingRate::new(now),
+        }
+    }
+
+    pub(crate) fn record_upload(&mut self, bytes: u64, now: Instant) {
+        self.uploaded += bytes;
+        self.upload_rate.record(bytes, now);
+    }
+
+    pub(crate) fn record_download(&mut self, bytes: u64, now: Instant) {
+        self.downloaded += bytes;
+        self.download_rate.record(bytes, now);
+    }
+
+    pub(crate) fn snapshot(&self, now: Instant) -> PeerStats {
+        PeerStats {
+            uploaded: self.uploaded,
+            downloaded: self.downloaded,
+            upload_rate: self.upload_rate.rate(SHORT_WINDOW, now),
+            upload_rate_30s: self.upload_rate.rate(LONG_WINDOW, now),
+            download_rate: self.download_rate.rate(SHORT_WINDOW, now),
+            download_rate_30s: self.download_rate.rate(LONG_WINDOW, now),
+            snubbed: false,
+        }
+    }
+}
+
+impl TorrentStats {
+    /// stats of a torrent which has transferred what's in transfer, and has left of the wanted
+    /// bytes still to download
+    pub(crate) fn new(transfer: PeerStats, left: u64, wanted: u64) -> TorrentStats {
+        let progress = match wanted {
+            0 => 100.0,
+            _ => (wanted - left.min(wanted)) as f64 / wanted as f64 * 100.0,
+        };
+        let eta = match (left, transfer.download_rate_30s) {
+            (0, _) => Some(Duration::ZERO),
+            (_, 0) => None,
+            (left, rate) => Some(Duration::from_secs(left.div_ceil(rate))),
+        };
+
+        TorrentStats {
+            uploaded: transfer.uploaded,
+            downloaded: transfer.downloaded,
+            left,
+            progress,
+            upload_rate: transfer.upload_rate,
+            upload_rate_30s: transfer.upload_rate_30s,
+            download_rate: transfer.download_rate,
+            download_rate_30s: transfer.download_rate_30s,
+            eta,
+            cache_hits: 0,
+            cache_misses: 0,
+        }
+    }
+}
+
+impl SessionStats {
+    /// count a torrent's stats towards the session's
+    pub(crate) fn add(&mut self, torrent: &TorrentStats) {
+        self.uploaded += torrent.uploaded;
+        self.downloaded += torrent.downloaded;
+        self.upload_rate += torrent.upload_rate;
+        self.download_rate += torrent.download_rate;
+        self.cache_hits += torrent.cache_hits;
+        self.cache_misses += torrent.cache_misses;
+    }
+}
+
+// RollingRate counts bytes in one second buckets, keeping the last LONG_WINDOW seconds. rates
+// are taken over complete seconds, so the second in progress doesn't drag them down
+#[derive(Debug, Clone)]
+struct RollingRate {
+    // bytes transferred in each second since start, indexed by second % LONG_WINDOW
+    buckets: [u64; LONG_WINDOW as usize],
+    start: Instant,
+    // the last second bytes were recorded in. buckets for older seconds than LONG_WINDOW before
+    // it have been reused
+    last: u64,
+}
+
+impl RollingRate {
+    fn new(now: Instant) -> RollingRate {
+        RollingRate {
+            buckets: [0; LONG_WINDOW as usize],
+            start: now,
+            last: 0,
+        }
+    }
+
+    fn second(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.start).as_secs()
+    }
+
+    fn record(&mut self, bytes: u64, now: Instant) {
+        let second = self.second(now).max(self.last);
+        // clear the buckets skipped since the last record
+        for s in (self.last + 1..=second).take(LONG_WINDOW as usize) {
+            self.buckets[(s % LONG_WINDOW) as usize] = 0;
+        }
+
+        self.last = second;
+        self.buckets[(second % LONG_WINDOW) as usize] += bytes;
+    }
+
+    // average bytes/s over the window seconds before the current one
+    fn rate(&self, window: u64, now: Instant) -> u64 {
+        let second = self.second(now);
+        let first = second.saturating_sub(window);
+
+        let recent = (first..second).filter(|&s| s <= self.last && self.last - s < LONG_WINDOW);
+        let bucket = |s: u64| self.buckets[(s % LONG_WINDOW) as usize];
+        recent.map(bucket).sum::<u64>() / window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::stats::{PeerStats, SessionStats, TorrentStats, TransferStats};
+
+    #[test]
+    fn rates() {
+        let start = Instant::now();
+        let at = |secs: f64| start + Duration::from_secs_f64(secs);
+        let mut stats = TransferStats::new(start);
+
+        // 1000 bytes/s down for 10s
+        for s in 0..10 {
+            stats.record_download(600, at(s as f64));
+            stats.record_download(400, at(s as f64 + 0.5));
+        }
+        stats.record_upload(3000, at(9.0));
+
+        let expected = PeerStats {
+            uploaded: 3000,
+            downloaded: 10_000,
+            upload_rate: 600,
+            upload_rate_30s: 100,
+            download_rate: 1000,
+            download_rate_30s: 333,
+            snubbed: false,
+        };
+        assert_eq!(stats.snapshot(at(10.0)), expected);
+
+        // the second in progress isn't counted
+        stats.record_download(5000, at(10.5));
+        assert_eq!(stats.snapshot(at(10.9)).download_rate, 1000);
+        assert_eq!(stats.snapshot(at(11.0)).download_rate, 1800);
+
+        // old seconds fall out of the windows
+        let idle = stats.snapshot(at(20.0));
+        assert_eq!((idle.download_rate, idle.download_rate_30s), (0, 500));
+        let idle = stats.snapshot(at(60.0));
+        assert_eq!((idle.download_rate, idle.download_rate_30s), (0, 0));
+        assert_eq!(idle.downloaded, 15_000);
+
+        // buckets are reused once the windows have moved past them
+        stats.record_download(100, at(45.0));
+        stats.record_download(100, at(46.0));
+        let stats = stats.snapshot(at(47.0));
+        assert_eq!((stats.download_rate, stats.download_rate_30s), (40, 6));
+    }
+
+    #[test]
+    fn torrent_stats() {
+        let transfer = PeerStats {
+            uploaded: 500,
+            downloaded: 3000,
+            upload_rate: 50,
+            download_rate: 200,
+            download_rate_30s: 100,
+            ..Default::default()
+        };
+
+        // the eta goes by the 30s rate
+        let stats = TorrentStats::new(transfer, 1050, 4000);
+        assert_eq!(stats.progress, 73.75);
+        assert_eq!(stats.eta, Some(Duration::from_secs(11)));
+        assert_eq!((stats.uploaded, stats.downloaded), (500, 3000));
+
+        // nothing arriving means there's no telling when we'll finish
+        let idle = PeerStats {
+            download_rate_30s: 0,
+            ..transfer
+        };
+        assert_eq!(TorrentStats::new(idle, 1000, 4000).eta, None);
+        let done = TorrentStats::new(idle, 0, 4000);
+        assert_eq!((done.progress, done.eta), (100.0, Some(Duration::ZERO)));
+        assert_eq!(TorrentStats::new(idle, 0, 0).progress, 100.0);
+
+        let mut session = SessionStats::default();
+        session.add(&stats);
+        session.add(&TorrentStats {
+            cache_hits: 3,
+            cache_misses: 1,
+            ..done
+        });
+        let expected = SessionStats {
+            uploaded: 1000,
+            downloaded: 6000,
+            upload_rate: 100,
+            download_rate: 400,
+            cache_hits: 3,
+            cache_misses: 1,
+        };
+        assert_eq!(session, expected);
+    }
+}
@@ -0,0 +1,1137 @@
+use std::{
+    fmt, fs, io,
+    io::{Read, Seek, SeekFrom, Write},
+    mem,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use futures::future::{self, BoxFuture};
+use tokio::task;
+
+use crate::config::{Allocation, Incomplete};
+
+// size of the zeros written at a time when a file can't be allocated any other way
+const ZEROS: usize = 64 * 1024;
+
+/// FileSpan is a single file of a torrent as laid out in the torrent's pieces
+#[derive(Debug, Clone)]
+pub struct FileSpan {
+    pub path: PathBuf,
+    pub length: u64,
+    // padding files are all zeros and never exist on disk
+    pub padding: bool,
+}
+
+/// Attr is a BEP-47 file attribute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attr {
+    // padding files only exist to align the next file to a piece boundary. they are never
+    // written to disk or reported to the user, but still count towards piece offsets
+    Padding,
+    Executable,
+    Hidden,
+    Symlink,
+}
+
+/// Segment is the part of a range of a piece which lies in a single file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Segment {
+    // index of the file in [Layout::files]
+    pub(crate) file: usize,
+    // where the segment starts within the file
+    pub(crate) offset: u64,
+    pub(crate) length: u64,
+}
+
+/// Storage is where a torrent's pieces are kept once they've passed their hash check, and read
+/// back from for peers. tsunami keeps them in the torrent's files on disk, see [DiskStorage], but
+/// any type implementing this trait can be used, eg. to keep them in object storage or encrypted
+/// at rest, see [StorageProvider]. [MemoryStorage] keeps them in memory, so tests can run whole
+/// swarms without touching the filesystem
+pub trait Storage: fmt::Debug + Send + Sync {
+    /// read length bytes starting at begin in piece. parts of files which were never written
+    /// may read as zeros or fail
+    fn read_block(&self, piece: u32, begin: u32, length: u64)
+        -> BoxFuture<'_, io::Result<Vec<u8>>>;
+
+    /// write data starting at begin in piece
+    fn write_block(&self, piece: u32, begin: u32, data: Vec<u8>) -> BoxFuture<'_, io::Result<()>>;
+
+    /// make everything written so far durable, eg. once the torrent is stopped
+    fn flush(&self) -> BoxFuture<'_, io::Result<()>>;
+
+    /// move the torrent's files into dir, keeping their layout relative to the directory
+    /// they're in now. stores which don't keep files in a directory have nothing to do
+    fn move_to<'a>(&'a self, dir: &'a Path) -> BoxFuture<'a, io::Result<()>>;
+
+    /// give each file in renames, by index, the path alongside it. stores which don't keep
+    /// files by name have nothing to do, which is the default
+    fn rename<'a>(&'a self, renames: &'a [(usize, PathBuf)]) -> BoxFuture<'a, io::Result<()>> {
+        let _ = renames;
+        Box::pin(async { Ok(()) })
+    }
+
+    /// bytes each of the torrent's files takes up in the store, in order
+    fn sizes(&self) -> Vec<u64>;
+
+    /// file, which has the BEP-47 attributes attrs, has been downloaded in full. empty files
+    /// are complete as soon as the torrent is added. stores which don't keep files on disk can
+    /// ignore attrs, which is the default
+    fn file_completed<'a>(
+        &'a self,
+        file: usize,
+        attrs: &'a [Attr],
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let _ = (file, attrs);
+        Box::pin(async { Ok(()) })
+    }
+
+    /// make file a symlink to target, which is relative to the directory the file is in. stores
+    /// which don't keep files on disk have nothing to do, which is the default
+    fn symlink<'a>(&'a self, file: usize, target: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        let _ = (file, target);
+        Box::pin(async { Ok(()) })
+    }
+
+    /// make room for every file up front, see [Allocation::Full]. nothing by default
+    fn preallocate(&self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// StorageProvider creates the [Storage] for each torrent added with it, see
+/// [crate::config::AddTorrentOptions::storage]
+pub trait StorageProvider: fmt::Debug + Send + Sync {
+    /// storage for a torrent whose files are laid out end to end in pieces of piece_length
+    /// bytes. the files' paths are where they'd be downloaded to on disk, within dir
+    fn storage(&self, dir: &Path, files: &[FileSpan], piece_length: u32) -> Arc<dyn Storage>;
+}
+
+/// Layout maps a torrent's pieces onto its files, which are laid out end to end in the order
+/// they're listed
+#[derive(Debug, Clone)]
+pub(crate) struct Layout {
+    files: Arc<[FileSpan]>,
+    piece_length: u64,
+    total_length: u64,
+}
+
+/// DiskStorage keeps a torrent's files on disk, reading and writing them in the background, see
+/// [FileIo]. files and their directories are created as they're first written to, see
+/// [Allocation], padding files never are
+#[derive(Debug)]
+pub struct DiskStorage {
+    // the files' paths change as they're moved, along with the directory they're in
+    layout: Arc<RwLock<Layout>>,
+    dir: RwLock<PathBuf>,
+    allocation: Allocation,
+    incomplete: Incomplete,
+    // where each file written elsewhere while it's incomplete belongs, see [Incomplete]
+    finals: Arc<RwLock<Vec<Option<PathBuf>>>>,
+    io: Arc<dyn FileIo>,
+}
+
+/// MemoryStorage keeps a torrent's files in memory. like sparse files on disk, each is created
+/// at its full length once it's first written to, and padding files never are
+#[derive(Debug)]
+pub struct MemoryStorage {
+    layout: Layout,
+    // contents of each file, empty until it's written to
+    files: Mutex<Vec<Vec<u8>>>,
+}
+
+/// InMemory gives every torrent added with it a [MemoryStorage]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InMemory;
+
+/// FileIo carries out a [DiskStorage]'s reads and writes, each of which is a list of segments
+/// of its files. writes open files with [open]
+pub(crate) trait FileIo: fmt::Debug + Send + Sync {
+    /// read segments of files into a single buffer, padding reads as zeros
+    fn read(
+        &self,
+        files: Arc<[FileSpan]>,
+        segments: Vec<Segment>,
+    ) -> BoxFuture<'static, io::Result<Vec<u8>>>;
+
+    /// write data over segments of files in order, padding is skipped
+    fn write(
+        &self,
+        files: Arc<[FileSpan]>,
+        segments: Vec<Segment>,
+        data: Vec<u8>,
+        allocation: Allocation,
+    ) -> BoxFuture<'static, io::Result<()>>;
+}
+
+/// BlockingIo does storage io with std's file apis on tokio's blocking pool
+#[derive(Debug)]
+pub(crate) struct BlockingIo;
+
+impl Attr {
+    /// parse a BEP-47 attr string, ignoring any unknown attributes
+    pub(crate) fn parse(attr: &str) -> Vec<Attr> {
+        attr.chars()
+            .filter_map(|c| match c {
+                'p' => Some(Attr::Padding),
+                'x' => Some(Attr::Executable),
+                'h' => Some(Attr::Hidden),
+                'l' => Some(Attr::Symlink),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl Layout {
+    pub(crate) fn new(files: Vec<FileSpan>, piece_length: u32) -> Layout {
+        Layout {
+            total_length: files.iter().map(|f| f.length).sum(),
+            files: files.into(),
+            piece_length: piece_length as u64,
+        }
+    }
+
+    pub(crate) fn files(&self) -> &[FileSpan] {
+        &self.files
+    }
+
+    /// length of piece, the last one may be short. None if there's no such piece
+    pub(crate) fn piece_size(&self, piece: u32) -> Option<u64> {
+        let start = piece as u64 * self.piece_length;
+        let left = self.total_length.saturating_sub(start);
+        (left > 0).then(|| left.min(self.piece_length))
+    }
+
+    /// the parts of files which the length bytes at begin in piece lie in, in order. None if
+    /// they run past the end of the piece
+    pub(crate) fn segments(&self, piece: u32, begin: u32, length: u64) -> Option<Vec<Segment>> {
+        if begin as u64 + length > self.piece_size(piece)? {
+            return None;
+        }
+
+        let start = piece as u64 * self.piece_length + begin as u64;
+        let end = start + length;
+        let (mut offset, mut segments) = (0, vec![]);
+        for (file, span) in self.files.iter().enumerate() {
+            if offset >= end {
+                break;
+            }
+            let (lo, hi) = (offset.max(start), (offset + span.length).min(end));
+            if lo < hi {
+                let (offset, length) = (lo - offset, hi - lo);
+                segments.push(Segment {
+                    file,
+                    offset,
+                    length,
+                });
+            }
+            offset += span.length;
+        }
+        Some(segments)
+    }
+
+    // segments of a read or write, or an error if it doesn't fit in the piece
+    fn find(&self, piece: u32, begin: u32, length: u64) -> io::Result<Vec<Segment>> {
+        let segments = self.segments(piece, begin, length);
+        segments.ok_or_else(|| io::ErrorKind::InvalidInput.into())
+    }
+}
+
+impl DiskStorage {
+    pub fn new(files: Vec<FileSpan>, piece_length: u32) -> DiskStorage {
+        DiskStorage {
+            finals: Arc::new(RwLock::new(vec![None; files.len()])),
+            layout: Arc::new(RwLock::new(Layout::new(files, piece_length))),
+            dir: Default::default(),
+            allocation: Allocation::default(),
+            incomplete: Incomplete::default(),
+            io: default_io(),
+        }
+    }
+
+    /// create files as allocation says once they're first written to
+    pub fn with_allocation(self, allocation: Allocation) -> DiskStorage {
+        DiskStorage { allocation, ..self }
+    }
+
+    /// the directory the files are in, which [Storage::move_to] keeps their layout relative to
+    pub fn with_dir(self, dir: PathBuf) -> DiskStorage {
+        let dir = RwLock::new(dir);
+        DiskStorage { dir, ..self }
+    }
+
+    /// write files as incomplete says until they're complete, see [Storage::file_completed].
+    /// files which already exist are left where they are. the directory the files are in must
+    /// be set first, see [DiskStorage::with_dir]
+    pub fn with_incomplete(self, incomplete: Incomplete) -> DiskStorage {
+        let layout = self.layout();
+        let dir = self.dir.read().unwrap().clone();
+        let mut files = layout.files.to_vec();
+        let mut finals = vec![None; files.len()];
+        for (file, done) in files.iter_mut().zip(&mut finals) {
+            let path = incomplete_path(&incomplete, &file.path, &dir);
+            if file.padding || path == file.path || exists(&file.path) {
+                continue;
+            }
+            *done = Some(mem::replace(&mut file.path, path));
+        }
+
+        let layout = Arc::new(RwLock::new(Layout {
+            files: files.into(),
+            ..layout
+        }));
+        let finals = Arc::new(RwLock::new(finals));
+        DiskStorage {
+            layout,
+            incomplete,
+            finals,
+            ..self
+        }
+    }
+
+    fn layout(&self) -> Layout {
+        self.layout.read().unwrap().clone()
+    }
+}
+
+impl Storage for DiskStorage {
+    fn read_block(
+        &self,
+        piece: u32,
+        begin: u32,
+        length: u64,
+    ) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        let layout = self.layout();
+        Box::pin(async move {
+            let segments = layout.find(piece, begin, length)?;
+            match self.io.read(layout.files.clone(), segments.clone()).await {
+                // the file was moved since, eg. into place once it was complete
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    let files = self.layout().files;
+                    if Arc::ptr_eq(&files, &layout.files) {
+                        return Err(e);
+                    }
+                    self.io.read(files, segments).await
+                }
+                res => res,
+            }
+        })
+    }
+
+    fn write_block(&self, piece: u32, begin: u32, data: Vec<u8>) -> BoxFuture<'_, io::Result<()>> {
+        let layout = self.layout();
+        Box::pin(async move {
+            let segments = layout.find(piece, begin, data.len() as u64)?;
+            let (files, allocation) = (layout.files, self.allocation);
+            self.io.write(files, segments, data, allocation).await
+        })
+    }
+
+    /// sync every file which exists to disk
+    fn flush(&self) -> BoxFuture<'_, io::Result<()>> {
+        let files = self.layout().files;
+        Box::pin(blocking(move || {
+            for file in files.iter().filter(|f| !f.padding) {
+                match fs::OpenOptions::new().write(true).open(&file.path) {
+                    Ok(f) => f.sync_all()?,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        }))
+    }
+
+    /// files which haven't been created yet are only moved in name. nothing is moved if any
+    /// file would overwrite another
+    fn move_to<'a>(&'a self, dir: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        let (layout, from) = (self.layout(), self.dir.read().unwrap().clone());
+        let moved = |path: &PathBuf| dir.join(path.strip_prefix(&from).unwrap_or(path));
+        let finals = self.finals.read().unwrap().clone();
+        let finals: Vec<_> = finals.iter().map(|f| f.as_ref().map(moved)).collect();
+        // incomplete files stay wherever they're written for where they now belong, which for
+        // an incomplete directory is where they already are
+        let to = |(f, done): (&FileSpan, &Option<PathBuf>)| match done {
+            Some(done) => (f.path.clone(), incomplete_path(&self.incomplete, done, dir)),
+            None => (f.path.clone(), moved(&f.path)),
+        };
+        let moves: Vec<_> = layout.files.iter().zip(&finals).map(to).collect();
+
+        Box::pin(async move {
+            let paths: Vec<_> = moves.iter().map(|(_, to)| to.clone()).collect();
+            blocking(move || move_files(moves)).await?;
+
+            let files = layout.files.iter().zip(paths);
+            let files = files.map(|(f, path)| FileSpan { path, ..f.clone() });
+            let files = files.collect();
+            *self.layout.write().unwrap() = Layout { files, ..layout };
+            *self.finals.write().unwrap() = finals;
+            *self.dir.write().unwrap() = dir.to_path_buf();
+            Ok(())
+        })
+    }
+
+    /// nothing is renamed if any file would overwrite another. directories left empty are
+    /// removed
+    fn rename<'a>(&'a self, renames: &'a [(usize, PathBuf)]) -> BoxFuture<'a, io::Result<()>> {
+        let (layout, dir) = (self.layout(), self.dir.read().unwrap().clone());
+        let mut finals = self.finals.read().unwrap().clone();
+        // incomplete files are renamed where they are, and belong at the new name after
+        let mut paths = vec![];
+        for (i, to) in renames {
+            match finals.get_mut(*i) {
+                Some(Some(done)) => {
+                    paths.push(incomplete_path(&self.incomplete, to, &dir));
+                    *done = to.clone();
+                }
+                _ => paths.push(to.clone()),
+            }
+        }
+        let moves: Option<Vec<_>> = renames
+            .iter()
+            .zip(&paths)
+            .map(|((i, _), to)| Some((layout.files.get(*i)?.path.clone(), to.clone())))
+            .collect();
+
+        Box::pin(async move {
+            let moves = moves.ok_or(io::ErrorKind::InvalidInput)?;
+            let from: Vec<_> = moves.iter().map(|(from, _)| from.clone()).collect();
+            blocking(move || {
+                move_files(moves)?;
+                for path in from {
+                    remove_empty_dirs(&path, &dir);
+                }
+                Ok(())
+            })
+            .await?;
+
+            let mut files = layout.files.to_vec();
+            for ((i, _), path) in renames.iter().zip(paths) {
+                files[*i].path = path;
+            }
+            let files = files.into();
+            *self.layout.write().unwrap() = Layout { files, ..layout };
+            *self.finals.write().unwrap() = finals;
+            Ok(())
+        })
+    }
+
+    /// files which haven't been created yet take up nothing
+    fn sizes(&self) -> Vec<u64> {
+        let files = self.layout().files;
+        let size = |f: &FileSpan| match f.padding {
+            true => 0,
+            false => fs::metadata(&f.path).map_or(0, |m| m.len()),
+        };
+        files.iter().map(size).collect()
+    }
+
+    /// the file is moved into place if it was written elsewhere while it was incomplete, see
+    /// [DiskStorage::with_incomplete], then made executable or hidden where the OS has such a
+    /// thing, see [set_attributes]
+    fn file_completed<'a>(
+        &'a self,
+        file: usize,
+        attrs: &'a [Attr],
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let (layout, finals) = (self.layout.clone(), self.finals.clone());
+        let incomplete_dir = match &self.incomplete {
+            Incomplete::Dir(dir) => dir.clone(),
+            _ => PathBuf::new(),
+        };
+        let executable = attrs.contains(&Attr::Executable);
+        let hidden = attrs.contains(&Attr::Hidden);
+        Box::pin(async move {
+            let path = blocking(move || {
+                // the layout stays locked while the file's moved, so it's never read from
+                // where it no longer is
+                let mut layout = layout.write().unwrap();
+                let span = layout.files.get(file).ok_or(io::ErrorKind::InvalidInput)?;
+                let path = span.path.clone();
+                // empty files are never written to, so they're only created here
+                if span.length == 0 && !span.padding {
+                    open(span, Allocation::Sparse)?;
+                }
+                let Some(done) = finals.read().unwrap()[file].clone() else {
+                    return Ok(path);
+                };
+                move_files(vec![(path.clone(), done.clone())])?;
+                remove_empty_dirs(&path, &incomplete_dir);
+
+                let mut files = layout.files.to_vec();
+                files[file].path = done.clone();
+                layout.files = files.into();
+                finals.write().unwrap()[file] = None;
+                Ok(done)
+            })
+            .await?;
+            blocking(move || set_attributes(&path, executable, hidden)).await
+        })
+    }
+
+    /// links which are already in place are left alone
+    fn symlink<'a>(&'a self, file: usize, target: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        let path = self.layout().files.get(file).map(|f| f.path.clone());
+        let target = target.to_path_buf();
+        Box::pin(blocking(move || {
+            let path = path.ok_or(io::ErrorKind::InvalidInput)?;
+            if fs::read_link(&path).is_ok_and(|old| old == target) {
+                return Ok(());
+            }
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let dir = path.parent().is_some_and(|p| p.join(&target).is_dir());
+            symlink(&target, &path, dir)
+        }))
+    }
+
+    /// create every file at its full length up front, see [Allocation::Full], so a lack of disk
+    /// space is found straight away. files which already exist are left as they are. if any
+    /// can't be allocated, the files and directories created so far are removed again
+    fn preallocate(&self) -> BoxFuture<'_, io::Result<()>> {
+        let files = self.layout().files;
+        Box::pin(blocking(move || {
+            let mut created = vec![];
+            for file in files.iter().filter(|f| !f.padding && !f.path.exists()) {
+                // the directory the file's new directories are created in
+                let root = file.path.ancestors().skip(1).find(|dir| dir.exists());
+                created.push((&file.path, root.unwrap_or(Path::new(""))));
+                if let Err(e) = open(file, Allocation::Full) {
+                    for &(path, _) in &created {
+                        let _ = fs::remove_file(path);
+                    }
+                    for (path, root) in created {
+                        remove_empty_dirs(path, root);
+                    }
+                    return Err(e);
+                }
+            }
+            Ok(())
+        }))
+    }
+}
+
+impl MemoryStorage {
+    pub fn new(files: Vec<FileSpan>, piece_length: u32) -> MemoryStorage {
+        MemoryStorage {
+            files: Mutex::new(vec![vec![]; files.len()]),
+            layout: Layout::new(files, piece_length),
+        }
+    }
+
+    fn read(&self, piece: u32, begin: u32, length: u64) -> io::Result<Vec<u8>> {
+        let segments = self.layout.find(piece, begin, length)?;
+        let files = self.files.lock().unwrap();
+
+        let mut buf = vec![];
+        for segment in segments {
+            let (start, len) = (segment.offset as usize, segment.length as usize);
+            let padding = self.layout.files[segment.file].padding;
+            match (&files[segment.file], padding) {
+                (_, true) => buf.resize(buf.len() + len, 0),
+                (data, false) if data.is_empty() => return Err(io::ErrorKind::NotFound.into()),
+                (data, false) => buf.extend_from_slice(&data[start..][..len]),
+            }
+        }
+        Ok(buf)
+    }
+
+    fn write(&self, piece: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        let segments = self.layout.find(piece, begin, data.len() as u64)?;
+        let mut files = self.files.lock().unwrap();
+
+        let mut written = 0;
+        for segment in segments {
+            let (start, len) = (segment.offset as usize, segment.length as usize);
+            let span = &self.layout.files[segment.file];
+            if !span.padding {
+                let file = &mut files[segment.file];
+                file.resize(span.length as usize, 0);
+                file[start..][..len].copy_from_slice(&data[written..][..len]);
+            }
+            written += len;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read_block(
+        &self,
+        piece: u32,
+        begin: u32,
+        length: u64,
+    ) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(future::ready(self.read(piece, begin, length)))
+    }
+
+    fn write_block(&self, piece: u32, begin: u32, data: Vec<u8>) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(future::ready(self.write(piece, begin, &data)))
+    }
+
+    fn flush(&self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn move_to<'a>(&'a self, _: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn sizes(&self) -> Vec<u64> {
+        let files = self.files.lock().unwrap();
+        files.iter().map(|f| f.len() as u64).collect()
+    }
+}
+
+impl StorageProvider for InMemory {
+    fn storage(&self, _: &Path, files: &[FileSpan], piece_length: u32) -> Arc<dyn Storage> {
+        Arc::new(MemoryStorage::new(files.to_vec(), piece_length))
+    }
+}
+
+impl FileIo for BlockingIo {
+    fn read(
+        &self,
+        files: Arc<[FileSpan]>,
+        segments: Vec<Segment>,
+    ) -> BoxFuture<'static, io::Result<Vec<u8>>> {
+        Box::pin(blocking(move || Self::read_segments(&files, &segments)))
+    }
+
+    fn write(
+        &self,
+        files: Arc<[FileSpan]>,
+        segments: Vec<Segment>,
+        data: Vec<u8>,
+        allocation: Allocation,
+    ) -> BoxFuture<'static, io::Result<()>> {
+        let write = move || Self::write_segments(&files, &segments, &data, allocation);
+        Box::pin(blocking(write))
+    }
+}
+
+impl BlockingIo {
+    fn read_segments(files: &[FileSpan], segments: &[Segment]) -> io::Result<Vec<u8>> {
+        let len = segments.iter().map(|s| s.length).sum::<u64>();
+        let mut buf = vec![0; len as usize];
+
+        let mut filled = 0;
+        for segment in segments {
+            let file = &files[segment.file];
+            let dst = &mut buf[filled..][..segment.length as usize];
+            if !file.padding {
+                let mut f = fs::File::open(&file.path)?;
+                f.seek(SeekFrom::Start(segment.offset))?;
+                f.read_exact(dst)?;
+            }
+            filled += dst.len();
+        }
+
+        Ok(buf)
+    }
+
+    fn write_segments(
+        files: &[FileSpan],
+        segments: &[Segment],
+        data: &[u8],
+        allocation: Allocation,
+    ) -> io::Result<()> {
+        let mut written = 0;
+        for segment in segments {
+            let file = &files[segment.file];
+            let src = &data[written..][..segment.length as usize];
+            if !file.padding {
+                let mut f = open(file, allocation)?;
+                f.seek(SeekFrom::Start(segment.offset))?;
+                f.write_all(src)?;
+            }
+            written += src.len();
+        }
+
+        Ok(())
+    }
+}
+
+// run f on tokio's blocking pool
+pub(crate) async fn blocking<T, F>(f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    match task::spawn_blocking(f).await {
+        Ok(res) => res,
+        Err(_) => Err(io::ErrorKind::Other.into()),
+    }
+}
+
+// move each file from one path to the other, creating directories as needed. files which
+// don't exist, or stay where they are, are skipped. if any can't be moved, the ones moved so
+// far are moved back
+fn move_files(mut moves: Vec<(PathBuf, PathBuf)>) -> io::Result<()> {
+    moves.retain(|(from, to)| from != to);
+    // don't start moving anything if we'd have to overwrite some other file
+    if let Some((_, to)) = moves.iter().find(|(_, to)| exists(to)) {
+        let err = io::Error::new(io::ErrorKind::AlreadyExists, to.display().to_string());
+        return Err(err);
+    }
+
+    let moves: Vec<_> = moves.into_iter().filter(|(from, _)| exists(from)).collect();
+    for (i, (from, to)) in moves.iter().enumerate() {
+        if let Err(e) = move_file(from, to) {
+            for (from, to) in moves[..i].iter().rev() {
+                let _ = move_file(to, from);
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// move the file at from to to, creating to's directory as needed. nothing's left at to if it
+// can't be moved
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // rename fails across filesystems, fall back to copying
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    let copied = match fs::read_link(from) {
+        Ok(target) => symlink(&target, to, from.is_dir()),
+        Err(_) => fs::copy(from, to).map(drop),
+    };
+    let res = copied.and_then(|_| fs::remove_file(from));
+    if res.is_err() {
+        let _ = fs::remove_file(to);
+    }
+    res
+}
+
+// whether there's a file at path. symlinks count, whether or not what they link to exists yet
+fn exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+// where the file at path, in dir, is written while it's incomplete
+fn incomplete_path(incomplete: &Incomplete, path: &Path, dir: &Path) -> PathBuf {
+    match incomplete {
+        Incomplete::InPlace => path.to_path_buf(),
+        Incomplete::PartSuffix => {
+            let mut path = path.as_os_str().to_owned();
+            path.push(".part");
+            path.into()
+        }
+        Incomplete::Dir(incomplete) => incomplete.join(path.strip_prefix(dir).unwrap_or(path)),
+    }
+}
+
+// create a symlink at path to target. windows needs to know whether target is a directory
+fn symlink(target: &Path, path: &Path, dir: bool) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let _ = dir;
+        std::os::unix::fs::symlink(target, path)
+    }
+
+    #[cfg(windows)]
+    match dir {
+        true => std::os::windows::fs::symlink_dir(target, path),
+        false => std::os::windows::fs::symlink_file(target, path),
+    }
+}
+
+// remove the directories path was in which are now empty, up to but not including root
+fn remove_empty_dirs(path: &Path, root: &Path) {
+    if root.as_os_str().is_empty() {
+        return;
+    }
+    let dirs = path.ancestors().skip(1);
+    for dir in dirs.take_while(|&dir| dir.starts_with(root) && dir != root) {
+        if fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+// mark the file at path executable or hidden. unix files are hidden by their name, which the
+// torrent chose, and windows ones can't be made executable
+fn set_attributes(path: &Path, executable: bool, hidden: bool) -> io::Result<()> {
+    #[cfg(unix)]
+    if executable {
+        use std::os::unix::fs::PermissionsExt;
+
+        // whoever can read the file can run it, like chmod +x
+        let mut permissions = fs::metadata(path)?.permissions();
+        let mode = permissions.mode();
+        permissions.set_mode(mode | ((mode & 0o444) >> 2));
+        fs::set_permissions(path, permissions)?;
+    }
+
+    #[cfg(windows)]
+    if hidden {
+        use std::os::windows::{ffi::OsStrExt, fs::MetadataExt};
+
+        use windows_sys::Win32::Storage::FileSystem::{SetFileAttributesW, FILE_ATTRIBUTE_HIDDEN};
+
+        let attrs = fs::metadata(path)?.file_attributes() | FILE_ATTRIBUTE_HIDDEN;
+        let path: Vec<u16> = path.as_os_str().encode_wide().chain([0]).collect();
+        // SAFETY: path is a nul terminated wide string which outlives the call
+        if unsafe { SetFileAttributesW(path.as_ptr(), attrs) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    // neither applies everywhere
+    let _ = (path, executable, hidden);
+    Ok(())
+}
+
+// io_uring where it's enabled and the kernel supports it, the blocking pool otherwise
+fn default_io() -> Arc<dyn FileIo> {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    {
+        if let Some(uring) = crate::uring::shared() {
+            return uring;
+        }
+    }
+    Arc::new(BlockingIo)
+}
+
+/// open file for writing, creating and allocating it, and its directory, if it doesn't exist
+/// yet. a file created by another write in the meantime is left as it is
+pub(crate) fn open(file: &FileSpan, allocation: Allocation) -> io::Result<fs::File> {
+    let open = || fs::OpenOptions::new().write(true).open(&file.path);
+    match open() {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        res => return res,
+    }
+
+    if let Some(dir) = file.path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let create = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&file.path);
+    let mut f = match create {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return open(),
+        res => res?,
+    };
+    match allocation {
+        // extending the file leaves a hole, which takes up no space until it's written
+        Allocation::Sparse => f.set_len(file.length)?,
+        Allocation::Full => allocate(&mut f, file.length)?,
+    }
+    Ok(f)
+}
+
+// reserve len bytes of disk for f, which is empty, so writing to it later can't run out of
+// space. filesystems which can't do so directly have the file filled with zeros instead
+fn allocate(f: &mut fs::File, len: u64) -> io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let len = libc::off_t::try_from(len).map_err(|_| io::ErrorKind::InvalidInput)?;
+        // SAFETY: the fd is open for as long as f is borrowed
+        match unsafe { libc::posix_fallocate(f.as_raw_fd(), 0, len) } {
+            0 => return Ok(()),
+            libc::EOPNOTSUPP | libc::EINVAL => {}
+            err => return Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+
+    // extending a file which isn't sparse allocates it on windows, set_len does so with
+    // SetFileInformationByHandle
+    #[cfg(windows)]
+    return f.set_len(len);
+
+    #[cfg(not(windows))]
+    {
+        let zeros = [0; ZEROS];
+        let mut left = len;
+        while left > 0 {
+            let n = left.min(ZEROS as u64) as usize;
+            f.write_all(&zeros[..n])?;
+            left -= n as u64;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        path::{Path, PathBuf},
+        process,
+    };
+
+    use crate::{
+        config::{Allocation, Incomplete},
+        storage::{Attr, DiskStorage, FileSpan, Layout, MemoryStorage, Segment, Storage},
+    };
+
+    #[tokio::test]
+    async fn storage() {
+        let dir = env::temp_dir().join(format!("tsunami_storage_{}", process::id()));
+        let span = |name, length, padding| FileSpan {
+            path: dir.join(name),
+            length,
+            padding,
+        };
+        let files = vec![
+            span("a", 6, false),
+            span("empty", 0, false),
+            span("pad", 2, true),
+            span("sub/b", 4, false),
+        ];
+        let layout = Layout::new(files.clone(), 4);
+
+        // ranges are split wherever a file ends, empty files are skipped
+        let segment = |file, offset, length| Segment {
+            file,
+            offset,
+            length,
+        };
+        let expected = vec![segment(0, 5, 1), segment(2, 0, 2)];
+        assert_eq!(layout.segments(1, 1, 4), None);
+        assert_eq!(layout.segments(1, 1, 3), Some(expected));
+        assert_eq!(layout.segments(2, 0, 4), Some(vec![segment(3, 0, 4)]));
+        assert_eq!(layout.piece_size(2), Some(4));
+        assert_eq!(layout.piece_size(3), None);
+
+        // files and directories are created on demand, padding never is
+        let storage = DiskStorage::new(files, 4).with_dir(dir.clone());
+        storage.write_block(2, 1, b"jkl".to_vec()).await.unwrap();
+        storage.write_block(0, 0, b"abcd".to_vec()).await.unwrap();
+        storage.write_block(1, 0, b"ef\0\0".to_vec()).await.unwrap();
+        storage.flush().await.unwrap();
+        assert_eq!(storage.read_block(1, 1, 3).await.unwrap(), b"f\0\0");
+        assert_eq!(fs::read(dir.join("a")).unwrap(), b"abcdef");
+        assert_eq!(fs::read(dir.join("sub/b")).unwrap(), b"\0jkl");
+        assert!(!dir.join("pad").exists());
+        assert!(storage.write_block(2, 0, b"ijklm".to_vec()).await.is_err());
+        assert_eq!(storage.sizes(), [6, 0, 0, 4]);
+
+        // files are moved along with the directory they're in, and read from there after
+        let moved = dir.join("moved");
+        storage.move_to(&moved).await.unwrap();
+        assert_eq!(fs::read(moved.join("sub/b")).unwrap(), b"\0jkl");
+        assert!(!dir.join("a").exists());
+        assert_eq!(storage.read_block(0, 0, 4).await.unwrap(), b"abcd");
+
+        // as are renamed ones, directories left empty are removed
+        storage.rename(&[(3, moved.join("c"))]).await.unwrap();
+        assert!(!moved.join("sub").exists());
+        assert_eq!(storage.read_block(2, 0, 4).await.unwrap(), b"\0jkl");
+        assert!(storage.rename(&[(0, moved.join("c"))]).await.is_err());
+        // a file can't be moved beneath one that was just moved, so that one's moved back
+        let renames = [(0, moved.join("d")), (3, moved.join("d/e"))];
+        assert!(storage.rename(&renames).await.is_err());
+        assert!(moved.join("a").exists() && !moved.join("d").exists());
+        assert_eq!(storage.read_block(0, 0, 4).await.unwrap(), b"abcd");
+        fs::write(dir.join("a"), b"").unwrap();
+        assert!(storage.move_to(&dir).await.is_err());
+        assert!(moved.join("a").exists());
+
+        // completed files take on their attributes where the OS has them
+        let attrs = [Attr::Executable, Attr::Hidden];
+        storage.file_completed(0, &attrs).await.unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(moved.join("a")).unwrap().permissions().mode();
+            assert_eq!(mode & 0o111, (mode & 0o444) >> 2);
+        }
+        assert!(storage.file_completed(4, &attrs).await.is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn allocation() {
+        let dir = env::temp_dir().join(format!("tsunami_allocation_{}", process::id()));
+        let files = |name| {
+            let span = FileSpan {
+                path: dir.join(name),
+                length: 200 * 1024,
+                padding: false,
+            };
+            vec![span]
+        };
+
+        // files are created at their full length either way, but sparse ones only take up the
+        // space which has been written
+        for (name, allocation) in [("sparse", Allocation::Sparse), ("full", Allocation::Full)] {
+            let storage = DiskStorage::new(files(name), 16 * 1024).with_allocation(allocation);
+            storage.write_block(1, 0, vec![7; 10]).await.unwrap();
+            let data = fs::read(dir.join(name)).unwrap();
+            assert_eq!(data.len(), 200 * 1024);
+            assert_eq!(data[16 * 1024..][..11], [7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 0]);
+        }
+
+        // a file which already exists isn't touched
+        let storage = DiskStorage::new(files("full"), 16 * 1024);
+        let storage = storage.with_allocation(Allocation::Full);
+        storage.write_block(0, 0, vec![1; 10]).await.unwrap();
+        let data = fs::read(dir.join("full")).unwrap();
+        assert_eq!(data[..10], [1; 10]);
+        assert_eq!(data[16 * 1024], 7);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn preallocate() {
+        let dir = env::temp_dir().join(format!("tsunami_preallocate_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("b"), b"old").unwrap();
+        let span = |name, length, padding| FileSpan {
+            path: dir.join(name),
+            length,
+            padding,
+        };
+
+        // every missing file is created at its full length, existing ones are left alone
+        let files = vec![
+            span("a", 100, false),
+            span("pad", 28, true),
+            span("b", 50, false),
+            span("sub/c", 10, false),
+        ];
+        DiskStorage::new(files, 16).preallocate().await.unwrap();
+        assert_eq!(fs::read(dir.join("a")).unwrap(), [0; 100]);
+        assert_eq!(fs::read(dir.join("b")).unwrap(), b"old");
+        assert_eq!(fs::read(dir.join("sub/c")).unwrap(), [0; 10]);
+        assert!(!dir.join("pad").exists());
+
+        // nothing is left behind if a file can't be created
+        let files = vec![
+            span("d", 10, false),
+            span("new/sub/f", 10, false),
+            span("sub/g", 10, false),
+            span("b/e", 10, false),
+        ];
+        assert!(DiskStorage::new(files, 16).preallocate().await.is_err());
+        assert!(!dir.join("d").exists());
+        assert!(!dir.join("new").exists());
+        assert!(!dir.join("sub/g").exists());
+        assert!(dir.join("sub/c").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn incomplete() {
+        let dir = env::temp_dir().join(format!("tsunami_incomplete_{}", process::id()));
+        let span = |name, length| FileSpan {
+            path: dir.join(name),
+            length,
+            padding: false,
+        };
+        let files = vec![span("a", 4), span("sub/b", 4), span("empty", 0)];
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/b"), b"efgh").unwrap();
+
+        // files are written with a suffix until they're complete, unless they're already there
+        let storage = DiskStorage::new(files.clone(), 4).with_dir(dir.clone());
+        let storage = storage.with_incomplete(Incomplete::PartSuffix);
+        storage.write_block(0, 0, b"abcd".to_vec()).await.unwrap();
+        storage.write_block(1, 0, b"EFGH".to_vec()).await.unwrap();
+        assert_eq!(fs::read(dir.join("a.part")).unwrap(), b"abcd");
+        assert!(!dir.join("a").exists());
+        assert_eq!(fs::read(dir.join("sub/b")).unwrap(), b"EFGH");
+        storage.file_completed(0, &[]).await.unwrap();
+        storage.file_completed(1, &[]).await.unwrap();
+        assert_eq!(fs::read(dir.join("a")).unwrap(), b"abcd");
+        assert!(!dir.join("a.part").exists());
+        assert_eq!(storage.read_block(0, 0, 4).await.unwrap(), b"abcd");
+        // empty files are created once they're complete, which they always are
+        storage.file_completed(2, &[]).await.unwrap();
+        assert_eq!(fs::read(dir.join("empty")).unwrap(), b"");
+        assert!(!dir.join("empty.part").exists());
+        fs::remove_dir_all(&dir).unwrap();
+
+        // or in a directory of their own, where they're renamed, and left when the rest are
+        // moved
+        let part = dir.join("part");
+        let storage = DiskStorage::new(files, 4).with_dir(dir.clone());
+        let storage = storage.with_incomplete(Incomplete::Dir(part.clone()));
+        storage.write_block(1, 0, b"efgh".to_vec()).await.unwrap();
+        assert_eq!(fs::read(part.join("sub/b")).unwrap(), b"efgh");
+        storage.rename(&[(1, dir.join("c"))]).await.unwrap();
+        assert_eq!(fs::read(part.join("c")).unwrap(), b"efgh");
+        let moved = dir.join("moved");
+        storage.move_to(&moved).await.unwrap();
+        assert_eq!(storage.read_block(1, 0, 4).await.unwrap(), b"efgh");
+        assert!(part.join("c").exists());
+        storage.file_completed(1, &[]).await.unwrap();
+        assert_eq!(fs::read(moved.join("c")).unwrap(), b"efgh");
+        assert!(!part.join("c").exists());
+        assert_eq!(storage.read_block(1, 0, 4).await.unwrap(), b"efgh");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlink() {
+        let dir = env::temp_dir().join(format!("tsunami_symlink_{}", process::id()));
+        let span = |name, length, padding| FileSpan {
+            path: dir.join(name),
+            length,
+            padding,
+        };
+        let files = vec![span("a", 4, false), span("sub/link", 0, true)];
+        let storage = DiskStorage::new(files, 4).with_dir(dir.clone());
+
+        // links are created before what they link to, and left alone once they're in place
+        let target = Path::new("../a");
+        storage.symlink(1, target).await.unwrap();
+        storage.write_block(0, 0, b"abcd".to_vec()).await.unwrap();
+        storage.symlink(1, target).await.unwrap();
+        assert_eq!(fs::read(dir.join("sub/link")).unwrap(), b"abcd");
+        assert!(storage.symlink(0, target).await.is_err());
+
+        // they're moved themselves, and still point at the same file after
+        let moved = dir.join("moved");
+        storage.move_to(&moved).await.unwrap();
+        assert_eq!(fs::read_link(moved.join("sub/link")).unwrap(), target);
+        assert_eq!(fs::read(moved.join("sub/link")).unwrap(), b"abcd");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory() {
+        let span = |length, padding| FileSpan {
+            path: PathBuf::new(),
+            length,
+            padding,
+        };
+        let files = vec![span(6, false), span(2, true), span(4, false)];
+        let storage = MemoryStorage::new(files, 4);
+
+        // files are created whole as they're first written to, padding never is
+        assert!(storage.read_block(0, 0, 4).await.is_err());
+        storage.write_block(1, 0, b"ef\0\0".to_vec()).await.unwrap();
+        assert_eq!(storage.sizes(), [6, 0, 0]);
+        assert_eq!(storage.read_block(0, 0, 4).await.unwrap(), [0; 4]);
+        storage.write_block(0, 0, b"abcd".to_vec()).await.unwrap();
+        storage.write_block(2, 1, b"jkl".to_vec()).await.unwrap();
+        assert_eq!(storage.read_block(1, 1, 3).await.unwrap(), b"f\0\0");
+        assert_eq!(storage.read_block(2, 0, 4).await.unwrap(), b"\0jkl");
+        assert_eq!(storage.sizes(), [6, 0, 4]);
+        assert!(storage.write_block(2, 0, b"ijklm".to_vec()).await.is_err());
+
+        // there's nothing on disk to move or flush
+        storage.move_to(&PathBuf::from("/bar")).await.unwrap();
+        storage.flush().await.unwrap();
+        assert_eq!(storage.read_block(0, 0, 4).await.unwrap(), b"abcd");
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use bitvec::prelude::{bitbox, BitBox};
+
+use crate::request_queue::{Block, BLOCK_LEN};
+
+// pieces due within this long, or overdue, have their blocks requested from several peers at
+// once, whichever sends a block first wins and the other requests are cancelled
+const URGENT: Duration = Duration::from_secs(2);
+// number of peers each block of an urgent piece is requested from
+const URGENT_COPIES: usize = 2;
+
+/// PieceDeadlines is the pieces a reader wants by a certain time, eg. the next few seconds of a
+/// video being played while it downloads, see [crate::torrent::Torrent::set_piece_deadline].
+/// their blocks are requested ahead of everything else, soonest deadline first, from the fastest
+/// peers. a piece's deadline is dropped once all of its blocks have arrived
+#[derive(Debug, Default)]
+pub(crate) struct PieceDeadlines {
+    pieces: HashMap<u32, Pending>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    deadline: Instant,
+    length: u32,
+    // blocks which have arrived
+    received: BitBox,
+}
+
+impl PieceDeadlines {
+    /// want piece index, which is length bytes long, by deadline. a piece which already has a
+    /// deadline keeps the blocks it's received
+    pub(crate) fn set(&mut self, index: u32, length: u32, deadline: Instant) {
+        let blocks = length.div_ceil(BLOCK_LEN) as usize;
+        let pending = self.pieces.entry(index).or_insert_with(|| Pending {
+            deadline,
+            length,
+            received: bitbox![0; blocks],
+        });
+        pending.deadline = deadline;
+    }
+
+    /// stop hurrying piece index, returning whether it had a deadline
+    pub(crate) fn clear(&mut self, index: u32) -> bool {
+        self.pieces.remove(&index).is_some()
+    }
+
+    pub(crate) fn deadline(&self, index: u32) -> Option<Instant> {
+        self.pieces.get(&index).map(|p| p.deadline)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pieces.is_empty()
+    }
+
+    /// record a block a peer sent, returning whether it's one of a piece with a deadline
+    pub(crate) fn received(&mut self, block: Block) -> bool {
+        let Some(pending) = self.pieces.get_mut(&block.index) else {
+            return false;
+        };
+        let i = (block.begin / BLOCK_LEN) as usize;
+        if i >= pending.received.len() || pending.block(block.index, i) != block {
+            return false;
+        }
+
+        pending.received.set(i, true);
+        if pending.received.all() {
+            self.pieces.remove(&block.index);
+        }
+        true
+    }
+
+    /// the blocks which haven't arrived yet, soonest deadline first, each with the number of
+    /// peers it should be requested from at now
+    pub(crate) fn wanted(&self, now: Instant) -> Vec<(Block, usize)> {
+        let mut pieces: Vec<_> = self.pieces.iter().collect();
+        pieces.sort_by_key(|&(&index, p)| (p.deadline, index));
+
+        let blocks = pieces.into_iter().flat_map(|(&index, pending)| {
+            let copies = match pending.deadline.saturating_duration_since(now) < URGENT {
+                true => URGENT_COPIES,
+                false => 1,
+            };
+            let missing = pending.received.iter_zeros();
+            missing.map(move |i| (pending.block(index, i), copies))
+        });
+        blocks.collect()
+    }
+}
+
+impl Pending {
+    // the i'th block of the piece, the last one may be short
+    fn block(&self, index: u32, i: usize) -> Block {
+        let begin = i as u32 * BLOCK_LEN;
+        Block {
+            index,
+            begin,
+            length: (self.length - begin).min(BLOCK_LEN),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::{
+        request_queue::{Block, BLOCK_LEN},
+        streaming::PieceDeadlines,
+    };
+
+    #[test]
+    fn deadlines() {
+        let now = Instant::now();
+        let mut deadlines = PieceDeadlines::default();
+        deadlines.set(3, BLOCK_LEN + 10, now + Duration::from_secs(10));
+        deadlines.set(7, 10, now + Duration::from_secs(1));
+
+        let block = |index, begin, length| Block {
+            index,
+            begin,
+            length,
+        };
+        // the piece due soonest comes first, and is asked of two peers since it's nearly due
+        let wanted = vec![
+            (block(7, 0, 10), 2),
+            (block(3, 0, BLOCK_LEN), 1),
+            (block(3, BLOCK_LEN, 10), 1),
+        ];
+        assert_eq!(deadlines.wanted(now), wanted);
+
+        // blocks which arrive aren't asked for again, and a complete piece is dropped
+        assert!(deadlines.received(block(3, 0, BLOCK_LEN)));
+        assert!(!deadlines.received(block(3, 5, 10)));
+        assert!(!deadlines.received(block(3, BLOCK_LEN, 11)));
+        assert!(!deadlines.received(block(4, 0, 10)));
+        assert!(deadlines.received(block(7, 0, 10)));
+        assert_eq!(deadlines.deadline(7), None);
+        assert_eq!(deadlines.wanted(now), vec![(block(3, BLOCK_LEN, 10), 1)]);
+
+        // moving a deadline keeps what's arrived
+        deadlines.set(3, BLOCK_LEN + 10, now);
+        assert_eq!(deadlines.wanted(now), vec![(block(3, BLOCK_LEN, 10), 2)]);
+        assert!(deadlines.clear(3));
+        assert!(!deadlines.clear(3));
+        assert!(deadlines.is_empty());
+    }
+}
@@ -1,43 +1,321 @@
 use std::{
-    collections::HashMap,
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
     fmt::Write,
+    future::Future,
+    io,
     iter::once,
-    net::{Ipv4Addr, SocketAddrV4},
-    path::{Path, PathBuf},
-    sync::Arc,
+    mem,
+    net::{IpAddr, SocketAddr},
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
 };
 
-use byteorder::{ByteOrder, BE};
+use bitvec::prelude::{bitbox, Msb0};
 use chrono::{DateTime, Duration, Utc};
-use hyper::body::Bytes;
-use rand::{rngs::SmallRng, seq::SliceRandom, SeedableRng};
+use futures::future::{self, join_all, BoxFuture};
+use hyper::{body::Bytes, http::request::Builder, Body, Request, Uri};
+use rand::{rngs::SmallRng, seq::SliceRandom, Rng, SeedableRng};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 
 use crate::{
-    error::{Error, Result},
-    peer::Peer,
+    block_scheduler::{BlockScheduler, BLOCK_TIMEOUT},
+    choker::{Candidate, Choker},
+    client::ClientInfo,
+    config::{
+        AddTorrentOptions, Allocation, AnnouncePolicy, Config, ConflictPolicy, SanitizePolicy,
+        TrackerAuth, UploadSlots,
+    },
+    connection_limits::{ConnectionLimits, ConnectionPermit},
+    disk::{DiskReader, ReadCache, CACHE_SIZE},
+    error::{CommandError, Error, Result, TorrentParseError},
+    events::{Event, EventSender},
+    extension::{
+        ExtensionHandshake, Holepunch, HolepunchError, PexFlags, PexMessage, LT_DONTHAVE,
+        MAX_PEX_PEERS, UT_HOLEPUNCH, UT_PEX,
+    },
+    handle::{self, Command, Reply, TorrentHandle},
+    hasher::{Hasher, PieceCheck},
+    listener::LISTEN_PORT,
+    merkle::{FileTree, HashRequest, PieceHash, Sha256Hash},
+    peer::{Bitfield, Message, Peer, PeerId, ReservedBits, Timeouts},
+    peer_class::PeerClass,
+    peer_handle::{PeerEvent, PeerHandle},
+    peer_store::{PeerSources, PeerStore},
+    picker::{FilePriority, PiecePicker},
+    proxy::Dialer,
+    request_queue::{Block, SNUB_TIMEOUT},
+    resume::ResumeData,
+    smart_ban::{BanList, SmartBan},
+    stats::{PeerStats, TorrentStats, TransferStats},
+    storage::{Attr, DiskStorage, FileSpan, Storage},
+    streaming::PieceDeadlines,
     torrent_ast::{Bencode, InfoAST, TorrentAST},
-    utils,
+    tracker::{self, AnnounceReq, Announcer, TrackerProtocol},
+    utils::{self, HttpClient, PercentEncode},
+    web_seed::{self, WebSeed},
 };
 
 pub type Sha1Hash = [u8; 20];
 
+// piece lengths accepted unless AddTorrentOptions::lenient_piece_length is set
+const MIN_PIECE_LENGTH: u32 = 16 * 1024;
+const MAX_PIECE_LENGTH: u32 = 128 * 1024 * 1024;
+
+// delay before retrying a tracker after its first failed announce, doubled for every failure
+// after that up to RETRY_MAX
+const RETRY_BASE: i64 = 15;
+const RETRY_MAX: i64 = 30 * 60;
+// bounds on the seconds between announces, whatever interval trackers ask for
+const MIN_ANNOUNCE_INTERVAL: u64 = 5 * 60;
+const MAX_ANNOUNCE_INTERVAL: u64 = 24 * 60 * 60;
+
+// number of recently working peers remembered in resume data
+const MAX_WARM_PEERS: usize = 50;
+// bytes of part downloaded pieces kept in resume data, see [BlockScheduler::partial]
+const MAX_PARTIAL: usize = 4 * 1024 * 1024;
+
+// seconds between PEX messages, BEP-11 asks for no more than one a minute
+const PEX_INTERVAL: i64 = 60;
+
+// seconds between choosing which peers get our upload slots
+const RECHOKE_INTERVAL: i64 = 10;
+// seconds a peer keeps an optimistic unchoke before another is picked
+const OPTIMISTIC_INTERVAL: i64 = 30;
+
+// seconds between asking peers for the v2 piece hashes we're missing
+const HASH_REQUEST_INTERVAL: i64 = 10;
+
+// bytes of complete pieces waiting to be checked and written before we stop requesting more,
+// unless Config::max_write_queue says otherwise
+const MAX_WRITE_QUEUE: u64 = 64 * 1024 * 1024;
+
+// most relays asked to introduce us to the same peer, see [Torrent::rendezvous]
+const MAX_HOLEPUNCH_RELAYS: usize = 3;
+// connection attempts made to a peer a relay introduced us to, and the delay between them
+const HOLEPUNCH_ATTEMPTS: u32 = 3;
+const HOLEPUNCH_RETRY: std::time::Duration = std::time::Duration::from_secs(1);
+
+// seconds before a peer we couldn't connect to is dialed again
+const REDIAL_INTERVAL: i64 = 60;
+
 /// Torrent keeps a torrents metadata in a more workable format
 #[derive(Debug)]
 pub struct Torrent {
     info: Info,
-    peers: HashMap<SocketAddrV4, Option<Peer>>,
+    // the original metainfo file. this is kept around so unknown keys survive re-encoding
+    metainfo: Vec<u8>,
+    peers: PeerStore,
+    // peers we most recently connected to successfully, oldest first
+    recent_peers: Vec<SocketAddr>,
+    // peers connected at the last PEX round and the ones of them we advertised, see
+    // [Torrent::send_pex]
+    pex_connected: HashSet<SocketAddr>,
+    pex_advertised: HashSet<SocketAddr>,
+    next_pex: DateTime<Utc>,
+    // decides which peers we upload to, see [Torrent::rechoke]
+    choker: Choker,
+    next_rechoke: DateTime<Utc>,
+    // the choked peer given a slot on top of the others, see [Choker::optimistic_unchoke]
+    optimistic: Option<SocketAddr>,
+    next_optimistic: DateTime<Utc>,
+    // when we next ask for missing piece hashes, see [Torrent::request_hashes]
+    next_hash_request: DateTime<Utc>,
+    // peers we asked relays to introduce us to and the relays asked so far, see
+    // [Torrent::rendezvous]
+    holepunches: HashMap<SocketAddr, Vec<SocketAddr>>,
+    // peers being dialed, and the peers we couldn't connect to with when to dial them again,
+    // see [Torrent::connect_peers]
+    dialing: HashSet<SocketAddr>,
+    redial_at: HashMap<SocketAddr, DateTime<Utc>>,
+    // who sent the pieces we haven't verified yet, and the session's peers banned for sending
+    // corrupt ones
+    smart_ban: SmartBan,
+    ban_list: BanList,
+    // how many peers have each piece and which we're after, the blocks of the pieces being
+    // downloaded, and pieces wanted by a certain time, see [Torrent::set_piece_deadline]
+    picker: PiecePicker,
+    scheduler: BlockScheduler,
+    deadlines: PieceDeadlines,
+    // pieces which passed their hash check, sent to peers as they connect
+    have: Bitfield,
+    // how much each of info.files is wanted, see [Torrent::set_file_priority]
+    file_priorities: Vec<FilePriority>,
+    // http servers with a copy of the torrent's files, see [Torrent::request_web_seeds]
+    web_seeds: Vec<WebSeed>,
 
     // trackers is a group of one or more trackers followed by an optional list of backup groups.
-    // this will always contain at least one tracker (`announce_list[0][0]`)
+    // this is empty for trackerless torrents, which must rely on DHT/PEX/LSD for peers
     //
     // example: vec![ vec!["tracker1", "tr2"], vec!["backup1"] ]
     trackers: Vec<Vec<String>>,
     next_announce: DateTime<Utc>,
+    announce_policy: AnnouncePolicy,
+    announcer: Announcer,
+    // seeder and leecher counts from the last announce which included them
+    swarm: Option<SwarmStats>,
+    // trackers whose last announce failed, keyed by url
+    tracker_status: HashMap<String, TrackerStatus>,
+    // whether trackers have been sent the started/completed events, see [AnnounceEvent]
+    announced_started: bool,
+    announced_completed: bool,
+    announced_paused: bool,
+    // we have every piece we want but not the whole torrent, see [Torrent::set_partial_seed]
+    partial_seed: bool,
 
-    peer_id: Arc<String>,
+    config: Arc<Config>,
+    state: State,
+    // a resume sent through a handle, waiting on the session to check it fits in the disk
+    // quota, see [Torrent::take_resume]
+    pending_resume: Option<Reply>,
+    // commands sent from this torrent's handles
+    handle: TorrentHandle,
+    commands: UnboundedReceiver<Command>,
+    // events from the tasks driving our peer connections, see [PeerHandle]
+    peer_events: UnboundedReceiver<PeerEvent>,
+    peer_events_tx: UnboundedSender<PeerEvent>,
+    // session events, None until the torrent is added to a session
+    events: Option<EventSender>,
+    external_ip: ExternalIp,
+    // port we accept peers on, which may change once the session binds its listener
+    listen_port: Arc<AtomicU16>,
+    // caps on the session's peer connections, shared by every torrent
+    connection_limits: ConnectionLimits,
+    // directory this torrent's files are downloaded into, see [Torrent::move_storage]
+    base_dir: PathBuf,
+    // how names of renamed files are sanitized, see [AddTorrentOptions::sanitize]
+    sanitize: SanitizePolicy,
+    // paths of the files which were renamed, relative to base_dir, see [Torrent::rename]
+    renamed: BTreeMap<usize, PathBuf>,
+    // reads the blocks our peers ask for, see [Torrent::serve_uploads]
+    disk: Arc<DiskReader>,
+    // keeps the pieces disk read last, shared with the rest of the session
+    read_cache: ReadCache,
+    // how files are created on disk, see [AddTorrentOptions::allocation]
+    allocation: Allocation,
+    // checks pieces against their hashes, shared with the rest of the session
+    hasher: Hasher,
+    // bytes of complete pieces being checked and written, see [Config::max_write_queue]
+    writing: u64,
+    // existing data was found on disk when the torrent was added and must be verified before
+    // downloading
+    recheck: bool,
+    // number of pieces checked so far while a recheck is running, see [Torrent::force_recheck]
+    checking: Option<u32>,
+    // local address peer and tracker connections are bound to
+    bind_address: Option<IpAddr>,
+    // opens our peer connections, bound to bind_address and through Config::peer_proxy
+    dialer: Dialer,
+    // client for tracker requests, bound to bind_address. this is the session's client unless
+    // this torrent binds to a different address
+    http: HttpClient,
+    peer_id: Arc<PeerId>,
+    // bytes of the files we want in pieces we want and don't have, see [Torrent::update_left]
     bytes_left: u64,
+    // bytes of the files we want in each piece, see [Torrent::count_wanted]
+    piece_wanted: Vec<u64>,
     uploaded: u64,
     downloaded: u64,
+    // rates of the piece data sent and verified, see [Torrent::stats]
+    transfer: TransferStats,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Active,
+    Paused,
+    // all peer connections are closed, see [Torrent::stop]
+    Stopped,
+}
+
+/// TrackerStatus tracks a tracker's failed announces so it isn't retried too soon
+#[derive(Debug)]
+struct TrackerStatus {
+    failures: u32,
+    retry_at: DateTime<Utc>,
+    // why the last announce failed
+    error: Error,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct AnnounceResp {
+    pub(crate) interval: u64,
+    pub(crate) min_interval: Option<u64>,
+    pub(crate) stats: Option<SwarmStats>,
+    pub(crate) peers: Vec<SocketAddr>,
+    // the announce succeeded, but the tracker had something to tell us
+    pub(crate) warning: Option<String>,
+    // our address as seen by the tracker, see BEP-24
+    pub(crate) external_ip: Option<IpAddr>,
+}
+
+/// Announced is the outcome of an announce built by [Torrent::prepare_announce]: the event it
+/// carried, and every tracker it was sent to along with that tracker's response
+#[derive(Debug)]
+pub(crate) struct Announced {
+    event: Option<AnnounceEvent>,
+    resps: Vec<(String, Result<AnnounceResp>)>,
+}
+
+/// ExternalIp is our public address as last reported by a tracker. it's shared by every torrent
+/// in a session
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ExternalIp(Arc<Mutex<Option<IpAddr>>>);
+
+impl ExternalIp {
+    pub(crate) fn get(&self) -> Option<IpAddr> {
+        *self.0.lock().unwrap()
+    }
+
+    fn set(&self, ip: IpAddr) {
+        *self.0.lock().unwrap() = Some(ip);
+    }
+}
+
+/// SwarmStats are the number of peers in a torrent's swarm, as reported by its tracker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwarmStats {
+    pub seeders: u64,
+    pub leechers: u64,
+}
+
+/// AnnounceEvent is sent to trackers to inform them of a change in a torrent's status
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    // first announce after the torrent is started
+    Started,
+    // the download finished. this isn't sent if the torrent was already complete when started
+    Completed,
+    // the torrent is being stopped or removed
+    Stopped,
+    // we're a partial seed, sent in place of started and with every announce after, BEP-21
+    Paused,
+}
+
+impl AnnounceEvent {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Completed => "completed",
+            AnnounceEvent::Stopped => "stopped",
+            AnnounceEvent::Paused => "paused",
+        }
+    }
+}
+
+/// swarm statistics for a torrent, as reported by a tracker's scrape endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeData {
+    // peers with the complete torrent
+    pub seeders: u64,
+    // peers still downloading
+    pub leechers: u64,
+    // number of times the tracker has seen the torrent completed
+    pub completed: u64,
 }
 
 #[derive(Debug, PartialEq)]
@@ -47,10 +325,22 @@ struct Info {
     piece_length: u32,
     pieces: Vec<Sha1Hash>,
     info_hash: Sha1Hash,
+    // merkle trees of v2 files keyed by pieces root, empty for v1 torrents
+    file_trees: HashMap<Sha256Hash, FileTree>,
+    // v2 files with a pieces root in the order their pieces are laid out
+    v2_files: Vec<V2File>,
 
     private: bool,
 }
 
+// V2File is where a v2 file's pieces are among the torrent's. every v2 file starts a new piece
+#[derive(Debug, PartialEq)]
+struct V2File {
+    first_piece: u32,
+    pieces_root: Sha256Hash,
+    length: u64,
+}
+
 #[derive(Debug, PartialEq)]
 struct File {
     // absolute location where file is saved. this defaults to base_path, but may be sanitized for
@@ -58,11 +348,21 @@ struct File {
     // default: OS_DOWNLOAD_DIR | HOME + base_path
     file: PathBuf,
     length: u64,
+    attrs: Vec<Attr>,
+    // BEP-47 symlinks are created rather than downloaded, linking to this path relative to the
+    // directory they're in. it's always within the torrent
+    symlink: Option<PathBuf>,
 }
 
 impl Torrent {
-    pub fn new(buf: &[u8], peer_id: Arc<String>, base_dir: &Path) -> Option<Torrent> {
-        Self::validate(&peer_id, base_dir)?;
+    pub fn new(
+        buf: &[u8],
+        config: Arc<Config>,
+        peer_id: Arc<PeerId>,
+        base_dir: &Path,
+        opts: &AddTorrentOptions,
+    ) -> Result<Torrent, TorrentParseError> {
+        Self::validate(base_dir)?;
         let torrent = TorrentAST::decode(buf)?;
         let info = torrent.info;
 
@@ -76,282 +376,5076 @@ impl Torrent {
             let mut rng = SmallRng::seed_from_u64(Utc::now().timestamp_millis() as u64);
 
             trs.into_iter()
+                .filter(|tr| !tr.is_empty())
                 .map(|mut tr| {
                     tr.shuffle(&mut rng);
                     tr.into_iter().map(String::from).collect()
                 })
                 .collect()
+        } else if let Some(announce) = torrent.announce {
+            vec![vec![announce.into()]]
         } else {
-            vec![vec![torrent.announce.into()]]
+            vec![]
         };
 
-        let files = Self::build_files(&info, base_dir)?;
-        let total_bytes = files
+        let name = utils::sanitize_path(info.name, opts.sanitize)
+            .ok_or_else(|| TorrentParseError::InvalidPath(info.name.into()))?;
+        let (name, recheck) = Self::resolve_conflict(&info, &name, base_dir, opts.conflict)?;
+        let mut files = Self::build_files(&info, &name, base_dir, opts.sanitize)?;
+        files
             .iter()
             .map(|f| f.length)
-            .try_fold(0u64, u64::checked_add)?;
+            .try_fold(0u64, u64::checked_add)
+            .ok_or(TorrentParseError::SizeOverflow)?;
 
-        Some(Torrent {
-            info: Info {
-                files,
-                piece_length: info.piece_length.try_into().ok()?,
-                pieces,
-                info_hash: Bencode::hash_dict(buf, "info")?,
-                private: info.private == Some(1),
-            },
-            peers: HashMap::new(),
+        let piece_length: u32 = match info.piece_length.try_into() {
+            Ok(len @ 1..) => len,
+            _ => return Err(TorrentParseError::InvalidPieceLength),
+        };
+        let sane = (MIN_PIECE_LENGTH..=MAX_PIECE_LENGTH).contains(&piece_length);
+        if !opts.lenient_piece_length && !(sane && piece_length.is_power_of_two()) {
+            return Err(TorrentParseError::InvalidPieceLength);
+        }
+
+        let file_trees = Self::build_file_trees(&info, torrent.piece_layers.as_ref());
+        let v2_files = Self::build_v2_files(&info);
+        let multi = info.files.as_ref();
+        let paths: Option<Vec<_>> = multi.map(|fs| fs.iter().map(|f| &f.path[..]).collect());
+        let web_seeds = torrent.url_list.iter().flatten();
+        let web_seeds = web_seeds.filter_map(|url| WebSeed::new(url, info.name, paths.clone()));
+
+        let http = utils::http_client(opts.bind_address, config.http_timeout, config.proxy.clone());
+        let info_hash =
+            Bencode::hash_dict(buf, "info").ok_or(TorrentParseError::InvalidKey("info"))?;
+        let http_seeds = torrent.httpseeds.iter().flatten();
+        let http_seeds = http_seeds.filter_map(|url| WebSeed::http_seed(url, &info_hash));
+        let web_seeds = web_seeds.chain(http_seeds).collect();
+        let (handle, commands) = handle::channel(info_hash);
+        let resume = opts.resume.as_ref().filter(|r| r.info_hash == info_hash);
+        let renamed = match resume {
+            Some(resume) => {
+                Self::apply_renames(&mut files, base_dir, &resume.renamed, opts.sanitize)
+            }
+            None => BTreeMap::new(),
+        };
+        let (peer_events_tx, peer_events) = mpsc::unbounded_channel();
+        let listen_port = config.listen_port.unwrap_or(LISTEN_PORT);
+        let connection_limits = ConnectionLimits::new(&config);
+        let dialer = Dialer::new(config.peer_proxy.clone(), opts.bind_address);
+        let mut choker = Choker::new(opts.upload_slots.unwrap_or(config.upload_slots), None);
+        choker.set_exempt_lan(config.exempt_lan);
+        choker.set_seed_choking(config.seed_choking);
+        let info = Info {
+            files,
+            piece_length,
+            pieces,
+            info_hash,
+            file_trees,
+            v2_files,
+            private: info.private == Some(1),
+        };
+        let files = Self::file_spans(&info);
+        let storage: Arc<dyn Storage> = match &opts.storage {
+            Some(provider) => provider.storage(base_dir, &files, info.piece_length),
+            None => {
+                let storage = DiskStorage::new(files, info.piece_length);
+                let storage = storage.with_allocation(opts.allocation);
+                let storage = storage.with_dir(base_dir.to_path_buf());
+                Arc::new(storage.with_incomplete(opts.incomplete.clone()))
+            }
+        };
+        let read_cache = ReadCache::new(config.read_cache_size.unwrap_or(CACHE_SIZE));
+        let disk = Arc::new(Self::disk_reader(&info, &read_cache, storage));
+        let picker = PiecePicker::new(info.pieces.len());
+        let picker = picker.with_strategy(opts.piece_strategy.clone());
+        let have = bitbox![u8, Msb0; 0; info.pieces.len()];
+        let file_priorities = vec![FilePriority::Normal; info.files.len()];
+        let total_length = info.files.iter().map(|f| f.length).sum();
+        let scheduler = BlockScheduler::new(info.piece_length, total_length);
+        let mut torrent = Torrent {
+            metainfo: buf.to_vec(),
+            info,
+            peers: PeerStore::default(),
+            recent_peers: vec![],
+            pex_connected: HashSet::new(),
+            pex_advertised: HashSet::new(),
+            next_pex: Utc::now(),
+            choker,
+            next_rechoke: Utc::now(),
+            optimistic: None,
+            next_optimistic: Utc::now(),
+            next_hash_request: Utc::now(),
+            holepunches: HashMap::new(),
+            dialing: HashSet::new(),
+            redial_at: HashMap::new(),
+            smart_ban: SmartBan::default(),
+            ban_list: BanList::default(),
+            picker,
+            scheduler,
+            deadlines: PieceDeadlines::default(),
+            have,
+            file_priorities,
+            web_seeds,
 
             trackers,
             next_announce: Utc::now(),
+            announce_policy: opts.announce,
+            announcer: Announcer::new(),
+            swarm: None,
+            tracker_status: HashMap::new(),
+            announced_started: false,
+            announced_completed: false,
+            announced_paused: false,
+            partial_seed: false,
 
+            config,
+            state: State::Active,
+            pending_resume: None,
+            handle,
+            commands,
+            peer_events,
+            peer_events_tx,
+            events: None,
+            external_ip: Default::default(),
+            listen_port: Arc::new(AtomicU16::new(listen_port)),
+            connection_limits,
+            base_dir: base_dir.to_path_buf(),
+            sanitize: opts.sanitize,
+            renamed,
+            disk,
+            read_cache,
+            allocation: opts.allocation,
+            hasher: Default::default(),
+            writing: 0,
+            // resume data already says which of the pieces we have
+            recheck: recheck && resume.is_none(),
+            checking: None,
+            bind_address: opts.bind_address,
+            dialer,
+            http,
             peer_id,
-            bytes_left: total_bytes,
+            bytes_left: 0,
+            piece_wanted: vec![],
             uploaded: 0,
             downloaded: 0,
-        })
-    }
+            transfer: TransferStats::new(std::time::Instant::now()),
+        };
+        torrent.count_wanted();
+        torrent.bytes_left = torrent.wanted_left();
 
-    fn validate(peer_id: &str, base_dir: &Path) -> Option<()> {
-        if peer_id.len() != 20 {
-            return None;
-        }
+        // warm up with peers that worked last time. they're tried before we hear from any tracker
+        if let Some(resume) = &opts.resume
+            && resume.info_hash == torrent.info.info_hash
+        {
+            if let Some(trackers) = &resume.trackers {
+                torrent.set_trackers(trackers.clone());
+            }
 
-        if !base_dir.has_root() {
-            return None;
+            // resume data lists the newest first
+            torrent.recent_peers = resume.peers.iter().rev().copied().collect();
+            for &peer in &resume.peers {
+                torrent.peers.add(peer, PeerSources::RESUME);
+            }
+            torrent.restore_pieces(resume);
         }
 
-        Some(())
+        Ok(torrent)
     }
 
-    fn build_files(info: &InfoAST, base_dir: &Path) -> Option<Vec<File>> {
-        // single file case, info.name is filename
-        if let Some(len) = info.length {
-            let file = File::new(len, base_dir, &[info.name][..])?;
-            return Some(vec![file]);
-        }
+    pub fn info_hash(&self) -> &Sha1Hash {
+        &self.info.info_hash
+    }
 
-        let base_dir = {
-            let d = utils::valid_path(info.name).then(|| info.name)?;
-            base_dir.join(Path::new(d))
-        };
+    /// the info dictionary exactly as it was encoded in the metainfo file. info_hash is the SHA-1
+    /// of these bytes, so anything sending the info dict to others should use them as is
+    pub fn info_bytes(&self) -> &[u8] {
+        // info was found when this torrent was created
+        Bencode::raw_value(&self.metainfo, "info").unwrap()
+    }
 
-        info.files
-            .as_ref()?
+    /// total number of bytes this torrent occupies on disk once complete, excluding padding files
+    pub fn total_size(&self) -> u64 {
+        self.info
+            .files
             .iter()
-            .map(|file| File::new(file.length, &base_dir, &file.path))
-            .try_collect()
+            .filter(|f| !f.is_padding())
+            .map(|f| f.length)
+            .sum()
     }
 
-    async fn refresh_peers(&mut self) -> Result<()> {
-        if self.next_announce <= Utc::now() && !self.peers.is_empty() {
-            return Ok(());
+    /// number of pieces the torrent is split into
+    pub(crate) fn total_pieces(&self) -> usize {
+        self.info.pieces.len()
+    }
+
+    /// encode this torrent as a .torrent file. keys tsunami doesn't understand are preserved as
+    /// is, but the tracker list reflects any changes made since the torrent was loaded
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // metainfo was successfully decoded when this torrent was created
+        let mut metainfo = Bencode::decode(&self.metainfo)
+            .and_then(Bencode::dict)
+            .unwrap();
+
+        // write info back byte for byte, so the info hash can never drift
+        metainfo.insert(b"info", Bencode::Raw(self.info_bytes()));
+
+        metainfo.remove(&b"announce"[..]);
+        let had_list = metainfo.remove(&b"announce-list"[..]).is_some();
+
+        if let Some(announce) = self.trackers.first().and_then(|tier| tier.first()) {
+            metainfo.insert(b"announce", Bencode::Str(announce));
         }
 
-        let mut url_buf = String::new();
-
-        // find the first available tracker we can reach and move it the the front of its own list.
-        //
-        // for example, if b3 is the first tracker to respond:
-        //     [ [a1, a2], [b1, b2, b3], [c1] ]
-        //
-        // the new tracker list becomes:
-        //     [ [a1, a2], [b3, b1, b2], [c1] ]
-        //
-        // See BEP-12 for more details
-        for outer in 0..self.trackers.len() {
-            for inner in 0..self.trackers[outer].len() {
-                let tracker = &self.trackers[outer][inner];
-                self.build_tracker_url(tracker, &mut url_buf);
-
-                // request peers from tracker
-                let body = utils::get_body(&url_buf).await?;
-                let Ok((interval, peers)) = Self::parse_tracker_resp(body) else {
-                    continue;
-                };
+        if had_list || self.trackers.iter().flatten().count() > 1 {
+            let tiers = self
+                .trackers
+                .iter()
+                .map(|tier| Bencode::List(tier.iter().map(|tr| Bencode::Str(tr)).collect()));
+            metainfo.insert(b"announce-list", Bencode::List(tiers.collect()));
+        }
 
-                // make current tracker the first we try next time (in its local inner group, maintaining
-                // outer tracker group order)
-                self.trackers[outer][..=inner].rotate_right(1);
+        let mut buf = Vec::with_capacity(self.metainfo.len());
+        Bencode::Dict(metainfo).encode(&mut buf);
+        buf
+    }
 
-                // set next tracker update interval, min 5m
-                let interval = Duration::seconds(interval.clamp(300, i64::MAX as u64) as i64);
-                self.next_announce = Utc::now() + interval;
+    /// state worth saving between runs, pass it to [AddTorrentOptions::resume] when adding this
+    /// torrent again
+    pub fn resume_data(&self) -> ResumeData {
+        ResumeData {
+            info_hash: self.info.info_hash,
+            peers: self.recent_peers.iter().rev().copied().collect(),
+            trackers: Some(self.trackers.clone()),
+            pieces: Some(self.have.clone()),
+            partial: self.scheduler.partial(MAX_PARTIAL),
+            renamed: self.renamed.clone(),
+        }
+    }
 
-                // update our list of peers
-                for peer in peers {
-                    self.peers.entry(peer).or_insert(None);
-                }
+    // take the pieces we had, and the blocks of pieces which had arrived, from resume data
+    fn restore_pieces(&mut self, resume: &ResumeData) {
+        let pieces = self.info.pieces.len();
+        if let Some(have) = &resume.pieces {
+            for index in have.iter_ones().take_while(|&i| i < pieces) {
+                self.have.set(index, true);
+                self.picker.set_ours(index as u32, true);
+            }
+            self.update_left();
+        }
 
-                return Ok(());
+        for partial in &resume.partial {
+            let have = self.have.get(partial.index as usize).is_none_or(|b| *b);
+            if !have && self.scheduler.restore(partial) {
+                self.picker.set_ours(partial.index, true);
             }
         }
+    }
 
-        Err(Error::NoTrackerAvailable)
+    /// number of connected peers which have each piece
+    pub fn availability(&self) -> &[u16] {
+        self.picker.availability()
     }
 
-    fn build_tracker_url(&self, tracker: &str, mut buffer: &mut String) {
-        const HEXES: &[u8; 16] = b"0123456789ABCDEF";
-        buffer.clear();
+    /// how many complete copies of the torrent our peers have between them, see
+    /// [PiecePicker::distributed_copies]
+    pub fn distributed_copies(&self) -> f64 {
+        self.picker.distributed_copies()
+    }
+
+    /// seeders and leechers reported by the last tracker announce, if the tracker sent them
+    pub fn swarm_stats(&self) -> Option<SwarmStats> {
+        self.swarm
+    }
+
+    /// send tracker requests with http instead of a client of our own, so connections are
+    /// pooled with the rest of the session
+    pub(crate) fn set_http_client(&mut self, http: HttpClient) {
+        self.http = http;
+    }
+
+    /// emit events, such as tracker warnings, to the session's consumers
+    pub(crate) fn set_events(&mut self, events: EventSender) {
+        self.events = Some(events);
+    }
+
+    /// share the external ip trackers report with the rest of the session
+    pub(crate) fn set_external_ip(&mut self, external_ip: ExternalIp) {
+        self.external_ip = external_ip;
+    }
+
+    /// share peers banned for sending corrupt data with the rest of the session
+    pub(crate) fn set_ban_list(&mut self, ban_list: BanList) {
+        self.ban_list = ban_list;
+    }
+
+    /// allocate every file up front if [AddTorrentOptions::allocation] is [Allocation::Full].
+    /// files which already exist are left alone
+    pub(crate) async fn preallocate(&self) -> io::Result<()> {
+        if self.allocation != Allocation::Full {
+            return Ok(());
+        }
+        self.disk.storage().preallocate().await
+    }
 
-        let mut info_hash = String::with_capacity(60);
-        for b in self.info.info_hash {
-            info_hash.push('%');
-            info_hash.push(HEXES[b as usize >> 4] as char);
-            info_hash.push(HEXES[b as usize & 15] as char);
+    /// create the torrent's BEP-47 symlinks, see [Storage::symlink]
+    pub(crate) async fn create_symlinks(&self) -> io::Result<()> {
+        let storage = self.disk.storage();
+        for (i, file) in self.info.files.iter().enumerate() {
+            if let Some(target) = &file.symlink {
+                storage.symlink(i, target).await?;
+            }
         }
+        Ok(())
+    }
 
-        let _ = write!(
-            &mut buffer,
-            "{tracker}?info_hash={}&peer_id={}&port={}&downloaded={}&uploaded={}&compact={}&left={}",
-            info_hash,
-            self.peer_id,
-            6881,
-            self.downloaded,
-            self.uploaded,
-            1,
-            self.bytes_left,
-        );
+    /// hash pieces on the session's hashing workers, see [Hasher]
+    pub(crate) fn set_hasher(&mut self, hasher: Hasher) {
+        self.hasher = hasher;
     }
 
-    fn parse_tracker_resp(resp: Bytes) -> Result<(u64, Vec<SocketAddrV4>)> {
-        // todo: propagate error
-        let Some(mut tracker) = (try { Bencode::decode(&resp)?.dict()? }) else {
-            return Err(Error::InvalidTrackerResp(None))
+    /// keep recently read pieces in the session's read cache, see [Config::read_cache_size]
+    pub(crate) fn set_read_cache(&mut self, read_cache: ReadCache) {
+        let storage = self.disk.storage().clone();
+        self.disk = Arc::new(Self::disk_reader(&self.info, &read_cache, storage));
+        self.read_cache = read_cache;
+    }
+
+    /// count this torrent's connections towards the session's caps, see
+    /// [Config::max_connections] and [Config::max_half_open]
+    pub(crate) fn set_connection_limits(&mut self, connection_limits: ConnectionLimits) {
+        self.connection_limits = connection_limits;
+    }
+
+    /// report the port the session's listener is bound to, see [crate::tsunami::Tsunami::listen]
+    pub(crate) fn set_listen_port(&mut self, listen_port: Arc<AtomicU16>) {
+        self.listen_port = listen_port;
+    }
+
+    /// our public address, as last reported by a tracker
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external_ip.get()
+    }
+
+    /// tracker tiers in the order they're tried, see BEP-12
+    pub fn trackers(&self) -> &[Vec<String>] {
+        &self.trackers
+    }
+
+    /// where we learned of the peer at addr, or None if it isn't known
+    pub fn peer_sources(&self, addr: SocketAddr) -> Option<PeerSources> {
+        self.peers.sources(addr)
+    }
+
+    /// transfer stats of every connected peer
+    pub fn peer_stats(&self) -> Vec<(SocketAddr, PeerStats)> {
+        let peers = self.peers.handles();
+        let stats = |(addr, peer): (_, &PeerHandle)| {
+            let mut stats = peer.info().stats();
+            stats.snubbed = peer.requests().is_snubbed();
+            (addr, stats)
         };
+        peers.map(stats).collect()
+    }
 
-        // TODO - avoid allocs
-        if let Some(fail_msg) = tracker.remove(&b"failure reason"[..]) {
-            let reason = try { fail_msg.str()?.into() };
-            return Err(Error::InvalidTrackerResp(reason));
+    /// progress, transfer rates and time left to finish, see [TorrentStats]
+    pub fn stats(&self) -> TorrentStats {
+        let transfer = self.transfer.snapshot(std::time::Instant::now());
+        let mut stats = TorrentStats::new(transfer, self.bytes_left, self.wanted_size());
+        (stats.cache_hits, stats.cache_misses) = self.disk.cache_stats();
+        stats
+    }
+
+    /// client software of every connected peer, where it could be identified
+    pub fn peer_clients(&self) -> Vec<(SocketAddr, Option<ClientInfo>)> {
+        let peers = self.peers.handles();
+        let client = |(addr, peer): (_, &PeerHandle)| (addr, peer.info().client());
+        peers.map(client).collect()
+    }
+
+    /// the extension handshake to send our peers, see [ExtensionHandshake::ours]. partial seeds
+    /// say they're upload only
+    pub(crate) fn extension_handshake(&self) -> ExtensionHandshake {
+        let metadata_size = Some(self.info_bytes().len() as u64);
+        ExtensionHandshake {
+            upload_only: Some(self.partial_seed),
+            ..ExtensionHandshake::ours(metadata_size, self.info.private)
         }
+    }
 
-        // parse response into a (interval, sockaddr's) pair
-        let parse_resp = try {
-            let interval = tracker.remove(&b"interval"[..])?.num()?.try_into().ok()?;
+    /// mark whether we have every piece we want without having the whole torrent, eg. once the
+    /// files chosen for download are complete. partial seeds announce as paused rather than
+    /// completed, and peers are sent a new extension handshake saying whether we're upload only,
+    /// see BEP-21
+    pub(crate) fn set_partial_seed(&mut self, partial: bool) {
+        if partial == self.partial_seed {
+            return;
+        }
+        self.partial_seed = partial;
+        self.announced_paused = false;
+        if partial {
+            self.announcer.wake();
+        }
 
-            let peers = tracker.remove(&b"peers"[..])?;
-            let sock_addrs = if let Bencode::BStr(peers) = peers {
-                peers
-                    .chunks(6)
-                    .map(|host| {
-                        let ipv4 = Ipv4Addr::new(host[0], host[1], host[2], host[3]);
-                        let port = BE::read_u16(&host[4..]);
+        let payload = self.extension_handshake().encode();
+        for (_, peer) in self.peers.handles() {
+            if peer.info().reserved().contains(ReservedBits::EXTENSION) {
+                let payload = payload[..].into();
+                peer.send(Message::Extended { id: 0, payload });
+            }
+        }
+    }
 
-                        SocketAddrV4::new(ipv4, port)
-                    })
-                    .collect()
-            } else if let Bencode::List(peers) = peers {
-                peers
-                    .into_iter()
-                    .map(|peer| {
-                        let mut peer = peer.dict()?;
-                        let ip = peer.remove(&b"ip"[..])?.str()?.parse().ok()?;
-                        let port = peer.remove(&b"port"[..])?.str()?.parse().ok()?;
+    /// forget who sent piece index once it passes its hash check, see [SmartBan], and tell our
+    /// peers we have it. peers which already have it aren't told, they won't want it from us
+    pub(crate) fn piece_passed(&mut self, index: u32) {
+        self.smart_ban.piece_passed(index);
+        self.picker.set_ours(index, true);
 
-                        Some(SocketAddrV4::new(ip, port))
-                    })
-                    .try_collect()?
-            } else {
-                return Err(Error::InvalidTrackerResp(None));
-            };
+        let have = self.have.get_mut(index as usize);
+        if have.is_none_or(|mut have| have.replace(true)) {
+            return;
+        }
+        let left = self.bytes_left.saturating_sub(self.piece_wanted(index));
+        self.set_left(left);
+        for (_, peer) in self.peers.handles() {
+            if !peer.info().has(index) {
+                peer.send(Message::Have(index));
+            }
+        }
+        self.complete_files(index);
+    }
 
-            (interval, sock_addrs)
-        }: Option<_>;
+    // finish off the files piece index was the last of, see [Storage::file_completed]. this is
+    // best effort
+    fn complete_files(&self, index: u32) {
+        for i in self.completed_files(Some(index)) {
+            tokio::spawn(self.complete_file(i));
+        }
+    }
 
-        parse_resp.ok_or(Error::InvalidTrackerResp(None))
+    /// finish off the files which were already complete when the torrent was added, eg. going
+    /// by its resume data, see [Storage::file_completed]. this is best effort
+    pub(crate) async fn complete_existing_files(&self) {
+        for i in self.completed_files(None) {
+            self.complete_file(i).await;
+        }
     }
-}
 
-impl File {
-    fn new(length: i64, torrent_dir: &Path, paths: &[&str]) -> Option<File> {
-        if length <= 0 {
-            return None;
+    // finish off file i, failures are reported as an [Event::FileError]
+    fn complete_file(&self, i: usize) -> impl Future<Output = ()> + 'static {
+        let storage = self.disk.storage().clone();
+        let attrs = self.info.files[i].attrs.clone();
+        let (events, info_hash) = (self.events.clone(), self.info.info_hash);
+        async move {
+            let Err(e) = storage.file_completed(i, &attrs).await else {
+                return;
+            };
+            if let Some(events) = events {
+                let error = Event::FileError {
+                    info_hash,
+                    file: i,
+                    error: e.to_string(),
+                };
+                events.emit(error).await;
+            }
         }
+    }
 
-        // todo: os specific clean_path fns
-        let parts = paths.iter().filter(|p| utils::valid_path(p)).map(Path::new);
-        let file_path = PathBuf::from_iter(once(torrent_dir).into_iter().chain(parts));
+    // indexes of the files we have every piece of, only those piece is part of if it's Some.
+    // padding and symlinks have nothing to finish off
+    fn completed_files(&self, piece: Option<u32>) -> Vec<usize> {
+        let piece_length = self.info.piece_length as u64;
+        let have = |p: u64| self.have.get(p as usize).is_some_and(|b| *b);
 
-        // parts were empty or all path segments were filtered out
-        if file_path.ends_with(torrent_dir) {
-            return None;
+        let mut completed = vec![];
+        let mut start = 0;
+        for (i, file) in self.info.files.iter().enumerate() {
+            let pieces = start / piece_length..(start + file.length).div_ceil(piece_length);
+            start += file.length;
+            if file.is_padding() || file.symlink.is_some() {
+                continue;
+            }
+            let part_of = piece.is_none_or(|p| pieces.contains(&(p as u64)));
+            if part_of && pieces.clone().all(have) {
+                completed.push(i);
+            }
         }
+        completed
+    }
 
-        Some(File {
-            file: file_path,
-            length: length.try_into().ok()?,
-        })
+    // check a piece whose blocks have all arrived against its hash on the [Hasher], writing
+    // it to disk if it passes. pieces of hybrid torrents must match their v2 hash too, once we
+    // know it. the result comes back as a PeerEvent::Verified
+    fn verify_piece(&mut self, index: u32, data: Vec<u8>) {
+        let Some(check) = self.piece_check(index) else {
+            return;
+        };
+        self.writing += data.len() as u64;
+        let (disk, events) = (self.disk.clone(), self.peer_events_tx.clone());
+        let hasher = self.hasher.clone();
+        tokio::spawn(async move {
+            let res = match hasher.check(check, data.into()).await {
+                Some((true, data)) => disk.write(index, data.into()).await.map(|_| true),
+                Some((false, _)) => Ok(false),
+                None => Err(io::ErrorKind::Other.into()),
+            };
+            let _ = events.send(PeerEvent::Verified(index, res));
+        });
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        path::{Path, PathBuf},
-        sync::Arc,
-    };
+    // piece index was checked, see verify_piece. a piece which failed, or couldn't be written,
+    // is downloaded again
+    fn piece_verified(&mut self, index: u32, res: io::Result<bool>) {
+        let size = self.piece_size(index).unwrap_or_default();
+        self.writing = self.writing.saturating_sub(size as u64);
+        let web_seed = self.web_seeds.iter().position(|s| s.piece() == Some(index));
+        let corrupt = matches!(res, Ok(false));
+        match res {
+            Ok(true) => {
+                let new = !self.have.get(index as usize).is_some_and(|b| *b);
+                self.piece_passed(index);
+                if new {
+                    self.add_downloaded(size as u64);
+                }
+                if let Some(seed) = web_seed {
+                    self.web_seeds[seed].succeeded();
+                }
+                return;
+            }
+            Ok(false) => self.piece_failed(index),
+            Err(_) => {}
+        }
 
-    use chrono::Utc;
+        // pieces from web seeds are picked again rather than handed to our peers, a seed which
+        // sent corrupt data is backed off
+        let Some(seed) = web_seed else {
+            self.scheduler.start(index);
+            return;
+        };
+        match corrupt {
+            true => self.web_seed_failed(seed, index, None),
+            false => {
+                self.web_seeds[seed].release();
+                self.picker.set_ours(index, false);
+            }
+        }
+    }
 
-    use crate::torrent::{File, Info, Torrent};
+    /// read every piece back from disk and check it against its hash in the background, eg.
+    /// after files were changed behind our back. pieces which pass are ours and pieces which
+    /// don't are downloaded again, nothing new is requested until the check is done. progress is
+    /// reported as [Event::RecheckProgress]. a recheck which is already running carries on
+    pub fn force_recheck(&mut self) {
+        let pieces = self.info.pieces.len() as u32;
+        if self.checking.is_some() || pieces == 0 {
+            return;
+        }
+        self.checking = Some(0);
 
-    #[test]
-    fn new() {
-        let tor_gen = |base: &Path, prefix: &str| Torrent {
-            trackers: vec![
-                vec!["http://tracker.example.com".into()],
-                vec!["http://tracker2.example.com".into()],
-            ],
-            info: Info {
-                piece_length: 32768,
-                pieces: vec![[
-                    0, 72, 105, 249, 236, 50, 141, 28, 177, 230, 77, 80, 106, 67, 249, 35, 207,
-                    173, 235, 151,
-                ]],
-                private: true,
-                files: vec![File {
-                    file: PathBuf::from_iter(
-                        [base, Path::new(prefix), Path::new("file.txt")].iter(),
-                    ),
-                    length: 10,
-                }],
-                info_hash: if prefix == "" {
-                    [
-                        11, 5, 171, 161, 242, 160, 178, 230, 220, 146, 241, 219, 17, 67, 62, 95,
-                        58, 130, 11, 173,
-                    ]
-                } else {
-                    [
-                        116, 83, 104, 101, 231, 122, 204, 114, 242, 152, 196, 136, 195, 44, 49,
-                        171, 155, 150, 152, 177,
-                    ]
-                },
-            },
-            peer_id: Arc::new("".into()),
-            bytes_left: 0,
-            uploaded: 0,
-            downloaded: 0,
-            next_announce: Utc::now(),
-            peers: Default::default(),
+        // the files may have changed since they were last read, start with an empty cache
+        let storage = self.disk.storage().clone();
+        self.disk = Arc::new(Self::disk_reader(&self.info, &self.read_cache, storage));
+        let checks: Vec<_> = (0..pieces)
+            .map(|i| (i, self.piece_size(i).zip(self.piece_check(i))))
+            .collect();
+        let (disk, events) = (self.disk.clone(), self.peer_events_tx.clone());
+        let hasher = self.hasher.clone();
+        tokio::spawn(async move {
+            for (index, check) in checks {
+                let passed = async {
+                    let (size, check) = check?;
+                    let data = disk.read(index, 0, size).await.ok()?;
+                    let (passed, _) = hasher.check(check, data).await?;
+                    Some(passed)
+                };
+                let passed = passed.await.unwrap_or(false);
+                if events.send(PeerEvent::Checked(index, passed)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    // piece index was read back and checked by a recheck, see force_recheck. peers are told of
+    // pieces we no longer have, and once every piece has been checked what's left to download is
+    // worked out again
+    async fn piece_checked(&mut self, index: u32, passed: bool) {
+        let Some(checked) = self.checking.as_mut() else {
+            return;
         };
+        *checked += 1;
+        let checked = *checked;
 
-        let test_files = [
-            (&include_bytes!("test_data/mock_dir.torrent")[..], "mock"),
-            (&include_bytes!("test_data/mock_file.torrent")[..], ""),
-        ];
+        if passed {
+            self.piece_passed(index);
+        } else if self.have.get(index as usize).is_some_and(|b| *b) {
+            self.have.set(index as usize, false);
+            self.picker.set_ours(index, false);
+            self.bytes_left += self.piece_wanted(index);
+            for (_, peer) in self.peers.handles() {
+                peer.send_extended("lt_donthave", &index.to_be_bytes());
+            }
+        }
 
-        for (file, dir_name) in test_files {
-            let base_dir = PathBuf::from("/foo");
-            let torrent =
-                Torrent::new(file, Arc::new("-TS0001-|testClient|".into()), &base_dir).unwrap();
-            let expected = tor_gen(&base_dir, dir_name);
+        let pieces = self.info.pieces.len() as u32;
+        if checked == pieces {
+            self.checking = None;
+            self.update_left();
+        }
 
-            assert_eq!(torrent.trackers, expected.trackers);
-            assert_eq!(torrent.info, expected.info);
-            assert_eq!(torrent.info.info_hash, expected.info.info_hash);
+        // report progress at most once per percent
+        let percent = |n| n as u64 * 100 / pieces as u64;
+        if checked != pieces && percent(checked) == percent(checked - 1) {
+            return;
+        }
+        if let Some(events) = &self.events {
+            let progress = Event::RecheckProgress {
+                info_hash: self.info.info_hash,
+                checked,
+                pieces,
+            };
+            events.emit(progress).await;
+        }
+    }
+
+    /// blame the peers which sent piece index for it failing its hash check, banning the ones
+    /// implicated too often from the whole session, see [SmartBan]
+    pub(crate) fn piece_failed(&mut self, index: u32) {
+        for ip in self.smart_ban.piece_failed(index) {
+            self.ban_list.insert(ip);
+            self.ban_peer(ip);
         }
     }
 
+    /// disconnect and forget every peer at ip, and ignore it from now on
+    pub fn ban_peer(&mut self, ip: IpAddr) {
+        for peer in self.peers.ban(ip) {
+            self.forget_peer(&peer);
+            peer.shutdown();
+        }
+        self.recent_peers.retain(|addr| addr.ip() != ip);
+    }
+
+    /// why the last announce to tracker failed, if it did
+    pub fn tracker_error(&self, tracker: &str) -> Option<&Error> {
+        self.tracker_status.get(tracker).map(|s| &s.error)
+    }
+
+    /// replace this torrent's trackers. empty tiers are dropped
+    pub fn set_trackers(&mut self, mut trackers: Vec<Vec<String>>) {
+        trackers.retain(|tier| !tier.is_empty());
+        self.trackers = trackers;
+    }
+
+    /// add url to the end of tier, or to a new last tier if tier doesn't exist. returns false if
+    /// this torrent already has the tracker
+    pub fn add_tracker(&mut self, url: String, tier: usize) -> bool {
+        if self.trackers.iter().flatten().any(|tr| *tr == url) {
+            return false;
+        }
+
+        match self.trackers.get_mut(tier) {
+            Some(tier) => tier.push(url),
+            None => self.trackers.push(vec![url]),
+        }
+        true
+    }
+
+    /// remove url from this torrent's trackers, returning false if it wasn't one of them
+    pub fn remove_tracker(&mut self, url: &str) -> bool {
+        let len = self.trackers.iter().flatten().count();
+        for tier in &mut self.trackers {
+            tier.retain(|tr| tr != url);
+        }
+        self.trackers.retain(|tier| !tier.is_empty());
+        self.tracker_status.remove(url);
+
+        len != self.trackers.iter().flatten().count()
+    }
+
+    /// reader serving this torrent's pieces to peers from storage. reads are shared between
+    /// every peer using the same reader, so only one should be created per torrent
+    fn disk_reader(info: &Info, cache: &ReadCache, storage: Arc<dyn Storage>) -> DiskReader {
+        DiskReader::new(Self::file_spans(info), info.piece_length)
+            .with_cache(cache.clone())
+            .with_storage(storage)
+    }
+
+    // where the torrent's files lie in its pieces
+    fn file_spans(info: &Info) -> Vec<FileSpan> {
+        let files = info.files.iter().map(|f| FileSpan {
+            path: f.file.clone(),
+            length: f.length,
+            // symlinks have no content to store either
+            padding: f.is_padding() || f.symlink.is_some(),
+        });
+        files.collect()
+    }
+
+    /// connect to any peers saved in resume data, then announce to our trackers. from then on
+    /// the torrent re-announces in the background as its trackers ask, see
+    /// [Torrent::process_commands]
+    pub async fn start(&mut self) -> Result<()> {
+        self.connect_peers();
+        let res = self.refresh_peers(None).await;
+
+        self.schedule_announce(&res);
+        self.announcer.start(self.handle.clone());
+        res
+    }
+
+    /// a handle to control this torrent from elsewhere. commands sent through it are carried
+    /// out by [Torrent::process_commands], which [crate::tsunami::Tsunami::run] calls
+    pub fn handle(&self) -> TorrentHandle {
+        self.handle.clone()
+    }
+
+    /// carry out any commands sent from this torrent's handles and any announces its
+    /// background announce task found due, handle whatever our peers sent, dial the peers we
+    /// know of but aren't connected to and check for peers snubbing us, then rechoke and send
+    /// PEX messages if they're due. finally start reading the blocks our peers asked for. data
+    /// found on disk when the torrent was added is rechecked from the first call, see
+    /// [ConflictPolicy::Reuse]
+    pub async fn process_commands(&mut self) {
+        if mem::take(&mut self.recheck) {
+            self.force_recheck();
+        }
+        while let Ok(cmd) = self.commands.try_recv() {
+            match cmd {
+                Command::Pause(reply) => {
+                    self.pause();
+                    let _ = reply.send(Ok(()));
+                }
+                // the session decides whether there's room on disk for us
+                Command::Resume(reply)
+                    if self.config.disk_quota.is_some() && self.state != State::Active =>
+                {
+                    self.pending_resume = Some(reply);
+                }
+                Command::Resume(reply) => {
+                    self.resume();
+                    let _ = reply.send(Ok(()));
+                }
+                Command::Reannounce(reply) => {
+                    self.next_announce = Utc::now();
+                    self.announce_in_background(Some(reply));
+                }
+                Command::Announce => self.announce_in_background(None),
+                Command::MoveStorage(dir, reply) => {
+                    let _ = reply.send(self.move_storage(&dir).await);
+                }
+                Command::RenameFile(index, name, reply) => {
+                    let _ = reply.send(self.rename_file(index, &name).await);
+                }
+                Command::RenameRoot(name, reply) => {
+                    let _ = reply.send(self.rename_root(&name).await);
+                }
+                Command::AddTracker(url, tier, reply) => {
+                    let res = if Self::valid_tracker(&url) {
+                        self.add_tracker(url, tier);
+                        Ok(())
+                    } else {
+                        Err(CommandError::InvalidTracker(url))
+                    };
+                    let _ = reply.send(res);
+                }
+                Command::RemoveTracker(url, reply) => {
+                    let res = match self.remove_tracker(&url) {
+                        true => Ok(()),
+                        false => Err(CommandError::UnknownTracker(url)),
+                    };
+                    let _ = reply.send(res);
+                }
+                Command::SetFilePriority(index, priority, reply) => {
+                    let res = match self.set_file_priority(index, priority) {
+                        true => Ok(()),
+                        false => Err(CommandError::InvalidFileIndex(index)),
+                    };
+                    let _ = reply.send(res);
+                }
+                Command::SetPiecePriority(index, priority, reply) => {
+                    let res = match self.set_piece_priority(index, priority) {
+                        true => Ok(()),
+                        false => Err(CommandError::InvalidPieceIndex(index)),
+                    };
+                    let _ = reply.send(res);
+                }
+                Command::ForceRecheck(reply) => {
+                    self.force_recheck();
+                    let _ = reply.send(Ok(()));
+                }
+                Command::SetTrackers(trackers, reply) => {
+                    let invalid = trackers
+                        .iter()
+                        .flatten()
+                        .find(|tr| !Self::valid_tracker(tr));
+                    let res = match invalid {
+                        Some(url) => Err(CommandError::InvalidTracker(url.clone())),
+                        None => {
+                            self.set_trackers(trackers);
+                            Ok(())
+                        }
+                    };
+                    let _ = reply.send(res);
+                }
+            }
+        }
+
+        self.process_peer_events().await;
+        self.connect_peers();
+        self.check_snubbed();
+        if Utc::now() >= self.next_rechoke {
+            self.rechoke();
+        }
+        self.send_pex();
+        self.request_hashes();
+        self.request_deadlines();
+        self.request_blocks();
+        self.request_web_seeds();
+        self.serve_uploads();
+    }
+
+    /// mark the peers which haven't sent what we asked for in [Config::snub_timeout] as snubbed
+    fn check_snubbed(&mut self) {
+        let timeout = self.config.snub_timeout.unwrap_or(SNUB_TIMEOUT);
+        for (_, peer) in self.peers.handles_mut() {
+            peer.check_snubbed(timeout);
+        }
+    }
+
+    /// handle the messages our peers sent since we last looked, see [PeerHandle]. peers which
+    /// send something invalid are disconnected
+    async fn process_peer_events(&mut self) {
+        while let Ok(event) = self.peer_events.try_recv() {
+            let (addr, msg) = match event {
+                PeerEvent::Message(addr, msg) => (addr, msg),
+                PeerEvent::Read(addr, block, data) => {
+                    let Some(peer) = self.peers.connection_mut(addr) else {
+                        continue;
+                    };
+                    if peer.finish_upload(block, data) {
+                        let length = block.length as u64;
+                        let now = std::time::Instant::now();
+                        self.uploaded += length;
+                        self.transfer.record_upload(length, now);
+                    }
+                    continue;
+                }
+                PeerEvent::Closed(addr, _) => {
+                    if let Some(peer) = self.peers.disconnected(addr) {
+                        self.forget_peer(&peer);
+                    }
+                    continue;
+                }
+                PeerEvent::Verified(index, res) => {
+                    self.piece_verified(index, res);
+                    continue;
+                }
+                PeerEvent::Checked(index, passed) => {
+                    self.piece_checked(index, passed).await;
+                    continue;
+                }
+                PeerEvent::WebSeed(seed, index, res) => {
+                    self.web_seed_fetched(seed, index, res);
+                    continue;
+                }
+                PeerEvent::Holepunched(addr, peer, permit) => {
+                    self.holepunched(addr, *peer, permit);
+                    continue;
+                }
+                PeerEvent::Dialed(addr, peer, permit) => {
+                    self.dialed(addr, peer, permit);
+                    continue;
+                }
+                PeerEvent::Announced(announced, reply) => {
+                    let res = self.announced(announced).await;
+                    self.schedule_announce(&res);
+                    if let Some(reply) = reply {
+                        let _ = reply.send(res.map_err(CommandError::from));
+                    }
+                    continue;
+                }
+            };
+
+            // messages may still arrive from peers we've since dropped
+            let Some(peer) = self.peers.connection_mut(addr) else {
+                continue;
+            };
+            // availability follows the peer's bitfield. a have adds a single piece, other changes
+            // take the old bitfield off and add the new one
+            let have = match msg {
+                Message::Have(index) if !peer.info().has(index) => Some(index),
+                _ => None,
+            };
+            let replaced = match msg {
+                Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => true,
+                Message::Extended { id, .. } => id == LT_DONTHAVE,
+                _ => false,
+            };
+            if replaced {
+                self.picker.remove_peer(peer.info().bitfield());
+            }
+            let res = peer.update(&msg);
+            if replaced {
+                self.picker.add_peer(peer.info().bitfield());
+            }
+            if let Some(index) = have.filter(|&i| peer.info().has(i)) {
+                self.picker.have(index);
+            }
+
+            match res {
+                Ok(true) => {}
+                // messages which aren't allowed right now are dropped
+                Ok(false) => continue,
+                Err(_) => {
+                    peer.shutdown();
+                    continue;
+                }
+            }
+
+            match msg {
+                Message::Extended {
+                    id: UT_PEX,
+                    payload,
+                } => {
+                    self.add_pex_peers(&payload);
+                }
+                Message::Extended {
+                    id: UT_HOLEPUNCH,
+                    payload,
+                } => self.on_holepunch(addr, &payload),
+                Message::HashRequest(req) => {
+                    let resp = self.hash_response(req);
+                    if let Some(peer) = self.peers.connection(addr) {
+                        peer.send(resp);
+                    }
+                }
+                Message::Hashes(req, hashes) => {
+                    self.add_hashes(req, &hashes);
+                }
+                Message::Piece {
+                    index,
+                    begin,
+                    block: data,
+                } => {
+                    let block = Block {
+                        index,
+                        begin,
+                        length: data.len() as u32,
+                    };
+                    // the first copy of a block wins, late copies from other peers are dropped
+                    self.cancel_duplicates(block);
+                    if !self.scheduler.needs(block) {
+                        continue;
+                    }
+
+                    self.smart_ban.record_block(index, addr.ip());
+                    self.deadlines.received(block);
+                    if let Some(piece) = self.scheduler.received(index, begin, &data) {
+                        self.verify_piece(index, piece);
+                    }
+                }
+                Message::Choke | Message::RejectRequest { .. } => self.release_requests(addr),
+                Message::Request {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    let block = Block {
+                        index,
+                        begin,
+                        length,
+                    };
+                    if !self.can_upload(block)
+                        && let Some(peer) = self.peers.connection_mut(addr)
+                    {
+                        peer.refuse_upload(block);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// request as many blocks as each peer which isn't choking us has room for, see
+    /// [BlockScheduler]. the pieces already being downloaded are finished first, then new ones are
+    /// picked rarest first. peers are told whether they have anything we want, and requests which
+    /// time out are cancelled so they can be asked of someone else. nothing new is requested
+    /// while [Config::max_write_queue] bytes are waiting to be written, or while we're paused
+    fn request_blocks(&mut self) {
+        if self.state != State::Active {
+            return;
+        }
+        if self.bytes_left == 0 || self.partial_seed || self.checking.is_some() {
+            return;
+        }
+
+        let now = std::time::Instant::now();
+        for (addr, block) in self.scheduler.timed_out(now, BLOCK_TIMEOUT) {
+            if let Some(peer) = self.peers.connection_mut(addr) {
+                peer.timed_out(block);
+            }
+        }
+
+        // pieces are arriving faster than they can be written, hold off until the disk catches
+        // up rather than keeping ever more of them in memory
+        if self.write_queue_full() {
+            return;
+        }
+
+        // the most reliable peers go first, so blocks which timed out are asked of someone
+        // likely to send them
+        let reliability =
+            |(addr, peer): (SocketAddr, &PeerHandle)| (peer.requests().reliability(), addr);
+        let mut peers: Vec<_> = self.peers.handles().map(reliability).collect();
+        peers.sort_unstable_by_key(|&(reliability, _)| Reverse(reliability));
+
+        let mut rng = SmallRng::seed_from_u64(Utc::now().timestamp_millis() as u64);
+        for (_, addr) in peers {
+            let Some(peer) = self.peers.connection_mut(addr) else {
+                continue;
+            };
+            let has = peer.info().bitfield();
+            let wanted = self.scheduler.wants(has) || self.picker.pick(has, &mut rng).is_some();
+            peer.set_interested(wanted);
+            if !wanted || peer.is_choking_us() {
+                continue;
+            }
+
+            let (has, room) = (peer.info().bitfield(), peer.requests().room());
+            let mut blocks = self.scheduler.next_requests(addr, has, room, now);
+            while blocks.len() < room {
+                let Some(index) = self.picker.pick(has, &mut rng) else {
+                    break;
+                };
+                self.picker.set_ours(index, true);
+                self.scheduler.start(index);
+                let left = room - blocks.len();
+                blocks.extend(self.scheduler.next_requests(addr, has, left, now));
+            }
+
+            // blocks of pieces with a deadline may already have been asked for
+            for block in blocks {
+                if !peer.requests().contains(block) {
+                    peer.request(block);
+                }
+            }
+        }
+    }
+
+    // whether there are [Config::max_write_queue] bytes waiting to be written
+    fn write_queue_full(&self) -> bool {
+        let max_write_queue = self.config.max_write_queue.unwrap_or(MAX_WRITE_QUEUE);
+        self.writing >= max_write_queue
+    }
+
+    /// fetch a piece from each web seed which isn't busy or backing off, see [WebSeed]. pieces
+    /// are picked as if the seed were a peer with every piece, so seeds and peers never work on
+    /// the same piece. what arrives is checked like pieces from peers, see [Torrent::verify_piece]
+    fn request_web_seeds(&mut self) {
+        if self.web_seeds.is_empty() || self.state != State::Active {
+            return;
+        }
+        let done = self.bytes_left == 0 || self.partial_seed;
+        if done || self.checking.is_some() || self.write_queue_full() {
+            return;
+        }
+
+        let now = Utc::now();
+        let all = bitbox![u8, Msb0; 1; self.info.pieces.len()];
+        let layout = self.disk.layout();
+        let mut rng = SmallRng::seed_from_u64(now.timestamp_millis() as u64);
+        for (i, seed) in self.web_seeds.iter_mut().enumerate() {
+            if !seed.is_ready(now) {
+                continue;
+            }
+            let Some(index) = self.picker.pick(&all, &mut rng) else {
+                break;
+            };
+            self.picker.set_ours(index, true);
+
+            let req = seed.start(index, layout);
+            let (http, events) = (self.http.clone(), self.peer_events_tx.clone());
+            tokio::spawn(async move {
+                let res = web_seed::fetch(&http, req).await;
+                let _ = events.send(PeerEvent::WebSeed(i, index, res));
+            });
+        }
+    }
+
+    // piece index arrived from a web seed, or couldn't be fetched. busy BEP-17 seeds say how
+    // long to wait before asking again
+    fn web_seed_fetched(&mut self, seed: usize, index: u32, res: Result<Vec<u8>>) {
+        match res {
+            Ok(data) => self.verify_piece(index, data),
+            Err(Error::WebSeedBusy(secs)) => {
+                let retry = Duration::seconds(secs as i64);
+                self.web_seed_failed(seed, index, Some(retry))
+            }
+            Err(_) => self.web_seed_failed(seed, index, None),
+        }
+    }
+
+    // back off a web seed which failed to send piece index, which can be picked again. the
+    // delay is retry if set, otherwise it grows with the seed's failures
+    fn web_seed_failed(&mut self, seed: usize, index: u32, retry: Option<Duration>) {
+        let Some(seed) = self.web_seeds.get_mut(seed) else {
+            return;
+        };
+        let mut rng = SmallRng::seed_from_u64(Utc::now().timestamp_millis() as u64);
+        let delay = retry.unwrap_or_else(|| Self::retry_delay(seed.failures() + 1, &mut rng));
+        seed.failed(Utc::now(), delay);
+        if !self.have.get(index as usize).is_some_and(|b| *b) {
+            self.picker.set_ours(index, false);
+        }
+    }
+
+    /// urls of the torrent's web seeds, see BEP-19 and BEP-17. https seeds aren't supported,
+    /// there's no tls, so they're left out
+    pub fn web_seeds(&self) -> Vec<&str> {
+        self.web_seeds.iter().map(WebSeed::url).collect()
+    }
+
+    // forget the requests to addr which the scheduler thinks are outstanding but the peer has
+    // dropped, eg. after choking us
+    fn release_requests(&mut self, addr: SocketAddr) {
+        let Some(peer) = self.peers.connection(addr) else {
+            return;
+        };
+        let requests = peer.requests();
+        let outstanding = |block| requests.contains(block);
+        self.scheduler.retain_requests(addr, outstanding);
+    }
+
+    // stop counting a peer which is going away towards piece availability, its requests are
+    // asked of someone else
+    fn forget_peer(&mut self, peer: &PeerHandle) {
+        self.picker.remove_peer(peer.info().bitfield());
+        self.scheduler.release_peer(peer.addr());
+    }
+
+    /// request the blocks of pieces with a deadline, soonest first, from the fastest peers which
+    /// have them and aren't choking us, see [PieceDeadlines::wanted]. peers with pieces we want
+    /// are told we're interested so they'll unchoke us
+    fn request_deadlines(&mut self) {
+        if self.deadlines.is_empty() || self.state != State::Active {
+            return;
+        }
+
+        let wanted = self.deadlines.wanted(std::time::Instant::now());
+        let mut peers: Vec<_> = self.peers.handles_mut().map(|(_, peer)| peer).collect();
+        peers.sort_by_key(|peer| Reverse(peer.requests().rate()));
+
+        for (block, copies) in wanted {
+            let requested = peers.iter().filter(|p| p.requests().contains(block));
+            let mut requested = requested.count();
+            for peer in peers.iter_mut() {
+                if requested >= copies {
+                    break;
+                }
+                if !peer.info().has(block.index) || peer.requests().contains(block) {
+                    continue;
+                }
+
+                peer.set_interested(true);
+                if !peer.is_choking_us() && peer.request(block) {
+                    requested += 1;
+                }
+            }
+        }
+    }
+
+    // a block arrived, the other peers it was asked of needn't send it
+    fn cancel_duplicates(&mut self, block: Block) {
+        for (_, peer) in self.peers.handles_mut() {
+            peer.cancel(block);
+        }
+    }
+
+    // whether block is within a piece we have, and so can be sent to peers
+    fn can_upload(&self, block: Block) -> bool {
+        let Some(size) = self.piece_size(block.index) else {
+            return false;
+        };
+        let end = block.begin as u64 + block.length as u64;
+        end <= size as u64 && self.have[block.index as usize]
+    }
+
+    /// start reading the blocks our peers asked for, as many as each can take, see
+    /// [PeerHandle::next_upload]. blocks are read in the background and sent once they're back,
+    /// see [Torrent::process_peer_events]. blocks of pieces we no longer have, eg. after a
+    /// recheck, are refused
+    fn serve_uploads(&mut self) {
+        let have = &self.have;
+        for (addr, peer) in self.peers.handles_mut() {
+            while let Some(block) = peer.next_upload() {
+                if !have[block.index as usize] {
+                    peer.finish_upload(block, Err(io::ErrorKind::NotFound.into()));
+                    continue;
+                }
+                let (disk, events) = (self.disk.clone(), self.peer_events_tx.clone());
+                tokio::spawn(async move {
+                    let data = disk.read(block.index, block.begin, block.length).await;
+                    let _ = events.send(PeerEvent::Read(addr, block, data));
+                });
+            }
+        }
+    }
+
+    /// give our upload slots to the interested peers the choker ranks best, choking everyone
+    /// else, see [Choker::unchoke]. the slots are recounted from our upload rate first
+    fn rechoke(&mut self) {
+        self.next_rechoke = Utc::now() + Duration::seconds(RECHOKE_INTERVAL);
+        let now = std::time::Instant::now();
+
+        let mut upload_rate = 0;
+        let mut candidates = vec![];
+        for (addr, peer) in self.peers.handles() {
+            let stats = peer.info().stats();
+            upload_rate += stats.upload_rate;
+            if peer.info().is_interested() {
+                candidates.push(Candidate {
+                    addr,
+                    download_rate: stats.download_rate,
+                    upload_rate: stats.upload_rate,
+                    last_unchoked: peer.last_unchoked(now),
+                    snubbed: peer.requests().is_snubbed(),
+                });
+            }
+        }
+        self.choker.set_measured_rate(upload_rate);
+
+        // partial seeds have nothing left to download either
+        let complete = self.bytes_left == 0 || self.partial_seed;
+        let unchoke = self.choker.unchoke(&candidates, complete);
+        let mut unchoke: HashSet<_> = unchoke.into_iter().collect();
+
+        // one of the peers left out is tried every so often, so peers we've nothing from yet
+        // get a chance to show what they'll send. it's picked again early if it goes away
+        let choked = candidates.iter().filter(|c| !unchoke.contains(&c.addr));
+        let choked: Vec<_> = choked.map(|c| (c.addr, c.snubbed)).collect();
+        let still_choked = |addr: &SocketAddr| choked.iter().any(|(a, _)| a == addr);
+        let kept = self.optimistic.filter(still_choked);
+        if kept.is_none() || Utc::now() >= self.next_optimistic {
+            let mut rng = SmallRng::seed_from_u64(Utc::now().timestamp_millis() as u64);
+            self.optimistic = Choker::optimistic_unchoke(&choked, &mut rng);
+            self.next_optimistic = Utc::now() + Duration::seconds(OPTIMISTIC_INTERVAL);
+        } else {
+            self.optimistic = kept;
+        }
+        unchoke.extend(self.optimistic);
+        for (addr, peer) in self.peers.handles_mut() {
+            let unchoked = unchoke.contains(&addr);
+            peer.set_choked(!unchoked);
+
+            // the peers we upload to split our upload limit once we're using all of it
+            let class = PeerClass::of(addr.ip());
+            let share = unchoked.then(|| self.choker.upload_share(class)).flatten();
+            peer.set_upload_share(share);
+        }
+    }
+
+    /// cap this torrent's upload rate at limit bytes/s, None lifts the cap. once it's reached
+    /// each unchoked peer is held to an even share of it, see [Choker::upload_share]. this
+    /// takes effect at the next rechoke
+    pub fn set_upload_limit(&mut self, limit: Option<u64>) {
+        self.choker.set_rate_limit(limit);
+    }
+
+    /// cap the piece data sent to the peer at addr at limit bytes/s, None lifts the cap. returns
+    /// false if we aren't connected to addr
+    pub fn set_peer_upload_limit(&mut self, addr: SocketAddr, limit: Option<u64>) -> bool {
+        let Some(peer) = self.peers.connection_mut(addr) else {
+            return false;
+        };
+        peer.set_upload_limit(limit);
+        true
+    }
+
+    /// want piece index by deadline, eg. to play a video while it downloads. pieces with a
+    /// deadline are requested before anything else, soonest first, and ones which are nearly due
+    /// are requested from more than one peer at once. the deadline is dropped once the piece has
+    /// arrived. returns false if there's no such piece
+    pub fn set_piece_deadline(&mut self, index: u32, deadline: std::time::Instant) -> bool {
+        let Some(length) = self.piece_size(index) else {
+            return false;
+        };
+        self.deadlines.set(index, length, deadline);
+        self.picker.set_ours(index, true);
+        self.scheduler.start(index);
+        true
+    }
+
+    /// when piece index is wanted by, None if it has no deadline or has since arrived
+    pub fn piece_deadline(&self, index: u32) -> Option<std::time::Instant> {
+        self.deadlines.deadline(index)
+    }
+
+    /// stop hurrying piece index, returning whether it had a deadline
+    pub fn clear_piece_deadline(&mut self, index: u32) -> bool {
+        self.deadlines.clear(index)
+    }
+
+    // length of piece index, the last piece may be short. padding counts, it's part of the pieces
+    fn piece_size(&self, index: u32) -> Option<u32> {
+        if index as usize >= self.info.pieces.len() {
+            return None;
+        }
+
+        let total: u64 = self.info.files.iter().map(|f| f.length).sum();
+        let piece_length = self.info.piece_length as u64;
+        let left = total.saturating_sub(index as u64 * piece_length);
+        Some(left.min(piece_length) as u32)
+    }
+
+    /// change how many peers this torrent uploads to at once, overriding [Config::upload_slots].
+    /// this takes effect at the next rechoke
+    pub fn set_upload_slots(&mut self, slots: UploadSlots) {
+        self.choker.set_policy(slots);
+    }
+
+    /// hand a newly connected peer to a task of its own, see [PeerHandle], and send it our
+    /// bitfield and extension handshake. permit is the connection's place under
+    /// [Config::max_connections], if it counts towards it
+    fn spawn_peer(
+        &self,
+        peer: Peer,
+        addr: SocketAddr,
+        permit: Option<ConnectionPermit>,
+    ) -> PeerHandle {
+        let peer = PeerHandle::spawn(peer, addr, self.peer_events_tx.clone(), permit);
+        // the bitfield may be left out while we have nothing, peers using the fast extension
+        // must be sent one of bitfield, have all or have none
+        let bitfield = match (peer.fast(), self.have.all(), self.have.any()) {
+            (true, true, _) => Some(Message::HaveAll),
+            (true, _, false) => Some(Message::HaveNone),
+            (_, _, true) => Some(Message::Bitfield(self.have.clone())),
+            (false, _, false) => None,
+        };
+        if let Some(msg) = bitfield {
+            peer.send(msg);
+        }
+        // peers which didn't set the extension bit don't understand extended messages
+        if peer.info().reserved().contains(ReservedBits::EXTENSION) {
+            let payload = self.extension_handshake().encode().into();
+            peer.send(Message::Extended { id: 0, payload });
+        }
+        peer
+    }
+
+    /// check url could be announced to, ie. it's an absolute url
+    fn valid_tracker(url: &str) -> bool {
+        match url.parse::<Uri>() {
+            Ok(uri) => uri.scheme().is_some() && uri.host().is_some(),
+            Err(_) => false,
+        }
+    }
+
+    /// move every file into dir, keeping their layout relative to the current base directory,
+    /// see [Storage::move_to]
+    async fn move_storage(&mut self, dir: &Path) -> Result<(), CommandError> {
+        if self.state == State::Active {
+            return Err(CommandError::StorageBusy);
+        }
+
+        let storage = self.disk.storage().clone();
+        storage.move_to(dir).await?;
+        for file in &mut self.info.files {
+            let rel = file.file.strip_prefix(&self.base_dir).unwrap_or(&file.file);
+            file.file = dir.join(rel);
+        }
+
+        self.base_dir = dir.to_path_buf();
+        self.disk = Arc::new(Self::disk_reader(&self.info, &self.read_cache, storage));
+        Ok(())
+    }
+
+    /// rename file index, keeping it in the same directory, see [Torrent::rename]
+    async fn rename_file(&mut self, index: usize, name: &str) -> Result<(), CommandError> {
+        let file = self.info.files.get(index).filter(|f| !f.is_padding());
+        let file = file.ok_or(CommandError::InvalidFileIndex(index))?;
+        let sanitized = utils::sanitize_path(name, self.sanitize);
+        let name = sanitized.ok_or_else(|| CommandError::InvalidFileName(name.into()))?;
+        let path = file.file.with_file_name(&*name);
+        self.rename(vec![(index, path)]).await
+    }
+
+    /// rename the directory every file is in, which is the file itself for single-file
+    /// torrents, see [Torrent::rename]
+    async fn rename_root(&mut self, name: &str) -> Result<(), CommandError> {
+        let sanitized = utils::sanitize_path(name, self.sanitize);
+        let name = sanitized.ok_or_else(|| CommandError::InvalidFileName(name.into()))?;
+        let root = self.base_dir.join(&*name);
+        let renames = self.info.files.iter().enumerate().filter_map(|(i, f)| {
+            let mut rel = f.file.strip_prefix(&self.base_dir).ok()?.components();
+            rel.next()?;
+            // joining an empty path would leave a trailing slash
+            match rel.as_path().as_os_str().is_empty() {
+                true => Some((i, root.clone())),
+                false => Some((i, root.join(rel.as_path()))),
+            }
+        });
+        let renames = renames.collect();
+        self.rename(renames).await
+    }
+
+    /// give files new paths, which are kept in resume data. like moving storage, the torrent
+    /// must not be active. paths must be within base_dir and can't be another file's
+    async fn rename(&mut self, renames: Vec<(usize, PathBuf)>) -> Result<(), CommandError> {
+        if self.state == State::Active {
+            return Err(CommandError::StorageBusy);
+        }
+
+        let renames: Vec<_> = renames
+            .into_iter()
+            .filter(|(i, path)| self.info.files[*i].file != *path)
+            .collect();
+        let mut paths: HashMap<_, _> = self.info.files.iter().map(|f| &f.file).zip(0..).collect();
+        for (i, path) in &renames {
+            let taken = paths.get(path).is_some_and(|j| j != i);
+            if taken || !utils::is_contained(&self.base_dir, path) {
+                return Err(CommandError::InvalidFileName(path.display().to_string()));
+            }
+            paths.insert(path, *i);
+        }
+
+        // padding files don't exist anywhere, there's nothing to rename
+        let files = &self.info.files;
+        let moves = renames.iter().filter(|(i, _)| !files[*i].is_padding());
+        let moves: Vec<_> = moves.cloned().collect();
+        self.disk.storage().rename(&moves).await?;
+        for (i, path) in renames {
+            let rel = path.strip_prefix(&self.base_dir).unwrap_or(&path);
+            self.renamed.insert(i, rel.to_path_buf());
+            self.info.files[i].file = path;
+        }
+        Ok(())
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn pause(&mut self) {
+        self.state = State::Paused;
+    }
+
+    pub fn resume(&mut self) {
+        self.state = State::Active;
+    }
+
+    /// take a resume sent through a handle while a disk quota is set, along with where to send
+    /// its outcome. it's left to the session to check the quota, see
+    /// [crate::tsunami::Tsunami::resume_torrent]
+    pub(crate) fn take_resume(&mut self) -> Option<Reply> {
+        self.pending_resume.take()
+    }
+
+    /// gracefully stop the torrent. unlike [Torrent::pause] our requests are cancelled and every
+    /// peer connection is flushed and closed, leaving the torrent quiescent until it is resumed.
+    /// known peer addresses are kept so they can be reconnected to later. once the data is
+    /// flushed to disk the torrent's resume data is emitted as an [Event::Stopped]. background
+    /// announces stop until [Torrent::start]
+    pub async fn stop(&mut self) {
+        self.state = State::Stopped;
+        self.announcer.stop();
+
+        let peers: Vec<_> = self.peers.take_connections().collect();
+        for mut peer in peers {
+            self.forget_peer(&peer);
+            peer.cancel_all();
+            peer.set_interested(false);
+            peer.shutdown();
+        }
+        let _ = self.disk.storage().flush().await;
+        if let Some(events) = &self.events {
+            let stopped = Event::Stopped {
+                info_hash: self.info.info_hash,
+                resume: Box::new(self.resume_data()),
+            };
+            events.emit(stopped).await;
+        }
+
+        // trackers only need to hear we stopped if they were told we started. this is best
+        // effort, we're stopping regardless
+        if self.announced_started {
+            let _ = self.refresh_peers(Some(AnnounceEvent::Stopped)).await;
+        }
+    }
+
+    // move files to where resume data says they were renamed to, returning those which were.
+    // paths which aren't valid here, or would put a file outside base_dir, are ignored
+    fn apply_renames(
+        files: &mut [File],
+        base_dir: &Path,
+        renamed: &BTreeMap<usize, PathBuf>,
+        policy: SanitizePolicy,
+    ) -> BTreeMap<usize, PathBuf> {
+        let valid = |c: Component| {
+            let Component::Normal(name) = c else {
+                return false;
+            };
+            let name = name.to_str();
+            name.is_some_and(|n| utils::sanitize_path(n, policy).is_some_and(|s| s == n))
+        };
+
+        let mut applied = BTreeMap::new();
+        for (&index, rel) in renamed {
+            let path = base_dir.join(rel);
+            let Some(file) = files.get_mut(index) else {
+                continue;
+            };
+            if rel.components().all(valid) && utils::is_contained(base_dir, &path) {
+                file.file = path;
+                applied.insert(index, rel.clone());
+            }
+        }
+        applied
+    }
+
+    fn validate(base_dir: &Path) -> Result<(), TorrentParseError> {
+        if !base_dir.has_root() {
+            return Err(TorrentParseError::InvalidBaseDir);
+        }
+
+        Ok(())
+    }
+
+    /// check if the torrent's file (or directory for multi-file torrents) already exists in
+    /// base_dir, returning the name to download into and whether existing data should be
+    /// verified
+    fn resolve_conflict(
+        info: &InfoAST,
+        name: &str,
+        base_dir: &Path,
+        policy: ConflictPolicy,
+    ) -> Result<(String, bool), TorrentParseError> {
+        let root = base_dir.join(name);
+        if !root.exists() {
+            return Ok((name.into(), false));
+        }
+
+        match policy {
+            ConflictPolicy::Reuse => Ok((name.into(), true)),
+            ConflictPolicy::Fail => Err(TorrentParseError::FileExists(root)),
+            ConflictPolicy::Rename => {
+                // only single files have an extension to preserve, `dir.v2` is a directory name
+                let (stem, ext) = match name.rsplit_once('.') {
+                    Some((stem, ext)) if info.length.is_some() && !stem.is_empty() => {
+                        (stem, format!(".{ext}"))
+                    }
+                    _ => (name, String::new()),
+                };
+
+                let name = (1..)
+                    .map(|n| format!("{stem} ({n}){ext}"))
+                    .find(|name| !base_dir.join(name).exists())
+                    .unwrap();
+                Ok((name, false))
+            }
+        }
+    }
+
+    fn build_files(
+        info: &InfoAST,
+        name: &str,
+        base_dir: &Path,
+        policy: SanitizePolicy,
+    ) -> Result<Vec<File>, TorrentParseError> {
+        // single file case, name is filename
+        let files = if let Some(len) = info.length {
+            // a single file has nothing else in the torrent to link to
+            let file = File::new(len, base_dir, &[name], info.attr, None, policy)?;
+            vec![file]
+        } else {
+            let torrent_dir = base_dir.join(Path::new(name));
+
+            info.files
+                .as_ref()
+                .ok_or(TorrentParseError::InvalidFileLayout)?
+                .iter()
+                .map(|f| {
+                    let (attr, symlink) = (f.attr, f.symlink_path.as_deref());
+                    File::new(f.length, &torrent_dir, &f.path, attr, symlink, policy)
+                })
+                .try_collect()?
+        };
+
+        // names are sanitized, but make sure nothing slipped through that would let a torrent
+        // write outside of base_dir
+        let escaped = files
+            .iter()
+            .find(|f| !utils::is_contained(base_dir, &f.file));
+        if let Some(file) = escaped {
+            return Err(TorrentParseError::PathTraversal(file.file.clone()));
+        }
+
+        // links only stay within the torrent if they're where their path says. anything beneath
+        // another link would really be wherever that link points
+        let links: Vec<_> = files.iter().filter(|f| f.symlink.is_some()).collect();
+        let beneath = |f: &File| {
+            let under = |link: &&File| f.file != link.file && f.file.starts_with(&link.file);
+            links.iter().any(under)
+        };
+        if let Some(file) = files.iter().find(|f| beneath(f)) {
+            return Err(TorrentParseError::PathTraversal(file.file.clone()));
+        }
+
+        Ok(files)
+    }
+
+    /// the merkle tree of every v2 file with a pieces root, with the piece layers the torrent
+    /// included. piece layers have already been checked by [TorrentAST::decode]
+    fn build_file_trees(
+        info: &InfoAST,
+        piece_layers: Option<&HashMap<&[u8], &[u8]>>,
+    ) -> HashMap<Sha256Hash, FileTree> {
+        let mut trees = HashMap::new();
+        for file in info.file_tree.iter().flatten() {
+            let Some(Ok(root)) = file.pieces_root.map(Sha256Hash::try_from) else {
+                continue;
+            };
+
+            let mut tree = FileTree::new(root, file.length as u64, info.piece_length as u64);
+            if let Some(layer) = piece_layers.and_then(|l| l.get(&root[..])) {
+                let layer: Vec<_> = layer.chunks(32).map(|h| h.try_into().unwrap()).collect();
+                tree.set_piece_layer(&layer);
+            }
+            trees.insert(root, tree);
+        }
+
+        trees
+    }
+
+    /// where the pieces of every v2 file with a pieces root start. files are laid out in the
+    /// order of the file tree, each one starting a new piece
+    fn build_v2_files(info: &InfoAST) -> Vec<V2File> {
+        let piece_length = info.piece_length as u64;
+        let mut first_piece = 0;
+        let mut files = vec![];
+        for file in info.file_tree.iter().flatten() {
+            let length = file.length as u64;
+            if let Some(Ok(pieces_root)) = file.pieces_root.map(Sha256Hash::try_from) {
+                files.push(V2File {
+                    first_piece: first_piece as u32,
+                    pieces_root,
+                    length,
+                });
+            }
+            first_piece += length.div_ceil(piece_length);
+        }
+
+        files
+    }
+
+    /// announce to our trackers as chosen by our [AnnouncePolicy], adding any new peers they
+    /// respond with. event is sent along with the announce. if it's None any pending
+    /// started/completed event is sent instead, and partial seeds send paused. announces with an
+    /// event are always sent, regardless of the tracker's interval. trackers which recently failed
+    /// are skipped until their backoff expires
+    async fn refresh_peers(&mut self, event: Option<AnnounceEvent>) -> Result<()> {
+        let Some(announce) = self.prepare_announce(event)? else {
+            return Ok(());
+        };
+        let announced = announce.await;
+        self.announced(announced).await
+    }
+
+    /// send an announce as [Torrent::refresh_peers] does, but from a task of its own so a slow
+    /// tracker can't hold up the torrent. the responses come back as a PeerEvent::Announced,
+    /// and the outcome is sent to reply once they've been handled
+    fn announce_in_background(&mut self, reply: Option<Reply>) {
+        let res = match self.prepare_announce(None) {
+            Ok(Some(announce)) => {
+                let events = self.peer_events_tx.clone();
+                tokio::spawn(async move {
+                    let _ = events.send(PeerEvent::Announced(announce.await, reply));
+                });
+                return;
+            }
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+
+        self.schedule_announce(&res);
+        if let Some(reply) = reply {
+            let _ = reply.send(res.map_err(CommandError::from));
+        }
+    }
+
+    /// build the announce [Torrent::refresh_peers] sends, or None if it isn't due yet. the
+    /// announce doesn't borrow the torrent, its responses are handled by [Torrent::announced]
+    fn prepare_announce(
+        &self,
+        event: Option<AnnounceEvent>,
+    ) -> Result<Option<impl Future<Output = Announced> + Send + 'static>> {
+        let event = event.or_else(|| self.pending_event());
+        // a torrent without any connected peers can't wait out the interval
+        let starving = self.peers.connections().next().is_none();
+        let due = event.is_some() || Utc::now() >= self.next_announce || starving;
+        if !due {
+            return Ok(None);
+        }
+        let partial_seed = self.partial_seed.then_some(AnnounceEvent::Paused);
+        let event = event.or(partial_seed);
+
+        // todo: fall back to DHT/PEX/LSD once they're supported
+        if self.trackers.is_empty() {
+            return Err(Error::NoPeerSource);
+        }
+
+        // trackers which recently failed are left out
+        let announce = |tr: &String| (tr.clone(), self.announce(tr, event));
+        let tiers = self.trackers.iter().map(|tier| {
+            let tier = tier.iter().filter(|tr| !self.backing_off(tr));
+            tier.map(&announce).collect::<Vec<_>>()
+        });
+        let tiers: Vec<_> = tiers.collect();
+        let policy = self.announce_policy;
+
+        Ok(Some(async move {
+            let resps = match policy {
+                AnnouncePolicy::FailoverTiers => {
+                    let mut resps = vec![];
+                    for tier in tiers {
+                        resps.extend(announce_tier(tier).await);
+                        if resps.last().is_some_and(|(_, resp)| resp.is_ok()) {
+                            break;
+                        }
+                    }
+                    resps
+                }
+                AnnouncePolicy::AllTiers => {
+                    let tiers = join_all(tiers.into_iter().map(announce_tier)).await;
+                    tiers.into_iter().flatten().collect()
+                }
+                AnnouncePolicy::AllTrackers => {
+                    let trackers = tiers.into_iter().flatten();
+                    let (trackers, announces): (Vec<_>, Vec<_>) = trackers.unzip();
+                    let resps = join_all(announces).await;
+                    trackers.into_iter().zip(resps).collect()
+                }
+            };
+            Announced { event, resps }
+        }))
+    }
+
+    /// handle the responses to an announce built by [Torrent::prepare_announce], backing off
+    /// from the trackers which failed and adding any new peers the others responded with
+    async fn announced(&mut self, announced: Announced) -> Result<()> {
+        let Announced { event, resps } = announced;
+        let mut announced = vec![];
+        for (tracker, resp) in resps {
+            match resp {
+                Ok(resp) => {
+                    self.tracker_status.remove(&tracker);
+                    if self.announce_policy != AnnouncePolicy::AllTrackers {
+                        self.promote_tracker(&tracker);
+                    }
+                    announced.push((tracker, resp));
+                }
+                Err(e) => self.tracker_failed(tracker, e),
+            }
+        }
+
+        if announced.is_empty() {
+            return Err(Error::NoTrackerAvailable);
+        }
+
+        for (tracker, resp) in &announced {
+            if let Some(events) = &self.events
+                && let Some(message) = &resp.warning
+            {
+                let warning = Event::TrackerWarning {
+                    info_hash: self.info.info_hash,
+                    tracker: tracker.clone(),
+                    message: message.clone(),
+                };
+                events.emit(warning).await;
+            }
+        }
+        let announced: Vec<_> = announced.into_iter().map(|(_, resp)| resp).collect();
+
+        if let Some(ip) = announced.iter().find_map(|resp| resp.external_ip) {
+            self.external_ip.set(ip);
+        }
+
+        self.next_announce = Utc::now() + Self::announce_interval(&announced);
+        self.record_event(event);
+
+        // trackers can disagree about the swarm, keep the most complete count
+        let stats = announced.iter().filter_map(|resp| resp.stats);
+        self.swarm = stats.max_by_key(|s| s.seeders + s.leechers).or(self.swarm);
+
+        // update our list of peers, unless we're on our way out
+        if event != Some(AnnounceEvent::Stopped) {
+            // trackers may include us in their peer list
+            let port = self.listen_port();
+            let ours = [self.external_ip.get(), self.bind_address];
+            for ip in ours.into_iter().flatten() {
+                self.peers.add_ours(SocketAddr::new(ip, port));
+            }
+            for peer in announced.into_iter().flat_map(|resp| resp.peers) {
+                self.peers.add(peer, PeerSources::TRACKER);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// time until the next announce, respecting the interval of every tracker we heard from.
+    /// this is at least 5m or the tracker's min interval if longer, and at most a day
+    fn announce_interval(announced: &[AnnounceResp]) -> Duration {
+        let interval = announced
+            .iter()
+            .map(|resp| resp.interval.max(resp.min_interval.unwrap_or(0)))
+            .max()
+            .unwrap_or(0);
+        let interval = interval.clamp(MIN_ANNOUNCE_INTERVAL, MAX_ANNOUNCE_INTERVAL);
+        Duration::seconds(interval as i64)
+    }
+
+    /// schedule the background announce after res, the outcome of [Torrent::refresh_peers]
+    fn schedule_announce(&self, res: &Result<()>) {
+        let next = match res {
+            Ok(()) => Some(self.next_announce),
+            // every tracker failed, try again once the first of them is out of backoff
+            Err(Error::NoTrackerAvailable) => {
+                self.tracker_status.values().map(|s| s.retry_at).min()
+            }
+            // there's no one to announce to until trackers are added
+            Err(_) => None,
+        };
+
+        if let Some(at) = next {
+            self.announcer.schedule(at);
+        }
+    }
+
+    /// move tracker, which just responded, to the front of its tier so it's tried first next
+    /// time
+    ///
+    /// for example, if b3 is the first tracker to respond:
+    ///     [ [a1, a2], [b1, b2, b3], [c1] ]
+    ///
+    /// the new tracker list becomes:
+    ///     [ [a1, a2], [b3, b1, b2], [c1] ]
+    ///
+    /// See BEP-12 for more details
+    fn promote_tracker(&mut self, tracker: &str) {
+        for tier in &mut self.trackers {
+            if let Some(inner) = tier.iter().position(|tr| tr == tracker) {
+                tier[..=inner].rotate_right(1);
+                return;
+            }
+        }
+    }
+
+    /// send a single announce to tracker, using the protocol its url's scheme calls for. the
+    /// announce is built up front so it can be sent without the torrent
+    fn announce(
+        &self,
+        tracker: &str,
+        event: Option<AnnounceEvent>,
+    ) -> BoxFuture<'static, Result<AnnounceResp>> {
+        let url = tracker.to_string();
+        let (timeout, local) = (self.config.http_timeout, self.bind_address);
+        match TrackerProtocol::of(tracker) {
+            Some(TrackerProtocol::Http) => {
+                let (http, req) = (self.http.clone(), self.http_announce(tracker, event));
+                Box::pin(async move {
+                    let resp = utils::get_body(&http, req?).await?;
+                    Self::parse_tracker_resp(resp)
+                })
+            }
+            // udp can't go through our proxies, and going around them would leak our address
+            Some(TrackerProtocol::Udp) if self.config.proxy.is_none() => {
+                let req = self.announce_req(event);
+                Box::pin(async move { tracker::announce_udp(&url, &req, local, timeout).await })
+            }
+            // there's no tls for wss:// trackers
+            Some(TrackerProtocol::WebSocket)
+                if self.config.proxy.is_none() && tracker.starts_with("ws://") =>
+            {
+                let req = self.announce_req(event);
+                Box::pin(async move { tracker::announce_ws(&url, &req, local, timeout).await })
+            }
+            _ => Box::pin(future::ready(Err(Error::UnsupportedTracker(url)))),
+        }
+    }
+
+    /// the GET request announcing event to an http tracker
+    fn http_announce(&self, tracker: &str, event: Option<AnnounceEvent>) -> Result<Request<Body>> {
+        let mut url = String::new();
+        self.build_tracker_url(tracker, event, &mut url);
+        self.tracker_request(tracker, &url)
+    }
+
+    /// request sent to udp and WebSocket trackers
+    fn announce_req(&self, event: Option<AnnounceEvent>) -> AnnounceReq {
+        AnnounceReq {
+            info_hash: self.info.info_hash,
+            peer_id: *self.peer_id,
+            downloaded: self.downloaded,
+            left: self.bytes_left,
+            uploaded: self.uploaded,
+            event,
+            port: self.listen_port(),
+        }
+    }
+
+    /// whether tracker recently failed and shouldn't be retried yet
+    fn backing_off(&self, tracker: &str) -> bool {
+        match self.tracker_status.get(tracker) {
+            Some(status) => Utc::now() < status.retry_at,
+            None => false,
+        }
+    }
+
+    /// dial the known peers we aren't connected to or already dialing, up to
+    /// [Config::max_peers] and [Config::max_connections]. at most [Config::max_half_open]
+    /// connections are made at once across the session. dials run in the background and come
+    /// back as a PeerEvent::Dialed, peers which can't be connected to are left for
+    /// [REDIAL_INTERVAL] seconds
+    fn connect_peers(&mut self) {
+        if self.state != State::Active {
+            return;
+        }
+        let now = Utc::now();
+        self.redial_at.retain(|_, at| now < *at);
+
+        let mut room = self.connection_room();
+        let pending = self.peers.pending();
+        let pending = pending.filter(|addr| {
+            !self.dialing.contains(addr)
+                && !self.redial_at.contains_key(addr)
+                && !self.ban_list.contains(addr.ip())
+        });
+        // capped peers take a place under max_connections before they're dialed
+        let pending = pending.filter_map(|addr| match self.is_capped(addr) {
+            false => Some((addr, None)),
+            true if room > 0 => {
+                let permit = self.connection_limits.try_connection()?;
+                room -= 1;
+                Some((addr, Some(permit)))
+            }
+            true => None,
+        });
+        let pending: Vec<_> = pending.collect();
+
+        let (info_hash, peer_id) = (self.info.info_hash, *self.peer_id);
+        let pieces = self.info.pieces.len();
+        let timeouts = Timeouts::new(&self.config);
+        for (addr, permit) in pending {
+            self.dialing.insert(addr);
+            let (limits, dialer) = (self.connection_limits.clone(), self.dialer.clone());
+            let events = self.peer_events_tx.clone();
+            tokio::spawn(async move {
+                let dialing = limits.half_open().await;
+                let peer = Peer::connect(addr, &info_hash, &peer_id, pieces, &dialer, timeouts);
+                let peer = peer.await.ok().map(Box::new);
+                drop(dialing);
+                let _ = events.send(PeerEvent::Dialed(addr, peer, permit));
+            });
+        }
+    }
+
+    // a dial started by connect_peers finished. we may have connected to the peer some other
+    // way in the meantime
+    fn dialed(
+        &mut self,
+        addr: SocketAddr,
+        peer: Option<Box<Peer>>,
+        permit: Option<ConnectionPermit>,
+    ) {
+        self.dialing.remove(&addr);
+        let Some(peer) = peer else {
+            let redial = Utc::now() + Duration::seconds(REDIAL_INTERVAL);
+            self.redial_at.insert(addr, redial);
+
+            // peers other peers told us of may be behind a NAT one of them can get us through
+            let sources = self.peers.sources(addr);
+            let pex = sources.is_some_and(|s| s.contains(PeerSources::PEX));
+            if pex && !self.holepunches.contains_key(&addr) {
+                self.rendezvous(addr);
+            }
+            // ask the trackers for more peers rather than waiting out their interval
+            if self.peers.connections().next().is_none() && self.dialing.is_empty() {
+                self.announcer.wake();
+            }
+            return;
+        };
+        if self.state != State::Active || self.peers.connection(addr).is_some() {
+            return;
+        }
+
+        let peer = self.spawn_peer(*peer, addr, permit);
+        if self.peers.connected(addr, peer) {
+            self.remember_peer(addr);
+        }
+    }
+
+    /// advertise the peers we connected to and dropped since the last round to every connected
+    /// peer supporting ut_pex, at most once every [PEX_INTERVAL] seconds. peers connected since
+    /// the last round are sent every peer instead. private torrents never use PEX
+    fn send_pex(&mut self) {
+        if self.info.private || Utc::now() < self.next_pex {
+            return;
+        }
+        self.next_pex = Utc::now() + Duration::seconds(PEX_INTERVAL);
+
+        let mut connected = HashMap::new();
+        for (addr, peer) in self.peers.handles() {
+            let seed = match peer.info().is_seed() {
+                true => PexFlags::SEED,
+                false => PexFlags::empty(),
+            };
+            connected.insert(addr, PexFlags::REACHABLE | seed);
+        }
+        // peers which connected to us did so from a port they don't accept connections on
+        let mut advertised = connected.clone();
+        advertised.retain(|&addr, _| self.peers.sources(addr) != Some(PeerSources::INCOMING));
+
+        let (prev, everyone) = (&self.pex_advertised, &advertised);
+        let added = everyone.iter().filter(|(addr, _)| !prev.contains(addr));
+        let added: Vec<_> = added.collect();
+        let dropped = prev.iter().filter(|addr| !everyone.contains_key(addr));
+        let dropped: Vec<_> = dropped.collect();
+
+        for (to, peer) in self.peers.handles() {
+            let (added, dropped) = match self.pex_connected.contains(&to) {
+                true => (added.clone(), dropped.clone()),
+                false => (everyone.iter().collect(), vec![]),
+            };
+            // a peer doesn't need to hear of itself
+            let added = added.into_iter().filter(|&(&addr, _)| addr != to);
+            let msg = PexMessage {
+                added: added.map(|(&a, &f)| (a, f)).take(MAX_PEX_PEERS).collect(),
+                dropped: dropped.into_iter().copied().take(MAX_PEX_PEERS).collect(),
+            };
+            if !msg.is_empty() {
+                peer.send_extended("ut_pex", &msg.encode());
+            }
+        }
+
+        self.pex_connected = connected.into_keys().collect();
+        self.pex_advertised = advertised.into_keys().collect();
+    }
+
+    /// add the peers a ut_pex message from a connected peer added, returning how many are new.
+    /// dropped peers are kept since we may hear of them elsewhere. private torrents ignore PEX
+    pub(crate) fn add_pex_peers(&mut self, payload: &[u8]) -> usize {
+        let Some(msg) = PexMessage::decode(payload) else {
+            return 0;
+        };
+        if self.info.private {
+            return 0;
+        }
+
+        let mut new = 0;
+        for (addr, _) in msg.added {
+            if self.peers.add(addr, PeerSources::PEX) {
+                new += 1;
+            }
+        }
+        new
+    }
+
+    /// ask a connected peer supporting ut_holepunch to introduce us to target, a peer we can't
+    /// connect to directly. each attempt at the same peer asks a relay not asked before, up to
+    /// [MAX_HOLEPUNCH_RELAYS]. returns whether a relay was asked
+    pub(crate) fn rendezvous(&mut self, target: SocketAddr) -> bool {
+        let tried = self.holepunches.entry(target).or_default();
+        let mut relays = self.peers.handles().filter(|&(addr, peer)| {
+            let supported = peer.info().extension_id("ut_holepunch").is_some();
+            addr != target && !tried.contains(&addr) && supported
+        });
+
+        let msg = Holepunch::Rendezvous(target).encode();
+        if tried.len() < MAX_HOLEPUNCH_RELAYS
+            && let Some((relay, peer)) = relays.next()
+            && peer.send_extended("ut_holepunch", &msg)
+        {
+            tried.push(relay);
+            return true;
+        }
+
+        self.holepunches.remove(&target);
+        false
+    }
+
+    /// handle a ut_holepunch message from the connected peer at from. we introduce peers to
+    /// each other as a relay, connect to peers we're introduced to and ask another relay when
+    /// one we asked isn't connected to the peer
+    pub(crate) fn on_holepunch(&mut self, from: SocketAddr, payload: &[u8]) {
+        let Some(msg) = Holepunch::decode(payload) else {
+            return;
+        };
+
+        match msg {
+            Holepunch::Rendezvous(target) => self.relay(from, target),
+            Holepunch::Connect(addr) => self.holepunch_connect(addr),
+            Holepunch::Error(target, err) => {
+                let asked = self.holepunches.get(&target);
+                if !asked.is_some_and(|relays| relays.contains(&from)) {
+                    return;
+                }
+
+                match err {
+                    // another relay may be connected to the peer
+                    HolepunchError::NotConnected | HolepunchError::NoSupport => {
+                        self.rendezvous(target);
+                    }
+                    HolepunchError::NoSuchPeer | HolepunchError::NoSelf => {
+                        self.holepunches.remove(&target);
+                    }
+                }
+            }
+        }
+    }
+
+    /// introduce the peers at from and target to each other, or tell from why we can't
+    fn relay(&self, from: SocketAddr, target: SocketAddr) {
+        let err = if target == from {
+            Some(HolepunchError::NoSelf)
+        } else if self.peers.sources(target).is_none() {
+            Some(HolepunchError::NoSuchPeer)
+        } else {
+            let connect = Holepunch::Connect(from).encode();
+            match self.peers.connection(target) {
+                Some(peer) if peer.info().extension_id("ut_holepunch").is_none() => {
+                    Some(HolepunchError::NoSupport)
+                }
+                Some(peer) if peer.send_extended("ut_holepunch", &connect) => None,
+                _ => Some(HolepunchError::NotConnected),
+            }
+        };
+
+        let reply = match err {
+            Some(err) => Holepunch::Error(target, err),
+            None => Holepunch::Connect(target),
+        };
+        if let Some(peer) = self.peers.connection(from) {
+            peer.send_extended("ut_holepunch", &reply.encode());
+        }
+    }
+
+    /// connect to a peer a relay introduced us to. the peer is connecting to us at the same
+    /// time, so the first attempts may fail while its NAT catches up. the attempts are made in
+    /// the background, a peer connected to comes back as a PeerEvent::Holepunched
+    fn holepunch_connect(&mut self, addr: SocketAddr) {
+        self.holepunches.remove(&addr);
+        if self.peers.connection(addr).is_some()
+            || self.peers.is_banned(addr.ip())
+            || self.ban_list.contains(addr.ip())
+            || self.is_capped(addr) && self.connection_room() == 0
+        {
+            return;
+        }
+        let permit = match self.is_capped(addr) {
+            true => match self.connection_limits.try_connection() {
+                Some(permit) => Some(permit),
+                None => return,
+            },
+            false => None,
+        };
+
+        let (info_hash, peer_id) = (self.info.info_hash, *self.peer_id);
+        let pieces = self.info.pieces.len();
+        let (limits, dialer) = (self.connection_limits.clone(), self.dialer.clone());
+        let timeouts = Timeouts::new(&self.config);
+        let events = self.peer_events_tx.clone();
+        tokio::spawn(async move {
+            for attempt in 0..HOLEPUNCH_ATTEMPTS {
+                if attempt > 0 {
+                    tokio::time::sleep(HOLEPUNCH_RETRY).await;
+                }
+
+                let dialing = limits.half_open().await;
+                let peer = Peer::connect(addr, &info_hash, &peer_id, pieces, &dialer, timeouts);
+                let peer = peer.await;
+                drop(dialing);
+                if let Ok(peer) = peer {
+                    let _ = events.send(PeerEvent::Holepunched(addr, Box::new(peer), permit));
+                    return;
+                }
+            }
+        });
+    }
+
+    // a peer a relay introduced us to was connected to, see holepunch_connect. we may have
+    // connected to it some other way in the meantime
+    fn holepunched(&mut self, addr: SocketAddr, peer: Peer, permit: Option<ConnectionPermit>) {
+        if self.state != State::Active || self.peers.connection(addr).is_some() {
+            return;
+        }
+        // the relay told us of the peer, much like it would over PEX
+        self.peers.add(addr, PeerSources::PEX);
+        let peer = self.spawn_peer(peer, addr, permit);
+        if self.peers.connected(addr, peer) {
+            self.remember_peer(addr);
+        }
+    }
+
+    /// take a peer which connected to us and completed its handshake, see [Peer::accept],
+    /// returning whether it was kept. peers are refused while the torrent isn't active, or if
+    /// we're already connected to them or have no room for them
+    pub(crate) fn add_incoming(&mut self, peer: Peer, addr: SocketAddr) -> bool {
+        if self.state != State::Active
+            || self.peers.connection(addr).is_some()
+            || self.ban_list.contains(addr.ip())
+            || self.is_capped(addr) && self.connection_room() == 0
+        {
+            return false;
+        }
+        let permit = match self.is_capped(addr) {
+            true => match self.connection_limits.try_connection() {
+                Some(permit) => Some(permit),
+                None => return false,
+            },
+            false => None,
+        };
+
+        // banned addresses and our own aren't kept
+        self.peers.add(addr, PeerSources::INCOMING);
+        if self.peers.sources(addr).is_none() {
+            return false;
+        }
+        let peer = self.spawn_peer(peer, addr, permit);
+        self.peers.connected(addr, peer)
+    }
+
+    /// answer a peer's BEP-52 hash request from our v2 file trees
+    pub(crate) fn hash_response(&self, req: HashRequest) -> Message {
+        let tree = self.info.file_trees.get(&req.pieces_root);
+        match tree.and_then(|tree| tree.hashes(&req)) {
+            Some(hashes) => Message::Hashes(req, hashes.into()),
+            None => Message::HashReject(req),
+        }
+    }
+
+    /// check hashes a peer sent in answer to req, keeping the piece hashes among them. returns
+    /// whether they were valid
+    pub(crate) fn add_hashes(&mut self, req: HashRequest, hashes: &[Sha256Hash]) -> bool {
+        let tree = self.info.file_trees.get_mut(&req.pieces_root);
+        tree.is_some_and(|tree| tree.add_hashes(&req, hashes))
+    }
+
+    /// hash requests for every piece hash of our v2 files we don't know yet. torrents added
+    /// without their piece layers need these before their pieces can be verified
+    pub(crate) fn hash_requests(&self) -> Vec<HashRequest> {
+        let trees = self.info.file_trees.values();
+        trees.flat_map(FileTree::missing).collect()
+    }
+
+    /// spread [Torrent::hash_requests] over the connected peers which advertised v2 support, at
+    /// most once every [HASH_REQUEST_INTERVAL] seconds. rejected requests are asked again of
+    /// another peer next round
+    fn request_hashes(&mut self) {
+        if Utc::now() < self.next_hash_request || self.state != State::Active {
+            return;
+        }
+        self.next_hash_request = Utc::now() + Duration::seconds(HASH_REQUEST_INTERVAL);
+
+        let requests = self.hash_requests();
+        let peers = self.peers.handles().map(|(_, peer)| peer);
+        let mut peers: Vec<_> = peers
+            .filter(|peer| peer.info().reserved().contains(ReservedBits::V2))
+            .collect();
+        if requests.is_empty() || peers.is_empty() {
+            return;
+        }
+        let mut rng = SmallRng::seed_from_u64(Utc::now().timestamp_millis() as u64);
+        peers.shuffle(&mut rng);
+        for (req, peer) in requests.into_iter().zip(peers.iter().cycle()) {
+            peer.send(Message::HashRequest(req));
+        }
+    }
+
+    // what piece index must hash to, None if there's no such piece
+    fn piece_check(&self, index: u32) -> Option<PieceCheck> {
+        Some(PieceCheck {
+            sha1: *self.info.pieces.get(index as usize)?,
+            v2: self.v2_piece(index),
+        })
+    }
+
+    // the hash piece index must match as a piece of a v2 file, along with the number of its bytes
+    // which are the file's, the rest is padding. None for v1 torrents, or if we don't know the
+    // hash yet
+    fn v2_piece(&self, index: u32) -> Option<(PieceHash, usize)> {
+        let files = &self.info.v2_files;
+        let after = files.partition_point(|f| f.first_piece <= index);
+        let file = &files[after.checked_sub(1)?];
+
+        let piece_length = self.info.piece_length as u64;
+        let offset = (index - file.first_piece) as u64 * piece_length;
+        if offset >= file.length {
+            return None;
+        }
+        let tree = self.info.file_trees.get(&file.pieces_root)?;
+        let hash = tree.piece((index - file.first_piece) as usize)?;
+        Some((hash, (file.length - offset).min(piece_length) as usize))
+    }
+
+    /// number of new connections [Config::max_peers] allows
+    fn connection_room(&self) -> usize {
+        let Some(max) = self.config.max_peers else {
+            return usize::MAX;
+        };
+
+        let connected = self.peers.connections();
+        max.saturating_sub(connected.filter(|&addr| self.is_capped(addr)).count())
+    }
+
+    /// port peers connect to us on
+    fn listen_port(&self) -> u16 {
+        self.listen_port.load(Ordering::Relaxed)
+    }
+
+    /// whether connections to addr count towards [Config::max_peers]
+    fn is_capped(&self, addr: SocketAddr) -> bool {
+        !self.config.exempt_lan || PeerClass::of(addr.ip()) == PeerClass::Wan
+    }
+
+    /// move addr to the back of recent_peers, dropping the oldest peer if we have too many
+    fn remember_peer(&mut self, addr: SocketAddr) {
+        self.recent_peers.retain(|&p| p != addr);
+        if self.recent_peers.len() == MAX_WARM_PEERS {
+            self.recent_peers.remove(0);
+        }
+
+        self.recent_peers.push(addr);
+    }
+
+    /// ask our trackers for swarm statistics, returning the first successful response. trackers
+    /// whose announce url doesn't follow the /scrape convention are skipped
+    pub async fn scrape(&self) -> Result<ScrapeData> {
+        let mut scrapable = false;
+
+        let http = self
+            .trackers
+            .iter()
+            .flatten()
+            .filter(|tr| TrackerProtocol::of(tr) == Some(TrackerProtocol::Http));
+        for tracker in http {
+            let Some(scrape) = Self::scrape_url(tracker) else {
+                continue;
+            };
+            scrapable = true;
+
+            let mut url = scrape;
+            let sep = Self::query_separator(&url);
+            let info_hash = PercentEncode(&self.info.info_hash);
+            let _ = write!(&mut url, "{sep}info_hash={info_hash}");
+            self.write_auth_query(tracker, &mut url);
+
+            let req = self.tracker_request(tracker, &url)?;
+            let Ok(body) = utils::get_body(&self.http, req).await else {
+                continue;
+            };
+            if let Ok(data) = Self::parse_scrape_resp(body, &self.info.info_hash) {
+                return Ok(data);
+            }
+        }
+
+        Err(match scrapable {
+            true => Error::NoTrackerAvailable,
+            false => Error::ScrapeUnsupported,
+        })
+    }
+
+    /// convert an announce url to its scrape url. this only works if the last path segment of
+    /// tracker starts with "announce", which is replaced with "scrape"
+    ///
+    /// See BEP-48 for more details
+    fn scrape_url(tracker: &str) -> Option<String> {
+        let (path, query) = tracker.split_once('?').unwrap_or((tracker, ""));
+        let slash = path.rfind('/')?;
+        let rest = path[slash + 1..].strip_prefix("announce")?;
+
+        let mut url = format!("{}scrape{rest}", &path[..=slash]);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(query);
+        }
+
+        Some(url)
+    }
+
+    /// back off from tracker after a failed announce
+    fn tracker_failed(&mut self, tracker: String, error: Error) {
+        let failures = self.tracker_status.get(&tracker).map_or(0, |s| s.failures) + 1;
+
+        let mut rng = SmallRng::seed_from_u64(Utc::now().timestamp_millis() as u64);
+        let status = TrackerStatus {
+            failures,
+            retry_at: Utc::now() + Self::retry_delay(failures, &mut rng),
+            error,
+        };
+        self.tracker_status.insert(tracker, status);
+    }
+
+    /// exponential backoff for a tracker or web seed that failed failures times in a row. the
+    /// delay is jittered by up to 25% so peers that lost the same tracker don't all retry at once
+    fn retry_delay(failures: u32, rng: &mut impl Rng) -> Duration {
+        let delay = RETRY_BASE << failures.saturating_sub(1).min(16);
+        let delay = delay.min(RETRY_MAX) as f64 * rng.gen_range(0.75..1.25);
+
+        Duration::milliseconds((delay * 1000.0) as i64)
+    }
+
+    /// count bytes of newly verified pieces, see [Torrent::piece_passed] for what's left
+    pub(crate) fn add_downloaded(&mut self, bytes: u64) {
+        self.downloaded += bytes;
+        let now = std::time::Instant::now();
+        self.transfer.record_download(bytes, now);
+    }
+
+    // work out how much of the files we want is left to download from scratch, eg. once which
+    // are wanted changes. pieces which pass their hash check are taken off as they do
+    fn update_left(&mut self) {
+        let left = self.wanted_left();
+        self.set_left(left);
+    }
+
+    // record that left bytes of the files we want are still to download. once they're complete
+    // we're done, and trackers are told straight away, or a partial seed if we skipped any
+    fn set_left(&mut self, left: u64) {
+        let finished = self.bytes_left > 0 && left == 0;
+        self.bytes_left = left;
+        if finished {
+            self.announcer.wake();
+        }
+        self.set_partial_seed(left == 0 && !self.have.all());
+    }
+
+    // bytes of the files we want in pieces we want, whether we have them or not
+    fn wanted_size(&self) -> u64 {
+        self.wanted_bytes(|_| true)
+    }
+
+    // bytes of the files we want which are in pieces we want and don't have yet
+    fn wanted_left(&self) -> u64 {
+        self.wanted_bytes(|piece| !self.have.get(piece as usize).is_some_and(|b| *b))
+    }
+
+    // bytes of the files we want in the pieces we want that count says to
+    fn wanted_bytes(&self, count: impl Fn(u64) -> bool) -> u64 {
+        let pieces = (0..self.info.pieces.len() as u32).filter(|&i| count(i as u64));
+        pieces.map(|i| self.piece_wanted(i)).sum()
+    }
+
+    // bytes of the files we want in piece index, none if the piece itself is skipped
+    fn piece_wanted(&self, index: u32) -> u64 {
+        match self.picker.priority(index) {
+            Some(FilePriority::Skip) | None => 0,
+            Some(_) => self.piece_wanted[index as usize],
+        }
+    }
+
+    // work out how many bytes of the files we want each piece holds, eg. once a file's
+    // priority changes
+    fn count_wanted(&mut self) {
+        let piece_length = self.info.piece_length as u64;
+        let mut wanted = vec![0; self.info.pieces.len()];
+        for (range, priority) in self.file_ranges() {
+            if priority == FilePriority::Skip {
+                continue;
+            }
+            for piece in range.start / piece_length..range.end.div_ceil(piece_length) {
+                let end = range.end.min((piece + 1) * piece_length);
+                if let Some(bytes) = wanted.get_mut(piece as usize) {
+                    *bytes += end - range.start.max(piece * piece_length);
+                }
+            }
+        }
+        self.piece_wanted = wanted;
+    }
+
+    // stop downloading piece index if it's skipped, cancelling the blocks asked for
+    fn drop_skipped(&mut self, index: u32) {
+        if self.picker.priority(index) != Some(FilePriority::Skip) {
+            return;
+        }
+        let Some(requests) = self.scheduler.cancel(index) else {
+            return;
+        };
+        for (addr, block) in requests {
+            if let Some(peer) = self.peers.connection_mut(addr) {
+                peer.cancel(block);
+            }
+        }
+        self.picker.set_ours(index, false);
+    }
+
+    // the bytes each file other than padding takes up in the torrent's pieces, and how much
+    // it's wanted
+    fn file_ranges(&self) -> impl Iterator<Item = (Range<u64>, FilePriority)> + '_ {
+        let files = self.info.files.iter().zip(&self.file_priorities);
+        let ranges = files.scan(0, |start, (file, &priority)| {
+            let range = *start..*start + file.length;
+            *start = range.end;
+            Some((file, range, priority))
+        });
+        ranges
+            .filter(|(file, ..)| !file.is_padding())
+            .map(|(_, range, priority)| (range, priority))
+    }
+
+    /// how much each of the torrent's files is wanted, in the order they're listed in the
+    /// metainfo
+    pub fn file_priorities(&self) -> &[FilePriority] {
+        &self.file_priorities
+    }
+
+    /// set how much file index is wanted, returning false if there's no such file. each piece
+    /// takes the priority of the most wanted file it holds part of, see [FilePriority], replacing
+    /// any set with [Torrent::set_piece_priority]
+    pub fn set_file_priority(&mut self, index: usize, priority: FilePriority) -> bool {
+        let Some(p) = self.file_priorities.get_mut(index) else {
+            return false;
+        };
+        *p = priority;
+
+        let piece_length = self.info.piece_length as u64;
+        let mut pieces = vec![FilePriority::Skip; self.info.pieces.len()];
+        for (range, priority) in self.file_ranges().filter(|(r, _)| !r.is_empty()) {
+            let first = (range.start / piece_length) as usize;
+            let last = ((range.end - 1) / piece_length) as usize;
+            for piece in pieces.iter_mut().take(last + 1).skip(first) {
+                *piece = (*piece).max(priority);
+            }
+        }
+        for (i, &priority) in pieces.iter().enumerate() {
+            self.picker.set_priority(i as u32, priority);
+            self.drop_skipped(i as u32);
+        }
+
+        self.count_wanted();
+        self.update_left();
+        true
+    }
+
+    /// set how much piece index is wanted, returning false if there's no such piece. the picker
+    /// takes higher priority pieces first and the rarest among equals. the piece takes its files'
+    /// priority again once any of them changes, see [Torrent::set_file_priority]
+    pub fn set_piece_priority(&mut self, index: u32, priority: FilePriority) -> bool {
+        if index as usize >= self.info.pieces.len() {
+            return false;
+        }
+        let have = self.have.get(index as usize).is_some_and(|b| *b);
+        let before = self.piece_wanted(index);
+        self.picker.set_priority(index, priority);
+        self.drop_skipped(index);
+        if !have {
+            let left = self.bytes_left.saturating_sub(before) + self.piece_wanted(index);
+            self.set_left(left);
+        }
+        true
+    }
+
+    /// how much piece index is wanted, None if there's no such piece. skipped pieces don't
+    /// count towards what's left to download, see [Torrent::set_piece_priority]
+    pub fn piece_priority(&self, index: u32) -> Option<FilePriority> {
+        self.picker.priority(index)
+    }
+
+    /// the started or completed event if trackers haven't been told about it yet
+    fn pending_event(&self) -> Option<AnnounceEvent> {
+        if self.partial_seed && !self.announced_paused {
+            Some(AnnounceEvent::Paused)
+        } else if !self.announced_started {
+            Some(AnnounceEvent::Started)
+        } else if self.bytes_left == 0 && !self.announced_completed {
+            Some(AnnounceEvent::Completed)
+        } else {
+            None
+        }
+    }
+
+    /// record that event was successfully announced
+    fn record_event(&mut self, event: Option<AnnounceEvent>) {
+        match event {
+            Some(AnnounceEvent::Started) => {
+                self.announced_started = true;
+                // we were already complete when started, there's no download to report
+                self.announced_completed = self.bytes_left == 0;
+            }
+            Some(AnnounceEvent::Completed) => self.announced_completed = true,
+            Some(AnnounceEvent::Stopped) => {
+                self.announced_started = false;
+                self.announced_paused = false;
+            }
+            Some(AnnounceEvent::Paused) => {
+                self.announced_started = true;
+                self.announced_paused = true;
+            }
+            None => {}
+        }
+    }
+
+    fn build_tracker_url(
+        &self,
+        tracker: &str,
+        event: Option<AnnounceEvent>,
+        mut buffer: &mut String,
+    ) {
+        buffer.clear();
+
+        let _ = write!(
+            &mut buffer,
+            "{tracker}{}info_hash={}&peer_id={}&port={}&downloaded={}&uploaded={}&compact={}&left={}",
+            Self::query_separator(tracker),
+            PercentEncode(&self.info.info_hash),
+            PercentEncode(&self.peer_id[..]),
+            self.listen_port(),
+            self.downloaded,
+            self.uploaded,
+            1,
+            self.bytes_left,
+        );
+
+        if let Some(event) = event {
+            let _ = write!(&mut buffer, "&event={}", event.as_str());
+        }
+
+        self.write_auth_query(tracker, buffer);
+    }
+
+    /// trackers may already have a query string, eg. `/announce?passkey=...`
+    fn query_separator(url: &str) -> char {
+        match url.contains('?') {
+            true => '&',
+            false => '?',
+        }
+    }
+
+    /// append any query parameters configured for tracker's host to url
+    fn write_auth_query(&self, tracker: &str, mut url: &mut String) {
+        for (key, val) in self.tracker_auth(tracker).map_or(&[][..], |a| &a.query) {
+            let (key, val) = (PercentEncode(key.as_bytes()), PercentEncode(val.as_bytes()));
+            let _ = write!(&mut url, "&{key}={val}");
+        }
+    }
+
+    /// credentials configured for tracker's host, if any
+    fn tracker_auth(&self, tracker: &str) -> Option<&TrackerAuth> {
+        let uri: Uri = tracker.parse().ok()?;
+        self.config.tracker_auth.get(uri.host()?)
+    }
+
+    /// build a GET request for url, attaching any cookies or basic auth configured for tracker
+    fn tracker_request(&self, tracker: &str, url: &str) -> Result<Request<Body>> {
+        let mut req = Request::get(url);
+        if let Some(auth) = self.tracker_auth(tracker) {
+            req = Self::apply_auth(req, auth);
+        }
+
+        Ok(req.body(Body::empty())?)
+    }
+
+    fn apply_auth(mut req: Builder, auth: &TrackerAuth) -> Builder {
+        if let Some(cookie) = &auth.cookie {
+            req = req.header("Cookie", cookie);
+        }
+
+        if let Some((user, pass)) = &auth.basic {
+            let creds = base64::encode(format!("{user}:{pass}"));
+            req = req.header("Authorization", format!("Basic {creds}"));
+        }
+
+        req
+    }
+
+    fn parse_tracker_resp(resp: Bytes) -> Result<AnnounceResp> {
+        // todo: propagate error
+        let Some(mut tracker) = (try { Bencode::decode(&resp)?.dict()? }) else {
+            return Err(Error::InvalidTrackerResp(None))
+        };
+
+        // TODO - avoid allocs
+        if let Some(fail_msg) = tracker.remove(&b"failure reason"[..]) {
+            let reason = try { fail_msg.str()?.into() };
+            return Err(Error::InvalidTrackerResp(reason));
+        }
+
+        // parse response into an interval and sockaddr's
+        let parse_resp = try {
+            let interval = tracker.remove(&b"interval"[..])?.num()?.try_into().ok()?;
+            let min_interval = match tracker.remove(&b"min interval"[..]) {
+                Some(min) => Some(min.num()?.try_into().ok()?),
+                None => None,
+            };
+
+            let mut count = |key: &[u8]| tracker.remove(key)?.num()?.try_into().ok();
+            let stats = match (count(b"complete"), count(b"incomplete")) {
+                (Some(seeders), Some(leechers)) => Some(SwarmStats { seeders, leechers }),
+                _ => None,
+            };
+
+            // IPv6 only trackers may send just peers6
+            let peers6 = tracker.remove(&b"peers6"[..]);
+            let peers = match tracker.remove(&b"peers"[..]) {
+                None if peers6.is_some() => Bencode::BStr(b""),
+                peers => peers?,
+            };
+
+            let mut sock_addrs: Vec<_> = if let Bencode::List(peers) = peers {
+                peers
+                    .into_iter()
+                    .map(|peer| {
+                        let mut peer = peer.dict()?;
+                        let ip: IpAddr = peer.remove(&b"ip"[..])?.str()?.parse().ok()?;
+                        let port = peer.remove(&b"port"[..])?.str()?.parse().ok()?;
+
+                        Some(SocketAddr::new(ip, port))
+                    })
+                    .try_collect()?
+            } else if let Some(peers) = peers.bytes() {
+                utils::parse_compact_peers(peers)
+            } else {
+                return Err(Error::InvalidTrackerResp(None));
+            };
+
+            if let Some(peers6) = peers6 {
+                sock_addrs.extend(utils::parse_compact_peers6(peers6.bytes()?));
+            }
+
+            // a malformed external ip isn't worth failing the announce over
+            let external_ip = tracker.remove(&b"external ip"[..]);
+            let external_ip = external_ip.and_then(|ip| utils::parse_compact_ip(ip.bytes()?));
+
+            // nor is a warning which isn't utf8, it's decoded lossily
+            let warning = tracker.remove(&b"warning message"[..]);
+            let warning = warning.and_then(|msg| msg.bytes());
+            let warning = warning.map(|msg| String::from_utf8_lossy(msg).into_owned());
+
+            AnnounceResp {
+                interval,
+                min_interval,
+                stats,
+                peers: sock_addrs,
+                warning,
+                external_ip,
+            }
+        }: Option<_>;
+
+        parse_resp.ok_or(Error::InvalidTrackerResp(None))
+    }
+
+    fn parse_scrape_resp(resp: Bytes, info_hash: &Sha1Hash) -> Result<ScrapeData> {
+        let Some(mut tracker) = (try { Bencode::decode(&resp)?.dict()? }) else {
+            return Err(Error::InvalidTrackerResp(None));
+        };
+
+        if let Some(fail_msg) = tracker.remove(&b"failure reason"[..]) {
+            let reason = try { fail_msg.str()?.into() };
+            return Err(Error::InvalidTrackerResp(reason));
+        }
+
+        let parse_resp = try {
+            let mut files = tracker.remove(&b"files"[..])?.dict()?;
+            let mut stats = files.remove(&info_hash[..])?.dict()?;
+            let mut stat = |key: &[u8]| stats.remove(key)?.num()?.try_into().ok();
+
+            ScrapeData {
+                seeders: stat(b"complete")?,
+                leechers: stat(b"incomplete")?,
+                completed: stat(b"downloaded")?,
+            }
+        }: Option<_>;
+
+        parse_resp.ok_or(Error::InvalidTrackerResp(None))
+    }
+}
+
+impl File {
+    fn new(
+        length: i64,
+        torrent_dir: &Path,
+        paths: &[&str],
+        attr: Option<&str>,
+        symlink_path: Option<&[&str]>,
+        policy: SanitizePolicy,
+    ) -> Result<File, TorrentParseError> {
+        let attrs = attr.map(Attr::parse).unwrap_or_default();
+        let symlink_path = symlink_path.filter(|_| attrs.contains(&Attr::Symlink));
+        if attrs.contains(&Attr::Symlink) != symlink_path.is_some() {
+            return Err(TorrentParseError::InvalidFile);
+        }
+        // symlinks have no content of their own
+        if length < 0 || (length == 0 && symlink_path.is_none()) {
+            return Err(TorrentParseError::InvalidFile);
+        }
+
+        let file = Self::path(torrent_dir, paths, policy)?;
+        let symlink = match symlink_path {
+            Some(paths) => {
+                let target = Self::path(torrent_dir, paths, policy)?;
+                // a link to itself or a directory it's in would be a loop
+                if file.starts_with(&target) {
+                    return Err(TorrentParseError::InvalidFile);
+                }
+                if !utils::is_contained(torrent_dir, &target) {
+                    return Err(TorrentParseError::PathTraversal(target));
+                }
+                Some(relative_path(file.parent().unwrap_or(torrent_dir), &target))
+            }
+            None => None,
+        };
+
+        Ok(File {
+            file,
+            length: length as u64,
+            attrs,
+            symlink,
+        })
+    }
+
+    // the sanitized path of a file within torrent_dir
+    fn path(
+        torrent_dir: &Path,
+        paths: &[&str],
+        policy: SanitizePolicy,
+    ) -> Result<PathBuf, TorrentParseError> {
+        // "", "." and ".." segments are dropped whatever the policy
+        let parts: Vec<_> = paths
+            .iter()
+            .filter(|p| !matches!(**p, "" | "." | ".."))
+            .map(|p| {
+                let part = utils::sanitize_path(p, policy);
+                part.ok_or_else(|| TorrentParseError::InvalidPath(p.to_string()))
+            })
+            .try_collect()?;
+
+        let parts = parts.iter().map(|p| Path::new(p.as_ref()));
+        let file_path = PathBuf::from_iter(once(torrent_dir).into_iter().chain(parts));
+
+        // parts were empty or all path segments were filtered out
+        if file_path.ends_with(torrent_dir) {
+            return Err(TorrentParseError::InvalidFile);
+        }
+
+        Ok(file_path)
+    }
+
+    fn is_padding(&self) -> bool {
+        self.attrs.contains(&Attr::Padding)
+    }
+}
+
+// announce to the trackers of a tier in order until one responds, returning each tracker tried
+// with its response. the tracker which responded, if any, comes last
+async fn announce_tier(
+    tier: Vec<(String, BoxFuture<'static, Result<AnnounceResp>>)>,
+) -> Vec<(String, Result<AnnounceResp>)> {
+    let mut resps = vec![];
+    for (tracker, announce) in tier {
+        let resp = announce.await;
+        let responded = resp.is_ok();
+        resps.push((tracker, resp));
+        if responded {
+            break;
+        }
+    }
+
+    resps
+}
+
+// target as a path relative to dir, both of which are absolute
+fn relative_path(dir: &Path, target: &Path) -> PathBuf {
+    let common = dir.components().zip(target.components());
+    let common = common.take_while(|(a, b)| a == b).count();
+    let up = dir.components().skip(common).map(|_| Component::ParentDir);
+    up.chain(target.components().skip(common)).collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        collections::HashMap,
+        env, fs, io,
+        net::SocketAddr,
+        path::{Path, PathBuf},
+        process,
+        sync::{
+            atomic::{AtomicU16, AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use bitvec::prelude::{bitbox, Msb0};
+    use chrono::Utc;
+    use rand::{rngs::SmallRng, SeedableRng};
+    use ring::digest;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc,
+        time,
+    };
+
+    use crate::{
+        block_scheduler::BlockScheduler,
+        choker::{Choker, UploadSlots},
+        config::{
+            AddTorrentOptions, AnnouncePolicy, Config, ConflictPolicy, Incomplete, SanitizePolicy,
+            TrackerAuth,
+        },
+        disk::{DiskReader, ReadCache},
+        error::{CommandError, Error, TorrentParseError},
+        events::{Event, EventSender},
+        extension::{
+            ExtensionHandshake, Holepunch, HolepunchError, PexFlags, PexMessage, LT_DONTHAVE,
+            UT_HOLEPUNCH,
+        },
+        handle,
+        merkle::{self, FileTree, HashRequest},
+        peer::{Message, Peer, ReservedBits, Timeouts},
+        peer_store::PeerSources,
+        picker::{FilePriority, PiecePicker},
+        request_queue::{Block, BLOCK_LEN},
+        resume::ResumeData,
+        smart_ban::BanList,
+        stats::TransferStats,
+        storage::{Attr, FileSpan, InMemory},
+        torrent::{
+            AnnounceEvent, File, Info, ScrapeData, State, SwarmStats, Torrent, V2File,
+            MAX_WARM_PEERS,
+        },
+        torrent_ast::Bencode,
+        tracker::Announcer,
+        utils,
+        web_seed::WebSeed,
+    };
+
+    #[test]
+    fn new() {
+        let tor_gen = |base: &Path, prefix: &str| Torrent {
+            metainfo: vec![],
+            handle: handle::channel([0; 20]).0,
+            commands: handle::channel([0; 20]).1,
+            peer_events: mpsc::unbounded_channel().1,
+            peer_events_tx: mpsc::unbounded_channel().0,
+            events: None,
+            external_ip: Default::default(),
+            listen_port: Arc::new(AtomicU16::new(6881)),
+            connection_limits: Default::default(),
+            base_dir: base.to_path_buf(),
+            sanitize: Default::default(),
+            renamed: Default::default(),
+            disk: Arc::new(DiskReader::new(vec![], 32768)),
+            read_cache: ReadCache::new(0),
+            allocation: Default::default(),
+            hasher: Default::default(),
+            writing: 0,
+            config: Default::default(),
+            trackers: vec![
+                vec!["http://tracker.example.com".into()],
+                vec!["http://tracker2.example.com".into()],
+            ],
+            info: Info {
+                piece_length: 32768,
+                pieces: vec![[
+                    0, 72, 105, 249, 236, 50, 141, 28, 177, 230, 77, 80, 106, 67, 249, 35, 207,
+                    173, 235, 151,
+                ]],
+                private: true,
+                file_trees: HashMap::new(),
+                v2_files: vec![],
+                files: vec![File {
+                    file: PathBuf::from_iter(
+                        [base, Path::new(prefix), Path::new("file.txt")].iter(),
+                    ),
+                    length: 10,
+                    attrs: vec![],
+                    symlink: None,
+                }],
+                info_hash: if prefix == "" {
+                    [
+                        11, 5, 171, 161, 242, 160, 178, 230, 220, 146, 241, 219, 17, 67, 62, 95,
+                        58, 130, 11, 173,
+                    ]
+                } else {
+                    [
+                        116, 83, 104, 101, 231, 122, 204, 114, 242, 152, 196, 136, 195, 44, 49,
+                        171, 155, 150, 152, 177,
+                    ]
+                },
+            },
+            peer_id: Arc::new([0; 20]),
+            bytes_left: 0,
+            uploaded: 0,
+            downloaded: 0,
+            transfer: TransferStats::new(std::time::Instant::now()),
+            next_announce: Utc::now(),
+            announce_policy: Default::default(),
+            announcer: Announcer::new(),
+            swarm: None,
+            tracker_status: Default::default(),
+            announced_started: false,
+            announced_completed: false,
+            announced_paused: false,
+            partial_seed: false,
+            state: State::Active,
+            pending_resume: None,
+            recheck: false,
+            checking: None,
+            bind_address: None,
+            dialer: Default::default(),
+            http: utils::http_client(None, None, None),
+            peers: Default::default(),
+            recent_peers: vec![],
+            pex_connected: Default::default(),
+            pex_advertised: Default::default(),
+            next_pex: Utc::now(),
+            choker: Choker::new(Default::default(), None),
+            next_rechoke: Utc::now(),
+            optimistic: None,
+            next_optimistic: Utc::now(),
+            next_hash_request: Utc::now(),
+            holepunches: Default::default(),
+            dialing: Default::default(),
+            redial_at: Default::default(),
+            smart_ban: Default::default(),
+            picker: PiecePicker::new(0),
+            scheduler: BlockScheduler::new(32768, 0),
+            deadlines: Default::default(),
+            have: Default::default(),
+            file_priorities: vec![],
+            piece_wanted: vec![],
+            web_seeds: vec![],
+            ban_list: Default::default(),
+        };
+
+        let test_files = [
+            (&include_bytes!("test_data/mock_dir.torrent")[..], "mock"),
+            (&include_bytes!("test_data/mock_file.torrent")[..], ""),
+        ];
+
+        for (file, dir_name) in test_files {
+            let base_dir = PathBuf::from("/foo");
+            let torrent = Torrent::new(
+                file,
+                Default::default(),
+                Arc::new(*b"-TS0001-|testClient|"),
+                &base_dir,
+                &AddTorrentOptions::default(),
+            )
+            .unwrap();
+            let expected = tor_gen(&base_dir, dir_name);
+
+            assert_eq!(torrent.trackers, expected.trackers);
+            assert_eq!(torrent.info, expected.info);
+            assert_eq!(torrent.info.info_hash, expected.info.info_hash);
+            assert_eq!(torrent.to_bytes(), file);
+        }
+    }
+
+    #[test]
+    fn sanitized_path() {
+        let dir = Path::new("/foo");
+        let reject = SanitizePolicy::Reject;
+        let replace = SanitizePolicy::ReplaceWithUnderscore;
+        for policy in [reject, replace] {
+            let path = File::path(dir, &["", "a", ".", "..", "b"], policy).unwrap();
+            assert_eq!(path, dir.join("a/b"));
+            assert!(File::path(dir, &["..", "."], policy).is_err());
+        }
+
+        let path = |policy| File::path(dir, &["a", "b\x07"], policy);
+        assert_eq!(path(replace).unwrap(), dir.join("a/b_"));
+        let rejected = path(reject).unwrap_err();
+        assert!(matches!(rejected, TorrentParseError::InvalidPath(_)));
+    }
+
+    #[tokio::test]
+    async fn name_conflict() {
+        let base_dir = env::temp_dir().join(format!("tsunami-conflict-{}", process::id()));
+        fs::create_dir_all(&base_dir).unwrap();
+        fs::write(base_dir.join("file.txt"), b"").unwrap();
+
+        let file = include_bytes!("test_data/mock_file.torrent");
+        let peer_id = Arc::new(*b"-TS0001-|testClient|");
+        let add = |conflict| {
+            let opts = AddTorrentOptions {
+                conflict,
+                ..Default::default()
+            };
+            Torrent::new(file, Default::default(), peer_id.clone(), &base_dir, &opts)
+        };
+
+        let mut reuse = add(ConflictPolicy::Reuse).unwrap();
+        assert!(reuse.recheck);
+        assert_eq!(reuse.info.files[0].file, base_dir.join("file.txt"));
+        // the existing data is checked once the torrent runs
+        reuse.process_commands().await;
+        assert!(!reuse.recheck && reuse.checking.is_some());
+        wait_until(&mut reuse, |torrent| torrent.checking.is_none()).await;
+        assert!(!reuse.have[0]);
+
+        let rename = add(ConflictPolicy::Rename).unwrap();
+        assert!(!rename.recheck);
+        assert_eq!(rename.info.files[0].file, base_dir.join("file (1).txt"));
+
+        let fail = add(ConflictPolicy::Fail).unwrap_err();
+        assert_eq!(
+            fail,
+            TorrentParseError::FileExists(base_dir.join("file.txt"))
+        );
+
+        fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinks() {
+        let parse = |link: &[u8]| {
+            let file = [
+                &b"d4:infod5:filesld6:lengthi5e4:pathl1:aee"[..],
+                b"d4:attr1:l6:lengthi0e4:pathl3:sub4:linke",
+                link,
+                b"ee4:name3:dir12:piece lengthi16e6:pieces20:",
+                &[0xff; 20],
+                b"ee",
+            ]
+            .concat();
+            let opts = AddTorrentOptions {
+                lenient_piece_length: true,
+                ..Default::default()
+            };
+            let base_dir = env::temp_dir().join(format!("tsunami_symlinks_{}", process::id()));
+            let peer_id = Arc::new(*b"-TS0001-|testClient|");
+            Torrent::new(&file, Default::default(), peer_id, &base_dir, &opts)
+        };
+
+        // links are relative to where they are, and have no content to download
+        let torrent = parse(b"12:symlink pathl1:ae").unwrap();
+        let link = &torrent.info.files[1];
+        assert_eq!(link.symlink.as_deref(), Some(Path::new("../a")));
+        assert_eq!(torrent.total_size(), 5);
+        torrent.create_symlinks().await.unwrap();
+        torrent.create_symlinks().await.unwrap();
+        assert_eq!(fs::read_link(&link.file).unwrap(), Path::new("../a"));
+        fs::remove_dir_all(link.file.parent().unwrap().parent().unwrap()).unwrap();
+
+        // a link needs a target, which mustn't be itself or the directory it's in
+        assert!(matches!(parse(b""), Err(TorrentParseError::InvalidFile)));
+        let err = parse(b"12:symlink pathl3:sube");
+        assert!(matches!(err, Err(TorrentParseError::InvalidFile)));
+        let err = parse(b"12:symlink pathl3:sub4:linke");
+        assert!(matches!(err, Err(TorrentParseError::InvalidFile)));
+
+        // nothing can be beneath a link, it would end up wherever the link points
+        let file = [
+            &b"d4:infod5:filesld6:lengthi5e4:pathl1:aee"[..],
+            b"d4:attr1:l6:lengthi0e4:pathl1:p1:le12:symlink pathl1:qee",
+            b"d4:attr1:l6:lengthi0e4:pathl1:p1:l1:xe12:symlink pathl1:aee",
+            b"e4:name3:dir12:piece lengthi16e6:pieces20:",
+            &[0xff; 20],
+            b"ee",
+        ]
+        .concat();
+        let opts = AddTorrentOptions {
+            lenient_piece_length: true,
+            ..Default::default()
+        };
+        let peer_id = Arc::new(*b"-TS0001-|testClient|");
+        let err = Torrent::new(&file, Default::default(), peer_id, Path::new("/foo"), &opts);
+        let nested = PathBuf::from("/foo/dir/p/l/x");
+        assert!(matches!(err, Err(TorrentParseError::PathTraversal(path)) if path == nested));
+
+        // a file already where a link should go is kept, the link is reported as a storage error
+        let torrent = parse(b"12:symlink pathl1:ae").unwrap();
+        let link = &torrent.info.files[1];
+        fs::create_dir_all(link.file.parent().unwrap()).unwrap();
+        fs::write(&link.file, b"file").unwrap();
+        let err = torrent.create_symlinks().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&link.file).unwrap(), b"file");
+        fs::remove_dir_all(link.file.parent().unwrap().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn padding_files() {
+        let file = [
+            &b"d4:infod5:filesld6:lengthi5e4:pathl1:aee"[..],
+            b"d4:attr1:p6:lengthi11e4:pathl4:.pad2:11eee",
+            b"4:name3:dir12:piece lengthi16e6:pieces20:",
+            &[0xff; 20],
+            b"ee",
+        ]
+        .concat();
+        let opts = AddTorrentOptions {
+            lenient_piece_length: true,
+            ..Default::default()
+        };
+        let torrent = Torrent::new(
+            &file,
+            Default::default(),
+            Arc::new(*b"-TS0001-|testClient|"),
+            Path::new("/foo"),
+            &opts,
+        )
+        .unwrap();
+
+        assert!(!torrent.info.files[0].is_padding());
+        assert!(torrent.info.files[1].is_padding());
+        assert_eq!(torrent.total_size(), 5);
+        assert_eq!(torrent.bytes_left, 5);
+    }
+
+    #[tokio::test]
+    async fn file_priorities() {
+        // pieces 0-1 hold a, 1 holds b and 1-3 hold c
+        let file = [
+            &b"d4:infod5:filesld6:lengthi20e4:pathl1:aee"[..],
+            b"d6:lengthi10e4:pathl1:bee",
+            b"d6:lengthi30e4:pathl1:ceee",
+            b"4:name3:dir12:piece lengthi16e6:pieces80:",
+            &[0xff; 80],
+            b"ee",
+        ]
+        .concat();
+        let opts = AddTorrentOptions {
+            lenient_piece_length: true,
+            ..Default::default()
+        };
+        let peer_id = Arc::new(*b"-TS0001-|testClient|");
+        let config = Default::default();
+        let torrent = Torrent::new(&file, config, peer_id, Path::new("/foo"), &opts);
+        let mut torrent = torrent.unwrap();
+        let handle = torrent.handle();
+        assert_eq!(torrent.file_priorities(), &[FilePriority::Normal; 3]);
+
+        // a piece is as wanted as its most wanted file, only wanted files count as left
+        let (res, _) = futures::join!(
+            handle.set_file_priority(1, FilePriority::High),
+            torrent.process_commands()
+        );
+        assert!(res.is_ok());
+        assert!(torrent.set_file_priority(2, FilePriority::Skip));
+        let priority = |i| torrent.piece_priority(i).unwrap();
+        let priorities: Vec<_> = (0..4).map(priority).collect();
+        let expected = [
+            FilePriority::Normal,
+            FilePriority::High,
+            FilePriority::Skip,
+            FilePriority::Skip,
+        ];
+        assert_eq!(priorities, expected);
+        assert_eq!(torrent.bytes_left, 30);
+
+        let (res, _) = futures::join!(
+            handle.set_file_priority(3, FilePriority::Low),
+            torrent.process_commands()
+        );
+        assert!(matches!(res, Err(CommandError::InvalidFileIndex(3))));
+
+        // once the wanted files are complete we're a partial seed
+        torrent.piece_verified(1, Ok(true));
+        assert_eq!(torrent.bytes_left, 16);
+        torrent.piece_verified(0, Ok(true));
+        assert_eq!(torrent.bytes_left, 0);
+        assert_eq!(torrent.downloaded, 32);
+        assert!(torrent.partial_seed);
+
+        // c's start was downloaded along with b
+        assert!(torrent.set_file_priority(2, FilePriority::Low));
+        assert_eq!(torrent.bytes_left, 28);
+        assert!(!torrent.partial_seed);
+
+        // single pieces can be steered until their files' priorities change, skipped ones
+        // aren't left to download
+        let (res, _) = futures::join!(
+            handle.set_piece_priority(3, FilePriority::High),
+            torrent.process_commands()
+        );
+        assert!(res.is_ok());
+        assert_eq!(torrent.piece_priority(3), Some(FilePriority::High));
+        assert!(!torrent.set_piece_priority(4, FilePriority::High));
+        assert_eq!(torrent.piece_priority(4), None);
+        torrent.scheduler.start(3);
+        assert!(torrent.set_piece_priority(3, FilePriority::Skip));
+        assert_eq!(torrent.bytes_left, 16);
+        assert!(!torrent.scheduler.wants(&bitbox![u8, Msb0; 1; 4]));
+        assert!(torrent.set_file_priority(0, FilePriority::Normal));
+        assert_eq!(torrent.piece_priority(3), Some(FilePriority::Low));
+        assert_eq!(torrent.bytes_left, 28);
+    }
+
+    #[test]
+    fn piece_length() {
+        let cases = [
+            (16 * 1024, false, true),
+            (128 * 1024 * 1024, false, true),
+            (8 * 1024, false, false),
+            (256 * 1024 * 1024, false, false),
+            (3 * 16 * 1024, false, false),
+            (3 * 16 * 1024, true, true),
+            (1, true, true),
+        ];
+
+        for (piece_length, lenient, ok) in cases {
+            let file = [
+                format!("d4:infod6:lengthi1e4:name1:a12:piece lengthi{piece_length}e6:pieces20:")
+                    .as_bytes(),
+                &[0xff; 20],
+                b"ee",
+            ]
+            .concat();
+            let opts = AddTorrentOptions {
+                lenient_piece_length: lenient,
+                ..Default::default()
+            };
+
+            let peer_id = Arc::new(*b"-TS0001-|testClient|");
+            let torrent = Torrent::new(&file, Default::default(), peer_id, Path::new("/"), &opts);
+            match ok {
+                true => assert!(torrent.is_ok(), "{piece_length}"),
+                false => assert_eq!(torrent.unwrap_err(), TorrentParseError::InvalidPieceLength),
+            }
+        }
+    }
+
+    #[test]
+    fn tracker_auth() {
+        let auth = TrackerAuth {
+            cookie: Some("uid=1".into()),
+            basic: Some(("user".into(), "pass".into())),
+            query: vec![("passkey".into(), "abc".into())],
+        };
+        let config = Config {
+            tracker_auth: [("tracker.example.com".into(), auth)].into(),
+            ..Default::default()
+        };
+
+        let torrent = mock_torrent_with(config, &AddTorrentOptions::default());
+
+        let mut url = String::new();
+        for tracker in ["http://tracker.example.com", "http://tracker2.example.com"] {
+            torrent.build_tracker_url(tracker, None, &mut url);
+            let req = torrent.tracker_request(tracker, &url).unwrap();
+            let authed = tracker == "http://tracker.example.com";
+
+            assert_eq!(url.ends_with("&passkey=abc"), authed);
+            assert!(url.contains("?info_hash="));
+            assert!(url.contains("&peer_id=-TS0001-%7CtestClient%7C"));
+            assert_eq!(req.headers().get("Cookie").is_some(), authed);
+            assert_eq!(
+                req.headers()
+                    .get("Authorization")
+                    .is_some_and(|h| h == "Basic dXNlcjpwYXNz"),
+                authed
+            );
+        }
+    }
+
+    #[test]
+    fn info_bytes() {
+        let pieces = [0xff; 20];
+        let info = [
+            &b"d6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:"[..],
+            &pieces,
+            b"1:xli1eee",
+        ]
+        .concat();
+        let file = [&b"d4:info"[..], &info, b"e"].concat();
+
+        let torrent = Torrent::new(
+            &file,
+            Default::default(),
+            Arc::new(*b"-TS0001-|testClient|"),
+            Path::new("/foo"),
+            &AddTorrentOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(torrent.info_bytes(), info);
+        assert_eq!(
+            Bencode::hash_dict(&file, "info"),
+            Some(*torrent.info_hash())
+        );
+        assert_eq!(torrent.to_bytes(), file);
+    }
+
+    #[test]
+    fn tracker_resp() {
+        let v4 = b"\x01\x02\x03\x04\x1a\xe1";
+        let v6 = b"\x20\x01\x0d\xb8\0\0\0\0\0\0\0\0\0\0\0\x01\x1a\xe1";
+        let peers: Vec<SocketAddr> = vec![
+            "1.2.3.4:6881".parse().unwrap(),
+            "[2001:db8::1]:6881".parse().unwrap(),
+        ];
+
+        let resp = [
+            &b"d8:intervali900e5:peers6:"[..],
+            v4,
+            b"6:peers618:",
+            v6,
+            b"e",
+        ]
+        .concat();
+        let resp = Torrent::parse_tracker_resp(resp.into()).unwrap();
+        assert_eq!(resp.interval, 900);
+        assert_eq!(resp.min_interval, None);
+        assert_eq!(resp.stats, None);
+        assert_eq!(resp.peers, peers);
+
+        let resp = [
+            &b"d8:completei5e10:incompletei10e8:intervali900e12:min intervali60e6:peers618:"[..],
+            v6,
+            b"e",
+        ]
+        .concat();
+        let resp = Torrent::parse_tracker_resp(resp.into()).unwrap();
+        assert_eq!(resp.min_interval, Some(60));
+        assert_eq!(
+            resp.stats,
+            Some(SwarmStats {
+                seeders: 5,
+                leechers: 10
+            })
+        );
+        assert_eq!(resp.peers, peers[1..]);
+        assert_eq!(resp.warning, None);
+
+        let resp = [
+            &b"d8:intervali900e5:peers6:"[..],
+            v4,
+            b"15:warning message4:slowe",
+        ]
+        .concat();
+        let resp = Torrent::parse_tracker_resp(resp.into()).unwrap();
+        assert_eq!(resp.peers, peers[..1]);
+        assert_eq!(resp.warning.as_deref(), Some("slow"));
+
+        let resp = b"d8:intervali900e5:peers0:15:warning message4:sl\xffwe";
+        let resp = Torrent::parse_tracker_resp(resp[..].into()).unwrap();
+        assert_eq!(resp.warning.as_deref(), Some("sl\u{fffd}w"));
+
+        let resp = b"d11:external ip4:\x0a\0\0\x078:intervali900e5:peers0:e";
+        let resp = Torrent::parse_tracker_resp(resp[..].into()).unwrap();
+        assert_eq!(resp.external_ip, Some("10.0.0.7".parse().unwrap()));
+
+        let resp = b"d8:intervali900ee";
+        assert!(Torrent::parse_tracker_resp(resp[..].into()).is_err());
+
+        // we announce at least once a day, however long the tracker asks us to wait
+        let resp = b"d8:intervali9223372036854775807e5:peers0:e";
+        let resp = Torrent::parse_tracker_resp(resp[..].into()).unwrap();
+        assert_eq!(resp.interval, i64::MAX as u64);
+        let interval = Torrent::announce_interval(&[resp]);
+        assert_eq!(interval, chrono::Duration::days(1));
+    }
+
+    #[test]
+    fn retry_backoff() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut delay = |failures| Torrent::retry_delay(failures, &mut rng).num_seconds();
+
+        for (failures, base) in [(1, 15), (2, 30), (3, 60), (8, 1800), (u32::MAX, 1800)] {
+            let d = delay(failures);
+            assert!(base * 3 / 4 <= d && d <= base * 5 / 4, "{failures}: {d}");
+        }
+    }
+
+    #[test]
+    fn scrape() {
+        let cases = [
+            ("http://tr.io/announce", Some("http://tr.io/scrape")),
+            ("http://tr.io/x/announce", Some("http://tr.io/x/scrape")),
+            ("http://tr.io/announce.php", Some("http://tr.io/scrape.php")),
+            ("http://tr.io/announce?x=/", Some("http://tr.io/scrape?x=/")),
+            ("http://tr.io/a", None),
+            ("http://tr.io/x%064announce", None),
+            ("http://tr.io/announce/x", None),
+        ];
+
+        for (announce, expected) in cases {
+            let url = Torrent::scrape_url(announce);
+            assert_eq!(url.as_deref(), expected, "{announce}");
+        }
+
+        let info_hash = [0xab; 20];
+        let resp = [
+            &b"d5:filesd20:"[..],
+            &info_hash,
+            b"d8:completei5e10:downloadedi50e10:incompletei10eeee",
+        ]
+        .concat();
+
+        let data = Torrent::parse_scrape_resp(resp.clone().into(), &info_hash).unwrap();
+        assert_eq!(
+            data,
+            ScrapeData {
+                seeders: 5,
+                leechers: 10,
+                completed: 50
+            }
+        );
+        assert!(Torrent::parse_scrape_resp(resp.into(), &[0; 20]).is_err());
+    }
+
+    #[test]
+    fn warm_peers() {
+        let new = |resume| {
+            let opts = AddTorrentOptions {
+                resume: Some(resume),
+                ..Default::default()
+            };
+            mock_torrent_with(Config::default(), &opts)
+        };
+
+        let peers = vec![
+            "1.2.3.4:6881".parse().unwrap(),
+            "10.0.0.1:80".parse().unwrap(),
+        ];
+        let mut torrent = new(ResumeData {
+            info_hash: [0; 20],
+            peers: peers.clone(),
+            ..Default::default()
+        });
+        assert!(torrent.peers.is_empty());
+
+        torrent = new(ResumeData {
+            info_hash: *torrent.info_hash(),
+            peers: peers.clone(),
+            ..Default::default()
+        });
+        assert_eq!(torrent.peers.len(), 2);
+        assert_eq!(torrent.resume_data().peers, peers);
+        // the order survives another save and load
+        let resume = torrent.resume_data();
+        assert_eq!(new(resume).resume_data().peers, peers);
+
+        for port in 0..MAX_WARM_PEERS as u16 {
+            torrent.remember_peer(SocketAddr::new([1, 1, 1, 1].into(), port));
+        }
+        torrent.remember_peer(peers[0]);
+
+        let warm = torrent.resume_data().peers;
+        assert_eq!(warm.len(), MAX_WARM_PEERS);
+        assert_eq!(warm[0], peers[0]);
+        assert!(!warm.contains(&peers[1]));
+    }
+
+    #[test]
+    fn pex_peers() {
+        let mut torrent = mock_torrent();
+
+        let added: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        let msg = PexMessage {
+            added: vec![(added, PexFlags::REACHABLE)],
+            dropped: vec!["5.6.7.8:6881".parse().unwrap()],
+        };
+        let payload = msg.encode();
+
+        // the mock torrent is private
+        assert_eq!(torrent.add_pex_peers(&payload), 0);
+        assert!(torrent.peers.is_empty());
+
+        torrent.info.private = false;
+        assert_eq!(torrent.add_pex_peers(&payload), 1);
+        assert_eq!(torrent.add_pex_peers(&payload), 0);
+        assert_eq!(torrent.peer_sources(added), Some(PeerSources::PEX));
+        assert_eq!(torrent.add_pex_peers(b"not bencode"), 0);
+        assert_eq!(torrent.peers.len(), 1);
+    }
+
+    // the single file mock torrent, stored under /foo
+    fn mock_torrent() -> Torrent {
+        mock_torrent_with(Config::default(), &AddTorrentOptions::default())
+    }
+
+    fn mock_torrent_with(config: Config, opts: &AddTorrentOptions) -> Torrent {
+        let buf = include_bytes!("test_data/mock_file.torrent");
+        let peer_id = Arc::new(*b"-TS0001-|testClient|");
+        Torrent::new(buf, config.into(), peer_id, Path::new("/foo"), opts).unwrap()
+    }
+
+    // keep torrent's first piece in a 10 byte temp file named after test, returning its path.
+    // the piece is expected to be 10 bytes of 7s
+    fn with_temp_disk(torrent: &mut Torrent, test: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("tsunami_{test}_{}", process::id()));
+        let span = FileSpan {
+            path: path.clone(),
+            length: 10,
+            padding: false,
+        };
+        torrent.disk = Arc::new(DiskReader::new(vec![span], 32768));
+        let hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &[7; 10]);
+        torrent.info.pieces[0] = hash.as_ref().try_into().unwrap();
+        path
+    }
+
+    // drive torrent the way Tsunami::run does until done holds, peer tasks hand their messages
+    // over in the background
+    async fn wait_until(torrent: &mut Torrent, mut done: impl FnMut(&Torrent) -> bool) {
+        while !done(torrent) {
+            run_for(torrent, 1).await;
+        }
+    }
+
+    // drive torrent for a number of ticks
+    async fn run_for(torrent: &mut Torrent, ticks: usize) {
+        for _ in 0..ticks {
+            time::sleep(std::time::Duration::from_millis(10)).await;
+            torrent.process_commands().await;
+        }
+    }
+
+    /// connect torrent to a new peer on localhost, exchanging extension handshakes. returns the
+    /// peer's address and its end of the connection
+    async fn connect_peer(torrent: &mut Torrent) -> (SocketAddr, Peer) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (info_hash, pieces) = (*torrent.info_hash(), torrent.info.pieces.len());
+
+        let connect = Peer::connect(
+            addr,
+            &info_hash,
+            &*torrent.peer_id,
+            pieces,
+            &torrent.dialer,
+            Timeouts::default(),
+        );
+        let accept = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let info_hashes = HashMap::from([(info_hash, pieces)]);
+            Peer::accept(stream, &info_hashes, b"-TS0001-|remotePeer|").await
+        };
+        let (ours, theirs) = futures::join!(connect, accept);
+        let (mut ours, (mut theirs, _)) = (ours.unwrap(), theirs.unwrap());
+
+        theirs.send_extension_handshake(None, false).await.unwrap();
+        ours.decode_message().await.unwrap();
+
+        // our bitfield, or have all or have none, and extension handshake are sent once the peer
+        // is spawned
+        torrent.peers.add(addr, PeerSources::TRACKER);
+        let ours = torrent.spawn_peer(ours, addr, None);
+        torrent.peers.connected(addr, ours);
+        let bitfield = match (torrent.have.all(), torrent.have.any()) {
+            (true, _) => Message::HaveAll,
+            (_, true) => Message::Bitfield(torrent.have.clone()),
+            (_, false) => Message::HaveNone,
+        };
+        assert_eq!(theirs.decode_message().await.unwrap(), bitfield);
+        let handshake = theirs.decode_message().await.unwrap();
+        assert!(matches!(handshake, Message::Extended { id: 0, .. }));
+        (addr, theirs)
+    }
+
+    /// read the next message from peer, which must be a ut_holepunch message
+    async fn recv_holepunch(peer: &mut Peer) -> Holepunch {
+        match peer.decode_message().await.unwrap() {
+            Message::Extended {
+                id: UT_HOLEPUNCH,
+                payload,
+            } => Holepunch::decode(&payload).unwrap(),
+            msg => panic!("expected a holepunch message, got {msg:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn holepunch() {
+        let mut torrent = mock_torrent();
+
+        let (a, mut peer_a) = connect_peer(&mut torrent).await;
+        let (b, mut peer_b) = connect_peer(&mut torrent).await;
+
+        // relaying between a and b
+        let msg = Holepunch::Rendezvous(b).encode();
+        torrent.on_holepunch(a, &msg);
+        assert_eq!(recv_holepunch(&mut peer_b).await, Holepunch::Connect(a));
+        assert_eq!(recv_holepunch(&mut peer_a).await, Holepunch::Connect(b));
+
+        let unknown: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let msg = Holepunch::Rendezvous(unknown).encode();
+        torrent.on_holepunch(a, &msg);
+        let err = Holepunch::Error(unknown, HolepunchError::NoSuchPeer);
+        assert_eq!(recv_holepunch(&mut peer_a).await, err);
+        let msg = Holepunch::Rendezvous(a).encode();
+        torrent.on_holepunch(a, &msg);
+        let err = Holepunch::Error(a, HolepunchError::NoSelf);
+        assert_eq!(recv_holepunch(&mut peer_a).await, err);
+
+        // asking a and b in turn to introduce us to unknown
+        assert!(torrent.rendezvous(unknown));
+        let first = torrent.holepunches[&unknown][0];
+        let (first_peer, second_peer) = match first == a {
+            true => (&mut peer_a, &mut peer_b),
+            false => (&mut peer_b, &mut peer_a),
+        };
+        let msg = Holepunch::Rendezvous(unknown);
+        assert_eq!(recv_holepunch(first_peer).await, msg);
+
+        let err = Holepunch::Error(unknown, HolepunchError::NotConnected).encode();
+        torrent.on_holepunch(first, &err);
+        assert_eq!(recv_holepunch(second_peer).await, msg);
+
+        // out of relays
+        let second = torrent.holepunches[&unknown][1];
+        torrent.on_holepunch(second, &err);
+        assert!(!torrent.holepunches.contains_key(&unknown));
+
+        // a peer from PEX we can't connect to directly is asked of a relay
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let unreachable = listener.local_addr().unwrap();
+        drop(listener);
+        torrent.peers.add(unreachable, PeerSources::PEX);
+        wait_until(&mut torrent, |torrent| {
+            torrent.holepunches.contains_key(&unreachable)
+        })
+        .await;
+        let relay = match torrent.holepunches[&unreachable][0] == a {
+            true => &mut peer_a,
+            false => &mut peer_b,
+        };
+        let msg = Holepunch::Rendezvous(unreachable);
+        assert_eq!(recv_holepunch(relay).await, msg);
+
+        // the peer a relay introduces us to is connected to in the background
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let c = listener.local_addr().unwrap();
+        torrent.on_holepunch(a, &Holepunch::Connect(c).encode());
+        let (stream, _) = listener.accept().await.unwrap();
+        let info_hashes = HashMap::from([(*torrent.info_hash(), torrent.info.pieces.len())]);
+        let accept = Peer::accept(stream, &info_hashes, b"-TS0001-|remotePeer|");
+        let _peer_c = accept.await.unwrap();
+        wait_until(&mut torrent, |torrent| {
+            torrent.peers.connection(c).is_some()
+        })
+        .await;
+        assert!(torrent.peer_sources(c).unwrap().contains(PeerSources::PEX));
+    }
+
+    #[tokio::test]
+    async fn peer_events() {
+        let mut torrent = mock_torrent();
+        torrent.info.private = false;
+        // keep PEX messages out of the way
+        torrent.next_pex = Utc::now() + chrono::Duration::minutes(1);
+
+        let (a, mut peer_a) = connect_peer(&mut torrent).await;
+        let (b, peer_b) = connect_peer(&mut torrent).await;
+        // pieces we didn't ask for are dropped
+        let block = Block {
+            index: 0,
+            begin: 0,
+            length: 10,
+        };
+        torrent.peers.connection_mut(a).unwrap().request(block);
+
+        let req = HashRequest {
+            pieces_root: [1; 32],
+            base_layer: 0,
+            index: 0,
+            length: 2,
+            proof_layers: 0,
+        };
+        let piece = Message::Piece {
+            index: 0,
+            begin: 0,
+            block: vec![0; 10].into(),
+        };
+        peer_a.send(piece).await.unwrap();
+        peer_a.send(Message::HashRequest(req)).await.unwrap();
+        let added: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        let msg = PexMessage {
+            added: vec![(added, PexFlags::REACHABLE)],
+            dropped: vec![],
+        };
+        assert!(peer_a.send_pex(&msg).await.unwrap());
+
+        // peer tasks hand their messages over in the background
+        wait_until(&mut torrent, |torrent| {
+            torrent.peer_sources(added).is_some()
+        })
+        .await;
+        let request = Message::Request {
+            index: 0,
+            begin: 0,
+            length: 10,
+        };
+        assert_eq!(peer_a.decode_message().await.unwrap(), request);
+        let msg = peer_a.decode_message().await.unwrap();
+        assert_eq!(msg, Message::HashReject(req));
+        let stats = torrent.peer_stats();
+        let (_, a_stats) = stats.iter().find(|&&(addr, _)| addr == a).unwrap();
+        assert_eq!(a_stats.downloaded, 10);
+        let clients = torrent.peer_clients();
+        let (_, a_client) = clients.iter().find(|&&(addr, _)| addr == a).unwrap();
+        let client = a_client.as_ref().unwrap();
+        assert_eq!(client.name, "Tsunami");
+        assert_eq!(client.version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+
+        // closed connections are forgotten, their addresses aren't
+        drop(peer_b);
+        wait_until(&mut torrent, |torrent| {
+            !torrent.peers.connections().any(|addr| addr == b)
+        })
+        .await;
+        assert_eq!(torrent.peers.connections().collect::<Vec<_>>(), [a]);
+        assert!(torrent.peers.pending().any(|addr| addr == b));
+    }
+
+    #[tokio::test]
+    async fn rechoke() {
+        let opts = AddTorrentOptions {
+            upload_slots: Some(UploadSlots::Fixed(1)),
+            ..Default::default()
+        };
+        let mut torrent = mock_torrent_with(Config::default(), &opts);
+
+        let (a, mut peer_a) = connect_peer(&mut torrent).await;
+        let (b, mut peer_b) = connect_peer(&mut torrent).await;
+        peer_a.send(Message::Interested).await.unwrap();
+        peer_a.flush().await.unwrap();
+        let interested = |torrent: &Torrent, addr| {
+            let peer = torrent.peers.connection(addr).unwrap();
+            peer.info().is_interested()
+        };
+        wait_until(&mut torrent, |torrent| interested(torrent, a)).await;
+
+        // only interested peers are unchoked
+        torrent.rechoke();
+        assert_eq!(peer_a.decode_message().await.unwrap(), Message::Unchoke);
+        let choked = |torrent: &Torrent| {
+            let peers = torrent.peers.handles();
+            peers.filter(|(_, peer)| peer.is_choked()).count()
+        };
+        assert_eq!(choked(&torrent), 1);
+
+        peer_a.send(Message::NotInterested).await.unwrap();
+        peer_a.flush().await.unwrap();
+        wait_until(&mut torrent, |torrent| !interested(torrent, a)).await;
+        torrent.rechoke();
+        assert_eq!(peer_a.decode_message().await.unwrap(), Message::Choke);
+        assert_eq!(choked(&torrent), 2);
+
+        // upload limits are set per peer
+        assert!(torrent.set_peer_upload_limit(a, Some(1024)));
+        let limit = torrent.peers.connection(a).unwrap().upload_limit();
+        assert_eq!(limit, Some(1024));
+        let unknown = "1.2.3.4:6881".parse().unwrap();
+        assert!(!torrent.set_peer_upload_limit(unknown, Some(1024)));
+
+        // a peer left out is unchoked optimistically on top of the slots, and keeps it for a
+        // while
+        for peer in [&mut peer_a, &mut peer_b] {
+            peer.send(Message::Interested).await.unwrap();
+            peer.flush().await.unwrap();
+        }
+        let both = |torrent: &Torrent| interested(torrent, a) && interested(torrent, b);
+        wait_until(&mut torrent, both).await;
+        torrent.rechoke();
+        assert_eq!(choked(&torrent), 0);
+        let optimistic = torrent.optimistic;
+        assert!(optimistic.is_some());
+        torrent.rechoke();
+        assert_eq!(torrent.optimistic, optimistic);
+    }
+
+    #[tokio::test]
+    async fn upload() {
+        let mut torrent = mock_torrent();
+        let path = with_temp_disk(&mut torrent, "upload");
+        fs::write(&path, b"abcdefghij").unwrap();
+
+        let (a, mut peer_a) = connect_peer(&mut torrent).await;
+        torrent.have.set(0, true);
+        let request = |index, begin, length| Message::Request {
+            index,
+            begin,
+            length,
+        };
+        let piece = |index, begin, block: &[u8]| Message::Piece {
+            index,
+            begin,
+            block: block.into(),
+        };
+
+        // requests from choked peers are rejected
+        peer_a.send(request(0, 0, 4)).await.unwrap();
+        peer_a.send(Message::Interested).await.unwrap();
+        peer_a.flush().await.unwrap();
+        let interested = |torrent: &Torrent| {
+            let peer = torrent.peers.connection(a).unwrap();
+            peer.info().is_interested()
+        };
+        wait_until(&mut torrent, |torrent| interested(torrent)).await;
+        assert_eq!(torrent.peers.connection(a).unwrap().uploads().len(), 0);
+        let reject = Message::RejectRequest {
+            index: 0,
+            begin: 0,
+            length: 4,
+        };
+        assert_eq!(peer_a.decode_message().await.unwrap(), reject);
+        torrent.rechoke();
+        assert_eq!(peer_a.decode_message().await.unwrap(), Message::Unchoke);
+
+        // cancelled, oversized and out of range blocks aren't sent
+        peer_a.send(request(0, 2, 4)).await.unwrap();
+        peer_a.send(request(0, 0, 4)).await.unwrap();
+        peer_a.send(request(0, 0, BLOCK_LEN + 1)).await.unwrap();
+        peer_a.send(request(0, 8, 4)).await.unwrap();
+        peer_a.send(request(1, 0, 4)).await.unwrap();
+        let cancel = Message::Cancel {
+            index: 0,
+            begin: 0,
+            length: 4,
+        };
+        peer_a.send(cancel).await.unwrap();
+        peer_a.send(request(0, 8, 2)).await.unwrap();
+        peer_a.flush().await.unwrap();
+        wait_until(&mut torrent, |torrent| torrent.uploaded >= 6).await;
+        // peers using the fast extension are told, ahead of any piece data
+        let (mut pieces, mut rejected) = (vec![], vec![]);
+        while pieces.len() + rejected.len() < 6 {
+            match peer_a.decode_message().await.unwrap() {
+                Message::RejectRequest {
+                    index,
+                    begin,
+                    length,
+                } => rejected.push((index, begin, length)),
+                msg => pieces.push(msg),
+            }
+        }
+        assert_eq!(pieces, [piece(0, 2, b"cdef"), piece(0, 8, b"ij")]);
+        rejected.sort();
+        let oversized = (0, 0, BLOCK_LEN + 1);
+        assert_eq!(rejected, [(0, 0, 4), oversized, (0, 8, 4), (1, 0, 4)]);
+        assert_eq!(torrent.peer_stats()[0].1.uploaded, 6);
+        assert_eq!(torrent.peers.connection(a).unwrap().uploads().len(), 0);
+
+        // nor are pieces we don't have
+        torrent.have.set(0, false);
+        peer_a.send(request(0, 0, 4)).await.unwrap();
+        peer_a.flush().await.unwrap();
+        run_for(&mut torrent, 5).await;
+        assert_eq!(torrent.uploaded, 6);
+        assert_eq!(torrent.peers.connection(a).unwrap().uploads().len(), 0);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn smart_ban() {
+        let mut torrent = mock_torrent();
+        let ban_list = BanList::default();
+        torrent.set_ban_list(ban_list.clone());
+
+        let (a, mut peer_a) = connect_peer(&mut torrent).await;
+        let block = Block {
+            index: 0,
+            begin: 0,
+            length: 10,
+        };
+        torrent.scheduler.start(0);
+        torrent.peers.connection_mut(a).unwrap().request(block);
+        let piece = Message::Piece {
+            index: 0,
+            begin: 0,
+            block: vec![0; 10].into(),
+        };
+        peer_a.send(piece).await.unwrap();
+        peer_a.flush().await.unwrap();
+
+        // the piece fails its hash check, a sent all of it so it alone is to blame
+        wait_until(&mut torrent, |_| ban_list.contains(a.ip())).await;
+        assert!(torrent.peers.connections().next().is_none());
+        assert!(!torrent.have[0]);
+        assert!(torrent.scheduler.wants(&bitbox![u8, Msb0; 1]));
+        let msg = peer_a.decode_message().await.unwrap();
+        assert!(matches!(msg, Message::Request { .. }));
+        assert!(peer_a.decode_message().await.is_err());
+
+        // banned addresses can't be added back
+        torrent.peers.add(a, PeerSources::TRACKER);
+        assert!(torrent.peers.pending().next().is_none());
+    }
+
+    #[tokio::test]
+    async fn connection_limits() {
+        let config = Config {
+            max_connections: Some(1),
+            max_half_open: Some(1),
+            ..Default::default()
+        };
+        let mut torrent = mock_torrent_with(config, &AddTorrentOptions::default());
+        let (info_hash, pieces) = (*torrent.info_hash(), torrent.info.pieces.len());
+
+        // the remote ends are kept open as long as their tasks' handles are held
+        let mut remotes = vec![];
+        for _ in 0..2 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            torrent.peers.add(addr, PeerSources::TRACKER);
+            remotes.push(tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let info_hashes = HashMap::from([(info_hash, pieces)]);
+                Peer::accept(stream, &info_hashes, b"-TS0001-|remotePeer|").await
+            }));
+        }
+
+        // only one peer is dialed while the session is full
+        wait_until(&mut torrent, |torrent| {
+            torrent.peers.connections().next().is_some()
+        })
+        .await;
+        run_for(&mut torrent, 5).await;
+        assert_eq!(torrent.peers.connections().count(), 1);
+        assert_eq!(torrent.peers.pending().count(), 1);
+        assert!(torrent.connection_limits.try_connection().is_none());
+
+        // closed connections give their place back
+        let closed: Vec<_> = torrent.peers.take_connections().collect();
+        drop(closed);
+        assert!(torrent.connection_limits.try_connection().is_some());
+    }
+
+    #[test]
+    fn hash_requests() {
+        let buf = include_bytes!("test_data/bittorrent-v2-hybrid-test.torrent");
+        let peer_id = Arc::new(*b"-TS0001-|testClient|");
+        let opts = AddTorrentOptions::default();
+        let config = Default::default();
+        let mut torrent = Torrent::new(buf, config, peer_id, Path::new("/foo"), &opts).unwrap();
+
+        // every piece layer came with the torrent
+        assert_eq!(torrent.info.file_trees.len(), 9);
+        assert!(torrent.hash_requests().is_empty());
+
+        // the first two pieces of a file spanning several, with every uncle up to its root
+        let mut trees = torrent.info.file_trees.values();
+        let tree = trees.find(|tree| tree.piece_hash(1).is_some());
+        let req = HashRequest {
+            pieces_root: *tree.unwrap().root(),
+            base_layer: 5,
+            index: 0,
+            length: 2,
+            proof_layers: 32,
+        };
+        let Message::Hashes(_, hashes) = torrent.hash_response(req) else {
+            panic!("hash request rejected");
+        };
+        assert!(torrent.add_hashes(req, &hashes));
+
+        let mut bad = hashes.to_vec();
+        bad[1][0] ^= 1;
+        assert!(!torrent.add_hashes(req, &bad));
+
+        // we don't keep the layers below the piece layer
+        let req = HashRequest {
+            base_layer: 0,
+            ..req
+        };
+        assert_eq!(torrent.hash_response(req), Message::HashReject(req));
+    }
+
+    #[tokio::test]
+    async fn request_hashes() {
+        let buf = include_bytes!("test_data/bittorrent-v2-hybrid-test.torrent");
+        let peer_id = Arc::new(*b"-TS0001-|testClient|");
+        let opts = AddTorrentOptions::default();
+        let config = Default::default();
+        let mut torrent = Torrent::new(buf, config, peer_id, Path::new("/foo"), &opts).unwrap();
+        // as if the torrent came without the piece layer of its largest file
+        let files = torrent.info.v2_files.iter();
+        let file = files.max_by_key(|f| f.length).unwrap();
+        let (root, piece_length) = (file.pieces_root, torrent.info.piece_length as u64);
+        let tree = FileTree::new(root, file.length, piece_length);
+        torrent.info.file_trees.insert(root, tree);
+        let missing = torrent.hash_requests();
+        assert!(!missing.is_empty());
+
+        // a peer which didn't set the v2 bit
+        let (_, mut v1) = connect_peer(&mut torrent).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (info_hash, pieces) = (*torrent.info_hash(), torrent.info.pieces.len());
+        let connect = Peer::connect(
+            addr,
+            &info_hash,
+            &*torrent.peer_id,
+            pieces,
+            &torrent.dialer,
+            Timeouts::default(),
+        );
+        let remote = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0; 68];
+            stream.read_exact(&mut handshake).await.unwrap();
+            let reserved = ReservedBits::EXTENSION | ReservedBits::V2;
+            handshake[20..28].copy_from_slice(&reserved.bits().to_be_bytes());
+            handshake[48..].copy_from_slice(b"-TS0001-|remotePeer|");
+            stream.write_all(&handshake).await.unwrap();
+            stream
+        };
+        let (peer, mut v2) = futures::join!(connect, remote);
+        torrent.peers.add(addr, PeerSources::TRACKER);
+        let peer = torrent.spawn_peer(peer.unwrap(), addr, None);
+        torrent.peers.connected(addr, peer);
+
+        // every missing hash is asked of the v2 peer, after its extension handshake
+        torrent.request_hashes();
+        let mut requests = 0;
+        while requests < missing.len() {
+            let mut msg = vec![0; v2.read_u32().await.unwrap() as usize];
+            v2.read_exact(&mut msg).await.unwrap();
+            if msg[0] == 21 {
+                assert_eq!(msg[1..33], root);
+                requests += 1;
+            }
+        }
+        let timeout = time::timeout(std::time::Duration::from_millis(50), v1.decode_message());
+        assert!(timeout.await.is_err());
+
+        // and not again until the next round
+        torrent.request_hashes();
+        let mut byte = [0];
+        let timeout = time::timeout(std::time::Duration::from_millis(50), v2.read(&mut byte));
+        assert!(timeout.await.is_err());
+    }
+
+    #[test]
+    fn lan_exempt() {
+        let config = Config {
+            max_peers: Some(0),
+            exempt_lan: true,
+            ..Default::default()
+        };
+        let torrent = mock_torrent_with(config, &AddTorrentOptions::default());
+
+        assert_eq!(torrent.connection_room(), 0);
+        assert!(torrent.is_capped("8.8.8.8:6881".parse().unwrap()));
+        assert!(!torrent.is_capped("192.168.1.2:6881".parse().unwrap()));
+    }
+
+    #[test]
+    fn resume_pieces() {
+        let buf = include_bytes!("test_data/debian.torrent");
+        let peer_id = Arc::new(*b"-TS0001-|testClient|");
+        let new = |resume| {
+            let opts = AddTorrentOptions {
+                resume,
+                ..Default::default()
+            };
+            let config = Default::default();
+            Torrent::new(buf, config, peer_id.clone(), Path::new("/foo"), &opts).unwrap()
+        };
+
+        let mut torrent = new(None);
+        torrent.have.set(1, true);
+        torrent.scheduler.start(2);
+        let full = vec![7; BLOCK_LEN as usize];
+        torrent.scheduler.received(2, BLOCK_LEN, &full);
+        let resume = ResumeData::decode(&torrent.resume_data().encode());
+        let partial = resume.as_ref().unwrap().partial.clone();
+        let torrent = new(resume);
+
+        // pieces we had are ours again straight away, without being checked
+        assert!(torrent.have[1] && !torrent.have[0]);
+        assert!(torrent.picker.ours()[1]);
+        let piece_length = torrent.info.piece_length as u64;
+        assert_eq!(torrent.bytes_left, torrent.total_size() - piece_length);
+
+        // blocks which had arrived aren't asked for again
+        let block = |begin| Block {
+            index: 2,
+            begin,
+            length: BLOCK_LEN,
+        };
+        assert!(torrent.scheduler.needs(block(0)));
+        assert!(!torrent.scheduler.needs(block(BLOCK_LEN)));
+        assert_eq!(torrent.resume_data().partial, partial);
+    }
+
+    #[test]
+    fn resume_trackers() {
+        let buf = include_bytes!("test_data/mock_dir.torrent");
+        let peer_id = Arc::new(*b"-TS0001-|testClient|");
+        let new = |resume| {
+            let opts = AddTorrentOptions {
+                resume,
+                ..Default::default()
+            };
+            let config = Default::default();
+            Torrent::new(buf, config, peer_id.clone(), Path::new("/foo"), &opts).unwrap()
+        };
+
+        let mut torrent = new(None);
+        let mut trackers = torrent.trackers().to_vec();
+        trackers.reverse();
+        trackers[0].push("http://added.example.com/announce".into());
+        trackers.push(vec![]);
+        torrent.set_trackers(trackers.clone());
+        trackers.pop();
+
+        let resume = ResumeData::decode(&torrent.resume_data().encode());
+        assert_eq!(new(resume).trackers(), trackers);
+
+        // no saved trackers keeps the metainfo's
+        let resume = ResumeData {
+            info_hash: *torrent.info_hash(),
+            ..Default::default()
+        };
+        assert_eq!(new(Some(resume)).trackers().len(), trackers.len());
+    }
+
+    #[test]
+    fn announce_events() {
+        let mut torrent = mock_torrent();
+
+        let mut url = String::new();
+        let event = torrent.pending_event();
+        torrent.build_tracker_url("http://tracker.example.com", event, &mut url);
+        assert!(url.contains("&event=started"));
+
+        torrent.record_event(event);
+        assert_eq!(torrent.pending_event(), None);
+
+        torrent.bytes_left = 0;
+        assert_eq!(torrent.pending_event(), Some(AnnounceEvent::Completed));
+        torrent.record_event(Some(AnnounceEvent::Completed));
+        assert_eq!(torrent.pending_event(), None);
+
+        // restarting a complete torrent doesn't report completion again
+        torrent.record_event(Some(AnnounceEvent::Stopped));
+        assert_eq!(torrent.pending_event(), Some(AnnounceEvent::Started));
+        torrent.record_event(Some(AnnounceEvent::Started));
+        assert_eq!(torrent.pending_event(), None);
+    }
+
+    #[tokio::test]
+    async fn partial_seed() {
+        let mut torrent = mock_torrent();
+        let (_, mut peer) = connect_peer(&mut torrent).await;
+
+        // peers are told we're upload only
+        torrent.set_partial_seed(true);
+        let Message::Extended { id: 0, payload } = peer.decode_message().await.unwrap() else {
+            panic!("expected an extension handshake");
+        };
+        let handshake = ExtensionHandshake::decode(&payload).unwrap();
+        assert_eq!(handshake.upload_only, Some(true));
+
+        // every announce says we're paused, in place of started and completed
+        let event = torrent.pending_event();
+        assert_eq!(event, Some(AnnounceEvent::Paused));
+        let mut url = String::new();
+        torrent.build_tracker_url("http://tracker.example.com", event, &mut url);
+        assert!(url.contains("&event=paused"));
+        torrent.record_event(event);
+        assert_eq!(torrent.pending_event(), None);
+
+        torrent.set_partial_seed(false);
+        peer.decode_message().await.unwrap();
+        assert_eq!(peer.info().extensions().upload_only, Some(false));
+        assert_eq!(torrent.pending_event(), None);
+    }
+
+    #[tokio::test]
+    async fn download() {
+        let mut torrent = mock_torrent();
+        torrent.next_pex = Utc::now() + chrono::Duration::minutes(1);
+        let path = with_temp_disk(&mut torrent, "download");
+        let (a, mut peer) = connect_peer(&mut torrent).await;
+        let requests = |torrent: &Torrent| {
+            let peer = torrent.peers.connection(a).unwrap();
+            peer.requests().len()
+        };
+
+        // we're interested in peers with pieces we need, and ask for them once unchoked
+        let bitfield = Message::Bitfield(bitbox![u8, Msb0; 1]);
+        peer.send(bitfield).await.unwrap();
+        peer.send(Message::Unchoke).await.unwrap();
+        peer.flush().await.unwrap();
+        wait_until(&mut torrent, |torrent| requests(torrent) != 0).await;
+        let request = Message::Request {
+            index: 0,
+            begin: 0,
+            length: 10,
+        };
+        assert_eq!(peer.decode_message().await.unwrap(), Message::Interested);
+        assert_eq!(peer.decode_message().await.unwrap(), request);
+        assert_eq!(torrent.availability(), &[1]);
+        assert_eq!(torrent.distributed_copies(), 1.0);
+
+        // with the fast extension a choke keeps our requests until they're rejected, they're
+        // asked for again once we're unchoked
+        let reject = Message::RejectRequest {
+            index: 0,
+            begin: 0,
+            length: 10,
+        };
+        peer.send(Message::Choke).await.unwrap();
+        peer.send(reject).await.unwrap();
+        peer.flush().await.unwrap();
+        wait_until(&mut torrent, |torrent| requests(torrent) != 1).await;
+        peer.send(Message::Unchoke).await.unwrap();
+        peer.flush().await.unwrap();
+        wait_until(&mut torrent, |torrent| requests(torrent) != 0).await;
+        assert_eq!(peer.decode_message().await.unwrap(), request);
+
+        // a complete piece which passes its hash check is written out
+        let piece = Message::Piece {
+            index: 0,
+            begin: 0,
+            block: vec![7; 10].into(),
+        };
+        peer.send(piece).await.unwrap();
+        peer.flush().await.unwrap();
+        wait_until(&mut torrent, |torrent| torrent.have[0]).await;
+        assert!(!torrent.scheduler.wants(&bitbox![u8, Msb0; 1]));
+        assert_eq!(fs::read(&path).unwrap(), [7; 10]);
+        assert_eq!(torrent.bytes_left, 0);
+        let stats = torrent.stats();
+        assert_eq!((stats.downloaded, stats.left), (10, 0));
+        assert_eq!(stats.progress, 100.0);
+        assert_eq!(stats.eta, Some(std::time::Duration::ZERO));
+        fs::remove_file(path).unwrap();
+
+        // peers which leave no longer count towards availability
+        drop(peer);
+        wait_until(&mut torrent, |torrent| {
+            torrent.peers.connection(a).is_none()
+        })
+        .await;
+        assert_eq!(torrent.availability(), &[0]);
+    }
+
+    #[tokio::test]
+    async fn paused() {
+        let mut torrent = mock_torrent();
+        let (a, mut peer) = connect_peer(&mut torrent).await;
+        // as the session does when adding the torrent would exceed its disk quota
+        torrent.pause();
+
+        // peers with pieces we need aren't asked for them while we're paused
+        let bitfield = Message::Bitfield(bitbox![u8, Msb0; 1]);
+        peer.send(bitfield).await.unwrap();
+        peer.send(Message::Unchoke).await.unwrap();
+        peer.flush().await.unwrap();
+        run_for(&mut torrent, 5).await;
+        assert!(torrent.peers.connection(a).unwrap().requests().is_empty());
+        let msg = time::timeout(std::time::Duration::from_millis(50), peer.decode_message());
+        assert!(msg.await.is_err());
+
+        torrent.resume();
+        run_for(&mut torrent, 1).await;
+        assert_eq!(peer.decode_message().await.unwrap(), Message::Interested);
+        let request = peer.decode_message().await.unwrap();
+        assert!(matches!(request, Message::Request { .. }));
+    }
+
+    #[tokio::test]
+    async fn broadcast_have() {
+        let mut torrent = mock_torrent();
+        torrent.next_pex = Utc::now() + chrono::Duration::minutes(1);
+        let (_, mut peer_a) = connect_peer(&mut torrent).await;
+        let (b, mut peer_b) = connect_peer(&mut torrent).await;
+
+        let bitfield = Message::Bitfield(bitbox![u8, Msb0; 1]);
+        peer_b.send(bitfield).await.unwrap();
+        peer_b.flush().await.unwrap();
+        wait_until(&mut torrent, |torrent| torrent.availability() == [1]).await;
+
+        // peers are told about verified pieces, unless they already have them
+        torrent.piece_passed(0);
+        torrent.piece_passed(0);
+        assert_eq!(peer_a.decode_message().await.unwrap(), Message::Have(0));
+        let handle = torrent.peers.connection(b).unwrap();
+        handle.send(Message::KeepAlive);
+        assert_eq!(peer_b.decode_message().await.unwrap(), Message::Interested);
+        assert_eq!(peer_b.decode_message().await.unwrap(), Message::KeepAlive);
+
+        // and new peers get our bitfield
+        let (_, peer_c) = connect_peer(&mut torrent).await;
+        assert_eq!(peer_c.info().bitfield(), &bitbox![u8, Msb0; 1]);
+    }
+
+    #[tokio::test]
+    async fn piece_deadline() {
+        let mut torrent = mock_torrent();
+        torrent.next_pex = Utc::now() + chrono::Duration::minutes(1);
+        let (a, mut peer_a) = connect_peer(&mut torrent).await;
+        let (b, mut peer_b) = connect_peer(&mut torrent).await;
+
+        assert!(!torrent.set_piece_deadline(1, std::time::Instant::now()));
+        let now = std::time::Instant::now();
+        assert!(torrent.set_piece_deadline(0, now));
+        assert_eq!(torrent.piece_deadline(0), Some(now));
+        for peer in [&mut peer_a, &mut peer_b] {
+            peer.send(Message::Have(0)).await.unwrap();
+            peer.send(Message::Unchoke).await.unwrap();
+            peer.flush().await.unwrap();
+        }
+
+        // the piece is due, so it's asked of both peers
+        let block = Block {
+            index: 0,
+            begin: 0,
+            length: 10,
+        };
+        let requested = |torrent: &Torrent, addr| {
+            let peer = torrent.peers.connection(addr).unwrap();
+            peer.requests().contains(block)
+        };
+        wait_until(&mut torrent, |torrent| {
+            requested(torrent, a) && requested(torrent, b)
+        })
+        .await;
+        let request = Message::Request {
+            index: 0,
+            begin: 0,
+            length: 10,
+        };
+        for peer in [&mut peer_a, &mut peer_b] {
+            assert_eq!(peer.decode_message().await.unwrap(), Message::Interested);
+            assert_eq!(peer.decode_message().await.unwrap(), request);
+        }
+
+        // whoever sends it first wins, the other request is cancelled
+        let piece = Message::Piece {
+            index: 0,
+            begin: 0,
+            block: vec![0; 10].into(),
+        };
+        peer_a.send(piece).await.unwrap();
+        peer_a.flush().await.unwrap();
+        wait_until(&mut torrent, |torrent| !requested(torrent, b)).await;
+        let cancel = Message::Cancel {
+            index: 0,
+            begin: 0,
+            length: 10,
+        };
+        assert_eq!(peer_b.decode_message().await.unwrap(), cancel);
+        assert_eq!(torrent.piece_deadline(0), None);
+        assert!(!torrent.clear_piece_deadline(0));
+    }
+
+    #[tokio::test]
+    async fn write_backpressure() {
+        let config = Config {
+            max_write_queue: Some(10),
+            ..Default::default()
+        };
+        let mut torrent = mock_torrent_with(config, &AddTorrentOptions::default());
+        torrent.next_pex = Utc::now() + chrono::Duration::minutes(1);
+        let (a, mut peer) = connect_peer(&mut torrent).await;
+        let requests = |torrent: &Torrent| {
+            let peer = torrent.peers.connection(a).unwrap();
+            peer.requests().len()
+        };
+
+        // nothing is asked for while the write queue is full
+        torrent.writing = 10;
+        let bitfield = Message::Bitfield(bitbox![u8, Msb0; 1]);
+        peer.send(bitfield).await.unwrap();
+        peer.send(Message::Unchoke).await.unwrap();
+        peer.flush().await.unwrap();
+        run_for(&mut torrent, 5).await;
+        assert_eq!(requests(&torrent), 0);
+
+        // once a piece is written there's room again
+        torrent.piece_verified(0, Err(io::ErrorKind::Other.into()));
+        assert_eq!(torrent.writing, 0);
+        wait_until(&mut torrent, |torrent| requests(torrent) != 0).await;
+    }
+
+    #[tokio::test]
+    async fn web_seeds() {
+        let mut torrent = mock_torrent();
+        torrent.next_pex = Utc::now() + chrono::Duration::minutes(1);
+        let path = with_temp_disk(&mut torrent, "web_seed");
+
+        // the first seed doesn't have the file, so it's backed off and the piece fetched from
+        // the second one, without any peers
+        let missing = fake_web_seed("/missing", vec![]).await;
+        let missing = missing.replace("missing", "file");
+        let seed = fake_web_seed("/file", vec![7; 10]).await;
+        for url in [&missing, &seed] {
+            let seed = WebSeed::new(url, "file", None).unwrap();
+            torrent.web_seeds.push(seed);
+        }
+        assert_eq!(torrent.web_seeds(), [&missing[..], &seed[..]]);
+        wait_until(&mut torrent, |torrent| torrent.have[0]).await;
+        assert_eq!(fs::read(&path).unwrap(), [7; 10]);
+        assert_eq!(torrent.bytes_left, 0);
+        assert!(!torrent.web_seeds[0].is_ready(Utc::now()));
+        assert!(torrent.web_seeds[1].is_ready(Utc::now()));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn http_seeds() {
+        let mut torrent = mock_torrent();
+        torrent.next_pex = Utc::now() + chrono::Duration::minutes(1);
+        let path = with_temp_disk(&mut torrent, "http_seed");
+
+        // the piece is asked for by index and info hash rather than by file
+        let info_hash = utils::PercentEncode(&torrent.info.info_hash);
+        let piece = format!("/seed?info_hash={info_hash}&piece=0");
+        let url = fake_web_seed(&piece, vec![7; 10]).await;
+        let (url, _) = url.split_once('?').unwrap();
+        let seed = WebSeed::http_seed(url, &torrent.info.info_hash).unwrap();
+        torrent.web_seeds.push(seed);
+        wait_until(&mut torrent, |torrent| torrent.have[0]).await;
+        assert_eq!(fs::read(&path).unwrap(), [7; 10]);
+        assert_eq!(torrent.web_seeds(), [url]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn duplicate_blocks() {
+        let mut torrent = mock_torrent();
+        torrent.next_pex = Utc::now() + chrono::Duration::minutes(1);
+        let path = with_temp_disk(&mut torrent, "duplicate");
+        let (a, mut peer_a) = connect_peer(&mut torrent).await;
+        let (b, mut peer_b) = connect_peer(&mut torrent).await;
+        for peer in [&mut peer_a, &mut peer_b] {
+            let bitfield = Message::Bitfield(bitbox![u8, Msb0; 1]);
+            peer.send(bitfield).await.unwrap();
+            peer.send(Message::Unchoke).await.unwrap();
+            peer.flush().await.unwrap();
+        }
+
+        // the block is asked of one peer, and then of the other too, eg. after a re-request race
+        let block = Block {
+            index: 0,
+            begin: 0,
+            length: 10,
+        };
+        let requested = |torrent: &Torrent, addr| {
+            let peer = torrent.peers.connection(addr).unwrap();
+            peer.requests().contains(block)
+        };
+        let choking = |torrent: &Torrent, addr| {
+            let peer = torrent.peers.connection(addr).unwrap();
+            peer.is_choking_us()
+        };
+        wait_until(&mut torrent, |torrent| {
+            let requested = requested(torrent, a) || requested(torrent, b);
+            requested && !choking(torrent, a) && !choking(torrent, b)
+        })
+        .await;
+        let (first, mut first_peer, mut late_peer) = match requested(&torrent, a) {
+            true => (b, peer_b, peer_a),
+            false => (a, peer_a, peer_b),
+        };
+        assert!(torrent.peers.connection_mut(first).unwrap().request(block));
+        let request = Message::Request {
+            index: 0,
+            begin: 0,
+            length: 10,
+        };
+        for peer in [&mut first_peer, &mut late_peer] {
+            assert_eq!(peer.decode_message().await.unwrap(), Message::Interested);
+            assert_eq!(peer.decode_message().await.unwrap(), request);
+        }
+
+        // the first copy is kept and the other request is cancelled straight away
+        let piece = Message::Piece {
+            index: 0,
+            begin: 0,
+            block: vec![7; 10].into(),
+        };
+        first_peer.send(piece).await.unwrap();
+        first_peer.flush().await.unwrap();
+        wait_until(&mut torrent, |torrent| torrent.have[0]).await;
+        let cancel = Message::Cancel {
+            index: 0,
+            begin: 0,
+            length: 10,
+        };
+        assert_eq!(late_peer.decode_message().await.unwrap(), cancel);
+
+        // a copy which arrives anyway is dropped without being counted
+        let late = Message::Piece {
+            index: 0,
+            begin: 0,
+            block: vec![0; 10].into(),
+        };
+        late_peer.send(late).await.unwrap();
+        late_peer.flush().await.unwrap();
+        run_for(&mut torrent, 5).await;
+        assert_eq!(torrent.downloaded, 10);
+        assert!(torrent.smart_ban.piece_failed(0).is_empty());
+        assert_eq!(torrent.peers.handles_mut().count(), 2);
+        assert_eq!(fs::read(&path).unwrap(), [7; 10]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn force_recheck() {
+        let mut torrent = mock_torrent();
+        let path = with_temp_disk(&mut torrent, "recheck");
+        fs::write(&path, [7; 10]).unwrap();
+        let (events, mut rx) = EventSender::new(Default::default());
+        torrent.set_events(events);
+        let handle = torrent.handle();
+
+        // data which was already there is found, and what's left worked out again
+        let (res, _) = futures::join!(handle.force_recheck(), torrent.process_commands());
+        assert!(res.is_ok());
+        wait_until(&mut torrent, |torrent| torrent.checking.is_none()).await;
+        assert!(torrent.have[0]);
+        assert_eq!(torrent.bytes_left, 0);
+        let progress = Event::RecheckProgress {
+            info_hash: torrent.info.info_hash,
+            checked: 1,
+            pieces: 1,
+        };
+        assert_eq!(rx.try_recv(), Some(progress));
+
+        // as is data which has gone bad since, which peers are told we no longer have
+        let (_, mut peer) = connect_peer(&mut torrent).await;
+        fs::write(&path, [8; 10]).unwrap();
+        torrent.force_recheck();
+        wait_until(&mut torrent, |torrent| torrent.checking.is_none()).await;
+        assert!(!torrent.have[0]);
+        assert_eq!(torrent.bytes_left, 10);
+        let dont_have = Message::Extended {
+            id: LT_DONTHAVE,
+            payload: [0; 4].into(),
+        };
+        assert_eq!(peer.decode_message().await.unwrap(), dont_have);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_storage() {
+        let opts = AddTorrentOptions {
+            storage: Some(Arc::new(InMemory)),
+            ..Default::default()
+        };
+        let mut torrent = mock_torrent_with(Config::default(), &opts);
+        let hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &[7; 10]);
+        torrent.info.pieces[0] = hash.as_ref().try_into().unwrap();
+
+        // pieces are kept in memory and served from there, nothing touches the filesystem
+        torrent.verify_piece(0, vec![7; 10]);
+        wait_until(&mut torrent, |torrent| torrent.have[0]).await;
+        assert_eq!(torrent.disk.read(0, 0, 10).await.unwrap(), &[7; 10][..]);
+        assert_eq!(torrent.disk.storage().sizes(), [10]);
+        assert!(!Path::new("/foo").exists());
+
+        // moving them only changes where the files would be
+        torrent.pause();
+        torrent.move_storage(Path::new("/bar")).await.unwrap();
+        assert!(torrent.info.files[0].file.starts_with("/bar"));
+        assert_eq!(torrent.disk.read(0, 0, 10).await.unwrap(), &[7; 10][..]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn file_attributes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut torrent = mock_torrent();
+        let path = with_temp_disk(&mut torrent, "attrs");
+        torrent.info.files[0].attrs = vec![Attr::Executable];
+
+        // the file is made executable once its last piece is written
+        torrent.verify_piece(0, vec![7; 10]);
+        let executable = |path| fs::metadata(path).unwrap().permissions().mode() & 0o111 != 0;
+        wait_until(&mut torrent, |torrent| torrent.have[0] && executable(&path)).await;
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn incomplete_files() {
+        let buf = include_bytes!("test_data/mock_file.torrent");
+        let peer_id = Arc::new(*b"-TS0001-|testClient|");
+        let opts = AddTorrentOptions {
+            incomplete: Incomplete::PartSuffix,
+            ..Default::default()
+        };
+        let config = Default::default();
+        let base = env::temp_dir().join(format!("tsunami_incomplete_{}", process::id()));
+        let mut torrent = Torrent::new(buf, config, peer_id, &base, &opts).unwrap();
+        let hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &[7; 10]);
+        torrent.info.pieces[0] = hash.as_ref().try_into().unwrap();
+        let path = torrent.info.files[0].file.clone();
+        let mut part = path.clone().into_os_string();
+        part.push(".part");
+
+        // the file is written under another name, and renamed once it's complete
+        let storage = torrent.disk.storage().clone();
+        storage.write_block(0, 0, vec![8; 4]).await.unwrap();
+        assert!(Path::new(&part).exists());
+        torrent.verify_piece(0, vec![7; 10]);
+        wait_until(&mut torrent, |torrent| torrent.have[0] && path.exists()).await;
+        assert_eq!(fs::read(&path).unwrap(), [7; 10]);
+        assert!(!Path::new(&part).exists());
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn verify_v2() {
+        let buf = include_bytes!("test_data/bittorrent-v2-hybrid-test.torrent");
+        let peer_id = Arc::new(*b"-TS0001-|testClient|");
+        let opts = AddTorrentOptions::default();
+        let torrent = Torrent::new(buf, Default::default(), peer_id, Path::new("/foo"), &opts);
+        let torrent = torrent.unwrap();
+
+        // every v2 file starts a piece, its last piece only checks the file's part of it
+        let piece_length = torrent.info.piece_length as u64;
+        assert_eq!(torrent.info.v2_files.len(), 9);
+        for file in &torrent.info.v2_files {
+            let tree = &torrent.info.file_trees[&file.pieces_root];
+            let last = (file.length - 1) / piece_length;
+            let (hash, len) = torrent.v2_piece(file.first_piece + last as u32).unwrap();
+            assert_eq!(Some(hash), tree.piece(last as usize));
+            assert_eq!(len as u64, file.length - last * piece_length);
+        }
+
+        let mut torrent = mock_torrent_with(Config::default(), &opts);
+        let path = with_temp_disk(&mut torrent, "verify_v2");
+        let set_root = |torrent: &mut Torrent, data: &[u8]| {
+            let pieces_root = merkle::hash(data);
+            let tree = FileTree::new(pieces_root, 10, 32768);
+            torrent.info.file_trees = HashMap::from([(pieces_root, tree)]);
+            torrent.info.v2_files = vec![V2File {
+                first_piece: 0,
+                pieces_root,
+                length: 10,
+            }];
+        };
+
+        // a piece of a hybrid torrent must match both its hashes, else it's downloaded again
+        set_root(&mut torrent, &[8; 10]);
+        torrent.verify_piece(0, vec![7; 10]);
+        let all = bitbox![u8, Msb0; 1];
+        wait_until(&mut torrent, |torrent| torrent.scheduler.wants(&all)).await;
+        assert!(!torrent.have[0]);
+
+        set_root(&mut torrent, &[7; 10]);
+        torrent.verify_piece(0, vec![7; 10]);
+        wait_until(&mut torrent, |torrent| torrent.have[0]).await;
+        assert_eq!(fs::read(&path).unwrap(), [7; 10]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rename_files() {
+        let buf = include_bytes!("test_data/mock_dir.torrent");
+        let base = env::temp_dir().join(format!("tsunami_rename_{}", process::id()));
+        let new = |resume| {
+            let opts = AddTorrentOptions {
+                resume,
+                ..Default::default()
+            };
+            let peer_id = Arc::new(*b"-TS0001-|testClient|");
+            Torrent::new(buf, Default::default(), peer_id, &base, &opts).unwrap()
+        };
+        let mut torrent = new(None);
+        torrent.disk.write(0, vec![7; 10]).await.unwrap();
+        let handle = torrent.handle();
+        let rename = handle.rename_file(0, "b".into());
+        let (res, _) = futures::join!(rename, torrent.process_commands());
+        assert!(matches!(res, Err(CommandError::StorageBusy)));
+
+        // names are sanitized, and the file is read from its new path
+        torrent.pause();
+        let dir = torrent.info.files[0].file.parent().unwrap().to_path_buf();
+        torrent.rename_file(0, "a/b").await.unwrap();
+        assert_eq!(torrent.info.files[0].file, dir.join("a_b"));
+        assert_eq!(fs::read(dir.join("a_b")).unwrap(), [7; 10]);
+        assert_eq!(torrent.disk.read(0, 0, 10).await.unwrap(), &[7; 10][..]);
+        let res = torrent.rename_file(0, "..").await;
+        assert!(matches!(res, Err(CommandError::InvalidFileName(_))));
+        let res = torrent.rename_file(1, "c").await;
+        assert!(matches!(res, Err(CommandError::InvalidFileIndex(1))));
+
+        // renaming the root moves every file, leaving nothing behind
+        torrent.rename_root("root").await.unwrap();
+        let path = torrent.info.files[0].file.clone();
+        assert!(path.starts_with(base.join("root")) && path.ends_with("a_b"));
+        assert_eq!(fs::read(&path).unwrap(), [7; 10]);
+        assert!(!dir.exists());
+
+        // renames survive a restart
+        let resume = ResumeData::decode(&torrent.resume_data().encode());
+        assert_eq!(new(resume).info.files[0].file, path);
+        fs::remove_dir_all(&base).unwrap();
+
+        // the root of a single-file torrent is the file itself
+        let buf = include_bytes!("test_data/mock_file.torrent");
+        let peer_id = Arc::new(*b"-TS0001-|testClient|");
+        let opts = Default::default();
+        let mut torrent = Torrent::new(buf, Default::default(), peer_id, &base, &opts).unwrap();
+        torrent.disk.write(0, vec![7; 10]).await.unwrap();
+        torrent.pause();
+        torrent.rename_root("file").await.unwrap();
+        assert_eq!(torrent.info.files[0].file, base.join("file"));
+        assert_eq!(fs::read(base.join("file")).unwrap(), [7; 10]);
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn handle_commands() {
+        let mut torrent = mock_torrent();
+        let handle = torrent.handle();
+
+        let (res, _) = futures::join!(handle.pause(), torrent.process_commands());
+        assert!(res.is_ok());
+        assert_eq!(torrent.state(), State::Paused);
+
+        torrent.resume();
+        let dir = PathBuf::from("/bar");
+        let (res, _) = futures::join!(handle.move_storage(dir), torrent.process_commands());
+        assert!(matches!(res, Err(CommandError::StorageBusy)));
+
+        drop(torrent);
+        let res = handle.resume().await;
+        assert!(matches!(res, Err(CommandError::TorrentRemoved)));
+    }
+
+    #[tokio::test]
+    async fn edit_trackers() {
+        let mut torrent = mock_torrent();
+        let (first, second) = ("http://tracker.example.com", "http://tracker2.example.com");
+        let added = "http://added.example.com/announce";
+
+        assert!(torrent.add_tracker(added.into(), 0));
+        assert!(!torrent.add_tracker(first.into(), 1));
+        assert!(torrent.add_tracker("udp://new.example.com:80".into(), 9));
+        assert_eq!(torrent.trackers()[0], [first, added]);
+        assert_eq!(torrent.trackers()[2], ["udp://new.example.com:80"]);
+
+        assert!(torrent.remove_tracker(second));
+        assert!(!torrent.remove_tracker(second));
+        assert_eq!(torrent.trackers().len(), 2);
+
+        let handle = torrent.handle();
+        let add = handle.add_tracker("not a url".into(), 0);
+        let (res, _) = futures::join!(add, torrent.process_commands());
+        assert!(matches!(res, Err(CommandError::InvalidTracker(_))));
+
+        let remove = handle.remove_tracker(second.into());
+        let (res, _) = futures::join!(remove, torrent.process_commands());
+        assert!(matches!(res, Err(CommandError::UnknownTracker(_))));
+
+        let set = handle.set_trackers(vec![vec![second.into()], vec![]]);
+        let (res, _) = futures::join!(set, torrent.process_commands());
+        assert!(res.is_ok());
+        assert_eq!(torrent.trackers(), [[second]]);
+    }
+
+    // a web seed which answers requests for data at path, ranged or not, and 404s anything else
+    async fn fake_web_seed(path: &str, data: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let url = format!("http://{addr}{path}");
+        let target = format!("get {} ", path.to_lowercase());
+
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let mut req = vec![];
+                while !req.ends_with(b"\r\n\r\n") {
+                    req.push(conn.read_u8().await.unwrap());
+                }
+                let req = String::from_utf8(req).unwrap().to_lowercase();
+                let range = req.lines().find_map(|l| l.strip_prefix("range: bytes="));
+                let range = range.and_then(|r| r.split_once('-'));
+                let range = range.map(|(a, b)| a.parse().unwrap()..b.parse::<usize>().unwrap() + 1);
+
+                let (status, body) = match range {
+                    _ if !req.starts_with(&target) => ("404 Not Found", &[][..]),
+                    Some(range) => ("206 Partial Content", &data[range]),
+                    None => ("200 OK", &data[..]),
+                };
+                let head = format!(
+                    "HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                    body.len()
+                );
+                conn.write_all(head.as_bytes()).await.unwrap();
+                conn.write_all(body).await.unwrap();
+            }
+        });
+        url
+    }
+
+    // a tracker which answers every announce with peer, counting the announces it gets
+    pub(crate) async fn fake_tracker(peer: SocketAddr) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicUsize::new(0));
+
+        let (peers, _) = utils::encode_compact_peers(&[peer]);
+        let mut body = b"d8:intervali1800e5:peers6:".to_vec();
+        body.extend(peers);
+        body.push(b'e');
+        let head = format!(
+            "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+            body.len()
+        );
+
+        let count = hits.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let mut req = vec![];
+                while !req.ends_with(b"\r\n\r\n") {
+                    req.push(conn.read_u8().await.unwrap());
+                }
+                count.fetch_add(1, Ordering::SeqCst);
+
+                conn.write_all(head.as_bytes()).await.unwrap();
+                conn.write_all(&body).await.unwrap();
+            }
+        });
+
+        (url, hits)
+    }
+
+    #[tokio::test]
+    async fn announce_policy() {
+        let mut trackers = vec![];
+        for i in 1..=3 {
+            trackers.push(fake_tracker(SocketAddr::from(([10, 0, 0, i], 6881))).await);
+        }
+        let url = |i: usize| trackers[i].0.clone();
+        // nothing listens on port 1
+        let dead = "http://127.0.0.1:1/announce".to_string();
+        let tiers = vec![vec![dead.clone(), url(0)], vec![url(1), url(2)]];
+
+        let cases = [
+            (AnnouncePolicy::FailoverTiers, [1, 0, 0]),
+            (AnnouncePolicy::AllTiers, [1, 1, 0]),
+            (AnnouncePolicy::AllTrackers, [1, 1, 1]),
+        ];
+        for (announce, hits) in cases {
+            let opts = AddTorrentOptions {
+                announce,
+                ..Default::default()
+            };
+            let mut torrent = mock_torrent_with(Config::default(), &opts);
+            torrent.set_trackers(tiers.clone());
+
+            torrent.refresh_peers(None).await.unwrap();
+            let counts = trackers.iter().map(|(_, n)| n.swap(0, Ordering::SeqCst));
+            assert_eq!(counts.collect::<Vec<_>>(), hits, "{announce:?}");
+            assert_eq!(torrent.peers.len(), hits.iter().sum(), "{announce:?}");
+
+            // the tracker which responded is tried first next time
+            if announce != AnnouncePolicy::AllTrackers {
+                assert_eq!(torrent.trackers()[0], [url(0), dead.clone()]);
+            }
+            assert!(torrent.tracker_status.contains_key(&dead));
+        }
+    }
+
+    #[tokio::test]
+    async fn unsupported_tracker() {
+        let mut torrent = mock_torrent();
+        let tracker = "gopher://tracker.example.com/announce";
+        torrent.set_trackers(vec![vec![tracker.into()]]);
+
+        let res = torrent.refresh_peers(None).await;
+        assert!(matches!(res, Err(Error::NoTrackerAvailable)));
+        let err = torrent.tracker_error(tracker);
+        assert!(matches!(err, Some(Error::UnsupportedTracker(_))));
+    }
+
+    #[tokio::test]
+    async fn background_announce() {
+        // the peer the tracker hands back is banned so it's never dialed, and failing to
+        // connect to it doesn't send another announce
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let (url, hits) = fake_tracker(addr).await;
+        let mut torrent = mock_torrent();
+        let ban_list = BanList::default();
+        ban_list.insert(addr.ip());
+        torrent.set_ban_list(ban_list);
+        torrent.set_trackers(vec![vec![url]]);
+
+        // poll for announces from the background task for a while, returning how many were sent
+        async fn announces(torrent: &mut Torrent, hits: &AtomicUsize) -> usize {
+            run_for(torrent, 20).await;
+            hits.swap(0, Ordering::SeqCst)
+        }
+
+        torrent.start().await.unwrap();
+        assert_eq!(hits.swap(0, Ordering::SeqCst), 1);
+        // the next announce isn't due for the tracker's whole interval
+        assert_eq!(announces(&mut torrent, &hits).await, 0);
+
+        torrent.announcer.wake();
+        assert_eq!(announces(&mut torrent, &hits).await, 1);
+
+        // nothing is announced once stopped, besides telling the tracker
+        torrent.stop().await;
+        assert_eq!(hits.swap(0, Ordering::SeqCst), 1);
+        torrent.announcer.wake();
+        assert_eq!(announces(&mut torrent, &hits).await, 0);
+    }
+
+    #[tokio::test]
+    async fn slow_tracker() {
+        // a tracker which takes connections but never responds
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let mut torrent = mock_torrent();
+        torrent.set_trackers(vec![vec![url]]);
+        let handle = torrent.handle();
+        let reannounce = tokio::spawn(async move { handle.reannounce().await });
+
+        // the torrent carries on while the announce waits on the tracker
+        let ticks = time::timeout(std::time::Duration::from_secs(1), run_for(&mut torrent, 10));
+        assert!(ticks.await.is_ok());
+        assert!(!reannounce.is_finished());
+    }
+
+    #[tokio::test]
+    async fn stop() {
+        let mut torrent = mock_torrent();
+        let (events, mut rx) = EventSender::new(Default::default());
+        torrent.set_events(events);
+        let (a, mut peer_a) = connect_peer(&mut torrent).await;
+        let block = Block {
+            index: 0,
+            begin: 0,
+            length: 10,
+        };
+        let conn = torrent.peers.connection_mut(a).unwrap();
+        conn.set_interested(true);
+        conn.request(block);
+        torrent.remember_peer(a);
+
+        // peers hear we've lost interest and what we no longer want before they're closed
+        torrent.stop().await;
+        let request = Message::Request {
+            index: 0,
+            begin: 0,
+            length: 10,
+        };
+        let cancel = Message::Cancel {
+            index: 0,
+            begin: 0,
+            length: 10,
+        };
+        for msg in [Message::Interested, request, cancel, Message::NotInterested] {
+            assert_eq!(peer_a.decode_message().await.unwrap(), msg);
+        }
+        assert!(peer_a.decode_message().await.is_err());
+
+        let stopped = Event::Stopped {
+            info_hash: torrent.info.info_hash,
+            resume: Box::new(torrent.resume_data()),
+        };
+        assert_eq!(rx.try_recv(), Some(stopped));
+        assert_eq!(torrent.resume_data().peers, [a]);
+    }
+
     // #[tokio::test]
     // async fn get_peers() {
     //     let data = include_bytes!("test_data/debian.torrent");
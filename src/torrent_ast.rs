@@ -10,13 +10,26 @@ use nom::{
 };
 use ring::digest;
 
+use crate::{
+    error::TorrentParseError,
+    merkle::{self, Sha256Hash, BLOCK_SIZE},
+};
+
 // TorrentAST is a structural representation of a torrent file; fields map over almost identically,
 // with dict's being represented as sub-structs
 #[derive(Debug, PartialEq)]
 pub struct TorrentAST<'a> {
-    pub announce: &'a str,
+    // trackerless torrents (DHT only) may omit announce entirely
+    pub announce: Option<&'a str>,
     pub announce_list: Option<Vec<Vec<&'a str>>>,
+    // BEP-19 web seeds, either a single url or a list of them
+    pub url_list: Option<Vec<&'a str>>,
+    // BEP-17 http seeds, which are asked for whole pieces by index
+    pub httpseeds: Option<Vec<&'a str>>,
     pub info: InfoAST<'a>,
+
+    // v2 only, maps a file's pieces root to the concatenated hashes of its piece layer
+    pub piece_layers: Option<HashMap<&'a [u8], &'a [u8]>>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -29,62 +42,212 @@ pub struct InfoAST<'a> {
     // length and files are mutually exclusive
     // single file case
     pub length: Option<i64>,
+    pub attr: Option<&'a str>,
     // multi-file case
     pub files: Option<Vec<FileAST<'a>>>,
+
+    // v2 and hybrid torrents
+    pub meta_version: Option<i64>,
+    pub file_tree: Option<Vec<FileTreeAST<'a>>>,
 }
 
 #[derive(Debug, PartialEq)]
 pub struct FileAST<'a> {
     pub path: Vec<&'a str>,
     pub length: i64,
+    // BEP-47 file attributes, eg. "p" for padding files or "xh" for hidden executables
+    pub attr: Option<&'a str>,
+    // where a file with the symlink attribute links to, relative to the torrent's directory
+    pub symlink_path: Option<Vec<&'a str>>,
+}
+
+// FileTreeAST is a single file from a v2 `file tree`, flattened into its full path
+#[derive(Debug, PartialEq)]
+pub struct FileTreeAST<'a> {
+    pub path: Vec<&'a str>,
+    pub length: i64,
+    // empty files have no pieces root
+    pub pieces_root: Option<&'a [u8]>,
 }
 
 impl<'a> TorrentAST<'a> {
-    pub fn decode(file: &'a [u8]) -> Option<TorrentAST<'a>> {
-        let mut torrent = Bencode::decode(file)?.dict()?;
-        let mut info = torrent.remove(&b"info"[..])?.dict()?;
+    pub fn decode(file: &'a [u8]) -> Result<TorrentAST<'a>, TorrentParseError> {
+        let mut torrent = Bencode::decode(file)
+            .and_then(Bencode::dict)
+            .ok_or(TorrentParseError::Bencode)?;
+        let mut info = required(&mut torrent, "info", Bencode::dict)?;
 
         TorrentAST {
-            announce: torrent.remove(&b"announce"[..])?.str()?,
+            announce: try { torrent.remove(&b"announce"[..])?.str()? },
             announce_list: try {
                 torrent
                     .remove(&b"announce-list"[..])?
                     .map_list(|l| l.map_list(Bencode::str))?
             },
+            url_list: match torrent.remove(&b"url-list"[..]) {
+                Some(Bencode::List(urls)) => urls.into_iter().map(Bencode::str).collect(),
+                Some(url) => try { vec![url.str()?] },
+                None => None,
+            },
+            httpseeds: try { torrent.remove(&b"httpseeds"[..])?.map_list(Bencode::str)? },
             info: InfoAST {
-                name: info.remove(&b"name"[..])?.str()?,
-                pieces: info.remove(&b"pieces"[..])?.bstr()?,
-                piece_length: info.remove(&b"piece length"[..])?.num()?,
+                name: required(&mut info, "name", Bencode::str)?,
+                pieces: required(&mut info, "pieces", Bencode::bstr)?,
+                piece_length: required(&mut info, "piece length", Bencode::num)?,
 
                 length: try { info.remove(&b"length"[..])?.num()? },
-                files: try { info.remove(&b"files"[..])?.map_list(FileAST::new)? },
+                attr: try { info.remove(&b"attr"[..])?.str()? },
+                files: match info.remove(&b"files"[..]) {
+                    Some(files) => Some(
+                        files
+                            .map_list(FileAST::new)
+                            .ok_or(TorrentParseError::InvalidFile)?,
+                    ),
+                    None => None,
+                },
                 private: try { info.remove(&b"private"[..])?.num()? },
+
+                meta_version: try { info.remove(&b"meta version"[..])?.num()? },
+                file_tree: match info.remove(&b"file tree"[..]) {
+                    Some(tree) => {
+                        Some(FileTreeAST::new(tree).ok_or(TorrentParseError::InvalidFile)?)
+                    }
+                    None => None,
+                },
+            },
+            piece_layers: match torrent.remove(&b"piece layers"[..]) {
+                Some(layers) => Some(
+                    layers
+                        .dict()
+                        .and_then(|l| l.into_iter().map(|(k, v)| Some((k, v.bytes()?))).collect())
+                        .ok_or(TorrentParseError::InvalidKey("piece layers"))?,
+                ),
+                None => None,
             },
         }
         .validate()
     }
 
-    fn validate(self) -> Option<TorrentAST<'a>> {
+    fn validate(self) -> Result<TorrentAST<'a>, TorrentParseError> {
         // pieces is a list of 20 byte sha1 hashes
         if self.info.pieces.len() % 20 != 0 {
-            return None;
+            return Err(TorrentParseError::InvalidPieces);
         }
 
         // we can have at most 2^32 pieces. this limit is not directly defined but since index
         // in a Peer's Request message is limited to u32 we can infer there must be fewer than
         // 2^32 pieces.
-        if self.info.pieces.len() > u32::MAX as usize {
-            return None;
+        if self.info.pieces.len() / 20 > u32::MAX as usize {
+            return Err(TorrentParseError::TooManyPieces);
         }
 
         // length and files are mutually exclusive for a valid torrent
-        if self.info.length.is_some() && self.info.files.is_some() {
-            return None;
-        } else if self.info.length.is_none() && self.info.files.is_none() {
-            return None;
+        if self.info.length.is_some() == self.info.files.is_some() {
+            return Err(TorrentParseError::InvalidFileLayout);
+        }
+
+        if let Some(file_tree) = &self.info.file_tree {
+            self.validate_piece_layers(file_tree)?;
+        }
+
+        Ok(self)
+    }
+
+    /// check that every file larger than a piece has a piece layer which hashes up to its
+    /// pieces root
+    fn validate_piece_layers(&self, file_tree: &[FileTreeAST]) -> Result<(), TorrentParseError> {
+        // v2 pieces must be a power of two and at least one block long
+        let piece_length = match u64::try_from(self.info.piece_length) {
+            Ok(len) if len >= BLOCK_SIZE && len.is_power_of_two() => len,
+            _ => return Err(TorrentParseError::InvalidPieceLength),
+        };
+        let pad = merkle::pad_hash((piece_length / BLOCK_SIZE).trailing_zeros());
+
+        for file in file_tree {
+            let Some(root) = file.pieces_root else {
+                continue;
+            };
+
+            // files fitting in a single piece have their root stored directly
+            if file.length as u64 <= piece_length {
+                continue;
+            }
+
+            let layer = self
+                .piece_layers
+                .as_ref()
+                .and_then(|l| l.get(root))
+                .ok_or(TorrentParseError::InvalidPieceLayers)?;
+
+            let num_pieces = (file.length as u64).div_ceil(piece_length);
+            if layer.len() as u64 != num_pieces * 32 {
+                return Err(TorrentParseError::InvalidPieceLayers);
+            }
+
+            let hashes: Vec<Sha256Hash> = layer.chunks(32).map(|h| h.try_into().unwrap()).collect();
+            if merkle::root(&hashes, pad) != root {
+                return Err(TorrentParseError::InvalidPieceLayers);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// remove key from dict and unwrap it with op, distinguishing between a missing key and one with
+/// an unexpected value
+fn required<'a, T>(
+    dict: &mut HashMap<&'a [u8], Bencode<'a>>,
+    key: &'static str,
+    op: impl FnOnce(Bencode<'a>) -> Option<T>,
+) -> Result<T, TorrentParseError> {
+    let val = dict
+        .remove(key.as_bytes())
+        .ok_or(TorrentParseError::MissingKey(key))?;
+
+    op(val).ok_or(TorrentParseError::InvalidKey(key))
+}
+
+impl<'a> FileTreeAST<'a> {
+    fn new(tree: Bencode<'a>) -> Option<Vec<FileTreeAST<'a>>> {
+        let mut files = vec![];
+        Self::walk(tree, &mut vec![], &mut files)?;
+
+        Some(files)
+    }
+
+    // file trees are nested dicts of path components, where a file is a dict with a single empty
+    // key holding its length and pieces root
+    //
+    // example: { "dir": { "file.txt": { "": { "length": 1, "pieces root": ... } } } }
+    fn walk(
+        node: Bencode<'a>,
+        path: &mut Vec<&'a str>,
+        files: &mut Vec<FileTreeAST<'a>>,
+    ) -> Option<()> {
+        let mut node: Vec<_> = node.dict()?.into_iter().collect();
+        node.sort_unstable_by_key(|(k, _)| *k);
+
+        for (name, child) in node {
+            if name.is_empty() {
+                let mut file = child.dict()?;
+                files.push(FileTreeAST {
+                    path: path.clone(),
+                    length: file.remove(&b"length"[..])?.num()?,
+                    pieces_root: match file.remove(&b"pieces root"[..]) {
+                        Some(root) => Some(root.bytes().filter(|r| r.len() == 32)?),
+                        None => None,
+                    },
+                });
+                continue;
+            }
+
+            path.push(std::str::from_utf8(name).ok()?);
+            Self::walk(child, path, files)?;
+            path.pop();
         }
 
-        Some(self)
+        Some(())
     }
 }
 
@@ -95,6 +258,8 @@ impl<'a> FileAST<'a> {
         Some(FileAST {
             path: file.remove(&b"path"[..])?.map_list(|p| p.str())?,
             length: file.remove(&b"length"[..])?.num()?,
+            attr: try { file.remove(&b"attr"[..])?.str()? },
+            symlink_path: try { file.remove(&b"symlink path"[..])?.map_list(|p| p.str())? },
         })
     }
 }
@@ -106,6 +271,8 @@ pub enum Bencode<'a> {
     BStr(&'a [u8]),
     List(Vec<Bencode<'a>>),
     Dict(HashMap<&'a [u8], Bencode<'a>>),
+    // already encoded bencode, written out as is by [Bencode::encode]. never produced by decode
+    Raw(&'a [u8]),
 }
 
 impl<'a> Bencode<'a> {
@@ -147,12 +314,29 @@ impl<'a> Bencode<'a> {
     /// assert!(Bencode::hash_dict(&input[..], "info") == expected);
     /// ```
     pub fn hash_dict(input: &[u8], key: &str) -> Option<[u8; 20]> {
-        // SHA-1 hash includes surrounding 'd' and 'e' tags
+        let dict = Bencode::raw_value(input, key)?;
+        digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, dict)
+            .as_ref()
+            .try_into()
+            .ok()
+    }
+
+    /// find the exact encoded bytes of key's value in the top-level dictionary in input
+    ///
+    /// # Examples
+    /// ```ignore
+    /// # use tsunami::torrent_ast::Bencode;
+    ///
+    /// let input = b"d4:infod5:helloi2eee";
+    /// assert!(Bencode::raw_value(&input[..], "info") == Some(&b"d5:helloi2ee"[..]));
+    /// ```
+    pub fn raw_value<'i>(input: &'i [u8], key: &str) -> Option<&'i [u8]> {
+        // raw bytes include surrounding 'd' and 'e' tags
         //
         // let input         = "d ... 4:infod ... e ... e";
         // let (start, end)  =     start -> [     ] <- end
         //
-        // sha1.sum( input[start..=end] )
+        // input[start..=end]
 
         map(
             delimited(
@@ -162,14 +346,9 @@ impl<'a> Bencode<'a> {
             ),
             |kv_pairs| {
                 kv_pairs
-                    .iter()
+                    .into_iter()
                     .find(|(k, _)| *k == key.as_bytes())
-                    .map(|(_, v)| {
-                        digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, v)
-                            .as_ref()
-                            .try_into()
-                            .unwrap()
-                    })
+                    .map(|(_, v)| v)
             },
         )(input)
         .ok()?
@@ -208,6 +387,24 @@ impl<'a> Bencode<'a> {
         }
     }
 
+    /// bytes unwraps either a [Bencode::Str] or [Bencode::BStr] variant as raw bytes. useful for
+    /// binary values such as hashes which may happen to be valid utf8
+    ///
+    /// # Examples
+    /// ```ignore
+    /// # use tsunami::torrent_ast::Bencode;
+    ///
+    /// assert!(Bencode::Str("str").bytes() == Some(&b"str"[..]));
+    /// assert!(Bencode::BStr(b"\xff").bytes() == Some(&b"\xff"[..]));
+    /// ```
+    pub fn bytes(self) -> Option<&'a [u8]> {
+        match self {
+            Bencode::Str(s) => Some(s.as_bytes()),
+            Bencode::BStr(s) => Some(s),
+            _ => None,
+        }
+    }
+
     /// num unwraps a [Bencode::Num] variant
     ///
     /// # Examples
@@ -279,6 +476,54 @@ impl<'a> Bencode<'a> {
     pub fn map_list<U>(self, op: impl Fn(Bencode<'a>) -> Option<U>) -> Option<Vec<U>> {
         self.list()?.into_iter().map(op).try_collect()
     }
+
+    /// encode a value into buf. dict keys are written in sorted order, so encoding a decoded
+    /// value reproduces the original input byte for byte
+    ///
+    /// # Examples
+    /// ```ignore
+    /// # use tsunami::torrent_ast::Bencode;
+    ///
+    /// let input = b"d3:numi42e4:strsl1:a1:bee";
+    /// let mut buf = vec![];
+    /// Bencode::decode(input).unwrap().encode(&mut buf);
+    ///
+    /// assert!(buf == input);
+    /// ```
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Bencode::Num(n) => {
+                buf.push(b'i');
+                buf.extend_from_slice(n.to_string().as_bytes());
+                buf.push(b'e');
+            }
+            Bencode::Str(s) => Self::encode_str(s.as_bytes(), buf),
+            Bencode::BStr(s) => Self::encode_str(s, buf),
+            Bencode::List(l) => {
+                buf.push(b'l');
+                l.iter().for_each(|v| v.encode(buf));
+                buf.push(b'e');
+            }
+            Bencode::Dict(d) => {
+                let mut kv_pairs: Vec<_> = d.iter().collect();
+                kv_pairs.sort_unstable_by_key(|(k, _)| *k);
+
+                buf.push(b'd');
+                for (k, v) in kv_pairs {
+                    Self::encode_str(k, buf);
+                    v.encode(buf);
+                }
+                buf.push(b'e');
+            }
+            Bencode::Raw(raw) => buf.extend_from_slice(raw),
+        }
+    }
+
+    fn encode_str(s: &[u8], buf: &mut Vec<u8>) {
+        buf.extend_from_slice(s.len().to_string().as_bytes());
+        buf.push(b':');
+        buf.extend_from_slice(s);
+    }
 }
 
 type Parsed<'a, T> = nom::IResult<&'a [u8], T>;
@@ -407,7 +652,10 @@ mod tests {
     use std::collections::HashMap;
 
     use super::Bencode as B;
-    use crate::torrent_ast::Bencode;
+    use crate::{
+        error::TorrentParseError as E,
+        torrent_ast::{Bencode, TorrentAST},
+    };
 
     macro_rules! hashmap {
         ($($k:expr => $v:expr),*) => ({
@@ -565,6 +813,23 @@ mod tests {
         }
     }
 
+    #[test]
+    fn encode() {
+        let test_files = [
+            &include_bytes!("test_data/mock_dir.torrent")[..],
+            &include_bytes!("test_data/mock_file.torrent")[..],
+            &include_bytes!("test_data/debian.torrent")[..],
+            &include_bytes!("test_data/bittorrent-v2-test.torrent")[..],
+            &include_bytes!("test_data/bittorrent-v2-hybrid-test.torrent")[..],
+        ];
+
+        for file in test_files {
+            let mut buf = vec![];
+            B::decode(file).unwrap().encode(&mut buf);
+            assert_eq!(buf, file);
+        }
+    }
+
     #[test]
     fn info_hash() {
         let cases = vec![
@@ -612,6 +877,104 @@ mod tests {
         }
     }
 
+    #[test]
+    fn decode_fail() {
+        let cases = [
+            (&b"i42e"[..], E::Bencode),
+            (b"de", E::MissingKey("info")),
+            (b"d4:infodee", E::MissingKey("name")),
+            (b"d8:announce1:a4:infod4:namei1eee", E::InvalidKey("name")),
+            (
+                b"d8:announce1:a4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces3:\xff\xfe\xfdee",
+                E::InvalidPieces,
+            ),
+            (
+                b"d8:announce1:a4:infod5:filesle6:lengthi1e4:name1:a12:piece lengthi1e6:pieces0:ee",
+                E::InvalidKey("pieces"),
+            ),
+            (
+                b"d8:announce1:a4:infod5:filesli1ee4:name1:a12:piece lengthi1e6:pieces1:\xffee",
+                E::InvalidFile,
+            ),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(TorrentAST::decode(input), Err(expected));
+        }
+    }
+
+    #[test]
+    fn decode_trackerless() {
+        let input = [
+            &b"d4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:"[..],
+            &[0xff; 20],
+            b"ee",
+        ]
+        .concat();
+        let torrent = TorrentAST::decode(&input).unwrap();
+
+        assert_eq!(torrent.announce, None);
+        assert_eq!(torrent.announce_list, None);
+        assert_eq!(torrent.url_list, None);
+        assert_eq!(torrent.httpseeds, None);
+    }
+
+    #[test]
+    fn decode_url_list() {
+        let info = [
+            &b"4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:"[..],
+            &[0xff; 20],
+            b"e",
+        ]
+        .concat();
+        let decode = |url_list: &[u8]| {
+            let input = [&b"d"[..], &info, b"8:url-list", url_list, b"e"].concat();
+            TorrentAST::decode(&input).unwrap().url_list.map(|urls| {
+                let urls = urls.into_iter().map(String::from);
+                urls.collect::<Vec<_>>()
+            })
+        };
+
+        // a single seed may be given on its own rather than in a list
+        let expected = vec!["http://a.example.com/".to_string()];
+        assert_eq!(decode(b"21:http://a.example.com/"), Some(expected));
+        let list = b"l21:http://a.example.com/22:http://b.example.com/ae";
+        let expected = ["http://a.example.com/", "http://b.example.com/a"];
+        assert_eq!(decode(list), Some(expected.map(String::from).to_vec()));
+        assert_eq!(decode(b"i1e"), None);
+    }
+
+    #[test]
+    fn decode_httpseeds() {
+        let input = [
+            &b"d9:httpseedsl21:http://a.example.com/e"[..],
+            b"4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:",
+            &[0xff; 20],
+            b"ee",
+        ]
+        .concat();
+        let torrent = TorrentAST::decode(&input).unwrap();
+
+        assert_eq!(torrent.httpseeds, Some(vec!["http://a.example.com/"]));
+        assert_eq!(torrent.url_list, None);
+    }
+
+    #[test]
+    fn piece_layers() {
+        let hybrid = include_bytes!("test_data/bittorrent-v2-hybrid-test.torrent");
+        let torrent = TorrentAST::decode(hybrid).unwrap();
+
+        assert_eq!(torrent.info.meta_version, Some(2));
+        assert_eq!(torrent.info.file_tree.as_ref().unwrap().len(), 9);
+        assert_eq!(torrent.piece_layers.as_ref().unwrap().len(), 8);
+
+        // flip a bit in the last piece layer hash
+        let mut corrupt = hybrid.to_vec();
+        let end = corrupt.len() - 3;
+        corrupt[end] ^= 1;
+        assert_eq!(TorrentAST::decode(&corrupt), Err(E::InvalidPieceLayers));
+    }
+
     #[test]
     fn decode_bt_test() {
         let test_files = [
@@ -667,6 +1030,7 @@ mod tests {
                 (0..spaces - 2).for_each(|_| print!(" "));
                 print!("}}");
             }
+            Bencode::Raw(r) => print!("Raw({r:?}),"),
         }
     }
 }
@@ -0,0 +1,534 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use byteorder::{ByteOrder, BE};
+use chrono::{DateTime, Utc};
+use futures::future::{self, Either};
+use hyper::Uri;
+use rand::{rngs::SmallRng, RngCore, SeedableRng};
+use tokio::{
+    net,
+    net::UdpSocket,
+    sync::watch,
+    task::{self, JoinHandle},
+    time,
+};
+
+use crate::{
+    error::{Error, Result},
+    handle::TorrentHandle,
+    json::Json,
+    peer::PeerId,
+    torrent::{AnnounceEvent, AnnounceResp, Sha1Hash, SwarmStats},
+    utils,
+    websocket::WebSocket,
+};
+
+/// TrackerProtocol is how a tracker is announced to, chosen by the scheme of its url
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackerProtocol {
+    /// `http://` and `https://`
+    Http,
+    /// `udp://`, see BEP-15
+    Udp,
+    /// `ws://` and `wss://` WebTorrent trackers. tsunami has no tls for websockets, so
+    /// announces to `wss://` trackers fail with [crate::error::Error::UnsupportedTracker]
+    WebSocket,
+}
+
+impl TrackerProtocol {
+    /// protocol for tracker, or None if its scheme isn't a tracker protocol we know of
+    pub fn of(tracker: &str) -> Option<TrackerProtocol> {
+        let (scheme, _) = tracker.split_once("://")?;
+
+        match scheme.to_ascii_lowercase().as_str() {
+            "http" | "https" => Some(TrackerProtocol::Http),
+            "udp" => Some(TrackerProtocol::Udp),
+            "ws" | "wss" => Some(TrackerProtocol::WebSocket),
+            _ => None,
+        }
+    }
+}
+
+/// Announcer is a torrent's background announce task. it sleeps until the next announce is due,
+/// or until woken early, then asks the torrent to announce through its command channel. the
+/// torrent reschedules it after every announce
+#[derive(Debug)]
+pub(crate) struct Announcer {
+    due: watch::Sender<DateTime<Utc>>,
+    // kept so schedules made before the task starts aren't lost
+    due_rx: watch::Receiver<DateTime<Utc>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl Announcer {
+    pub(crate) fn new() -> Announcer {
+        let (due, due_rx) = watch::channel(Utc::now());
+        Announcer {
+            due,
+            due_rx,
+            task: None,
+        }
+    }
+
+    /// spawn the announce task, which sends announces to handle. this does nothing if it's
+    /// already running
+    pub(crate) fn start(&mut self, handle: TorrentHandle) {
+        if self.task.is_none() {
+            let due = self.due_rx.clone();
+            self.task = Some(task::spawn(announce_task(due, handle)));
+        }
+    }
+
+    pub(crate) fn stop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+
+    /// announce at `at`, replacing any earlier schedule
+    pub(crate) fn schedule(&self, at: DateTime<Utc>) {
+        let _ = self.due.send(at);
+    }
+
+    /// announce now, eg. because the torrent completed or ran out of peers
+    pub(crate) fn wake(&self) {
+        self.schedule(Utc::now());
+    }
+}
+
+impl Drop for Announcer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+async fn announce_task(mut due: watch::Receiver<DateTime<Utc>>, handle: TorrentHandle) {
+    loop {
+        let at = *due.borrow_and_update();
+        let wait = (at - Utc::now()).to_std().unwrap_or_default();
+
+        let sleep = Box::pin(time::sleep(wait));
+        let fired = match future::select(sleep, Box::pin(due.changed())).await {
+            Either::Left(_) => true,
+            // rescheduled or woken early
+            Either::Right((Ok(()), _)) => false,
+            // the torrent was dropped
+            Either::Right((Err(_), _)) => return,
+        };
+
+        // wait for the torrent to reschedule us once it has announced
+        if fired && (!handle.request_announce() || due.changed().await.is_err()) {
+            return;
+        }
+    }
+}
+
+// magic constant identifying the udp tracker protocol
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+// time to wait for a response before resending, doubled after every attempt
+const UDP_RETRANSMIT: Duration = Duration::from_secs(15);
+// default time allowed for a whole udp or WebSocket announce, see Config::http_timeout
+const UDP_TIMEOUT: Duration = Duration::from_secs(30);
+
+// udp tracker actions
+const CONNECT: u32 = 0;
+const ANNOUNCE: u32 = 1;
+const ERROR: u32 = 3;
+
+/// AnnounceReq is everything sent in an announce to a udp or WebSocket tracker
+#[derive(Debug, Clone, Copy)]
+pub struct AnnounceReq {
+    pub info_hash: Sha1Hash,
+    pub peer_id: PeerId,
+    pub downloaded: u64,
+    pub left: u64,
+    pub uploaded: u64,
+    pub event: Option<AnnounceEvent>,
+    // WebSocket trackers don't need a port, their peers connect over WebRTC
+    pub port: u16,
+}
+
+/// announce to a udp tracker, giving up after timeout (30s if None). the socket is bound to
+/// local_addr if one is given
+///
+/// See BEP-15 for more details
+pub async fn announce_udp(
+    tracker: &str,
+    req: &AnnounceReq,
+    local_addr: Option<IpAddr>,
+    timeout: Option<Duration>,
+) -> Result<AnnounceResp> {
+    let announce = async {
+        let socket = udp_connect(tracker, local_addr).await?;
+        let mut rng = SmallRng::seed_from_u64(Utc::now().timestamp_millis() as u64);
+
+        // connect, to get an id proving we own our address
+        let tid = rng.next_u32();
+        let mut connect = [0; 16];
+        BE::write_u64(&mut connect, UDP_PROTOCOL_ID);
+        BE::write_u32(&mut connect[8..], CONNECT);
+        BE::write_u32(&mut connect[12..], tid);
+        let resp = udp_request(&socket, &connect, tid, 16).await?;
+        let conn_id = BE::read_u64(&resp[8..]);
+
+        let tid = rng.next_u32();
+        let event = match req.event {
+            None => 0,
+            Some(AnnounceEvent::Completed) => 1,
+            Some(AnnounceEvent::Started) => 2,
+            Some(AnnounceEvent::Stopped) => 3,
+            // udp trackers have no paused event, partial seeds announce as usual
+            Some(AnnounceEvent::Paused) => 0,
+        };
+        let mut announce = [0; 98];
+        BE::write_u64(&mut announce, conn_id);
+        BE::write_u32(&mut announce[8..], ANNOUNCE);
+        BE::write_u32(&mut announce[12..], tid);
+        announce[16..36].copy_from_slice(&req.info_hash);
+        announce[36..56].copy_from_slice(&req.peer_id);
+        BE::write_u64(&mut announce[56..], req.downloaded);
+        BE::write_u64(&mut announce[64..], req.left);
+        BE::write_u64(&mut announce[72..], req.uploaded);
+        BE::write_u32(&mut announce[80..], event);
+        // ip (84..88) and key (88..92) are left as 0, num_want of -1 lets the tracker decide
+        BE::write_i32(&mut announce[92..], -1);
+        BE::write_u16(&mut announce[96..], req.port);
+        let resp = udp_request(&socket, &announce, tid, 20).await?;
+
+        // peers are in the same address family the tracker was reached over
+        let peers = match socket.peer_addr()? {
+            SocketAddr::V4(_) => utils::parse_compact_peers(&resp[20..]),
+            SocketAddr::V6(_) => utils::parse_compact_peers6(&resp[20..]),
+        };
+
+        Ok(AnnounceResp {
+            interval: BE::read_u32(&resp[8..]) as u64,
+            min_interval: None,
+            stats: Some(SwarmStats {
+                leechers: BE::read_u32(&resp[12..]) as u64,
+                seeders: BE::read_u32(&resp[16..]) as u64,
+            }),
+            peers,
+            warning: None,
+            external_ip: None,
+        })
+    };
+
+    time::timeout(timeout.unwrap_or(UDP_TIMEOUT), announce)
+        .await
+        .unwrap_or(Err(Error::Timeout))
+}
+
+/// announce to a WebTorrent tracker over a WebSocket, giving up after timeout (30s if None).
+/// no WebRTC offers are sent, so this only learns the swarm's size and any peers the tracker
+/// lists with an ip and port
+pub async fn announce_ws(
+    tracker: &str,
+    req: &AnnounceReq,
+    local_addr: Option<IpAddr>,
+    timeout: Option<Duration>,
+) -> Result<AnnounceResp> {
+    // binary strings are sent with a char per byte
+    let binary = |b: &[u8]| b.iter().map(|&b| b as char).collect::<String>();
+    let info_hash = binary(&req.info_hash);
+
+    let mut msg = r#"{"action":"announce","info_hash":"#.to_string();
+    Json::encode_str(&info_hash, &mut msg);
+    msg.push_str(r#","peer_id":"#);
+    Json::encode_str(&binary(&req.peer_id), &mut msg);
+    let (uploaded, downloaded, left) = (req.uploaded, req.downloaded, req.left);
+    write!(
+        msg,
+        r#","uploaded":{uploaded},"downloaded":{downloaded},"left":{left}"#
+    )
+    .unwrap();
+    if let Some(event) = req.event {
+        write!(msg, r#","event":"{}""#, event.as_str()).unwrap();
+    }
+    msg.push_str(r#","numwant":0,"offers":[]}"#);
+
+    let announce = async {
+        let mut ws = WebSocket::connect(tracker, local_addr).await?;
+        ws.send_text(&msg).await?;
+
+        // other messages, eg. offers relayed from peers, may arrive before our response
+        let resp = loop {
+            let Some(resp) = Json::decode(&ws.recv_text().await?).and_then(Json::object) else {
+                return Err(Error::InvalidTrackerResp(None));
+            };
+
+            let is_announce = resp.get("action").and_then(Json::str) == Some("announce");
+            let ours = resp.get("info_hash").and_then(Json::str) == Some(&info_hash);
+            let answered = ["interval", "failure reason"]
+                .iter()
+                .any(|k| resp.contains_key(*k));
+            if is_announce && ours && answered {
+                break resp;
+            }
+        };
+        let _ = ws.close().await;
+
+        parse_ws_resp(resp)
+    };
+
+    time::timeout(timeout.unwrap_or(UDP_TIMEOUT), announce)
+        .await
+        .unwrap_or(Err(Error::Timeout))
+}
+
+fn parse_ws_resp(mut resp: HashMap<String, Json>) -> Result<AnnounceResp> {
+    if let Some(reason) = resp.get("failure reason") {
+        let reason = reason.str().map(String::from);
+        return Err(Error::InvalidTrackerResp(reason));
+    }
+
+    let peers = resp.remove("peers");
+    let resp: Option<_> = try {
+        let count = |key| resp.get(key).and_then(Json::uint);
+        let stats = match (count("complete"), count("incomplete")) {
+            (Some(seeders), Some(leechers)) => Some(SwarmStats { seeders, leechers }),
+            _ => None,
+        };
+
+        // hybrid trackers may list peers reachable over tcp
+        let peers = match peers {
+            Some(peers) => peers
+                .list()?
+                .into_iter()
+                .filter_map(|peer| {
+                    let peer = peer.object()?;
+                    let ip: IpAddr = peer.get("ip")?.str()?.parse().ok()?;
+                    let port = peer.get("port")?.uint()?.try_into().ok()?;
+                    Some(SocketAddr::new(ip, port))
+                })
+                .collect(),
+            None => vec![],
+        };
+
+        AnnounceResp {
+            interval: count("interval")?,
+            min_interval: count("min interval"),
+            stats,
+            peers,
+            warning: resp
+                .get("warning message")
+                .and_then(Json::str)
+                .map(String::from),
+            external_ip: None,
+        }
+    };
+
+    resp.ok_or(Error::InvalidTrackerResp(None))
+}
+
+/// open a udp socket connected to tracker
+async fn udp_connect(tracker: &str, local_addr: Option<IpAddr>) -> Result<UdpSocket> {
+    let uri: Uri = tracker.parse()?;
+    let (Some(host), Some(port)) = (uri.host(), uri.port_u16()) else {
+        return Err(Error::UnsupportedTracker(tracker.into()));
+    };
+
+    // ipv6 hosts come bracketed from the uri
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let mut addrs = net::lookup_host((host, port)).await?;
+    let addr = match local_addr {
+        Some(local) => addrs.find(|a| a.is_ipv4() == local.is_ipv4()),
+        None => addrs.next(),
+    };
+    let addr = addr.ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+
+    let local = match (local_addr, addr) {
+        (Some(local), _) => local,
+        (None, SocketAddr::V4(_)) => Ipv4Addr::UNSPECIFIED.into(),
+        (None, SocketAddr::V6(_)) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+    socket.connect(addr).await?;
+
+    Ok(socket)
+}
+
+/// send req until a response to it at least min_len bytes long arrives
+async fn udp_request(socket: &UdpSocket, req: &[u8], tid: u32, min_len: usize) -> Result<Vec<u8>> {
+    let action = BE::read_u32(&req[8..]);
+    let mut buf = vec![0; 2048];
+
+    let mut wait = UDP_RETRANSMIT;
+    loop {
+        socket.send(req).await?;
+
+        let Ok(len) = time::timeout(wait, socket.recv(&mut buf)).await else {
+            wait *= 2;
+            continue;
+        };
+        let len = len?;
+
+        // responses to an earlier attempt or someone else's request
+        if len < 8 || BE::read_u32(&buf[4..]) != tid {
+            continue;
+        }
+
+        match BE::read_u32(&buf) {
+            ERROR => {
+                let msg = String::from_utf8_lossy(&buf[8..len]).into_owned();
+                return Err(Error::InvalidTrackerResp(Some(msg)));
+            }
+            a if a == action && len >= min_len => {
+                buf.truncate(len);
+                return Ok(buf);
+            }
+            _ => return Err(Error::InvalidTrackerResp(None)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use byteorder::{ByteOrder, BE};
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, UdpSocket},
+    };
+
+    use super::{announce_udp, announce_ws, AnnounceReq, TrackerProtocol, UDP_PROTOCOL_ID};
+    use crate::{
+        json::Json,
+        torrent::{AnnounceEvent, SwarmStats},
+        websocket::tests::{accept, read_frame, text_frame},
+    };
+
+    #[test]
+    fn protocol() {
+        let cases = [
+            ("http://a.example/announce", Some(TrackerProtocol::Http)),
+            ("HTTPS://a.example/announce", Some(TrackerProtocol::Http)),
+            ("udp://a.example:1337", Some(TrackerProtocol::Udp)),
+            ("wss://a.example", Some(TrackerProtocol::WebSocket)),
+            ("magnet:?xt=urn:btih:abc", None),
+            ("a.example/announce", None),
+        ];
+
+        for (tracker, protocol) in cases {
+            assert_eq!(TrackerProtocol::of(tracker), protocol, "{tracker}");
+        }
+    }
+
+    #[tokio::test]
+    async fn udp_announce() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tracker = format!("udp://{}/announce", server.local_addr().unwrap());
+
+        let serve = async {
+            let mut buf = [0; 128];
+
+            let (len, from) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(len, 16);
+            assert_eq!(BE::read_u64(&buf), UDP_PROTOCOL_ID);
+            let mut resp = [0; 16];
+            resp[4..8].copy_from_slice(&buf[12..16]);
+            BE::write_u64(&mut resp[8..], 0xdead);
+            server.send_to(&resp, from).await.unwrap();
+
+            let (len, from) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(len, 98);
+            assert_eq!(BE::read_u64(&buf), 0xdead);
+            assert_eq!(BE::read_u32(&buf[8..]), 1);
+            assert_eq!(&buf[16..36], &[7; 20]);
+            assert_eq!(BE::read_u32(&buf[80..]), 2);
+            assert_eq!(BE::read_u16(&buf[96..]), 6881);
+
+            let mut resp = vec![0; 20];
+            BE::write_u32(&mut resp, 1);
+            resp[4..8].copy_from_slice(&buf[12..16]);
+            BE::write_u32(&mut resp[8..], 1800);
+            BE::write_u32(&mut resp[12..], 3);
+            BE::write_u32(&mut resp[16..], 5);
+            resp.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+            server.send_to(&resp, from).await.unwrap();
+        };
+
+        let req = AnnounceReq {
+            info_hash: [7; 20],
+            peer_id: *b"-TS0001-|testClient|",
+            downloaded: 0,
+            left: 10,
+            uploaded: 0,
+            event: Some(AnnounceEvent::Started),
+            port: 6881,
+        };
+        let announce = announce_udp(&tracker, &req, None, Some(Duration::from_secs(5)));
+        let (resp, _) = futures::join!(announce, serve);
+
+        let resp = resp.unwrap();
+        assert_eq!(resp.interval, 1800);
+        let stats = SwarmStats {
+            seeders: 5,
+            leechers: 3,
+        };
+        assert_eq!(resp.stats, Some(stats));
+        assert_eq!(resp.peers, ["10.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn ws_announce() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tracker = format!("ws://{}/announce", listener.local_addr().unwrap());
+
+        let serve = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            accept(&mut stream).await;
+
+            let (_, req) = read_frame(&mut stream).await;
+            let req = Json::decode(std::str::from_utf8(&req).unwrap()).unwrap();
+            let req = req.object().unwrap();
+            assert_eq!(req["action"].str(), Some("announce"));
+            assert_eq!(req["info_hash"].str(), Some("\u{7}".repeat(20).as_str()));
+            assert_eq!(req["event"].str(), Some("started"));
+            assert_eq!(req["left"].uint(), Some(10));
+
+            // an offer relayed from another peer comes first
+            let info_hash = format!(r#""{}""#, r"\u0007".repeat(20));
+            let offer = format!(r#"{{"action":"announce","info_hash":{info_hash},"offer":{{}}}}"#);
+            stream.write_all(&text_frame(&offer)).await.unwrap();
+
+            let resp = format!(
+                r#"{{"action":"announce","info_hash":{info_hash},"interval":120,"complete":5,
+                    "incomplete":3,"peers":[{{"ip":"10.0.0.1","port":6881}}]}}"#
+            );
+            stream.write_all(&text_frame(&resp)).await.unwrap();
+
+            // wait for the client to close
+            read_frame(&mut stream).await
+        };
+
+        let req = AnnounceReq {
+            info_hash: [7; 20],
+            peer_id: *b"-TS0001-|testClient|",
+            downloaded: 0,
+            left: 10,
+            uploaded: 0,
+            event: Some(AnnounceEvent::Started),
+            port: 6881,
+        };
+        let announce = announce_ws(&tracker, &req, None, Some(Duration::from_secs(5)));
+        let (resp, (close, _)) = futures::join!(announce, serve);
+        assert_eq!(close[0], 0x88);
+
+        let resp = resp.unwrap();
+        assert_eq!(resp.interval, 120);
+        let stats = SwarmStats {
+            seeders: 5,
+            leechers: 3,
+        };
+        assert_eq!(resp.stats, Some(stats));
+        assert_eq!(resp.peers, ["10.0.0.1:6881".parse().unwrap()]);
+    }
+}
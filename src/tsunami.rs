@@ -1,43 +1,519 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use chrono::Utc;
+use futures::{
+    future::{self, join_all},
+    select_biased, FutureExt,
+};
 use rand::{distributions::Alphanumeric, rngs::SmallRng, Rng, SeedableRng};
+use tokio::{
+    sync::mpsc::unbounded_channel,
+    time::{self, MissedTickBehavior},
+};
 
-use crate::torrent::Torrent;
+use crate::{
+    config::{AddTorrentOptions, Config},
+    connection_limits::ConnectionLimits,
+    disk::{ReadCache, CACHE_SIZE},
+    error::{AddTorrentError, CommandError},
+    events::{Event, EventReceiver, EventSender},
+    hasher::Hasher,
+    listener::{Listener, LISTEN_PORT},
+    peer::{Peer, PeerId},
+    smart_ban::BanList,
+    stats::SessionStats,
+    torrent::{ExternalIp, Sha1Hash, State, Torrent},
+    utils::{self, HttpClient},
+};
+
+// how often Tsunami::run drives each torrent
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
 
 /// Tsunami bittorrent client
 pub struct Tsunami {
-    peer_id: Arc<String>,
+    peer_id: Arc<PeerId>,
     base_dir: PathBuf,
+    config: Arc<Config>,
+    // shared by every torrent bound to the session's bind_address
+    http: HttpClient,
     torrents: Vec<Torrent>,
+    external_ip: ExternalIp,
+    // incoming peer connections, None until Tsunami::listen
+    listener: Option<Listener>,
+    // shared with every torrent, which report it to trackers and peers
+    listen_port: Arc<AtomicU16>,
+    // peers banned for sending corrupt data, shared with every torrent and the listener
+    ban_list: BanList,
+    // caps on peer connections across every torrent
+    connection_limits: ConnectionLimits,
+    // hashes the pieces of every torrent
+    hasher: Hasher,
+    // recently read pieces of every torrent, see Config::read_cache_size
+    read_cache: ReadCache,
+
+    events: EventSender,
+    events_rx: Option<EventReceiver>,
 }
 
 impl Tsunami {
     pub fn new(base_dir: PathBuf) -> Option<Tsunami> {
+        Self::with_config(base_dir, Config::default())
+    }
+
+    pub fn with_config(base_dir: PathBuf, config: Config) -> Option<Tsunami> {
         // todo: peer_id should be identifiable for user/clients/machine
-        let rng = SmallRng::seed_from_u64(Utc::now().timestamp_millis() as u64);
-        let peer_id = Arc::new(format!(
-            "-TS0001-{}",
-            rng.sample_iter(&Alphanumeric)
-                .take(12)
-                .map(char::from)
-                .collect(): String
-        ));
+        let mut rng = SmallRng::seed_from_u64(Utc::now().timestamp_millis() as u64);
+        let mut peer_id = *b"-TS0001-............";
+        for b in &mut peer_id[8..] {
+            *b = rng.sample(Alphanumeric);
+        }
+        let peer_id = Arc::new(peer_id);
 
         if !base_dir.has_root() {
             return None;
         }
 
+        let (events, events_rx) = EventSender::new(config.events);
+
         Some(Tsunami {
             peer_id,
             base_dir,
+            listen_port: Arc::new(AtomicU16::new(config.listen_port.unwrap_or(LISTEN_PORT))),
+            http: utils::http_client(
+                config.bind_address,
+                config.http_timeout,
+                config.proxy.clone(),
+            ),
+            connection_limits: ConnectionLimits::new(&config),
+            hasher: Default::default(),
+            read_cache: ReadCache::new(config.read_cache_size.unwrap_or(CACHE_SIZE)),
+            config: Arc::new(config),
             torrents: vec![],
+            external_ip: Default::default(),
+            listener: None,
+            ban_list: BanList::default(),
+
+            events,
+            events_rx: Some(events_rx),
         })
     }
 
-    pub fn add_torrent(&mut self, buf: &[u8]) -> Option<&mut Torrent> {
-        let torrent = Torrent::new(buf, self.peer_id.clone(), &self.base_dir)?;
+    /// take the receiving end of this session's event stream. this returns None if the receiver
+    /// was already taken
+    pub fn take_events(&mut self) -> Option<EventReceiver> {
+        self.events_rx.take()
+    }
+
+    /// add a torrent to this session with default options. if starting it would exceed the
+    /// session's disk quota the torrent is added paused and an [Event::QuotaExceeded] is emitted.
+    /// with [crate::events::EventPolicy::Lossless] this waits for room in the event queue, so
+    /// don't add torrents from the task consuming the events
+    pub async fn add_torrent(&mut self, buf: &[u8]) -> Result<&mut Torrent, AddTorrentError> {
+        let opts = AddTorrentOptions::default();
+        self.add_torrent_with(buf, &opts).await
+    }
+
+    /// add a torrent to this session with opts, see [Tsunami::add_torrent]
+    pub async fn add_torrent_with(
+        &mut self,
+        buf: &[u8],
+        opts: &AddTorrentOptions,
+    ) -> Result<&mut Torrent, AddTorrentError> {
+        let opts = AddTorrentOptions {
+            bind_address: opts.bind_address.or(self.config.bind_address),
+            ..opts.clone()
+        };
+
+        let mut torrent = Torrent::new(
+            buf,
+            self.config.clone(),
+            self.peer_id.clone(),
+            &self.base_dir,
+            &opts,
+        )?;
+        if opts.bind_address == self.config.bind_address {
+            torrent.set_http_client(self.http.clone());
+        }
+        torrent.set_events(self.events.clone());
+        torrent.set_external_ip(self.external_ip.clone());
+        torrent.set_listen_port(self.listen_port.clone());
+        torrent.set_ban_list(self.ban_list.clone());
+        torrent.set_connection_limits(self.connection_limits.clone());
+        torrent.set_hasher(self.hasher.clone());
+        torrent.set_read_cache(self.read_cache.clone());
+        // torrents which don't fit in the quota aren't given room on disk until they're resumed
+        if self.check_quota(&torrent).await {
+            torrent.preallocate().await?;
+            torrent.create_symlinks().await?;
+        } else {
+            torrent.pause();
+        }
+        torrent.complete_existing_files().await;
+
         self.torrents.push(torrent);
-        self.torrents.last_mut()
+        Ok(self.torrents.last_mut().unwrap())
+    }
+
+    /// drive every torrent until shutdown resolves, carrying out the commands sent through
+    /// their handles and handling their peers every [TICK_INTERVAL], see
+    /// [Torrent::process_commands]. torrents are controlled through their
+    /// [crate::handle::TorrentHandle]s while this runs. once [Tsunami::listen] has been called,
+    /// peers which connect to us are handed to the torrent they ask for
+    pub async fn run(&mut self, shutdown: impl Future<Output = ()>) {
+        let mut shutdown = Box::pin(shutdown.fuse());
+        let mut ticks = time::interval(TICK_INTERVAL);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        // handshakes run in the background, so a slow peer can't hold up the others
+        let info_hashes = self.torrents.iter();
+        let info_hashes = info_hashes.map(|t| (*t.info_hash(), t.total_pieces()));
+        let info_hashes = Arc::new(info_hashes.collect::<HashMap<_, _>>());
+        let (handshaken, mut accepted) = unbounded_channel();
+
+        loop {
+            let incoming = async {
+                match &mut self.listener {
+                    Some(listener) => listener.accept().await,
+                    None => future::pending().await,
+                }
+            };
+
+            select_biased! {
+                _ = shutdown => return,
+                incoming = Box::pin(incoming).fuse() => {
+                    let Ok(incoming) = incoming else {
+                        continue;
+                    };
+                    let (info_hashes, peer_id) = (info_hashes.clone(), self.peer_id.clone());
+                    let handshaken = handshaken.clone();
+                    tokio::spawn(async move {
+                        let addr = incoming.addr;
+                        let accept = |stream| async move {
+                            Ok(Peer::accept(stream, &info_hashes, &*peer_id).await)
+                        };
+                        if let Ok(Ok((peer, info_hash))) = incoming.handshake(accept).await {
+                            let _ = handshaken.send((peer, addr, info_hash));
+                        }
+                    });
+                }
+                peer = Box::pin(accepted.recv()).fuse() => {
+                    let Some((peer, addr, info_hash)) = peer else {
+                        continue;
+                    };
+                    let torrent = self.torrents.iter_mut().find(|t| *t.info_hash() == info_hash);
+                    if let Some(torrent) = torrent {
+                        torrent.add_incoming(peer, addr);
+                    }
+                }
+                _ = Box::pin(ticks.tick()).fuse() => {
+                    join_all(self.torrents.iter_mut().map(Torrent::process_commands)).await;
+                    self.resume_requested().await;
+                }
+            }
+        }
+    }
+
+    /// start accepting peer connections on [Config::listen_port], or the first free port of
+    /// [Config::listen_port_range]. returns the port bound, which is then reported to trackers
+    /// and peers. connections are accepted while [Tsunami::run] runs. this must be called from
+    /// within a tokio runtime
+    pub fn listen(&mut self) -> io::Result<u16> {
+        let unspecified = Ipv4Addr::UNSPECIFIED.into();
+        let ip = self.config.bind_address.unwrap_or(unspecified);
+        let mut listener = Listener::bind_port(ip, &self.config)?;
+        listener.set_ban_list(self.ban_list.clone());
+        let port = listener.local_addr()?.port();
+
+        self.listener = Some(listener);
+        self.listen_port.store(port, Ordering::Relaxed);
+        Ok(port)
+    }
+
+    /// port peers can connect to us on
+    pub fn listen_port(&self) -> u16 {
+        self.listen_port.load(Ordering::Relaxed)
+    }
+
+    /// our public address as last reported by any torrent's tracker (BEP-24), if one has
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external_ip.get()
+    }
+
+    /// whether peers at ip were banned for sending corrupt data. bans last as long as the session
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.ban_list.contains(ip)
+    }
+
+    pub fn torrent(&self, info_hash: &Sha1Hash) -> Option<&Torrent> {
+        self.torrents.iter().find(|t| t.info_hash() == info_hash)
+    }
+
+    /// resume a paused torrent, returning whether it is now active. a torrent will not be resumed
+    /// if doing so would exceed the session's disk quota, in which case an
+    /// [Event::QuotaExceeded] is emitted as for [Tsunami::add_torrent], or if its files can't be
+    /// allocated
+    pub async fn resume_torrent(&mut self, info_hash: &Sha1Hash) -> bool {
+        let Some(idx) = self.torrents.iter().position(|t| t.info_hash() == info_hash) else {
+            return false;
+        };
+
+        if self.torrents[idx].state() == State::Active {
+            return true;
+        }
+
+        if !self.check_quota(&self.torrents[idx]).await {
+            return false;
+        }
+
+        // torrents added paused weren't given room on disk
+        let torrent = &mut self.torrents[idx];
+        if torrent.preallocate().await.is_err() || torrent.create_symlinks().await.is_err() {
+            return false;
+        }
+        torrent.resume();
+        true
+    }
+
+    // carry out the resumes sent through handles, which have to fit in the disk quota too
+    async fn resume_requested(&mut self) {
+        for i in 0..self.torrents.len() {
+            let Some(reply) = self.torrents[i].take_resume() else {
+                continue;
+            };
+            let info_hash = *self.torrents[i].info_hash();
+            let res = match self.resume_torrent(&info_hash).await {
+                true => Ok(()),
+                false => Err(CommandError::QuotaExceeded),
+            };
+            let _ = reply.send(res);
+        }
+    }
+
+    /// stop and remove a torrent, telling its trackers we've stopped. returns false if no
+    /// torrent matches info_hash
+    pub async fn remove_torrent(&mut self, info_hash: &Sha1Hash) -> bool {
+        let Some(idx) = self.torrents.iter().position(|t| t.info_hash() == info_hash) else {
+            return false;
+        };
+
+        let mut torrent = self.torrents.remove(idx);
+        torrent.stop().await;
+        true
+    }
+
+    /// stop every torrent, announcing to their trackers that we've stopped
+    pub async fn shutdown(&mut self) {
+        join_all(self.torrents.iter_mut().map(Torrent::stop)).await;
+    }
+
+    /// piece data transferred by every torrent and the rates it's moving at, see
+    /// [Torrent::stats]
+    pub fn stats(&self) -> SessionStats {
+        let mut stats = SessionStats::default();
+        for torrent in &self.torrents {
+            stats.add(&torrent.stats());
+        }
+        stats
+    }
+
+    /// number of bytes all active torrents will occupy on disk once complete
+    pub fn projected_usage(&self) -> u64 {
+        self.torrents
+            .iter()
+            .filter(|t| t.state() == State::Active)
+            .map(Torrent::total_size)
+            .fold(0, u64::saturating_add)
+    }
+
+    /// check if torrent can be started without exceeding the disk quota, emitting an
+    /// [Event::QuotaExceeded] if it can't
+    async fn check_quota(&self, torrent: &Torrent) -> bool {
+        let Some(quota) = self.config.disk_quota else {
+            return true;
+        };
+
+        let projected = self.projected_usage().saturating_add(torrent.total_size());
+        if projected <= quota {
+            return true;
+        }
+
+        let event = Event::QuotaExceeded {
+            info_hash: *torrent.info_hash(),
+            projected,
+            quota,
+        };
+        self.events.emit(event).await;
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap, env, fs, net::SocketAddr, path::PathBuf, process, time::Duration,
+    };
+
+    use tokio::{net::TcpListener, time};
+
+    use crate::{
+        config::{AddTorrentOptions, Allocation, Config},
+        error::CommandError,
+        events::Event,
+        peer::{Peer, Timeouts},
+        proxy::Dialer,
+        torrent::{tests::fake_tracker, State},
+        tsunami::Tsunami,
+    };
+
+    #[tokio::test]
+    async fn run() {
+        let mut tsunami = Tsunami::new(PathBuf::from("/foo")).unwrap();
+        let torrent = tsunami
+            .add_torrent(include_bytes!("test_data/mock_file.torrent"))
+            .await
+            .unwrap();
+        let (info_hash, handle) = (*torrent.info_hash(), torrent.handle());
+
+        // commands are carried out while the session runs
+        tsunami.run(async { handle.pause().await.unwrap() }).await;
+        let torrent = tsunami.torrent(&info_hash).unwrap();
+        assert_eq!(torrent.state(), State::Paused);
+    }
+
+    #[tokio::test]
+    async fn incoming_peers() {
+        let config = Config {
+            listen_port: Some(0),
+            ..Default::default()
+        };
+        let mut tsunami = Tsunami::with_config(PathBuf::from("/foo"), config).unwrap();
+        let port = tsunami.listen().unwrap();
+        let torrent = tsunami
+            .add_torrent(include_bytes!("test_data/mock_file.torrent"))
+            .await
+            .unwrap();
+        let (info_hash, pieces) = (*torrent.info_hash(), torrent.total_pieces());
+
+        let mut remote = None;
+        let connect = async {
+            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+            let (peer_id, dialer) = (b"-TS0001-|remotePeer|", Dialer::new(None, None));
+            let timeouts = Timeouts::default();
+            let peer = Peer::connect(addr, &info_hash, peer_id, pieces, &dialer, timeouts);
+            remote = Some(peer.await.unwrap());
+            // give the session a moment to hand the connection over
+            time::sleep(Duration::from_millis(100)).await;
+        };
+        tsunami.run(connect).await;
+
+        let torrent = tsunami.torrent(&info_hash).unwrap();
+        assert_eq!(torrent.peer_stats().len(), 1);
+        drop(remote);
+    }
+
+    #[tokio::test]
+    async fn tracker_peers() {
+        let mut tsunami = Tsunami::new(PathBuf::from("/foo")).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (url, _) = fake_tracker(listener.local_addr().unwrap()).await;
+        let torrent = tsunami
+            .add_torrent(include_bytes!("test_data/mock_file.torrent"))
+            .await
+            .unwrap();
+        let (info_hash, pieces) = (*torrent.info_hash(), torrent.total_pieces());
+        torrent.set_trackers(vec![vec![url]]);
+        torrent.start().await.unwrap();
+
+        // the peer the tracker responded with is dialed while the session runs
+        let mut remote = None;
+        let accept = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let info_hashes = HashMap::from([(info_hash, pieces)]);
+            let peer = Peer::accept(stream, &info_hashes, b"-TS0001-|remotePeer|");
+            let (mut peer, _) = peer.await.unwrap();
+            // the torrent only messages the peer once it has taken the connection
+            peer.decode_message().await.unwrap();
+            remote = Some(peer);
+        };
+        tsunami.run(accept).await;
+
+        let torrent = tsunami.torrent(&info_hash).unwrap();
+        assert_eq!(torrent.peer_stats().len(), 1);
+        drop(remote);
+    }
+
+    #[tokio::test]
+    async fn disk_quota() {
+        let config = Config {
+            disk_quota: Some(15),
+            ..Default::default()
+        };
+        let mut tsunami = Tsunami::with_config(PathBuf::from("/foo"), config).unwrap();
+        let mut events = tsunami.take_events().unwrap();
+
+        let file = tsunami
+            .add_torrent(include_bytes!("test_data/mock_file.torrent"))
+            .await
+            .unwrap();
+        assert_eq!(file.state(), State::Active);
+
+        let dir = tsunami
+            .add_torrent(include_bytes!("test_data/mock_dir.torrent"))
+            .await
+            .unwrap();
+        let dir_hash = *dir.info_hash();
+        assert_eq!(dir.state(), State::Paused);
+        assert_eq!(
+            events.try_recv(),
+            Some(Event::QuotaExceeded {
+                info_hash: dir_hash,
+                projected: 20,
+                quota: 15
+            })
+        );
+
+        assert!(!tsunami.resume_torrent(&dir_hash).await);
+        assert_eq!(tsunami.projected_usage(), 10);
+
+        // resuming through a handle is held to the quota too
+        let handle = tsunami.torrent(&dir_hash).unwrap().handle();
+        let resume = async {
+            let res = handle.resume().await;
+            assert!(matches!(res, Err(CommandError::QuotaExceeded)));
+        };
+        tsunami.run(resume).await;
+        assert_eq!(tsunami.torrent(&dir_hash).unwrap().state(), State::Paused);
+    }
+
+    #[tokio::test]
+    async fn quota_allocation() {
+        let dir = env::temp_dir().join(format!("tsunami_quota_{}", process::id()));
+        let config = Config {
+            disk_quota: Some(5),
+            ..Default::default()
+        };
+        let mut tsunami = Tsunami::with_config(dir.clone(), config).unwrap();
+        let opts = AddTorrentOptions {
+            allocation: Allocation::Full,
+            ..Default::default()
+        };
+
+        // nothing is allocated for a torrent added paused
+        let buf = include_bytes!("test_data/mock_file.torrent");
+        let torrent = tsunami.add_torrent_with(buf, &opts).await.unwrap();
+        assert_eq!(torrent.state(), State::Paused);
+        assert!(!dir.exists());
+        let _ = fs::remove_dir_all(dir);
     }
 }
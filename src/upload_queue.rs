@@ -0,0 +1,113 @@
+use std::collections::VecDeque;
+
+use crate::{extension::REQQ, request_queue::Block};
+
+/// UploadQueue is the blocks a peer has asked us for which we haven't sent yet. requests wait
+/// their turn to be read from disk, and at most [REQQ] are kept as we advertise in our extension
+/// handshake, more are dropped. cancelled blocks are dropped even if they're already being read
+#[derive(Debug, Default)]
+pub(crate) struct UploadQueue {
+    // requests waiting to be read, oldest first
+    waiting: VecDeque<Block>,
+    // blocks being read from disk
+    reading: Vec<Block>,
+}
+
+impl UploadQueue {
+    /// queue a request from the peer, returning false if it's a duplicate or the queue is full
+    pub(crate) fn push(&mut self, block: Block) -> bool {
+        let queued = self.waiting.contains(&block) || self.reading.contains(&block);
+        if queued || self.len() >= REQQ as usize {
+            return false;
+        }
+
+        self.waiting.push_back(block);
+        true
+    }
+
+    /// the next block to read, which stays in the queue until it's [UploadQueue::finish]ed
+    pub(crate) fn next(&mut self) -> Option<Block> {
+        let block = self.waiting.pop_front()?;
+        self.reading.push(block);
+        Some(block)
+    }
+
+    /// take a block which has been read off the queue, returning whether the peer still wants
+    /// it
+    pub(crate) fn finish(&mut self, block: Block) -> bool {
+        let Some(i) = self.reading.iter().position(|&b| b == block) else {
+            return false;
+        };
+        self.reading.swap_remove(i);
+        true
+    }
+
+    /// drop a request the peer cancelled, returning whether it was queued
+    pub(crate) fn cancel(&mut self, block: Block) -> bool {
+        let len = self.len();
+        self.waiting.retain(|&b| b != block);
+        self.reading.retain(|&b| b != block);
+        self.len() != len
+    }
+
+    /// drop every request, eg. once the peer is choked, returning them
+    pub(crate) fn clear(&mut self) -> Vec<Block> {
+        let reading = self.reading.drain(..);
+        reading.chain(self.waiting.drain(..)).collect()
+    }
+
+    /// bytes being read from disk
+    pub(crate) fn reading(&self) -> usize {
+        self.reading.iter().map(|b| b.length as usize).sum()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.waiting.len() + self.reading.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{extension::REQQ, request_queue::Block, upload_queue::UploadQueue};
+
+    fn block(index: u32) -> Block {
+        Block {
+            index,
+            begin: 0,
+            length: 16 * 1024,
+        }
+    }
+
+    #[test]
+    fn requests() {
+        let mut queue = UploadQueue::default();
+        assert!(queue.push(block(0)));
+        assert!(queue.push(block(1)));
+        assert!(queue.push(block(2)));
+        assert!(!queue.push(block(1)));
+
+        // blocks are read in the order they were asked for
+        assert_eq!(queue.next(), Some(block(0)));
+        assert_eq!(queue.next(), Some(block(1)));
+        assert!(!queue.push(block(0)));
+        assert_eq!(queue.reading(), 2 * 16 * 1024);
+
+        // cancelled blocks aren't sent, even once they've been read
+        assert!(queue.cancel(block(1)));
+        assert!(queue.cancel(block(2)));
+        assert!(!queue.cancel(block(2)));
+        assert!(queue.finish(block(0)));
+        assert!(!queue.finish(block(1)));
+        assert_eq!(queue.next(), None);
+        assert_eq!(queue.len(), 0);
+
+        // at most REQQ requests are kept
+        for i in 0..REQQ + 1 {
+            assert_eq!(queue.push(block(i)), i < REQQ);
+        }
+        queue.next();
+        assert_eq!(queue.clear().len(), REQQ as usize);
+        assert_eq!(queue.len(), 0);
+        assert!(!queue.finish(block(0)));
+    }
+}
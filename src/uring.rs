@@ -0,0 +1,381 @@
+use std::{
+    fs, io, mem,
+    os::unix::{fs::FileExt, io::AsRawFd},
+    sync::{mpsc, Arc, OnceLock},
+    thread,
+};
+
+use futures::future::BoxFuture;
+use io_uring::{opcode, types, EnterFlags, IoUring};
+use tokio::sync::oneshot;
+
+use crate::{
+    config::Allocation,
+    storage::{self, FileIo, FileSpan, Segment},
+};
+
+// submission queue entries, batches with more reads or writes are submitted in several rounds
+const ENTRIES: u32 = 256;
+// jobs submitted together at most
+const BATCH: usize = 64;
+
+/// UringIo does storage io with io_uring on a thread of its own. jobs which queue up while the
+/// thread is busy are submitted together, so a burst of 16 KiB blocks costs a syscall or two
+/// rather than a thread hop and a syscall each. it's shared by every torrent, see [shared]
+#[derive(Debug)]
+pub(crate) struct UringIo {
+    jobs: mpsc::Sender<Batched>,
+}
+
+// Batched is a job for the ring's thread, its files already opened
+struct Batched {
+    done: oneshot::Sender<io::Result<Vec<u8>>>,
+    write: bool,
+    // where data is read into or written from
+    buf: Vec<u8>,
+    files: Vec<fs::File>,
+    ops: Vec<Op>,
+    error: Option<io::Error>,
+}
+
+// Op is a single read or write of a segment
+#[derive(Debug, Clone, Copy)]
+struct Op {
+    // index of the job in its batch, and of the file in the job's files
+    job: usize,
+    file: usize,
+    // where the segment is in the file, and in the job's buf
+    offset: u64,
+    at: usize,
+    len: usize,
+}
+
+/// the session wide uring thread, which is started on first use. None if io_uring isn't
+/// available, eg. on kernels older than 5.6 or where it's disabled
+pub(crate) fn shared() -> Option<Arc<dyn FileIo>> {
+    static SHARED: OnceLock<Option<Arc<UringIo>>> = OnceLock::new();
+    let uring = SHARED.get_or_init(|| UringIo::new().ok().map(Arc::new));
+    uring.clone().map(|uring| uring as Arc<dyn FileIo>)
+}
+
+impl UringIo {
+    fn new() -> io::Result<UringIo> {
+        let ring = IoUring::new(ENTRIES)?;
+        let (jobs, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("tsunami-uring".into())
+            .spawn(move || run(ring, rx))?;
+        Ok(UringIo { jobs })
+    }
+
+    fn send(
+        &self,
+        files: Arc<[FileSpan]>,
+        segments: Vec<Segment>,
+        write: Option<(Vec<u8>, Allocation)>,
+    ) -> BoxFuture<'static, io::Result<Vec<u8>>> {
+        let jobs = self.jobs.clone();
+        Box::pin(async move {
+            // files are opened, and created or allocated, off the ring's thread so a slow open
+            // doesn't hold up everyone else's io
+            let (done, rx) = oneshot::channel();
+            let open = move || Batched::open(&files, &segments, write, done);
+            let job = storage::blocking(open).await?;
+
+            let sent = jobs.send(job).is_ok();
+            match (sent, rx.await) {
+                (true, Ok(res)) => res,
+                _ => Err(io::ErrorKind::Other.into()),
+            }
+        })
+    }
+}
+
+impl FileIo for UringIo {
+    fn read(
+        &self,
+        files: Arc<[FileSpan]>,
+        segments: Vec<Segment>,
+    ) -> BoxFuture<'static, io::Result<Vec<u8>>> {
+        self.send(files, segments, None)
+    }
+
+    fn write(
+        &self,
+        files: Arc<[FileSpan]>,
+        segments: Vec<Segment>,
+        data: Vec<u8>,
+        allocation: Allocation,
+    ) -> BoxFuture<'static, io::Result<()>> {
+        let write = self.send(files, segments, Some((data, allocation)));
+        Box::pin(async move { write.await.map(drop) })
+    }
+}
+
+// take jobs off the queue, everything waiting at a time, until every sender is gone
+fn run(mut ring: IoUring, jobs: mpsc::Receiver<Batched>) {
+    while let Ok(job) = jobs.recv() {
+        let waiting = jobs.try_iter().take(BATCH - 1);
+        let mut batch: Vec<_> = [job].into_iter().chain(waiting).collect();
+
+        let mut ops = vec![];
+        for (i, batched) in batch.iter().enumerate() {
+            ops.extend(batched.ops.iter().map(|&op| Op { job: i, ..op }));
+        }
+        let res = submit(&mut ring, &ops, &mut batch);
+        for batched in batch {
+            batched.finish();
+        }
+
+        // ops the ring never took are still queued on it, they mustn't go to the kernel with
+        // the next batch
+        if res.is_err() {
+            match IoUring::new(ENTRIES) {
+                Ok(fresh) => ring = fresh,
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+// carry out ops on the ring, ENTRIES at a time, recording how each went against its job. once
+// the ring fails, the ops it never took fail and the rest of the batch isn't submitted
+fn submit(ring: &mut IoUring, ops: &[Op], batch: &mut [Batched]) -> io::Result<()> {
+    let mut res = Ok(());
+    for chunk in ops.chunks(ENTRIES as usize) {
+        let mut done = vec![false; chunk.len()];
+        if res.is_ok() {
+            let mut pushed = 0;
+            for (i, op) in chunk.iter().enumerate() {
+                let batched = &mut batch[op.job];
+                let fd = types::Fd(batched.files[op.file].as_raw_fd());
+                // SAFETY: at + len is within buf, which isn't touched until the op completes
+                let buf = unsafe { batched.buf.as_mut_ptr().add(op.at) };
+                let (len, offset) = (op.len as u32, op.offset);
+                let entry = match batched.write {
+                    true => opcode::Write::new(fd, buf, len).offset(offset).build(),
+                    false => opcode::Read::new(fd, buf, len).offset(offset).build(),
+                };
+
+                // SAFETY: the file and buffer outlive the op, which is waited on below
+                let entry = entry.user_data(i as u64);
+                if unsafe { ring.submission().push(&entry) }.is_err() {
+                    break;
+                }
+                pushed += 1;
+            }
+
+            res = complete(ring, chunk, &mut done[..pushed], batch);
+            if res.is_ok() && pushed < chunk.len() {
+                res = Err(io::ErrorKind::Other.into());
+            }
+        }
+
+        let failed = chunk.iter().zip(done).filter(|&(_, done)| !done);
+        for (op, _) in failed {
+            let error = &mut batch[op.job].error;
+            error.get_or_insert_with(|| io::ErrorKind::Other.into());
+        }
+    }
+    res
+}
+
+// submit the ops pushed onto the ring and wait for them to complete, marking each done as it
+// does. if the ring fails, the ops it already took are still waited for since the kernel uses
+// their files and buffers until they complete. the rest are left unsubmitted
+fn complete(
+    ring: &mut IoUring,
+    chunk: &[Op],
+    done: &mut [bool],
+    batch: &mut [Batched],
+) -> io::Result<()> {
+    let mut failed = None;
+    let mut left = done.len();
+    loop {
+        let in_flight = left - ring.submission().len();
+        let waited = match failed {
+            None if left == 0 => return Ok(()),
+            None => ring.submit_and_wait(left),
+            Some(_) if in_flight == 0 => break,
+            // SAFETY: nothing is submitted, this only waits for the ops already in flight
+            Some(_) => unsafe {
+                let getevents = EnterFlags::GETEVENTS.bits();
+                let submitter = ring.submitter();
+                submitter.enter::<libc::sigset_t>(0, in_flight as u32, getevents, None)
+            },
+        };
+        match waited {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            // the completion queue is full, it's emptied below
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {}
+            Err(e) if failed.is_none() => failed = Some(e),
+            // there's no telling when the kernel is done with the ops in flight, so their
+            // buffers and files are leaked rather than freed from under it
+            Err(e) => {
+                let in_flight = chunk.iter().zip(done.iter()).filter(|&(_, done)| !done);
+                for (op, _) in in_flight {
+                    let batched = &mut batch[op.job];
+                    mem::forget(mem::take(&mut batched.buf));
+                    mem::forget(mem::take(&mut batched.files));
+                }
+                return Err(e);
+            }
+        }
+
+        for entry in ring.completion() {
+            let i = entry.user_data() as usize;
+            batch[chunk[i].job].completed(chunk[i], entry.result());
+            done[i] = true;
+            left -= 1;
+        }
+    }
+    Err(failed.unwrap())
+}
+
+impl Batched {
+    // open a job's files and work out the op for each segment, padding needs none. files are
+    // created as needed for writes, see [storage::open]
+    fn open(
+        files: &[FileSpan],
+        segments: &[Segment],
+        write: Option<(Vec<u8>, Allocation)>,
+        done: oneshot::Sender<io::Result<Vec<u8>>>,
+    ) -> io::Result<Batched> {
+        let len = segments.iter().map(|s| s.length as usize).sum();
+        let (buf, allocation) = match write {
+            Some((data, allocation)) => (data, Some(allocation)),
+            None => (vec![0; len], None),
+        };
+        let mut batched = Batched {
+            done,
+            write: allocation.is_some(),
+            buf,
+            files: vec![],
+            ops: vec![],
+            error: None,
+        };
+
+        let mut at = 0;
+        for segment in segments {
+            let file = &files[segment.file];
+            let len = segment.length as usize;
+            if !file.padding {
+                let f = match allocation {
+                    Some(allocation) => storage::open(file, allocation)?,
+                    None => fs::File::open(&file.path)?,
+                };
+                batched.files.push(f);
+                batched.ops.push(Op {
+                    job: 0,
+                    file: batched.files.len() - 1,
+                    offset: segment.offset,
+                    at,
+                    len,
+                });
+            }
+            at += len;
+        }
+        Ok(batched)
+    }
+
+    // record the result of op, finishing off short reads and writes without the ring
+    fn completed(&mut self, op: Op, res: i32) {
+        if self.error.is_some() {
+            return;
+        }
+        if res < 0 {
+            self.error = Some(io::Error::from_raw_os_error(-res));
+            return;
+        }
+
+        let n = res as usize;
+        let (file, offset) = (&self.files[op.file], op.offset + n as u64);
+        let rest = &mut self.buf[op.at + n..op.at + op.len];
+        let res = match self.write {
+            true => file.write_all_at(rest, offset),
+            false => file.read_exact_at(rest, offset),
+        };
+        self.error = res.err();
+    }
+
+    fn finish(self) {
+        let res = match (self.error, self.write) {
+            (Some(e), _) => Err(e),
+            (None, true) => Ok(vec![]),
+            (None, false) => Ok(self.buf),
+        };
+        let _ = self.done.send(res);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf, process, sync::Arc};
+
+    use futures::future::join_all;
+
+    use crate::{
+        config::Allocation,
+        storage::{FileIo, FileSpan, Segment},
+        uring::UringIo,
+    };
+
+    #[tokio::test]
+    async fn uring() {
+        // io_uring may be unavailable, eg. in containers
+        let Ok(uring) = UringIo::new() else {
+            return;
+        };
+        let dir = env::temp_dir().join(format!("tsunami_uring_{}", process::id()));
+        let span = |name, length, padding| FileSpan {
+            path: dir.join(name),
+            length,
+            padding,
+        };
+        let files: Arc<[_]> = vec![
+            span("a", 6, false),
+            span("pad", 2, true),
+            span("sub/b", 32 * 1024, false),
+        ]
+        .into();
+        let segment = |file, offset, length| Segment {
+            file,
+            offset,
+            length,
+        };
+
+        // writes and reads queued together are carried out as a batch
+        let segments = vec![segment(0, 4, 2), segment(1, 0, 2), segment(2, 0, 4)];
+        let data = b"ef\0\0ghij".to_vec();
+        let write = uring.write(files.clone(), segments.clone(), data, Allocation::Sparse);
+        write.await.unwrap();
+        let blocks = (0..2u64).map(|i| {
+            let data = vec![i as u8 + 1; 16 * 1024 - 4];
+            let segments = vec![segment(2, i * 16 * 1024 + 4, 16 * 1024 - 4)];
+            uring.write(files.clone(), segments, data, Allocation::Sparse)
+        });
+        for res in join_all(blocks).await {
+            res.unwrap();
+        }
+
+        let reads = [segments, vec![segment(2, 16 * 1024 + 4, 16)]];
+        let reads = reads.map(|segments| uring.read(files.clone(), segments));
+        let [a, b] = join_all(reads).await.try_into().unwrap();
+        assert_eq!(a.unwrap(), b"ef\0\0ghij");
+        assert_eq!(b.unwrap(), [2; 16]);
+        assert_eq!(fs::read(dir.join("a")).unwrap(), b"\0\0\0\0ef");
+        assert!(!dir.join("pad").exists());
+
+        // files which don't exist can't be read
+        let missing: Arc<[_]> = vec![FileSpan {
+            path: PathBuf::from("/nonexistent/tsunami"),
+            length: 10,
+            padding: false,
+        }]
+        .into();
+        assert!(uring.read(missing, vec![segment(0, 0, 10)]).await.is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,24 +1,354 @@
-use std::{env::temp_dir, path::PathBuf};
+use std::{
+    borrow::Cow,
+    env::temp_dir,
+    fmt, fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
 
-use hyper::{body, body::Bytes, client::HttpConnector, Client};
-use lazy_static::lazy_static;
+use byteorder::{ByteOrder, BE};
+use hyper::{
+    body::{self, Bytes, HttpBody},
+    client::HttpConnector,
+    header::RANGE,
+    Body, Client, Request, StatusCode,
+};
+use tokio::time;
 
-use crate::error::Result;
+use crate::{
+    error::{Error, Result},
+    proxy::{Connector, Proxy},
+};
 
-pub async fn get_body(url: &str) -> Result<Bytes> {
-    lazy_static! {
-        static ref CLIENT: Client<HttpConnector> = Client::new();
+// default time allowed for a whole http request, see Config::http_timeout
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+// most of a busy http seed's answer read, which is the number of seconds to wait
+const RETRY_AFTER_LEN: u64 = 32;
+
+/// HttpClient sends tracker requests. clients are cheap to clone and clones share a connection
+/// pool
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    client: Client<Connector>,
+    timeout: Duration,
+}
+
+/// build an http client whose connections are bound to local_addr, or any address if None, and
+/// go through proxy if set. requests (including connecting) fail if they take longer than timeout
+pub fn http_client(
+    local_addr: Option<IpAddr>,
+    timeout: Option<Duration>,
+    proxy: Option<Proxy>,
+) -> HttpClient {
+    let timeout = timeout.unwrap_or(HTTP_TIMEOUT);
+
+    let mut conn = HttpConnector::new();
+    conn.set_local_address(local_addr);
+    conn.set_connect_timeout(Some(timeout));
+
+    HttpClient {
+        client: Client::builder().build(Connector::new(conn, proxy, local_addr)),
+        timeout,
+    }
+}
+
+pub async fn get_body(client: &HttpClient, req: Request<Body>) -> Result<Bytes> {
+    let get = async {
+        let resp = client.client.request(req).await?;
+        Ok(body::to_bytes(resp).await?)
+    };
+
+    time::timeout(client.timeout, get)
+        .await
+        .unwrap_or(Err(Error::Timeout))
+}
+
+/// fetch the bytes in range of the resource at uri, eg. part of a file from a web seed. servers
+/// which ignore the range and send the whole resource are handled too, without keeping what
+/// comes before range or reading past its end
+pub async fn get_range(client: &HttpClient, uri: &str, range: Range<u64>) -> Result<Bytes> {
+    let len = range.end - range.start;
+    let req = Request::get(uri)
+        .header(RANGE, format!("bytes={}-{}", range.start, range.end - 1))
+        .body(Body::empty())?;
+    let get = async {
+        let resp = client.client.request(req).await?;
+        let status = resp.status();
+        match status {
+            StatusCode::PARTIAL_CONTENT => match read_body(resp.into_body(), 0, len).await? {
+                (body, false) if body.len() as u64 == len => Ok(body),
+                _ => Err(Error::InvalidWebSeedResp),
+            },
+            StatusCode::OK => match read_body(resp.into_body(), range.start, len).await? {
+                (body, _) if body.len() as u64 == len => Ok(body),
+                _ => Err(Error::InvalidWebSeedResp),
+            },
+            status => Err(Error::HttpStatus(status.as_u16())),
+        }
+    };
+
+    time::timeout(client.timeout, get)
+        .await
+        .unwrap_or(Err(Error::Timeout))
+}
+
+/// fetch a whole len byte piece from a BEP-17 http seed at uri. a busy seed answers with the
+/// number of seconds to wait before asking again
+pub async fn get_piece(client: &HttpClient, uri: &str, len: u64) -> Result<Bytes> {
+    let req = Request::get(uri).body(Body::empty())?;
+    let get = async {
+        let resp = client.client.request(req).await?;
+        let status = resp.status();
+        match status {
+            StatusCode::OK => match read_body(resp.into_body(), 0, len).await? {
+                (body, false) if body.len() as u64 == len => Ok(body),
+                _ => Err(Error::InvalidWebSeedResp),
+            },
+            StatusCode::SERVICE_UNAVAILABLE => {
+                let (body, _) = read_body(resp.into_body(), 0, RETRY_AFTER_LEN).await?;
+                let retry = std::str::from_utf8(&body).ok().map(str::trim);
+                let retry = retry.and_then(|r| r.parse().ok());
+                Err(retry.map_or(Error::HttpStatus(503), Error::WebSeedBusy))
+            }
+            status => Err(Error::HttpStatus(status.as_u16())),
+        }
+    };
+
+    time::timeout(client.timeout, get)
+        .await
+        .unwrap_or(Err(Error::Timeout))
+}
+
+// read body up to limit bytes after dropping the first skip bytes, and whether there was more.
+// the rest is never read, so a server sending far more than asked for can't make us buffer it all
+async fn read_body(mut body: Body, mut skip: u64, limit: u64) -> Result<(Bytes, bool)> {
+    let mut buf = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        let skipped = skip.min(chunk.len() as u64);
+        skip -= skipped;
+        let chunk = &chunk[skipped as usize..];
+        let room = (limit - buf.len() as u64).min(chunk.len() as u64) as usize;
+        buf.extend_from_slice(&chunk[..room]);
+        if room < chunk.len() {
+            return Ok((buf.into(), true));
+        }
+    }
+    Ok((buf.into(), false))
+}
+
+/// an error for a peer, proxy or server which sent something we can't make sense of
+pub(crate) fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// an error for a proxy or server which turned down what we asked of it
+pub(crate) fn refused(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, msg)
+}
+
+/// parse peers in the compact format used by trackers, 4 bytes of IPv4 address followed by a 2
+/// byte port, both in network order. a trailing partial entry is ignored
+pub fn parse_compact_peers(buf: &[u8]) -> Vec<SocketAddr> {
+    buf.chunks_exact(6)
+        .map(|host| {
+            let ipv4 = Ipv4Addr::new(host[0], host[1], host[2], host[3]);
+            let port = BE::read_u16(&host[4..]);
+
+            SocketAddr::new(ipv4.into(), port)
+        })
+        .collect()
+}
+
+/// parse a 4 byte IPv4 or 16 byte IPv6 address, as in the `external ip` key of BEP-24
+pub fn parse_compact_ip(buf: &[u8]) -> Option<IpAddr> {
+    match buf.len() {
+        4 => Some(Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]).into()),
+        16 => Some(Ipv6Addr::from(<[u8; 16]>::try_from(buf).ok()?).into()),
+        _ => None,
+    }
+}
+
+/// same as [parse_compact_peers], but with 16 byte IPv6 addresses. See BEP-7 for more details
+pub fn parse_compact_peers6(buf: &[u8]) -> Vec<SocketAddr> {
+    buf.chunks_exact(18)
+        .map(|host| {
+            let ipv6: [u8; 16] = host[..16].try_into().unwrap();
+            let port = BE::read_u16(&host[16..]);
+
+            SocketAddr::new(Ipv6Addr::from(ipv6).into(), port)
+        })
+        .collect()
+}
+
+/// encode peers in the compact format, returning IPv4 and IPv6 peers separately
+pub fn encode_compact_peers(peers: &[SocketAddr]) -> (Vec<u8>, Vec<u8>) {
+    let (mut v4, mut v6) = (vec![], vec![]);
+    for peer in peers {
+        let buf = match peer.ip() {
+            IpAddr::V4(ip) => {
+                v4.extend_from_slice(&ip.octets());
+                &mut v4
+            }
+            IpAddr::V6(ip) => {
+                v6.extend_from_slice(&ip.octets());
+                &mut v6
+            }
+        };
+        buf.extend_from_slice(&peer.port().to_be_bytes());
+    }
+
+    (v4, v6)
+}
+
+/// PercentEncode displays bytes percent-encoded for use in a url's query string. only RFC 3986
+/// unreserved characters are left as is, so any bytes (eg. binary peer ids) can be encoded
+#[derive(Debug, Clone, Copy)]
+pub struct PercentEncode<'a>(pub &'a [u8]);
+
+impl fmt::Display for PercentEncode<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const HEXES: &[u8; 16] = b"0123456789ABCDEF";
+
+        for &b in self.0 {
+            match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    write!(f, "{}", b as char)?
+                }
+                _ => write!(
+                    f,
+                    "%{}{}",
+                    HEXES[b as usize >> 4] as char,
+                    HEXES[b as usize & 15] as char
+                )?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Lossy displays bytes which are usually, but not necessarily, utf8 such as peer ids. invalid
+/// sequences are shown as U+FFFD
+#[derive(Debug, Clone, Copy)]
+pub struct Lossy<'a>(pub &'a [u8]);
+
+impl fmt::Display for Lossy<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&String::from_utf8_lossy(self.0))
+    }
+}
+
+/// SanitizePolicy decides what happens to file names which aren't valid on this system
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SanitizePolicy {
+    /// refuse the torrent. "", "." and ".." path segments are dropped rather than refused
+    Reject,
+    /// replace offending characters with an underscore, eg. `a/b` becomes `a_b`
+    #[default]
+    ReplaceWithUnderscore,
+}
+
+// most filesystems limit a single path component to 255 bytes
+const MAX_NAME_LEN: usize = 255;
+
+/// sanitize a single path component from a torrent so it can safely be used as a file name on
+/// this OS. this returns None if name must be rejected; "", "." and ".." are always rejected
+pub fn sanitize_path(name: &str, policy: SanitizePolicy) -> Option<Cow<'_, str>> {
+    sanitize_path_for(name, policy, cfg!(windows))
+}
+
+fn sanitize_path_for(name: &str, policy: SanitizePolicy, windows: bool) -> Option<Cow<'_, str>> {
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+
+    let invalid_char =
+        |c: char| c.is_control() || c == '/' || c == '\\' || (windows && "<>:\"|?*".contains(c));
+    // windows silently strips trailing dots and spaces, so `a.` and `a` are the same file
+    let trailing = |c: char| windows && (c == '.' || c == ' ');
+    let reserved = windows && is_reserved_name(name);
+
+    if !name.contains(invalid_char)
+        && !name.ends_with(trailing)
+        && !reserved
+        && name.len() <= MAX_NAME_LEN
+    {
+        return Some(Cow::Borrowed(name));
+    } else if policy == SanitizePolicy::Reject {
+        return None;
+    }
+
+    let mut clean: String = name
+        .chars()
+        .map(|c| if invalid_char(c) { '_' } else { c })
+        .collect();
+
+    let len = clean.trim_end_matches(trailing).len();
+    let trimmed = clean.len() - len;
+    clean.truncate(len);
+    clean.push_str(&"_".repeat(trimmed));
+
+    // `CON.txt` -> `CON_.txt`
+    if reserved {
+        clean.insert(clean.find('.').unwrap_or(clean.len()), '_');
     }
 
-    let uri = url.parse()?;
-    let resp = CLIENT.get(uri).await?;
-    Ok(body::to_bytes(resp).await?)
+    // truncate long names, keeping short extensions intact
+    if clean.len() > MAX_NAME_LEN {
+        let ext = match clean.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() && ext.len() < 16 => format!(".{ext}"),
+            _ => String::new(),
+        };
+
+        let mut end = MAX_NAME_LEN - ext.len();
+        while !clean.is_char_boundary(end) {
+            end -= 1;
+        }
+        clean.truncate(end);
+        clean.push_str(&ext);
+    }
+
+    Some(Cow::Owned(clean))
+}
+
+/// check if name is a reserved device name on windows. these are reserved regardless of
+/// extension, so `nul.txt` is as invalid as `NUL`
+fn is_reserved_name(name: &str) -> bool {
+    const RESERVED: &[&str] = &[
+        "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+        "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    ];
+
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    RESERVED.iter().any(|r| r.eq_ignore_ascii_case(stem))
 }
 
-pub fn valid_path(p: &str) -> bool {
-    // todo: should we check for invalid paths? (incl os-specific blacklists) ?
+/// check that path lies strictly under root. every component below root must be a plain name
+/// (no `..`, prefixes or root dirs), and once symlinks in the existing part of path are resolved
+/// it must still be under root
+pub fn is_contained(root: &Path, path: &Path) -> bool {
+    let Ok(rel) = path.strip_prefix(root) else {
+        return false;
+    };
 
-    p != "." && p != ".." && p != ""
+    if rel.as_os_str().is_empty() || !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+        return false;
+    }
+
+    // nothing to resolve if root hasn't been created yet
+    let Ok(root) = fs::canonicalize(root) else {
+        return true;
+    };
+
+    // root exists, so at least one ancestor of path does
+    let existing = path.ancestors().find(|p| p.exists()).unwrap();
+    match fs::canonicalize(existing) {
+        Ok(existing) => existing.starts_with(root),
+        Err(_) => false,
+    }
 }
 
 pub fn download_dir() -> PathBuf {
@@ -26,3 +356,138 @@ pub fn download_dir() -> PathBuf {
         .or_else(dirs::home_dir)
         .unwrap_or_else(temp_dir)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::Path, process};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::{
+        error::Error,
+        utils::{
+            get_range, http_client, is_contained, sanitize_path_for, Lossy, PercentEncode,
+            SanitizePolicy::*,
+        },
+    };
+
+    #[test]
+    fn percent_encode() {
+        let cases: [(&[u8], &str); 4] = [
+            (b"", ""),
+            (b"az-AZ_09.~", "az-AZ_09.~"),
+            (b"a b&c=d/?", "a%20b%26c%3Dd%2F%3F"),
+            (b"\x00\x12\xab\xff", "%00%12%AB%FF"),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(PercentEncode(input).to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn lossy() {
+        assert_eq!(Lossy(b"-TS0001-").to_string(), "-TS0001-");
+        assert_eq!(Lossy(b"-UT3550-\xff\x00").to_string(), "-UT3550-\u{fffd}\0");
+    }
+
+    #[test]
+    fn sanitize_path() {
+        let long = "a".repeat(300) + ".txt";
+        let cases = [
+            ("file.txt", "file.txt", "file.txt"),
+            ("a/b\\c", "a_b_c", "a_b_c"),
+            ("bell\x07", "bell_", "bell_"),
+            ("what?.txt", "what?.txt", "what_.txt"),
+            ("trailing. .", "trailing. .", "trailing___"),
+            ("CON", "CON", "CON_"),
+            ("nul.tar.gz", "nul.tar.gz", "nul_.tar.gz"),
+            ("console", "console", "console"),
+        ];
+
+        for (input, unix, windows) in cases {
+            let replaced = |windows| sanitize_path_for(input, ReplaceWithUnderscore, windows);
+            assert_eq!(replaced(false).unwrap(), unix);
+            assert_eq!(replaced(true).unwrap(), windows);
+
+            let rejected = sanitize_path_for(input, Reject, true);
+            assert_eq!(rejected.is_some(), input == windows);
+        }
+
+        let truncated = sanitize_path_for(&long, ReplaceWithUnderscore, false).unwrap();
+        assert_eq!(truncated.len(), 255);
+        assert!(truncated.ends_with("a.txt"));
+
+        for input in ["", ".", ".."] {
+            assert_eq!(sanitize_path_for(input, ReplaceWithUnderscore, false), None);
+        }
+    }
+
+    #[test]
+    fn contained() {
+        let root = Path::new("/foo/torrent");
+        let cases = [
+            ("/foo/torrent/file", true),
+            ("/foo/torrent/dir/file", true),
+            ("/foo/torrent", false),
+            ("/foo/torrent/../file", false),
+            ("/foo/torrent/./file", true),
+            ("/foo/other/file", false),
+            ("/etc/passwd", false),
+        ];
+
+        for (path, expected) in cases {
+            assert_eq!(is_contained(root, Path::new(path)), expected, "{path}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn contained_symlink() {
+        let root = env::temp_dir().join(format!("tsunami-contained-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        std::os::unix::fs::symlink("/etc", root.join("link")).unwrap();
+
+        assert!(is_contained(&root, &root.join("file")));
+        assert!(!is_contained(&root, &root.join("link/passwd")));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn range() {
+        // a server which answers every request with status and far more data than asked for.
+        // byte i of the data is i % 256
+        let serve = |status: &'static str| async move {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/file", listener.local_addr().unwrap());
+            tokio::spawn(async move {
+                while let Ok((mut conn, _)) = listener.accept().await {
+                    let mut req = vec![];
+                    while !req.ends_with(b"\r\n\r\n") {
+                        req.push(conn.read_u8().await.unwrap());
+                    }
+                    let head = format!("HTTP/1.1 {status}\r\ncontent-length: 1048576\r\n\r\n");
+                    let _ = conn.write_all(head.as_bytes()).await;
+                    let data: Vec<_> = (0..1 << 20).map(|i| i as u8).collect();
+                    let _ = conn.write_all(&data).await;
+                }
+            });
+            url
+        };
+
+        // only what's needed of the whole file is read, but a range mustn't be any longer
+        let client = http_client(None, None, None);
+        let url = serve("200 OK").await;
+        let want = &[2, 3, 4, 5][..];
+        assert_eq!(get_range(&client, &url, 2..6).await.unwrap(), want);
+        let range = (1 << 19) + 2..(1 << 19) + 6;
+        assert_eq!(get_range(&client, &url, range).await.unwrap(), want);
+        let url = serve("206 Partial Content").await;
+        let res = get_range(&client, &url, 2..6).await;
+        assert!(matches!(res, Err(Error::InvalidWebSeedResp)));
+    }
+}
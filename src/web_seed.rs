@@ -0,0 +1,262 @@
+use std::ops::Range;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::{
+    error::Result,
+    storage::Layout,
+    torrent::Sha1Hash,
+    utils::{self, HttpClient, PercentEncode},
+};
+
+/// WebSeed is an http server with a copy of a torrent's files, listed in the `url-list` (BEP-19)
+/// or `httpseeds` (BEP-17) of its metainfo. pieces are fetched from it whole, either with a range
+/// request for each file the piece spans or by asking for the piece by index, and are checked
+/// like pieces from peers. a seed which fails or sends corrupt data is backed off, see
+/// [crate::torrent::Torrent::request_web_seeds]
+#[derive(Debug)]
+pub(crate) struct WebSeed {
+    url: String,
+    protocol: Protocol,
+    // piece being fetched or checked
+    piece: Option<u32>,
+    failures: u32,
+    retry_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+enum Protocol {
+    // BEP-19, the url of each of the torrent's files on the seed
+    Files(Vec<String>),
+    // BEP-17, the url pieces are asked for at, with the torrent's info hash in its query
+    Pieces(String),
+}
+
+/// Request is what to ask a seed for to get a piece
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Request {
+    // the parts of files making up the piece
+    Ranges(Vec<Segment>),
+    // the whole piece, which is this long, by index
+    Piece(String, u64),
+}
+
+// Segment is the part of a piece which lies in a single file, and where it's found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Segment {
+    // None for padding, which is all zeros
+    url: Option<String>,
+    range: Range<u64>,
+}
+
+impl WebSeed {
+    /// a BEP-19 seed at url for a torrent called name, with files at paths within it, or a single
+    /// file if paths is None. urls of multi-file torrents are directories the torrent's name and
+    /// file paths are appended to, as are urls of single-file torrents which end in a `/`. https
+    /// isn't supported, so those seeds are ignored
+    pub(crate) fn new(url: &str, name: &str, paths: Option<Vec<&[&str]>>) -> Option<WebSeed> {
+        if !url.starts_with("http://") {
+            return None;
+        }
+
+        let encode = |s: &str| PercentEncode(s.as_bytes()).to_string();
+        let files = match paths {
+            None if url.ends_with('/') => vec![format!("{url}{}", encode(name))],
+            None => vec![url.to_string()],
+            Some(paths) => {
+                let dir = url.trim_end_matches('/');
+                let file = |path: &[&str]| {
+                    let path: Vec<_> = path.iter().map(|p| encode(p)).collect();
+                    format!("{dir}/{}/{}", encode(name), path.join("/"))
+                };
+                paths.into_iter().map(file).collect()
+            }
+        };
+
+        Some(WebSeed::with_protocol(url, Protocol::Files(files)))
+    }
+
+    /// a BEP-17 seed at url for the torrent with info_hash. https isn't supported here either
+    pub(crate) fn http_seed(url: &str, info_hash: &Sha1Hash) -> Option<WebSeed> {
+        if !url.starts_with("http://") {
+            return None;
+        }
+
+        let sep = if url.contains('?') { '&' } else { '?' };
+        let pieces = format!("{url}{sep}info_hash={}", PercentEncode(info_hash));
+        Some(WebSeed::with_protocol(url, Protocol::Pieces(pieces)))
+    }
+
+    fn with_protocol(url: &str, protocol: Protocol) -> WebSeed {
+        WebSeed {
+            url: url.to_string(),
+            protocol,
+            piece: None,
+            failures: 0,
+            retry_at: DateTime::<Utc>::MIN_UTC,
+        }
+    }
+
+    pub(crate) fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub(crate) fn piece(&self) -> Option<u32> {
+        self.piece
+    }
+
+    /// whether the seed can be asked for a piece at now
+    pub(crate) fn is_ready(&self, now: DateTime<Utc>) -> bool {
+        self.piece.is_none() && now >= self.retry_at
+    }
+
+    /// start fetching piece, whose files are laid out as layout says, returning what to ask the
+    /// seed for
+    pub(crate) fn start(&mut self, piece: u32, layout: &Layout) -> Request {
+        self.piece = Some(piece);
+
+        let len = layout.piece_size(piece).unwrap_or_default();
+        let urls = match &self.protocol {
+            Protocol::Files(urls) => urls,
+            Protocol::Pieces(url) => return Request::Piece(format!("{url}&piece={piece}"), len),
+        };
+        let segments = layout.segments(piece, 0, len).unwrap_or_default();
+        let segments = segments.into_iter().map(|s| {
+            let padding = layout.files()[s.file].padding;
+            Segment {
+                url: urls.get(s.file).filter(|_| !padding).cloned(),
+                range: s.offset..s.offset + s.length,
+            }
+        });
+        Request::Ranges(segments.collect())
+    }
+
+    /// the piece being fetched arrived and passed its hash check
+    pub(crate) fn succeeded(&mut self) {
+        self.piece = None;
+        self.failures = 0;
+    }
+
+    /// the piece being fetched couldn't be, or failed its hash check, or the seed was busy. the
+    /// seed isn't asked for anything else until delay has passed
+    pub(crate) fn failed(&mut self, now: DateTime<Utc>, delay: Duration) {
+        self.piece = None;
+        self.failures += 1;
+        self.retry_at = now + delay;
+    }
+
+    /// give up on the piece being fetched without holding it against the seed, eg. once it
+    /// couldn't be written to disk
+    pub(crate) fn release(&mut self) {
+        self.piece = None;
+    }
+
+    pub(crate) fn failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+/// fetch a piece from a web seed, see [WebSeed::start]
+pub(crate) async fn fetch(http: &HttpClient, req: Request) -> Result<Vec<u8>> {
+    let segments = match req {
+        Request::Ranges(segments) => segments,
+        Request::Piece(url, len) => return Ok(utils::get_piece(http, &url, len).await?.to_vec()),
+    };
+    let mut piece = vec![];
+    for Segment { url, range } in segments {
+        match url {
+            Some(url) => piece.extend_from_slice(&utils::get_range(http, &url, range).await?),
+            None => piece.resize(piece.len() + (range.end - range.start) as usize, 0),
+        }
+    }
+    Ok(piece)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use chrono::{Duration, Utc};
+
+    use crate::{
+        storage::{FileSpan, Layout},
+        web_seed::{Protocol, Request, Segment, WebSeed},
+    };
+
+    fn files(seed: WebSeed) -> Vec<String> {
+        match seed.protocol {
+            Protocol::Files(files) => files,
+            Protocol::Pieces(_) => panic!("not a BEP-19 seed"),
+        }
+    }
+
+    #[test]
+    fn urls() {
+        let single = |url| files(WebSeed::new(url, "a b.iso", None).unwrap());
+        assert_eq!(single("http://x.com/a.iso"), ["http://x.com/a.iso"]);
+        assert_eq!(single("http://x.com/"), ["http://x.com/a%20b.iso"]);
+        assert!(WebSeed::new("https://x.com/", "a", None).is_none());
+
+        let paths = vec![&["dir", "a"][..], &["b"]];
+        let seed = WebSeed::new("http://x.com/files", "name", Some(paths)).unwrap();
+        let expected = ["http://x.com/files/name/dir/a", "http://x.com/files/name/b"];
+        assert_eq!(files(seed), expected);
+    }
+
+    #[test]
+    fn http_seed() {
+        // pieces are asked for whole, by index, whatever files they span
+        let mut seed = WebSeed::http_seed("http://x.com/seed", &[0xab; 20]).unwrap();
+        let files = vec![FileSpan {
+            path: PathBuf::new(),
+            length: 100,
+            padding: false,
+        }];
+        let layout = Layout::new(files, 30);
+        let url = format!("http://x.com/seed?info_hash={}&piece=3", "%AB".repeat(20));
+        assert_eq!(seed.start(3, &layout), Request::Piece(url, 10));
+        assert_eq!(seed.url(), "http://x.com/seed");
+
+        let seed = WebSeed::http_seed("http://x.com/seed?key=1", &[0; 20]).unwrap();
+        let Protocol::Pieces(url) = seed.protocol else {
+            panic!("not a BEP-17 seed");
+        };
+        assert!(url.starts_with("http://x.com/seed?key=1&info_hash=%00"));
+        assert!(WebSeed::http_seed("https://x.com/seed", &[0; 20]).is_none());
+    }
+
+    #[test]
+    fn segments() {
+        let paths = vec![&["a"][..], &[".pad", "2"], &["b"]];
+        let mut seed = WebSeed::new("http://x.com/", "t", Some(paths)).unwrap();
+        let span = |length, padding| FileSpan {
+            path: PathBuf::new(),
+            length,
+            padding,
+        };
+        let layout = Layout::new(vec![span(6, false), span(2, true), span(10, false)], 12);
+
+        // a piece spanning every file is split at each, padding isn't fetched
+        let segment = |url: Option<&str>, range| Segment {
+            url: url.map(String::from),
+            range,
+        };
+        let expected = vec![
+            segment(Some("http://x.com/t/a"), 0..6),
+            segment(None, 0..2),
+            segment(Some("http://x.com/t/b"), 0..4),
+        ];
+        assert_eq!(seed.start(0, &layout), Request::Ranges(expected));
+        assert!(!seed.is_ready(Utc::now()));
+
+        // a failed seed waits before it's asked again
+        let now = Utc::now();
+        seed.failed(now, Duration::seconds(10));
+        assert!(!seed.is_ready(now));
+        assert!(seed.is_ready(now + Duration::seconds(10)));
+        let expected = vec![segment(Some("http://x.com/t/b"), 4..10)];
+        assert_eq!(seed.start(1, &layout), Request::Ranges(expected));
+        seed.succeeded();
+        assert_eq!(seed.failures(), 0);
+    }
+}
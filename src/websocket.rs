@@ -0,0 +1,332 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+};
+
+use chrono::Utc;
+use hyper::Uri;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use ring::digest;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufStream},
+    net::{self, TcpSocket, TcpStream},
+};
+
+use crate::utils::{invalid, refused};
+
+// appended to our key by the server to prove it speaks the protocol, see RFC 6455 section 1.3
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// longest upgrade response (status line and headers) we accept
+const MAX_UPGRADE_RESP: usize = 8 * 1024;
+// longest message we accept, tracker messages are a few hundred bytes
+const MAX_MSG_LEN: usize = 1024 * 1024;
+
+// frame opcodes
+const CONTINUATION: u8 = 0;
+const TEXT: u8 = 1;
+const BINARY: u8 = 2;
+const CLOSE: u8 = 8;
+const PING: u8 = 9;
+const PONG: u8 = 10;
+
+/// WebSocket is the client end of an unencrypted (`ws://`) WebSocket connection, see RFC 6455.
+/// only what trackers need is supported: text messages, pings and closing
+#[derive(Debug)]
+pub struct WebSocket {
+    conn: BufStream<TcpStream>,
+    rng: SmallRng,
+}
+
+impl WebSocket {
+    /// connect to url and upgrade the connection to a WebSocket. our end of the connection is
+    /// bound to local_addr if one is given
+    pub async fn connect(url: &str, local_addr: Option<IpAddr>) -> io::Result<WebSocket> {
+        let uri: Uri = url.parse().map_err(|_| invalid("invalid url"))?;
+        let host = uri.host().ok_or_else(|| invalid("url has no host"))?;
+        let port = uri.port_u16().unwrap_or(80);
+        let path = uri.path_and_query().map_or("/", |p| p.as_str());
+
+        let stream = Self::dial(host, port, local_addr).await?;
+        let mut ws = WebSocket {
+            conn: BufStream::new(stream),
+            rng: SmallRng::seed_from_u64(Utc::now().timestamp_millis() as u64),
+        };
+
+        let mut key = [0; 16];
+        ws.rng.fill(&mut key);
+        let key = base64::encode(key);
+        let req = format!(
+            "GET {path} HTTP/1.1\r\nHost: {host}:{port}\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+        );
+        ws.conn.write_all(req.as_bytes()).await?;
+        ws.conn.flush().await?;
+
+        let mut resp = vec![];
+        while !resp.ends_with(b"\r\n\r\n") {
+            if resp.len() == MAX_UPGRADE_RESP {
+                return Err(invalid("upgrade response too long"));
+            }
+            resp.push(ws.conn.read_u8().await?);
+        }
+
+        let resp = String::from_utf8_lossy(&resp);
+        let mut lines = resp.split("\r\n");
+        if lines.next().and_then(|status| status.split(' ').nth(1)) != Some("101") {
+            return Err(refused("server refused websocket upgrade"));
+        }
+
+        let accept = digest::digest(
+            &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            format!("{key}{ACCEPT_GUID}").as_bytes(),
+        );
+        let accept = base64::encode(accept);
+        let accepted = lines
+            .filter_map(|line| line.split_once(':'))
+            .any(|(name, value)| {
+                name.eq_ignore_ascii_case("sec-websocket-accept") && value.trim() == accept
+            });
+        if !accepted {
+            return Err(invalid("server sent an invalid websocket accept key"));
+        }
+
+        Ok(ws)
+    }
+
+    async fn dial(host: &str, port: u16, local_addr: Option<IpAddr>) -> io::Result<TcpStream> {
+        // ipv6 hosts come bracketed from the uri
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let mut last_err = io::Error::from(io::ErrorKind::AddrNotAvailable);
+
+        for addr in net::lookup_host((host, port)).await? {
+            if local_addr.is_some_and(|local| local.is_ipv4() != addr.is_ipv4()) {
+                continue;
+            }
+
+            let socket = match addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            if let Some(local) = local_addr {
+                socket.bind(SocketAddr::new(local, 0))?;
+            }
+
+            match socket.connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = e,
+            }
+        }
+
+        Err(last_err)
+    }
+
+    pub async fn send_text(&mut self, msg: &str) -> io::Result<()> {
+        self.send_frame(TEXT, msg.as_bytes()).await
+    }
+
+    /// wait for the next text message, answering any pings in the meantime. binary messages
+    /// are skipped. a closed connection is an [io::ErrorKind::ConnectionAborted] error
+    pub async fn recv_text(&mut self) -> io::Result<String> {
+        let mut msg = vec![];
+        let mut msg_opcode = None;
+
+        loop {
+            let (fin, opcode, payload) = self.recv_frame().await?;
+            match opcode {
+                PING => self.send_frame(PONG, &payload).await?,
+                PONG => {}
+                CLOSE => {
+                    let _ = self.send_frame(CLOSE, &payload).await;
+                    return Err(io::ErrorKind::ConnectionAborted.into());
+                }
+                TEXT | BINARY | CONTINUATION => {
+                    // continuations belong to the last text or binary frame
+                    if opcode != CONTINUATION {
+                        msg.clear();
+                        msg_opcode = Some(opcode);
+                    }
+                    if msg.len() + payload.len() > MAX_MSG_LEN {
+                        return Err(invalid("message too long"));
+                    }
+                    msg.extend_from_slice(&payload);
+
+                    if fin && msg_opcode == Some(TEXT) {
+                        return String::from_utf8(msg).map_err(|_| invalid("message isn't utf8"));
+                    }
+                }
+                _ => return Err(invalid("unknown opcode")),
+            }
+        }
+    }
+
+    /// start closing the connection. the server closes its end once it sees this
+    pub async fn close(&mut self) -> io::Result<()> {
+        self.send_frame(CLOSE, &[]).await
+    }
+
+    async fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        // clients must mask every frame they send
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        let mut mask = [0; 4];
+        self.rng.fill(&mut mask);
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+
+        self.conn.write_all(&frame).await?;
+        self.conn.flush().await
+    }
+
+    /// read a frame, returning whether it's the final frame of a message, its opcode and its
+    /// payload
+    async fn recv_frame(&mut self) -> io::Result<(bool, u8, Vec<u8>)> {
+        let mut head = [0; 2];
+        self.conn.read_exact(&mut head).await?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0f;
+
+        let len = match head[1] & 0x7f {
+            126 => self.conn.read_u16().await? as usize,
+            127 => self.conn.read_u64().await? as usize,
+            len => len as usize,
+        };
+        if len > MAX_MSG_LEN {
+            return Err(invalid("frame too long"));
+        }
+
+        // servers shouldn't mask their frames, but unmask them if they do
+        let mut mask = None;
+        if head[1] & 0x80 != 0 {
+            let mut key = [0; 4];
+            self.conn.read_exact(&mut key).await?;
+            mask = Some(key);
+        }
+
+        let mut payload = vec![0; len];
+        self.conn.read_exact(&mut payload).await?;
+        if let Some(mask) = mask {
+            payload
+                .iter_mut()
+                .zip(mask.iter().cycle())
+                .for_each(|(b, m)| *b ^= m);
+        }
+
+        Ok((fin, opcode, payload))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use ring::digest;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{WebSocket, ACCEPT_GUID};
+
+    /// act as a WebSocket server, completing the upgrade request sent over stream. returns the
+    /// request
+    pub(crate) async fn accept(stream: &mut TcpStream) -> String {
+        let mut req = vec![];
+        while !req.ends_with(b"\r\n\r\n") {
+            req.push(stream.read_u8().await.unwrap());
+        }
+        let req = String::from_utf8(req).unwrap();
+
+        let key = req
+            .split("\r\n")
+            .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+            .unwrap();
+        let accept = format!("{key}{ACCEPT_GUID}");
+        let accept = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, accept.as_bytes());
+        let resp = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            base64::encode(accept)
+        );
+        stream.write_all(resp.as_bytes()).await.unwrap();
+
+        req
+    }
+
+    /// read a single masked frame sent by a client, returning its header and unmasked payload
+    pub(crate) async fn read_frame(stream: &mut TcpStream) -> ([u8; 2], Vec<u8>) {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).await.unwrap();
+        let len = match head[1] & 0x7f {
+            126 => stream.read_u16().await.unwrap() as usize,
+            127 => stream.read_u64().await.unwrap() as usize,
+            len => len as usize,
+        };
+
+        let mut mask = [0; 4];
+        stream.read_exact(&mut mask).await.unwrap();
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await.unwrap();
+        payload
+            .iter_mut()
+            .zip(mask.iter().cycle())
+            .for_each(|(b, m)| *b ^= m);
+
+        (head, payload)
+    }
+
+    /// an unmasked text frame, as a server sends them
+    pub(crate) fn text_frame(msg: &str) -> Vec<u8> {
+        let mut frame = vec![0x81];
+        match msg.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(msg.as_bytes());
+        frame
+    }
+
+    #[tokio::test]
+    async fn echo() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/announce", listener.local_addr().unwrap());
+
+        let server = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let req = accept(&mut stream).await;
+            assert!(req.starts_with("GET /announce HTTP/1.1\r\n"));
+
+            let (head, msg) = read_frame(&mut stream).await;
+            assert_eq!(head, [0x81, 0x82]);
+            assert_eq!(msg, b"hi");
+
+            // a ping, which must be answered, then "hello" split over two frames
+            stream.write_all(b"\x89\x01p").await.unwrap();
+            stream.write_all(b"\x01\x03hel\x80\x02lo").await.unwrap();
+
+            let (head, pong) = read_frame(&mut stream).await;
+            assert_eq!(head, [0x8a, 0x81]);
+            assert_eq!(pong, b"p");
+        };
+
+        let client = async {
+            let mut ws = WebSocket::connect(&url, None).await.unwrap();
+            ws.send_text("hi").await.unwrap();
+            ws.recv_text().await.unwrap()
+        };
+
+        let (msg, _) = futures::join!(client, server);
+        assert_eq!(msg, "hello");
+    }
+}